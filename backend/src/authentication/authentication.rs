@@ -53,6 +53,16 @@ pub async fn auth_middleware(
     Ok(next.run(req).await)
 }
 
+/// Whether a wallet is listed in `ADMIN_WALLETS` (comma separated). Admins
+/// log in with the `admin` role and settle disputes.
+pub fn is_admin_wallet(wallet_address: &str) -> bool {
+    env::var("ADMIN_WALLETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .any(|admin| !admin.is_empty() && admin.eq_ignore_ascii_case(wallet_address))
+}
+
 pub async fn login_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<LoginRequest>,
@@ -61,7 +71,9 @@ pub async fn login_handler(
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
-    let role = if let Some(_user) = user {
+    let role = if is_admin_wallet(&payload.wallet_address) {
+        "admin".to_string()
+    } else if let Some(_user) = user {
         let store = sqlx::query!(
             r#"
             SELECT id FROM stores WHERE owner_address = $1
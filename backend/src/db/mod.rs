@@ -1,2 +1,3 @@
 pub mod models;
 pub mod operations;
+pub mod order_status;
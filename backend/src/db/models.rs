@@ -1,3 +1,4 @@
+pub use crate::db::order_status::{OrderStatus, PaymentStatus};
 use serde::{Deserialize, Serialize};
 use sqlx::types::Decimal;
 use uuid::Uuid;
@@ -10,6 +11,10 @@ pub struct Store {
     pub description: Option<String>,
     pub owner_address: String,
     pub share_link: String,
+    pub accepts_native_celo: bool,
+    pub mint_receipts: bool,
+    /// Wallet payments are sent to; the owner wallet when `None`.
+    pub payout_address: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -20,26 +25,164 @@ pub struct Product {
     pub image_cid: Option<String>,
     pub description: Option<String>,
     pub price: Decimal,
+    pub currency: String,
     pub quantity: i32,
 }
 
+/// One store's share of a checkout. What was bought is in its `OrderItem`s;
+/// `amount` and `platform_fee` are their totals.
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Order {
     pub id: Uuid,
     pub order_id: String,
     pub store_id: Uuid,
-    pub product_id: Uuid,
     pub user_id: Option<Uuid>,
     pub buyer_address: String,
     pub seller_address: String,
     pub amount: Decimal,
-    pub status: String,
-    pub payment_status: String,
+    pub status: OrderStatus,
+    pub payment_status: PaymentStatus,
     pub transaction_hash: Option<String>,
+    pub platform_fee: Decimal,
+    /// Currency the product was listed in; `amount` is in the payment token.
+    pub currency: String,
+    pub list_amount: Decimal,
+    pub exchange_rate: Decimal,
+    pub receipt_token_id: Option<i64>,
+    /// The store wallet this order is paid to.
+    pub payout_address: String,
     pub created_at: Option<chrono::DateTime<chrono::Utc>>,
     pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// A product on an order, with its price as it was when it was bought.
+/// `unit_price` and `amount` are in the payment token, `list_price` in
+/// `currency`.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OrderItem {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub product_id: Uuid,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    pub list_price: Decimal,
+    pub currency: String,
+    pub exchange_rate: Decimal,
+    pub amount: Decimal,
+    pub platform_fee: Decimal,
+}
+
+/// One change to an order's `status` or `payment_status`. `from_status` is
+/// empty for the value the order was created with. `actor` is the wallet
+/// that made the change, or `system` when it followed from a payment, an
+/// escrow settlement or a refund.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OrderEvent {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub field: String,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub actor: String,
+    pub note: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderDetails {
+    #[serde(flatten)]
+    pub order: Order,
+    pub items: Vec<OrderItem>,
+    /// The order's history, oldest first.
+    pub events: Vec<OrderEvent>,
+    pub shipments: Vec<ShipmentDetails>,
+}
+
+/// A parcel sent for an order. `status` is `in_transit` until the carrier
+/// reports it `delivered`.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Shipment {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub carrier: String,
+    pub tracking_code: String,
+    pub tracking_url: Option<String>,
+    pub status: String,
+    pub estimated_delivery: Option<chrono::DateTime<chrono::Utc>>,
+    pub delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// How many of an order item went in a shipment.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ShipmentItem {
+    pub shipment_id: Uuid,
+    pub order_item_id: Uuid,
+    pub quantity: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShipmentDetails {
+    #[serde(flatten)]
+    pub shipment: Shipment,
+    pub items: Vec<ShipmentItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShipmentItemRequest {
+    pub order_item_id: Uuid,
+    pub quantity: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShipmentRequest {
+    pub carrier: String,
+    pub tracking_code: String,
+    /// Tracking page with `{tracking_code}` where the code goes. Defaults to
+    /// the carrier's own page for carriers we know.
+    pub tracking_url_template: Option<String>,
+    pub estimated_delivery: Option<chrono::DateTime<chrono::Utc>>,
+    /// Everything not shipped yet when empty.
+    #[serde(default)]
+    pub items: Vec<ShipmentItemRequest>,
+}
+
+/// Everything a checkout created: one order per store, paid by one transaction.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckoutSummary {
+    pub transaction_hash: String,
+    pub token: String,
+    pub total_amount: Decimal,
+    pub platform_fee: Decimal,
+    pub item_count: i32,
+    pub orders: Vec<OrderDetails>,
+}
+
+/// What the chain says about an order's payment, with explorer links.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderPayment {
+    pub order_id: Uuid,
+    pub transaction_hash: Option<String>,
+    /// `unpaid`, `not_found`, `pending`, `failed` or `confirmed`. A mined
+    /// payment stays `pending` until it has the required confirmations.
+    pub status: String,
+    pub confirmations: u64,
+    pub required_confirmations: u64,
+    pub block_number: Option<u64>,
+    pub block_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    pub token_symbol: String,
+    pub token_address: String,
+    /// What the transaction paid the order's payout address.
+    pub amount: Option<Decimal>,
+    pub payer: Option<String>,
+    pub chain_id: u64,
+    pub transaction_url: Option<String>,
+    pub payer_url: Option<String>,
+    pub token_url: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
     pub id: Uuid,
@@ -70,6 +213,9 @@ pub struct CreateStoreRequest {
     pub image: Option<String>,
     pub description: Option<String>,
     pub owner_address: String,
+    pub accepts_native_celo: Option<bool>,
+    /// Mint the buyer an NFT receipt when an order is delivered.
+    pub mint_receipts: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -78,6 +224,8 @@ pub struct AddProductRequest {
     pub image: Option<String>,
     pub description: Option<String>,
     pub price: Decimal,
+    /// ISO 4217 code, defaults to USD.
+    pub currency: Option<String>,
     pub quantity: i32,
 }
 
@@ -87,8 +235,12 @@ pub struct CreateOrderRequest {
     pub product_id: Uuid,
     pub user_id: Uuid,
     pub buyer_address: String,
-    pub seller_address: String,
-    pub amount: Decimal,
+    #[serde(default = "default_order_quantity")]
+    pub quantity: i32,
+}
+
+fn default_order_quantity() -> i32 {
+    1
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -112,6 +264,8 @@ pub struct CheckoutRequest {
     pub buyer_address: String,
     pub payment_type: String,
     pub transaction_hash: String,
+    #[serde(default)]
+    pub payment_intent_id: Option<Uuid>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -127,4 +281,488 @@ pub struct LoginResponse {
 #[derive(Deserialize)]
 pub struct UpdateOrderStatusRequest {
     pub status: String,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelOrderRequest {
+    pub reason: Option<String>,
+}
+
+/// The same status change for several of a store's orders at once.
+#[derive(Debug, Deserialize)]
+pub struct BulkUpdateOrderStatusRequest {
+    pub order_ids: Vec<Uuid>,
+    pub status: String,
+    pub note: Option<String>,
+}
+
+/// How one order of a bulk update went: the updated order, or why not.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkOrderStatusResult {
+    pub order_id: Uuid,
+    pub order: Option<Order>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSort {
+    CreatedAt,
+    Amount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+/// Filters and paging for order lists. Orders are newest first unless
+/// `sort`/`direction` say otherwise; `from` is inclusive and `to` exclusive.
+/// Pass a page's `next_cursor` as `cursor` to fetch the page after it.
+#[derive(Debug, Default, Deserialize)]
+pub struct OrderListQuery {
+    pub status: Option<OrderStatus>,
+    pub payment_status: Option<PaymentStatus>,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    pub sort: Option<OrderSort>,
+    pub direction: Option<SortDirection>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderPage {
+    pub orders: Vec<Order>,
+    /// `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentIntent {
+    pub id: Uuid,
+    pub reference: String,
+    pub user_id: Uuid,
+    pub cart_id: Uuid,
+    pub buyer_address: String,
+    pub token_symbol: String,
+    pub token_address: String,
+    pub total_amount: Decimal,
+    pub status: String,
+    pub transaction_hash: Option<String>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentIntentRecipient {
+    pub id: Uuid,
+    pub payment_intent_id: Uuid,
+    pub store_id: Option<Uuid>,
+    pub recipient_address: String,
+    pub amount: Decimal,
+    pub paid_transaction_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentIntentItem {
+    pub id: Uuid,
+    pub payment_intent_id: Uuid,
+    pub product_id: Uuid,
+    pub store_id: Uuid,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    pub currency: String,
+    pub list_price: Decimal,
+    pub exchange_rate: Decimal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentIntentResponse {
+    #[serde(flatten)]
+    pub intent: PaymentIntent,
+    pub recipients: Vec<PaymentIntentRecipient>,
+    pub items: Vec<PaymentIntentItem>,
+    /// One wallet-ready request per recipient, since a URI pays a single address.
+    pub payment_requests: Vec<PaymentRequest>,
+}
+
+/// An EIP-681 payment request for one recipient of a payment intent.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentRequest {
+    pub recipient_id: Uuid,
+    pub recipient_address: String,
+    pub amount: Decimal,
+    pub uri: String,
+    pub qr_code_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentQrQuery {
+    /// Which recipient to render; may be left out when there is only one.
+    pub recipient_id: Option<Uuid>,
+    /// `svg` (default) or `png`.
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatePaymentIntentRequest {
+    pub cart_id: Uuid,
+    pub buyer_address: String,
+    #[serde(default = "default_payment_token")]
+    pub token: String,
+}
+
+fn default_payment_token() -> String {
+    "cUSD".to_string()
+}
+
+/// Which payment to check the buyer's wallet against: an open payment intent,
+/// or the cart priced in `token`.
+#[derive(Debug, Deserialize)]
+pub struct PaymentPrecheckQuery {
+    pub buyer_address: String,
+    #[serde(default)]
+    pub cart_id: Option<Uuid>,
+    #[serde(default)]
+    pub payment_intent_id: Option<Uuid>,
+    #[serde(default = "default_payment_token")]
+    pub token: String,
+}
+
+/// Whether a wallet can cover a payment and its network fee. For CELO
+/// payments the fee comes out of the same balance, so it is part of
+/// `shortfall` and `fee_shortfall` stays zero.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentPrecheck {
+    pub buyer_address: String,
+    pub token_symbol: String,
+    pub amount_due: Decimal,
+    pub token_balance: Decimal,
+    pub native_balance: Decimal,
+    /// Transfers the buyer has to send, one per recipient.
+    pub transfers: i32,
+    /// When `amount_due` lapses: the intent's expiry, or for a cart the
+    /// earliest of the price quotes it was priced at.
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    /// Estimated gas for those transfers at the current gas price, in CELO.
+    pub estimated_network_fee: Decimal,
+    pub shortfall: Decimal,
+    pub fee_shortfall: Decimal,
+    /// What the escrow contract may pull, when escrow is enabled for the token.
+    pub escrow_allowance: Option<Decimal>,
+    pub can_afford: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ChainTransfer {
+    pub id: Uuid,
+    pub transaction_hash: String,
+    pub log_index: i64,
+    pub block_number: i64,
+    pub token_address: String,
+    pub from_address: String,
+    pub to_address: String,
+    pub amount: Decimal,
+    pub status: String,
+    pub payment_intent_id: Option<Uuid>,
+    pub order_id: Option<Uuid>,
+    pub note: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug)]
+pub struct NewChainTransfer {
+    pub transaction_hash: String,
+    pub log_index: i64,
+    pub block_number: i64,
+    pub token_address: String,
+    pub from_address: String,
+    pub to_address: String,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResolveTransferRequest {
+    pub order_id: Option<Uuid>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Escrow {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub contract_address: String,
+    pub buyer_address: String,
+    pub seller_address: String,
+    pub token_address: String,
+    pub amount: Decimal,
+    pub status: String,
+    pub deposit_transaction_hash: String,
+    pub release_transaction_hash: Option<String>,
+    pub refund_transaction_hash: Option<String>,
+    pub release_after: chrono::DateTime<chrono::Utc>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug)]
+pub struct NewEscrow {
+    pub order_id: Uuid,
+    pub contract_address: String,
+    pub buyer_address: String,
+    pub seller_address: String,
+    pub token_address: String,
+    pub amount: Decimal,
+    pub deposit_transaction_hash: String,
+    pub release_after: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EscrowDepositRequest {
+    pub transaction_hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Refund {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub requested_by: String,
+    pub amount: Decimal,
+    pub reason: Option<String>,
+    pub status: String,
+    pub token_symbol: String,
+    pub transaction_hash: Option<String>,
+    /// When the seller must have sent the money back by; set for refunds
+    /// owed on cancelled orders.
+    pub due_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRefundRequest {
+    /// Defaults to everything not yet refunded.
+    pub amount: Option<Decimal>,
+    pub reason: Option<String>,
+    /// Sellers may submit the refund transaction straight away.
+    pub transaction_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompleteRefundRequest {
+    pub transaction_hash: String,
+}
+
+/// A buyer asking to send back some of a delivered order line. `status`
+/// goes `requested` → `accepted` or `rejected` by the seller; a request the
+/// seller rejected or has not answered can become `disputed`, which an admin
+/// `resolved`. `refund_id` and `inventory_movement_id` are what settling it did.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ReturnRequest {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub order_item_id: Uuid,
+    pub quantity: i32,
+    pub reason_code: String,
+    pub details: Option<String>,
+    pub evidence_cids: Vec<String>,
+    pub status: String,
+    pub requested_by: String,
+    pub seller_note: Option<String>,
+    pub dispute_reason: Option<String>,
+    pub resolution_note: Option<String>,
+    pub resolved_by: Option<String>,
+    pub refund_id: Option<Uuid>,
+    pub inventory_movement_id: Option<Uuid>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateReturnRequest {
+    pub order_item_id: Uuid,
+    pub quantity: i32,
+    /// One of `damaged`, `wrong_item`, `not_as_described`, `missing_parts` or `other`.
+    pub reason_code: String,
+    pub details: Option<String>,
+    /// Base64 images, uploaded to IPFS as evidence.
+    #[serde(default)]
+    pub photos: Vec<String>,
+}
+
+/// A seller accepting a return. The refund defaults to the returned items'
+/// share of the order line.
+#[derive(Debug, Deserialize)]
+pub struct AcceptReturnRequest {
+    pub amount: Option<Decimal>,
+    /// Put the returned items back in stock.
+    #[serde(default)]
+    pub restock: bool,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RejectReturnRequest {
+    pub note: Option<String>,
+}
+
+/// An admin settling a dispute, for the buyer (`refund`) or the seller.
+#[derive(Debug, Deserialize)]
+pub struct ResolveReturnRequest {
+    pub refund: bool,
+    pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub restock: bool,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EscalateReturnRequest {
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ChainTransaction {
+    pub id: Uuid,
+    pub kind: String,
+    pub order_id: Option<Uuid>,
+    pub to_address: String,
+    /// Hex calldata; filled in just before sending for kinds that need preparing.
+    pub data: Option<String>,
+    pub status: String,
+    pub nonce: Option<i64>,
+    /// The latest broadcast; `transaction_hashes` keeps every one sent under `nonce`.
+    pub transaction_hash: Option<String>,
+    pub transaction_hashes: Vec<String>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: chrono::DateTime<chrono::Utc>,
+    pub sent_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// One signed change to a product's stock. `order_id` links sales and
+/// refunds to the order they came from; `created_by` is the wallet of the
+/// owner who made a manual entry.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct InventoryMovement {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub quantity_change: i32,
+    pub reason: String,
+    pub order_id: Option<Uuid>,
+    pub note: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A manual stock change by the store owner: a `restock` or an `adjustment`.
+#[derive(Debug, Deserialize)]
+pub struct RecordInventoryMovementRequest {
+    pub quantity_change: i32,
+    pub reason: String,
+    pub note: Option<String>,
+}
+
+/// A product's stock next to the total of its ledger, which should agree.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InventoryLedger {
+    pub product_id: Uuid,
+    pub stock: i32,
+    pub ledger_stock: i64,
+    pub in_sync: bool,
+    pub movements: Vec<InventoryMovement>,
+}
+
+/// A rule that only lets holders of an NFT buy a product (`discount_bps` is
+/// `None`) or gives them a discount on it.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ProductTokenGate {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub contract_address: String,
+    /// `None` matches any token from the contract.
+    pub token_id: Option<i64>,
+    pub discount_bps: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateProductTokenGateRequest {
+    pub contract_address: String,
+    pub token_id: Option<i64>,
+    pub discount_bps: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PayoutAddressChange {
+    pub id: Uuid,
+    pub store_id: Uuid,
+    pub previous_address: Option<String>,
+    pub payout_address: Option<String>,
+    pub signed_by: String,
+    pub message: String,
+    pub signature: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A payout address change, signed by the owner wallet over the message from
+/// `GET /stores/:id/payout-address/message`. `None` pays the owner wallet again.
+#[derive(Debug, Deserialize)]
+pub struct UpdatePayoutAddressRequest {
+    pub payout_address: Option<String>,
+    /// Unix timestamp the message was issued at.
+    pub issued_at: i64,
+    pub signature: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayoutAddressMessageQuery {
+    pub payout_address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PayoutAddressMessage {
+    pub message: String,
+    pub issued_at: i64,
+}
+
+/// Units of a product held for a cart while its buyer pays. Holds made when
+/// checkout starts have no `payment_intent_id`; holds for an intent expire
+/// with it. `status` is `active`, `released` or `consumed`.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct StockReservation {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub cart_id: Uuid,
+    pub payment_intent_id: Option<Uuid>,
+    pub quantity: i32,
+    pub status: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReserveStockRequest {
+    pub cart_id: Uuid,
+}
+
+/// The response saved for a caller's `Idempotency-Key`. `status_code` is
+/// empty while the first request is still running.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct IdempotencyRecord {
+    pub owner: String,
+    pub idempotency_key: String,
+    pub request_path: String,
+    pub fingerprint: String,
+    pub status_code: Option<i32>,
+    pub content_type: Option<String>,
+    pub response_body: Option<Vec<u8>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub locked_until: Option<chrono::DateTime<chrono::Utc>>,
 }
@@ -1,12 +1,29 @@
 use crate::db::models::*;
-use crate::routes::user_handler::verify_payment;
+use crate::routes::user_handler::{min_confirmations, verify_payment, verify_transfer};
+use crate::utils::balances::{erc20_allowance, erc20_balance, transfer_gas};
+use crate::utils::carriers::{default_tracking_url_template, tracking_url};
+use crate::utils::chain::chain_id;
+use crate::utils::eip681::payment_uri;
+use crate::utils::escrow::{escrow_contract, find_escrow_deposit, send_escrow_call};
+use crate::utils::explorer::{address_url, token_url, transaction_url};
+use crate::utils::fees::{treasury_address, FeeSchedule};
+use crate::utils::pricing::{convert_price, PriceQuote, PriceSource, DEFAULT_CURRENCY};
+use crate::utils::receipts::receipt_contract;
+use crate::utils::token_gates::TokenGateCache;
+use crate::utils::tokens::{
+    find_token, find_token_by_address, from_base_units, to_base_units, Token, CELO, CUSD,
+};
 use crate::CheckoutRequest;
 use crate::Order;
 use anyhow::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use num_traits::identities::Zero;
-use sqlx::{types::Decimal, PgPool};
+use sqlx::{types::Decimal, PgConnection, PgPool};
+use std::collections::HashMap;
+use std::str::FromStr;
 use uuid::Uuid;
-use web3::transports::Http;
+use web3::types::{Address, BlockId, BlockNumber, TransactionId, H256, U256};
 use web3::Web3;
 
 pub async fn register_user(db: &PgPool, payload: RegisterUserRequest) -> Result<User, sqlx::Error> {
@@ -59,14 +76,19 @@ pub async fn create_store(
 
     sqlx::query!(
         r#"
-        INSERT INTO stores (id, store_name, image_cid, description, owner_address)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO stores (
+            id, store_name, image_cid, description, owner_address, accepts_native_celo,
+            mint_receipts
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
         "#,
         store_id,
         payload.store_name,
         image_cid,
         payload.description,
-        payload.owner_address
+        payload.owner_address,
+        payload.accepts_native_celo.unwrap_or(false),
+        payload.mint_receipts.unwrap_or(false)
     )
     .execute(db)
     .await?;
@@ -74,7 +96,8 @@ pub async fn create_store(
     let store = sqlx::query_as!(
         Store,
         r#"
-        SELECT id, store_name, image_cid, description, owner_address, $1 as "share_link!"
+        SELECT id, store_name, image_cid, description, owner_address, $1 as "share_link!",
+            accepts_native_celo, mint_receipts, payout_address
         FROM stores
         WHERE id = $2
         "#,
@@ -93,12 +116,15 @@ pub async fn add_product(
     payload: AddProductRequest,
     image_cid: Option<String>,
 ) -> Result<Product, sqlx::Error> {
+    let mut tx = db.begin().await?;
     let product = sqlx::query_as!(
         Product,
         r#"
-        INSERT INTO products (id, store_id, product_name, image_cid, description, price, quantity)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
-        RETURNING id, store_id, product_name, image_cid, description, price, quantity
+        INSERT INTO products (
+            id, store_id, product_name, image_cid, description, price, currency, quantity
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id, store_id, product_name, image_cid, description, price, currency, quantity
         "#,
         Uuid::new_v4(),
         store_id,
@@ -106,11 +132,26 @@ pub async fn add_product(
         image_cid,
         payload.description,
         payload.price,
+        payload.currency.as_deref().unwrap_or(DEFAULT_CURRENCY),
         payload.quantity
     )
-    .fetch_one(db)
+    .fetch_one(&mut *tx)
     .await?;
 
+    if product.quantity != 0 {
+        insert_inventory_movement(
+            &mut tx,
+            product.id,
+            product.quantity,
+            "restock",
+            None,
+            Some("Initial stock"),
+            None,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
     Ok(product)
 }
 
@@ -118,7 +159,7 @@ pub async fn list_products(db: &PgPool, store_id: Uuid) -> Result<Vec<Product>,
     let products = sqlx::query_as!(
         Product,
         r#"
-        SELECT id, store_id, product_name, image_cid, description, price, quantity
+        SELECT id, store_id, product_name, image_cid, description, price, currency, quantity
         FROM products
         WHERE store_id = $1
         "#,
@@ -130,12 +171,17 @@ pub async fn list_products(db: &PgPool, store_id: Uuid) -> Result<Vec<Product>,
     Ok(products)
 }
 
+/// Units a buyer can still take: stock less what active reservations hold.
 pub async fn get_product_quantity(db: &PgPool, product_id: Uuid) -> Result<i32, sqlx::Error> {
     let quantity = sqlx::query_scalar!(
         r#"
-        SELECT quantity
-        FROM products
-        WHERE id = $1
+        SELECT p.quantity - COALESCE((
+            SELECT SUM(r.quantity)
+            FROM stock_reservations r
+            WHERE r.product_id = p.id AND r.status = 'active' AND r.expires_at > now()
+        ), 0)::INT4 as "available!"
+        FROM products p
+        WHERE p.id = $1
         "#,
         product_id
     )
@@ -266,246 +312,1499 @@ pub async fn calculate_cart_total(db: &PgPool, cart_id: Uuid) -> Result<Decimal,
     Ok(total)
 }
 
-pub async fn checkout(
-    pool: &PgPool,
-    web3: &Web3<Http>,
-    payload: CheckoutRequest,
-    user_id: Uuid,
-) -> Result<Order> {
-    let cart_items = sqlx::query!(
+struct CheckoutLine {
+    product_id: Uuid,
+    quantity: i32,
+    /// Unit price in the payment token, once `quote_lines` has converted it.
+    price: Decimal,
+    list_price: Decimal,
+    currency: String,
+    exchange_rate: Decimal,
+    store_id: Uuid,
+    stock: i32,
+    owner_address: String,
+    /// Where the store is paid; its owner wallet unless it set a payout address.
+    payout_address: String,
+    accepts_native_celo: bool,
+    platform_fee_bps: Option<i32>,
+    platform_fee_flat: Option<Decimal>,
+    /// Rate from the default currency to the payment token, for the flat fee.
+    fee_rate: Decimal,
+}
+
+impl CheckoutLine {
+    fn amount(&self) -> Decimal {
+        self.price * Decimal::from(self.quantity)
+    }
+
+    fn fee_schedule(&self) -> FeeSchedule {
+        FeeSchedule::for_store(self.platform_fee_bps, self.platform_fee_flat)
+            .converted(self.fee_rate)
+    }
+}
+
+/// Splits checkout lines into one group per store, in cart order. Each group
+/// is paid for, and placed as, one order.
+fn group_by_store(lines: Vec<CheckoutLine>) -> Vec<Vec<CheckoutLine>> {
+    let mut stores: Vec<Vec<CheckoutLine>> = Vec::new();
+    for line in lines {
+        match stores
+            .iter_mut()
+            .find(|group| group[0].store_id == line.store_id)
+        {
+            Some(group) => group.push(line),
+            None => stores.push(vec![line]),
+        }
+    }
+    stores
+}
+
+/// The platform fee on one store's order, charged once on its subtotal.
+fn order_platform_fee(lines: &[CheckoutLine]) -> Decimal {
+    let subtotal: Decimal = lines.iter().map(CheckoutLine::amount).sum();
+    lines[0].fee_schedule().fee_for(subtotal)
+}
+
+/// Shares an order's fee out over its lines in proportion to their amounts,
+/// with the rounding left on the last line so the shares add up to the fee.
+fn line_platform_fees(lines: &[CheckoutLine], fee: Decimal) -> Vec<Decimal> {
+    let subtotal: Decimal = lines.iter().map(CheckoutLine::amount).sum();
+    let mut remaining = fee;
+    let mut fees = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        let share = if i + 1 == lines.len() {
+            remaining
+        } else if subtotal.is_zero() {
+            Decimal::ZERO
+        } else {
+            (fee * line.amount() / subtotal).round_dp(2).min(remaining)
+        };
+        remaining -= share;
+        fees.push(share);
+    }
+    fees
+}
+
+/// What a store is paid for its part of a cart. Token payments send the
+/// platform fee to the treasury and the rest to the store. A native CELO
+/// transaction has a single recipient, so the store is paid in full and the
+/// fee is kept on the order, to come out of the store's payout.
+struct StoreShare {
+    store_id: Uuid,
+    payout_address: String,
+    amount: Decimal,
+    platform_fee: Decimal,
+}
+
+fn store_shares(lines: &[CheckoutLine], token: &Token) -> Vec<StoreShare> {
+    let mut shares: Vec<StoreShare> = Vec::new();
+    for line in lines {
+        if shares.iter().any(|share| share.store_id == line.store_id) {
+            continue;
+        }
+        let store_lines: Vec<&CheckoutLine> = lines
+            .iter()
+            .filter(|other| other.store_id == line.store_id)
+            .collect();
+        let subtotal: Decimal = store_lines.iter().map(|line| line.amount()).sum();
+        let platform_fee = line.fee_schedule().fee_for(subtotal);
+        shares.push(StoreShare {
+            store_id: line.store_id,
+            payout_address: line.payout_address.clone(),
+            amount: if token.native {
+                subtotal
+            } else {
+                subtotal - platform_fee
+            },
+            platform_fee,
+        });
+    }
+    shares
+}
+
+/// The treasury's transfer for the fees in `shares`, if it is paid directly.
+fn treasury_share(shares: &[StoreShare], token: &Token) -> Option<(String, Decimal)> {
+    let platform_fee: Decimal = shares.iter().map(|share| share.platform_fee).sum();
+    if token.native || platform_fee.is_zero() {
+        return None;
+    }
+    treasury_address().map(|treasury| (treasury, platform_fee))
+}
+
+/// A native CELO payment is one plain transfer, so it can only pay one wallet.
+fn require_single_native_payee(token: &Token, payees: usize) -> Result<()> {
+    if token.native && payees > 1 {
+        return Err(anyhow::anyhow!(
+            "A {} payment can only pay one store; check out each store separately",
+            token.symbol
+        ));
+    }
+    Ok(())
+}
+
+/// The transfers that pay for a cart: one per payout wallet, with stores
+/// that share a wallet paid together, then the platform fee to the treasury.
+fn cart_payees(shares: &[StoreShare], token: &Token) -> Result<Vec<(String, Decimal)>> {
+    let mut payees: Vec<(String, Decimal)> = Vec::new();
+    for share in shares {
+        match payees
+            .iter_mut()
+            .find(|(address, _)| address.eq_ignore_ascii_case(&share.payout_address))
+        {
+            Some((_, amount)) => *amount += share.amount,
+            None => payees.push((share.payout_address.clone(), share.amount)),
+        }
+    }
+    require_single_native_payee(token, payees.len())?;
+    payees.extend(treasury_share(shares, token));
+    Ok(payees)
+}
+
+/// The cart's lines at their listed prices, with stock net of what other
+/// buyers have reserved. The cart's own checkout holds do not count against
+/// it, but holds for its pending payment intents do.
+async fn cart_lines<'e, E: sqlx::PgExecutor<'e>>(
+    db: E,
+    cart_id: Uuid,
+) -> Result<Vec<CheckoutLine>, sqlx::Error> {
+    sqlx::query_as!(
+        CheckoutLine,
         r#"
-        SELECT ci.product_id, ci.quantity, p.price, p.store_id, p.quantity as stock, s.owner_address
+        SELECT ci.product_id, ci.quantity, p.price, p.price as list_price, p.currency,
+            1::NUMERIC as "exchange_rate!", p.store_id, s.owner_address,
+            COALESCE(s.payout_address, s.owner_address) as "payout_address!",
+            s.accepts_native_celo, s.platform_fee_bps, s.platform_fee_flat,
+            1::NUMERIC as "fee_rate!",
+            p.quantity - COALESCE((
+                SELECT SUM(r.quantity)
+                FROM stock_reservations r
+                WHERE r.product_id = p.id AND r.status = 'active' AND r.expires_at > now()
+                AND NOT (r.cart_id = ci.cart_id AND r.payment_intent_id IS NULL)
+            ), 0)::INT4 as "stock!"
         FROM cart_items ci
         JOIN products p ON ci.product_id = p.id
         JOIN stores s ON p.store_id = s.id
         WHERE ci.cart_id = $1
         "#,
-        payload.cart_id
+        cart_id
     )
-    .fetch_all(pool)
+    .fetch_all(db)
     .await
-    .map_err(|e| anyhow::anyhow!("Failed to fetch cart items: {}", e))?;
+}
 
-    if cart_items.is_empty() {
-        return Err(anyhow::anyhow!("Cart is empty"));
+/// The quote for `currency` in `token`, from `quotes` when it is already there
+/// and otherwise looked up and added to it.
+async fn quote_currency(
+    prices: &dyn PriceSource,
+    currency: &str,
+    token: &Token,
+    quotes: &mut HashMap<String, PriceQuote>,
+) -> Result<PriceQuote> {
+    if let Some(quote) = quotes.get(currency) {
+        return Ok(quote.clone());
     }
+    let quote = prices
+        .quote(currency, token.symbol)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot price {} in {}: {}", currency, token.symbol, e))?;
+    quotes.insert(currency.to_string(), quote.clone());
+    Ok(quote)
+}
 
-    let mut total_amount = Decimal::zero();
-    for item in &cart_items {
-        if item.stock < item.quantity {
-            return Err(anyhow::anyhow!(
-                "Insufficient stock for product: {}",
-                item.product_id
-            ));
+/// Converts each line's listed price, and any flat platform fee, into the
+/// payment token, returning when the earliest of the quotes used expires.
+/// Currencies already in `quotes` are priced at that quote; the others are
+/// quoted and added to it.
+async fn quote_lines(
+    prices: &dyn PriceSource,
+    lines: &mut [CheckoutLine],
+    token: &Token,
+    quotes: &mut HashMap<String, PriceQuote>,
+) -> Result<chrono::DateTime<chrono::Utc>> {
+    let mut expires_at: Option<chrono::DateTime<chrono::Utc>> = None;
+    for line in lines.iter_mut() {
+        let quote = quote_currency(prices, &line.currency, token, quotes).await?;
+        line.price = convert_price(line.list_price, &quote);
+        line.exchange_rate = quote.rate;
+        expires_at = Some(expires_at.map_or(quote.expires_at, |at| at.min(quote.expires_at)));
+
+        if !FeeSchedule::for_store(line.platform_fee_bps, line.platform_fee_flat)
+            .flat
+            .is_zero()
+        {
+            let quote = quote_currency(prices, DEFAULT_CURRENCY, token, quotes).await?;
+            line.fee_rate = quote.rate;
+            expires_at = expires_at.map(|at| at.min(quote.expires_at));
         }
-        total_amount += item.price * Decimal::from(item.quantity);
     }
 
-    let first_item = &cart_items[0];
-    verify_payment(
-        web3,
-        &payload.transaction_hash,
-        &first_item.owner_address,
-        total_amount,
-    )
-    .await
-    .map_err(|e| anyhow::anyhow!("Payment verification failed: {}", e))?;
-
-    let mut first_order = None;
-    for item in cart_items {
-        let order_request = CreateOrderRequest {
-            store_id: item.store_id,
-            product_id: item.product_id,
-            user_id,
-            buyer_address: payload.buyer_address.clone(),
-            seller_address: item.owner_address.clone(),
-            amount: item.price * Decimal::from(item.quantity),
-        };
+    expires_at.ok_or_else(|| anyhow::anyhow!("Cart is empty"))
+}
 
-        let mut order = create_order(pool, order_request)
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to create order: {}", e))?;
+/// Prices a cart for direct checkout. Each currency keeps the rate the cart
+/// was last quoted at until that quote expires, and a fresh quote is saved
+/// otherwise, so checkout verifies the amount the buyer was shown rather
+/// than a rate looked up after they paid.
+async fn quote_cart_lines(
+    pool: &PgPool,
+    prices: &dyn PriceSource,
+    cart_id: Uuid,
+    lines: &mut [CheckoutLine],
+    token: &Token,
+) -> Result<chrono::DateTime<chrono::Utc>> {
+    let mut quotes: HashMap<String, PriceQuote> = sqlx::query!(
+        r#"
+        SELECT currency, rate, expires_at
+        FROM cart_price_quotes
+        WHERE cart_id = $1 AND token_symbol = $2 AND expires_at > now()
+        "#,
+        cart_id,
+        token.symbol
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            row.currency,
+            PriceQuote {
+                rate: row.rate,
+                expires_at: row.expires_at,
+            },
+        )
+    })
+    .collect();
+    let saved: Vec<String> = quotes.keys().cloned().collect();
 
-        order.transaction_hash = Some(payload.transaction_hash.clone());
-        order.payment_status = "confirmed".to_string();
-        order.status = "pending".to_string();
-        order.updated_at = Some(chrono::Utc::now());
+    let expires_at = quote_lines(prices, lines, token, &mut quotes).await?;
 
+    for (currency, quote) in quotes.iter().filter(|(c, _)| !saved.contains(c)) {
         sqlx::query!(
             r#"
-            UPDATE orders
-            SET transaction_hash = $1, payment_status = $2, status = $3, updated_at = $4
-            WHERE id = $5
+            INSERT INTO cart_price_quotes (cart_id, token_symbol, currency, rate, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (cart_id, token_symbol, currency) DO UPDATE
+            SET rate = EXCLUDED.rate, expires_at = EXCLUDED.expires_at, created_at = now()
             "#,
-            order.transaction_hash,
-            &order.payment_status,
-            &order.status,
-            order.updated_at,
-            order.id
+            cart_id,
+            token.symbol,
+            currency,
+            quote.rate,
+            quote.expires_at
         )
         .execute(pool)
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to update order: {}", e))?;
+        .await?;
+    }
+    Ok(expires_at)
+}
 
-        sqlx::query!(
-            "UPDATE products SET quantity = quantity - $1 WHERE id = $2 AND quantity >= $1",
-            item.quantity,
-            item.product_id
+pub async fn checkout<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    prices: &dyn PriceSource,
+    gates: &TokenGateCache,
+    payload: CheckoutRequest,
+    user_id: Uuid,
+) -> Result<CheckoutSummary> {
+    if let Some(intent_id) = payload.payment_intent_id {
+        let intent = get_open_payment_intent(pool, intent_id, user_id, payload.cart_id).await?;
+        let token = find_token(&intent.intent.token_symbol).ok_or_else(|| {
+            anyhow::anyhow!("Unsupported payment token: {}", intent.intent.token_symbol)
+        })?;
+        for recipient in &intent.recipients {
+            verify_payment(
+                web3,
+                &payload.transaction_hash,
+                token,
+                &intent.intent.buyer_address,
+                &recipient.recipient_address,
+                recipient.amount,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Payment verification failed: {}", e))?;
+        }
+        let mut tx = pool.begin().await?;
+        claim_payment_transaction(
+            &mut tx,
+            &payload.transaction_hash,
+            token,
+            Some(user_id),
+            Some(intent_id),
         )
-        .execute(pool)
+        .await?;
+        let orders = complete_intent_in(&mut tx, intent_id, &payload.transaction_hash).await?;
+        tx.commit().await?;
+        return Ok(checkout_summary(&payload.transaction_hash, token, orders));
+    }
+
+    let mut cart_items = cart_lines(pool, payload.cart_id)
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to update stock: {}", e))?;
+        .map_err(|e| anyhow::anyhow!("Failed to fetch cart items: {}", e))?;
+
+    if cart_items.is_empty() {
+        return Err(anyhow::anyhow!("Cart is empty"));
+    }
 
-        if first_order.is_none() {
-            first_order = Some(order);
+    let token = find_token(&payload.payment_type)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported payment type: {}", payload.payment_type))?;
+    apply_token_gates(pool, web3, gates, &payload.buyer_address, &mut cart_items).await?;
+    quote_cart_lines(pool, prices, payload.cart_id, &mut cart_items, token).await?;
+
+    for item in &cart_items {
+        if token.native && !item.accepts_native_celo {
+            return Err(anyhow::anyhow!(
+                "Store {} does not accept {}",
+                item.store_id,
+                token.symbol
+            ));
+        }
+        if item.stock < item.quantity {
+            return Err(anyhow::anyhow!(
+                "Insufficient stock for product: {}",
+                item.product_id
+            ));
         }
     }
 
-    sqlx::query!("DELETE FROM cart_items WHERE cart_id = $1", payload.cart_id)
-        .execute(pool)
+    // Every store is paid its own share, so the transaction must carry a
+    // transfer to each payout wallet as well as the platform fee.
+    for (address, amount) in cart_payees(&store_shares(&cart_items, token), token)? {
+        verify_payment(
+            web3,
+            &payload.transaction_hash,
+            token,
+            &payload.buyer_address,
+            &address,
+            amount,
+        )
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to clear cart: {}", e))?;
-
-    let order = first_order.ok_or_else(|| anyhow::anyhow!("No orders created"))?;
+        .map_err(|e| anyhow::anyhow!("Payment verification failed: {}", e))?;
+    }
+    // The payment is verified; everything from here commits or rolls back
+    // together, so a failure cannot leave orders without their stock taken.
+    let mut tx = pool.begin().await?;
+    claim_payment_transaction(
+        &mut tx,
+        &payload.transaction_hash,
+        token,
+        Some(user_id),
+        None,
+    )
+    .await?;
+    let orders = place_orders(
+        &mut tx,
+        cart_items,
+        user_id,
+        &payload.buyer_address,
+        &payload.transaction_hash,
+        payload.cart_id,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(checkout_summary(&payload.transaction_hash, token, orders))
+}
 
-    if order.created_at.is_none() || order.updated_at.is_none() {
-        return Err(anyhow::anyhow!("Order missing timestamps"));
+/// Prices the cart the way checkout would and returns the total, the number
+/// of transfers it takes to pay it and when the quoted prices expire.
+async fn quote_cart<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    prices: &dyn PriceSource,
+    gates: &TokenGateCache,
+    cart_id: Uuid,
+    buyer_address: &str,
+    token: &Token,
+) -> Result<(Decimal, usize, chrono::DateTime<chrono::Utc>)> {
+    let mut cart_items = cart_lines(pool, cart_id).await?;
+    if cart_items.is_empty() {
+        return Err(anyhow::anyhow!("Cart is empty"));
     }
+    apply_token_gates(pool, web3, gates, buyer_address, &mut cart_items).await?;
+    let quote_expires_at = quote_cart_lines(pool, prices, cart_id, &mut cart_items, token).await?;
 
-    Ok(order)
+    let total = cart_items.iter().map(CheckoutLine::amount).sum::<Decimal>();
+    let transfers = cart_payees(&store_shares(&cart_items, token), token)?.len();
+    Ok((total, transfers, quote_expires_at))
 }
-pub async fn create_order(db: &PgPool, payload: CreateOrderRequest) -> Result<Order, sqlx::Error> {
-    let order_id = format!(
-        "{}-{}-{}",
-        payload.store_id,
-        payload.product_id,
-        Uuid::new_v4()
-    );
-    let amount = payload.amount;
 
-    let order = sqlx::query_as!(
-        Order,
-        r#"
-        INSERT INTO orders (
-            id, order_id, store_id, product_id, user_id, buyer_address, seller_address,
-            amount, status, payment_status, transaction_hash
+/// Reads the buyer's balances to tell them, before they send anything,
+/// whether a payment will go through.
+pub async fn payment_precheck<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    prices: &dyn PriceSource,
+    gates: &TokenGateCache,
+    user_id: Uuid,
+    query: PaymentPrecheckQuery,
+) -> Result<PaymentPrecheck> {
+    let (token, amount_due, transfers, expires_at) = match (query.payment_intent_id, query.cart_id)
+    {
+        (Some(intent_id), _) => {
+            let intent = get_payment_intent(pool, intent_id)
+                .await?
+                .filter(|intent| intent.intent.user_id == user_id)
+                .ok_or_else(|| anyhow::anyhow!("Payment intent not found"))?;
+            let token = find_token(&intent.intent.token_symbol).ok_or_else(|| {
+                anyhow::anyhow!("Unsupported payment token: {}", intent.intent.token_symbol)
+            })?;
+            let unpaid: Vec<_> = intent
+                .recipients
+                .iter()
+                .filter(|recipient| recipient.paid_transaction_hash.is_none())
+                .collect();
+            let amount = unpaid.iter().map(|recipient| recipient.amount).sum();
+            (token, amount, unpaid.len(), intent.intent.expires_at)
+        }
+        (None, Some(cart_id)) => {
+            let token = find_token(&query.token)
+                .ok_or_else(|| anyhow::anyhow!("Unsupported payment token: {}", query.token))?;
+            let (amount, transfers, expires_at) = quote_cart(
+                pool,
+                web3,
+                prices,
+                gates,
+                cart_id,
+                &query.buyer_address,
+                token,
+            )
+            .await?;
+            (token, amount, transfers, expires_at)
+        }
+        (None, None) => {
+            return Err(anyhow::anyhow!(
+                "Either cart_id or payment_intent_id is required"
+            ))
+        }
+    };
+
+    let buyer = Address::from_str(query.buyer_address.trim_start_matches("0x"))
+        .map_err(|_| anyhow::anyhow!("Invalid buyer address"))?;
+    let native_wei = web3.eth().balance(buyer, None).await?;
+    let gas_price = web3.eth().gas_price().await?;
+    let fee_wei = gas_price
+        .saturating_mul(transfer_gas(token))
+        .saturating_mul(transfers.into());
+    let native_balance = from_base_units(native_wei, CELO.decimals)?;
+    let estimated_network_fee = from_base_units(fee_wei, CELO.decimals)?;
+
+    let (token_balance, shortfall, fee_shortfall, escrow_allowance) = if token.native {
+        let needed = amount_due + estimated_network_fee;
+        (
+            native_balance,
+            (needed - native_balance).max(Decimal::ZERO),
+            Decimal::ZERO,
+            None,
         )
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, 'pending', 'pending', NULL)
-        RETURNING
-            id, order_id, store_id, product_id, user_id, buyer_address, seller_address,
-            amount, status, payment_status, transaction_hash, created_at, updated_at
+    } else {
+        let token_address = Address::from_str(token.address.trim_start_matches("0x"))?;
+        let balance = from_base_units(
+            erc20_balance(web3, token_address, buyer).await?,
+            token.decimals,
+        )?;
+        let allowance = match escrow_contract() {
+            Some(escrow) => Some(from_base_units(
+                erc20_allowance(web3, token_address, buyer, escrow).await?,
+                token.decimals,
+            )?),
+            None => None,
+        };
+        (
+            balance,
+            (amount_due - balance).max(Decimal::ZERO),
+            (estimated_network_fee - native_balance).max(Decimal::ZERO),
+            allowance,
+        )
+    };
+
+    Ok(PaymentPrecheck {
+        buyer_address: query.buyer_address,
+        token_symbol: token.symbol.to_string(),
+        amount_due,
+        token_balance,
+        native_balance,
+        transfers: transfers as i32,
+        expires_at,
+        estimated_network_fee,
+        shortfall,
+        fee_shortfall,
+        escrow_allowance,
+        can_afford: shortfall.is_zero() && fee_shortfall.is_zero(),
+    })
+}
+
+async fn payment_intent_lines<'e, E: sqlx::PgExecutor<'e>>(
+    db: E,
+    intent_id: Uuid,
+) -> Result<Vec<CheckoutLine>> {
+    let lines = sqlx::query_as!(
+        CheckoutLine,
+        r#"
+        SELECT pii.product_id, pii.quantity, pii.unit_price as price, pii.list_price,
+            pii.currency, pii.exchange_rate, pii.store_id,
+            p.quantity as stock, s.owner_address, pir.recipient_address as payout_address,
+            s.accepts_native_celo, s.platform_fee_bps, s.platform_fee_flat, pii.fee_rate
+        FROM payment_intent_items pii
+        JOIN products p ON pii.product_id = p.id
+        JOIN stores s ON pii.store_id = s.id
+        JOIN payment_intent_recipients pir
+            ON pir.payment_intent_id = pii.payment_intent_id AND pir.store_id = pii.store_id
+        WHERE pii.payment_intent_id = $1
         "#,
-        Uuid::new_v4(),
-        &order_id,
-        payload.store_id,
-        payload.product_id,
-        payload.user_id,
-        payload.buyer_address,
-        payload.seller_address,
-        amount
+        intent_id
     )
-    .fetch_one(db)
-    .await?;
+    .fetch_all(db)
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to fetch payment intent items: {}", e))?;
 
-    if order.created_at.is_none() || order.updated_at.is_none() {
-        return Err(sqlx::Error::RowNotFound);
-    }
+    Ok(lines)
+}
 
-    sqlx::query!(
+/// Turns a fully paid intent into orders. Used both by `/checkout` and by the
+/// transfer indexer once every recipient of the intent has been paid.
+async fn complete_intent_in(
+    tx: &mut PgConnection,
+    intent_id: Uuid,
+    transaction_hash: &str,
+) -> Result<Vec<OrderDetails>> {
+    let intent = sqlx::query!(
         r#"
-        UPDATE products
-        SET quantity = quantity - 1
-        WHERE id = $1 AND quantity > 0
+        UPDATE payment_intents
+        SET status = 'completed', transaction_hash = $1
+        WHERE id = $2 AND status = 'pending'
+        RETURNING user_id, buyer_address, cart_id
         "#,
-        payload.product_id
+        transaction_hash,
+        intent_id
     )
-    .execute(db)
-    .await?;
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to complete payment intent: {}", e))?
+    .ok_or_else(|| anyhow::anyhow!("Payment intent is no longer pending"))?;
 
-    Ok(order)
+    let lines = payment_intent_lines(&mut *tx, intent_id).await?;
+    place_orders(
+        tx,
+        lines,
+        intent.user_id,
+        &intent.buyer_address,
+        transaction_hash,
+        intent.cart_id,
+        Some(intent_id),
+    )
+    .await
 }
 
-pub async fn update_order_status(
-    db: &PgPool,
-    order_id: &str,
-    status: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+/// Records that a transaction has paid for a checkout. A transaction hash can
+/// only ever pay for one checkout, which stops the same payment being
+/// replayed; one transaction may still pay several recipients of an intent.
+async fn claim_payment_transaction(
+    tx: &mut PgConnection,
+    transaction_hash: &str,
+    token: &Token,
+    user_id: Option<Uuid>,
+    payment_intent_id: Option<Uuid>,
+) -> Result<()> {
+    let claimed = sqlx::query!(
         r#"
-        UPDATE orders
-        SET status = $1
-        WHERE order_id = $2
+        INSERT INTO payment_transactions (transaction_hash, token_symbol, user_id, payment_intent_id)
+        VALUES (lower($1), $2, $3, $4)
+        ON CONFLICT (transaction_hash) DO UPDATE
+        SET payment_intent_id = EXCLUDED.payment_intent_id
+        WHERE payment_transactions.payment_intent_id = EXCLUDED.payment_intent_id
         "#,
-        status,
-        order_id
+        transaction_hash,
+        token.symbol,
+        user_id,
+        payment_intent_id
     )
-    .execute(db)
-    .await?;
+    .execute(tx)
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to record payment transaction: {}", e))?;
+
+    if claimed.rows_affected() != 1 {
+        return Err(anyhow::anyhow!(
+            "Transaction {} has already been used for a payment",
+            transaction_hash
+        ));
+    }
 
     Ok(())
 }
 
-pub async fn get_store_orders(db: &PgPool, store_id: Uuid) -> Result<Vec<Order>, sqlx::Error> {
-    let orders = sqlx::query_as!(
-        Order,
+/// Locks the rows of the products being bought and checks that enough of
+/// each is left, net of active reservations. The cart's checkout holds and
+/// those of `payment_intent_id` are its own and are not counted. The locks
+/// are taken in id order so concurrent checkouts cannot deadlock.
+async fn lock_stock(
+    tx: &mut PgConnection,
+    lines: &[CheckoutLine],
+    cart_id: Uuid,
+    payment_intent_id: Option<Uuid>,
+) -> Result<()> {
+    let mut wanted: HashMap<Uuid, i32> = HashMap::new();
+    for line in lines {
+        *wanted.entry(line.product_id).or_default() += line.quantity;
+    }
+    let product_ids: Vec<Uuid> = wanted.keys().copied().collect();
+
+    let available = sqlx::query!(
         r#"
-        SELECT
-            id, order_id, store_id, product_id, user_id, buyer_address, seller_address,
-            amount, status, payment_status, transaction_hash, created_at, updated_at
-        FROM orders
-        WHERE store_id = $1
+        SELECT p.id, p.quantity - COALESCE((
+            SELECT SUM(r.quantity)
+            FROM stock_reservations r
+            WHERE r.product_id = p.id AND r.status = 'active' AND r.expires_at > now()
+            AND NOT (
+                r.cart_id = $2
+                AND (r.payment_intent_id IS NULL OR r.payment_intent_id = $3)
+            )
+        ), 0)::INT4 as "available!"
+        FROM products p
+        WHERE p.id = ANY($1)
+        ORDER BY p.id
+        FOR UPDATE OF p
         "#,
-        store_id
+        &product_ids,
+        cart_id,
+        payment_intent_id
     )
-    .fetch_all(db)
+    .fetch_all(&mut *tx)
     .await?;
 
-    for order in &orders {
-        if order.created_at.is_none() || order.updated_at.is_none() {
-            return Err(sqlx::Error::RowNotFound);
+    for (product_id, quantity) in wanted {
+        let in_stock = available
+            .iter()
+            .find(|row| row.id == product_id)
+            .map_or(0, |row| row.available);
+        if in_stock < quantity {
+            return Err(anyhow::anyhow!(
+                "Insufficient stock for product: {}",
+                product_id
+            ));
         }
     }
 
-    Ok(orders)
+    Ok(())
 }
 
-pub async fn update_store(
-    db: &PgPool,
-    store_id: Uuid,
-    payload: CreateStoreRequest,
-    image_cid: Option<String>,
-) -> Result<Store, sqlx::Error> {
-    let share_link = format!("https://jes-saas.onrender.com/store/{}", store_id);
+/// A short reference buyers and sellers can quote for an order.
+fn order_reference() -> String {
+    format!(
+        "ORD-{}",
+        Uuid::new_v4().simple().to_string()[..12].to_uppercase()
+    )
+}
 
-    sqlx::query!(
+async fn insert_order_item(
+    tx: &mut PgConnection,
+    order_id: Uuid,
+    line: &CheckoutLine,
+    platform_fee: Decimal,
+) -> Result<OrderItem, sqlx::Error> {
+    sqlx::query_as!(
+        OrderItem,
         r#"
-        UPDATE stores
-        SET store_name = $1, image_cid = $2, description = $3, owner_address = $4, updated_at = CURRENT_TIMESTAMP
-        WHERE id = $5
+        INSERT INTO order_items (
+            order_id, product_id, quantity, unit_price, list_price, currency, exchange_rate,
+            amount, platform_fee
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING
+            id, order_id, product_id, quantity, unit_price, list_price, currency, exchange_rate,
+            amount, platform_fee
         "#,
-        payload.store_name,
-        image_cid,
-        payload.description,
-        payload.owner_address,
-        store_id
+        order_id,
+        line.product_id,
+        line.quantity,
+        line.price,
+        line.list_price,
+        line.currency,
+        line.exchange_rate,
+        line.amount(),
+        platform_fee
     )
-    .execute(db)
-    .await?;
+    .fetch_one(tx)
+    .await
+}
 
-    let store = sqlx::query_as!(
-        Store,
+/// Creates one paid order per store with a line item per product, takes the
+/// stock and empties the cart. Runs inside the caller's transaction, and any
+/// shortfall fails all of it.
+async fn place_orders(
+    tx: &mut PgConnection,
+    cart_items: Vec<CheckoutLine>,
+    user_id: Uuid,
+    buyer_address: &str,
+    transaction_hash: &str,
+    cart_id: Uuid,
+    payment_intent_id: Option<Uuid>,
+) -> Result<Vec<OrderDetails>> {
+    lock_stock(tx, &cart_items, cart_id, payment_intent_id).await?;
+
+    let stores = group_by_store(cart_items);
+    let mut placed = Vec::with_capacity(stores.len());
+    for lines in stores {
+        let first = &lines[0];
+        let amount: Decimal = lines.iter().map(CheckoutLine::amount).sum();
+        let platform_fee = order_platform_fee(&lines);
+        // The header is priced in the first line's currency. Lines listed in
+        // another currency are valued at that line's rate; each item keeps
+        // its own listing.
+        let list_amount = if lines.iter().all(|line| line.currency == first.currency) {
+            lines
+                .iter()
+                .map(|line| line.list_price * Decimal::from(line.quantity))
+                .sum()
+        } else {
+            (amount / first.exchange_rate).round_dp(2)
+        };
+
+        let order = sqlx::query_as!(
+            Order,
+            r#"
+            INSERT INTO orders (
+                id, order_id, store_id, user_id, buyer_address, seller_address, amount, status,
+                payment_status, transaction_hash, platform_fee, currency, list_amount,
+                exchange_rate, payout_address
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, 'pending', 'confirmed', $8, $9, $10, $11, $12, $13)
+            RETURNING
+                id, order_id, store_id, user_id, buyer_address, seller_address, amount,
+                status as "status: OrderStatus",
+                payment_status as "payment_status: PaymentStatus", transaction_hash, platform_fee, currency, list_amount,
+                exchange_rate, receipt_token_id, payout_address, created_at, updated_at
+            "#,
+            Uuid::new_v4(),
+            order_reference(),
+            first.store_id,
+            user_id,
+            buyer_address,
+            first.owner_address,
+            amount,
+            transaction_hash,
+            platform_fee,
+            first.currency,
+            list_amount,
+            first.exchange_rate,
+            first.payout_address
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to create order: {}", e))?;
+
+        let line_fees = line_platform_fees(&lines, platform_fee);
+        let mut items = Vec::with_capacity(lines.len());
+        for (line, line_fee) in lines.iter().zip(line_fees) {
+            let item = insert_order_item(tx, order.id, line, line_fee)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to create order item: {}", e))?;
+            record_stock_movement(
+                tx,
+                line.product_id,
+                -line.quantity,
+                "sale",
+                Some(order.id),
+                None,
+                None,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to update stock: {}", e))?
+            .ok_or_else(|| {
+                anyhow::anyhow!("Insufficient stock for product: {}", line.product_id)
+            })?;
+            items.push(item);
+        }
+
+        let events = record_order_created(tx, &order, buyer_address).await?;
+        placed.push(OrderDetails {
+            order,
+            items,
+            events,
+            shipments: Vec::new(),
+        });
+    }
+
+    sqlx::query!("DELETE FROM cart_items WHERE cart_id = $1", cart_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to clear cart: {}", e))?;
+    sqlx::query!("DELETE FROM cart_price_quotes WHERE cart_id = $1", cart_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to clear price quotes: {}", e))?;
+
+    // The holds this purchase used are now sales; any others on the emptied
+    // cart are stale.
+    sqlx::query!(
         r#"
-        SELECT id, store_name, image_cid, description, owner_address, $1 as "share_link!"
-        FROM stores
-        WHERE id = $2
+        UPDATE stock_reservations
+        SET status = CASE
+            WHEN payment_intent_id IS NULL OR payment_intent_id = $2 THEN 'consumed'
+            ELSE 'released'
+        END
+        WHERE cart_id = $1 AND status = 'active'
         "#,
-        share_link,
-        store_id
+        cart_id,
+        payment_intent_id
     )
-    .fetch_one(db)
-    .await?;
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to settle stock reservations: {}", e))?;
 
-    Ok(store)
+    if placed.is_empty() {
+        return Err(anyhow::anyhow!("No orders created"));
+    }
+    Ok(placed)
+}
+
+fn checkout_summary(
+    transaction_hash: &str,
+    token: &Token,
+    orders: Vec<OrderDetails>,
+) -> CheckoutSummary {
+    CheckoutSummary {
+        transaction_hash: transaction_hash.to_string(),
+        token: token.symbol.to_string(),
+        total_amount: orders.iter().map(|order| order.order.amount).sum(),
+        platform_fee: orders.iter().map(|order| order.order.platform_fee).sum(),
+        item_count: orders
+            .iter()
+            .flat_map(|order| &order.items)
+            .map(|item| item.quantity)
+            .sum(),
+        orders,
+    }
+}
+
+/// One product at its listed price, as a checkout line for a direct order.
+async fn product_line(
+    db: &PgPool,
+    product_id: Uuid,
+    quantity: i32,
+) -> Result<Option<CheckoutLine>, sqlx::Error> {
+    sqlx::query_as!(
+        CheckoutLine,
+        r#"
+        SELECT p.id as product_id, $2::INT4 as "quantity!", p.price, p.price as list_price,
+            p.currency, 1::NUMERIC as "exchange_rate!", p.store_id, s.owner_address,
+            COALESCE(s.payout_address, s.owner_address) as "payout_address!",
+            s.accepts_native_celo, s.platform_fee_bps, s.platform_fee_flat,
+            1::NUMERIC as "fee_rate!", p.quantity as stock
+        FROM products p
+        JOIN stores s ON p.store_id = s.id
+        WHERE p.id = $1
+        "#,
+        product_id,
+        quantity
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Places a pending order for one product, to be paid in cUSD by a transfer
+/// the indexer matches later. The amount, payee and platform fee all come
+/// from the product and its store rather than the request, so the transfer
+/// that confirms the order is one the store actually priced.
+pub async fn create_order(
+    db: &PgPool,
+    prices: &dyn PriceSource,
+    payload: CreateOrderRequest,
+) -> Result<OrderDetails> {
+    if payload.quantity < 1 {
+        return Err(anyhow::anyhow!("Quantity must be at least 1"));
+    }
+    let line = product_line(db, payload.product_id, payload.quantity)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Product not found"))?;
+    if line.store_id != payload.store_id {
+        return Err(anyhow::anyhow!("Product does not belong to this store"));
+    }
+    let mut lines = vec![line];
+    quote_lines(prices, &mut lines, &CUSD, &mut HashMap::new()).await?;
+    let line = &lines[0];
+    let amount = line.amount();
+    let platform_fee = order_platform_fee(&lines);
+
+    let mut tx = db.begin().await?;
+    let order = sqlx::query_as!(
+        Order,
+        r#"
+        INSERT INTO orders (
+            id, order_id, store_id, user_id, buyer_address, seller_address, amount, list_amount,
+            status, payment_status, transaction_hash, platform_fee, currency, exchange_rate,
+            payout_address
+        )
+        VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, 'pending', 'pending', NULL, $9, $10, $11, $12
+        )
+        RETURNING
+            id, order_id, store_id, user_id, buyer_address, seller_address, amount,
+            status as "status: OrderStatus",
+            payment_status as "payment_status: PaymentStatus", transaction_hash, platform_fee, currency, list_amount, exchange_rate,
+            receipt_token_id, payout_address, created_at, updated_at
+        "#,
+        Uuid::new_v4(),
+        order_reference(),
+        line.store_id,
+        payload.user_id,
+        payload.buyer_address,
+        line.owner_address,
+        amount,
+        line.list_price * Decimal::from(line.quantity),
+        platform_fee,
+        line.currency,
+        line.exchange_rate,
+        line.payout_address
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if order.created_at.is_none() || order.updated_at.is_none() {
+        return Err(sqlx::Error::RowNotFound.into());
+    }
+
+    let item = insert_order_item(&mut tx, order.id, line, platform_fee).await?;
+
+    record_stock_movement(
+        &mut tx,
+        payload.product_id,
+        -payload.quantity,
+        "sale",
+        Some(order.id),
+        None,
+        None,
+    )
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Insufficient stock for product: {}", payload.product_id))?;
+    let events = record_order_created(&mut tx, &order, &order.buyer_address).await?;
+    tx.commit().await?;
+
+    Ok(OrderDetails {
+        order,
+        items: vec![item],
+        events,
+        shipments: Vec::new(),
+    })
+}
+
+/// Writes a ledger entry without touching `products.quantity`, for when the
+/// caller has already set it.
+async fn insert_inventory_movement(
+    tx: &mut PgConnection,
+    product_id: Uuid,
+    quantity_change: i32,
+    reason: &str,
+    order_id: Option<Uuid>,
+    note: Option<&str>,
+    created_by: Option<&str>,
+) -> Result<InventoryMovement, sqlx::Error> {
+    sqlx::query_as!(
+        InventoryMovement,
+        r#"
+        INSERT INTO inventory_movements (
+            product_id, quantity_change, reason, order_id, note, created_by
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, product_id, quantity_change, reason, order_id, note, created_by, created_at
+        "#,
+        product_id,
+        quantity_change,
+        reason,
+        order_id,
+        note,
+        created_by
+    )
+    .fetch_one(tx)
+    .await
+}
+
+/// Applies a stock change and records it in the ledger. Returns `None`
+/// without changing anything if stock would go negative.
+async fn record_stock_movement(
+    tx: &mut PgConnection,
+    product_id: Uuid,
+    quantity_change: i32,
+    reason: &str,
+    order_id: Option<Uuid>,
+    note: Option<&str>,
+    created_by: Option<&str>,
+) -> Result<Option<InventoryMovement>, sqlx::Error> {
+    let stock = sqlx::query_scalar!(
+        r#"
+        UPDATE products
+        SET quantity = quantity + $1
+        WHERE id = $2 AND quantity + $1 >= 0
+        RETURNING quantity
+        "#,
+        quantity_change,
+        product_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if stock.is_none() {
+        return Ok(None);
+    }
+    insert_inventory_movement(
+        tx,
+        product_id,
+        quantity_change,
+        reason,
+        order_id,
+        note,
+        created_by,
+    )
+    .await
+    .map(Some)
+}
+
+/// A restock or adjustment entered by the store owner.
+pub async fn adjust_inventory(
+    db: &PgPool,
+    product_id: Uuid,
+    payload: &RecordInventoryMovementRequest,
+    created_by: &str,
+) -> Result<InventoryMovement> {
+    let mut tx = db.begin().await?;
+    let movement = record_stock_movement(
+        &mut tx,
+        product_id,
+        payload.quantity_change,
+        &payload.reason,
+        None,
+        payload.note.as_deref(),
+        Some(created_by),
+    )
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Stock cannot go below zero"))?;
+    tx.commit().await?;
+    Ok(movement)
+}
+
+/// A product's ledger, newest entry first, checked against its stock.
+pub async fn get_inventory_ledger(
+    db: &PgPool,
+    product_id: Uuid,
+) -> Result<Option<InventoryLedger>, sqlx::Error> {
+    let Some(stock) =
+        sqlx::query_scalar!("SELECT quantity FROM products WHERE id = $1", product_id)
+            .fetch_optional(db)
+            .await?
+    else {
+        return Ok(None);
+    };
+
+    let movements = sqlx::query_as!(
+        InventoryMovement,
+        r#"
+        SELECT id, product_id, quantity_change, reason, order_id, note, created_by, created_at
+        FROM inventory_movements
+        WHERE product_id = $1
+        ORDER BY created_at DESC, id
+        "#,
+        product_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let ledger_stock = movements
+        .iter()
+        .map(|movement| i64::from(movement.quantity_change))
+        .sum::<i64>();
+    Ok(Some(InventoryLedger {
+        product_id,
+        stock,
+        ledger_stock,
+        in_sync: ledger_stock == i64::from(stock),
+        movements,
+    }))
+}
+
+/// Appends a change to an order's history.
+async fn record_order_event<'e, E: sqlx::PgExecutor<'e>>(
+    db: E,
+    order_id: Uuid,
+    field: &str,
+    from_status: Option<&str>,
+    to_status: &str,
+    actor: &str,
+    note: Option<&str>,
+) -> Result<OrderEvent, sqlx::Error> {
+    sqlx::query_as!(
+        OrderEvent,
+        r#"
+        INSERT INTO order_events (order_id, field, from_status, to_status, actor, note)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, order_id, field, from_status, to_status, actor, note, created_at
+        "#,
+        order_id,
+        field,
+        from_status,
+        to_status,
+        actor,
+        note
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Starts the history of a new order with the statuses it was created with.
+async fn record_order_created(
+    tx: &mut PgConnection,
+    order: &Order,
+    actor: &str,
+) -> Result<Vec<OrderEvent>, sqlx::Error> {
+    Ok(vec![
+        record_order_event(
+            &mut *tx,
+            order.id,
+            "status",
+            None,
+            order.status.as_str(),
+            actor,
+            None,
+        )
+        .await?,
+        record_order_event(
+            &mut *tx,
+            order.id,
+            "payment_status",
+            None,
+            order.payment_status.as_str(),
+            actor,
+            None,
+        )
+        .await?,
+    ])
+}
+
+pub async fn list_order_events(
+    db: &PgPool,
+    order_id: Uuid,
+) -> Result<Vec<OrderEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        OrderEvent,
+        r#"
+        SELECT id, order_id, field, from_status, to_status, actor, note, created_at
+        FROM order_events
+        WHERE order_id = $1
+        ORDER BY created_at, id
+        "#,
+        order_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Moves an order to `status` if its current status allows it, and records
+/// who made the change.
+pub async fn update_order_status(
+    pool: &PgPool,
+    order_id: Uuid,
+    status: OrderStatus,
+    actor: &str,
+    note: Option<&str>,
+) -> Result<Order> {
+    let mut tx = pool.begin().await?;
+    let order = set_order_status(&mut tx, order_id, status, actor, note).await?;
+    tx.commit().await?;
+
+    Ok(order)
+}
+
+/// `update_order_status` inside the caller's transaction.
+async fn set_order_status(
+    tx: &mut PgConnection,
+    order_id: Uuid,
+    status: OrderStatus,
+    actor: &str,
+    note: Option<&str>,
+) -> Result<Order> {
+    let current = sqlx::query_scalar!(
+        r#"SELECT status as "status: OrderStatus" FROM orders WHERE id = $1 FOR UPDATE"#,
+        order_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Order not found"))?;
+    if !current.can_transition_to(status) {
+        return Err(anyhow::anyhow!(
+            "Order cannot move from {} to {}",
+            current,
+            status
+        ));
+    }
+
+    let order = sqlx::query_as!(
+        Order,
+        r#"
+        UPDATE orders
+        SET status = $1, updated_at = now()
+        WHERE id = $2
+        RETURNING
+            id, order_id, store_id, user_id, buyer_address, seller_address, amount,
+            status as "status: OrderStatus",
+            payment_status as "payment_status: PaymentStatus", transaction_hash, platform_fee,
+            currency, list_amount, exchange_rate, receipt_token_id, payout_address, created_at,
+            updated_at
+        "#,
+        status as OrderStatus,
+        order_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if status == OrderStatus::Cancelled {
+        restore_order_stock(tx, order_id, actor).await?;
+    }
+    record_order_event(
+        &mut *tx,
+        order_id,
+        "status",
+        Some(current.as_str()),
+        status.as_str(),
+        actor,
+        note,
+    )
+    .await?;
+
+    Ok(order)
+}
+
+/// Puts a cancelled order's items back in stock, recording each in the ledger.
+async fn restore_order_stock(tx: &mut PgConnection, order_id: Uuid, actor: &str) -> Result<()> {
+    let items = sqlx::query!(
+        "SELECT product_id, quantity FROM order_items WHERE order_id = $1 ORDER BY product_id",
+        order_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for item in items {
+        record_stock_movement(
+            tx,
+            item.product_id,
+            item.quantity,
+            "cancellation",
+            Some(order_id),
+            None,
+            Some(actor),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Moves an order's payment to `payment_status` inside the caller's
+/// transaction. Setting the status it already has is a no-op.
+async fn set_payment_status(
+    tx: &mut PgConnection,
+    order_id: Uuid,
+    payment_status: PaymentStatus,
+    actor: &str,
+    note: Option<&str>,
+) -> Result<()> {
+    let current = sqlx::query_scalar!(
+        r#"
+        SELECT payment_status as "payment_status: PaymentStatus"
+        FROM orders
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        order_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if current == payment_status {
+        return Ok(());
+    }
+    if !current.can_transition_to(payment_status) {
+        return Err(anyhow::anyhow!(
+            "Order payment cannot move from {} to {}",
+            current,
+            payment_status
+        ));
+    }
+
+    sqlx::query!(
+        "UPDATE orders SET payment_status = $1, updated_at = now() WHERE id = $2",
+        payment_status as PaymentStatus,
+        order_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    record_order_event(
+        &mut *tx,
+        order_id,
+        "payment_status",
+        Some(current.as_str()),
+        payment_status.as_str(),
+        actor,
+        note,
+    )
+    .await?;
+
+    Ok(())
+}
+
+const DEFAULT_ORDER_PAGE_SIZE: i64 = 20;
+const MAX_ORDER_PAGE_SIZE: i64 = 100;
+
+/// The value an order is sorted on, as a number so one query can page by
+/// either: the amount, or `created_at` in epoch seconds.
+fn order_sort_key(order: &Order, sort: OrderSort) -> Decimal {
+    match sort {
+        OrderSort::Amount => order.amount,
+        OrderSort::CreatedAt => order
+            .created_at
+            .map(|at| Decimal::new(at.timestamp_micros(), 6))
+            .unwrap_or_default(),
+    }
+}
+
+fn encode_order_cursor(key: Decimal, id: Uuid) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}|{}", key, id))
+}
+
+fn decode_order_cursor(cursor: &str) -> Result<(Decimal, Uuid)> {
+    let invalid = || anyhow::anyhow!("Invalid cursor");
+    let decoded = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let (key, id) = decoded.split_once('|').ok_or_else(invalid)?;
+    Ok((
+        Decimal::from_str(key).map_err(|_| invalid())?,
+        Uuid::parse_str(id).map_err(|_| invalid())?,
+    ))
+}
+
+/// One page of a store's orders, a buyer's orders, or both when both are
+/// given. Pages are keyed on the sort value and id, so orders placed while
+/// paging neither repeat nor shift later pages.
+pub async fn list_orders(
+    db: &PgPool,
+    store_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    query: &OrderListQuery,
+) -> Result<OrderPage> {
+    let sort = query.sort.unwrap_or(OrderSort::CreatedAt);
+    let descending = query.direction.unwrap_or(SortDirection::Desc) == SortDirection::Desc;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ORDER_PAGE_SIZE)
+        .clamp(1, MAX_ORDER_PAGE_SIZE);
+    let (after_key, after_id) = query
+        .cursor
+        .as_deref()
+        .map(decode_order_cursor)
+        .transpose()?
+        .unzip();
+
+    let mut orders = sqlx::query_as!(
+        Order,
+        r#"
+        SELECT
+            id, order_id, store_id, user_id, buyer_address, seller_address, amount,
+            status as "status: OrderStatus",
+            payment_status as "payment_status: PaymentStatus", transaction_hash, platform_fee,
+            currency, list_amount, exchange_rate, receipt_token_id, payout_address, created_at,
+            updated_at
+        FROM orders
+        WHERE ($1::UUID IS NULL OR store_id = $1)
+        AND ($2::UUID IS NULL OR user_id = $2)
+        AND ($3::VARCHAR IS NULL OR status = $3)
+        AND ($4::VARCHAR IS NULL OR payment_status = $4)
+        AND ($5::TIMESTAMPTZ IS NULL OR created_at >= $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR created_at < $6)
+        AND (
+            $9::NUMERIC IS NULL
+            OR ($8 AND (
+                CASE WHEN $7 THEN amount ELSE EXTRACT(EPOCH FROM created_at) END, id
+            ) < ($9, $10::UUID))
+            OR (NOT $8 AND (
+                CASE WHEN $7 THEN amount ELSE EXTRACT(EPOCH FROM created_at) END, id
+            ) > ($9, $10::UUID))
+        )
+        ORDER BY
+            CASE WHEN $8 THEN
+                CASE WHEN $7 THEN amount ELSE EXTRACT(EPOCH FROM created_at) END
+            END DESC,
+            CASE WHEN $8 THEN id END DESC,
+            CASE WHEN NOT $8 THEN
+                CASE WHEN $7 THEN amount ELSE EXTRACT(EPOCH FROM created_at) END
+            END ASC,
+            CASE WHEN NOT $8 THEN id END ASC
+        LIMIT $11
+        "#,
+        store_id,
+        user_id,
+        query.status.map(OrderStatus::as_str),
+        query.payment_status.map(PaymentStatus::as_str),
+        query.from,
+        query.to,
+        sort == OrderSort::Amount,
+        descending,
+        after_key,
+        after_id,
+        limit + 1
+    )
+    .fetch_all(db)
+    .await?;
+
+    let next_cursor = if orders.len() as i64 > limit {
+        orders.truncate(limit as usize);
+        orders
+            .last()
+            .map(|order| encode_order_cursor(order_sort_key(order, sort), order.id))
+    } else {
+        None
+    };
+
+    Ok(OrderPage {
+        orders,
+        next_cursor,
+    })
+}
+
+pub async fn update_store(
+    db: &PgPool,
+    store_id: Uuid,
+    payload: CreateStoreRequest,
+    image_cid: Option<String>,
+) -> Result<Store, sqlx::Error> {
+    let share_link = format!("https://jes-saas.onrender.com/store/{}", store_id);
+
+    sqlx::query!(
+        r#"
+        UPDATE stores
+        SET store_name = $1, image_cid = $2, description = $3, owner_address = $4,
+            accepts_native_celo = COALESCE($5, accepts_native_celo),
+            mint_receipts = COALESCE($6, mint_receipts), updated_at = CURRENT_TIMESTAMP
+        WHERE id = $7
+        "#,
+        payload.store_name,
+        image_cid,
+        payload.description,
+        payload.owner_address,
+        payload.accepts_native_celo,
+        payload.mint_receipts,
+        store_id
+    )
+    .execute(db)
+    .await?;
+
+    let store = sqlx::query_as!(
+        Store,
+        r#"
+        SELECT id, store_name, image_cid, description, owner_address, $1 as "share_link!",
+            accepts_native_celo, mint_receipts, payout_address
+        FROM stores
+        WHERE id = $2
+        "#,
+        share_link,
+        store_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(store)
 }
 
 pub async fn delete_store(db: &PgPool, store_id: Uuid) -> Result<(), sqlx::Error> {
@@ -532,7 +1831,8 @@ pub async fn get_all_stores(db: &PgPool) -> Result<Vec<Store>, sqlx::Error> {
             image_cid, 
             description, 
             owner_address,
-            format('https://jes-saas.onrender.com/store/{}', id) as "share_link!"
+            format('https://jes-saas.onrender.com/store/{}', id) as "share_link!",
+            accepts_native_celo, mint_receipts, payout_address
         FROM stores
         "#
     )
@@ -552,7 +1852,8 @@ pub async fn get_store_by_id(db: &PgPool, store_id: Uuid) -> Result<Store, sqlx:
             image_cid, 
             description, 
             owner_address,
-            format('https://jes-saas.onrender.com/store/{}', id) as "share_link!"
+            format('https://jes-saas.onrender.com/store/{}', id) as "share_link!",
+            accepts_native_celo, mint_receipts, payout_address
         FROM stores
         WHERE id = $1
         "#,
@@ -561,3 +1862,2818 @@ pub async fn get_store_by_id(db: &PgPool, store_id: Uuid) -> Result<Store, sqlx:
     .fetch_one(db)
     .await
 }
+
+pub async fn create_payment_intent<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &web3::Web3<T>,
+    prices: &dyn PriceSource,
+    gates: &TokenGateCache,
+    user_id: Uuid,
+    payload: CreatePaymentIntentRequest,
+) -> Result<PaymentIntentResponse> {
+    let token = find_token(&payload.token)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported payment token: {}", payload.token))?;
+
+    let mut tx = pool.begin().await?;
+
+    let mut cart_items = cart_lines(&mut *tx, payload.cart_id).await?;
+
+    if cart_items.is_empty() {
+        return Err(anyhow::anyhow!("Cart is empty"));
+    }
+    apply_token_gates(pool, web3, gates, &payload.buyer_address, &mut cart_items).await?;
+    let quote_expires_at = quote_lines(prices, &mut cart_items, token, &mut HashMap::new()).await?;
+
+    for item in &cart_items {
+        if token.native && !item.accepts_native_celo {
+            return Err(anyhow::anyhow!(
+                "Store {} does not accept {}",
+                item.store_id,
+                token.symbol
+            ));
+        }
+        if item.stock < item.quantity {
+            return Err(anyhow::anyhow!(
+                "Insufficient stock for product: {}",
+                item.product_id
+            ));
+        }
+    }
+    let shares = store_shares(&cart_items, token);
+    require_single_native_payee(token, shares.len())?;
+    let treasury = treasury_share(&shares, token);
+
+    let intent_id = Uuid::new_v4();
+    let reference = format!(
+        "JES-{}",
+        Uuid::new_v4().simple().to_string()[..10].to_uppercase()
+    );
+    // Capped by the quotes: past them the amounts are no longer good.
+    let expires_at = (chrono::Utc::now() + chrono::Duration::seconds(payment_intent_ttl_secs()))
+        .min(quote_expires_at);
+
+    let mut payees: Vec<(Option<Uuid>, String, Decimal)> = shares
+        .into_iter()
+        .map(|share| (Some(share.store_id), share.payout_address, share.amount))
+        .collect();
+    payees.extend(treasury.map(|(address, fee)| (None, address, fee)));
+
+    // Created at the undusted total, which is settled once each recipient
+    // has been given its unique amount.
+    let intent = sqlx::query_as!(
+        PaymentIntent,
+        r#"
+        INSERT INTO payment_intents (
+            id, reference, user_id, cart_id, buyer_address, token_symbol, token_address,
+            total_amount, expires_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING
+            id, reference, user_id, cart_id, buyer_address, token_symbol, token_address,
+            total_amount, status, transaction_hash, expires_at, created_at
+        "#,
+        intent_id,
+        reference,
+        user_id,
+        payload.cart_id,
+        payload.buyer_address,
+        token.symbol,
+        token.address,
+        payees.iter().map(|(_, _, amount)| *amount).sum::<Decimal>(),
+        expires_at
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let mut recipients = Vec::with_capacity(payees.len());
+    for (store_id, recipient_address, payee_total) in payees {
+        let recipient = insert_payment_recipient(
+            &mut tx,
+            intent_id,
+            store_id,
+            token,
+            &recipient_address,
+            payee_total,
+        )
+        .await?;
+        recipients.push(recipient);
+    }
+    let intent = sqlx::query_as!(
+        PaymentIntent,
+        r#"
+        UPDATE payment_intents
+        SET total_amount = $1
+        WHERE id = $2
+        RETURNING
+            id, reference, user_id, cart_id, buyer_address, token_symbol, token_address,
+            total_amount, status, transaction_hash, expires_at, created_at
+        "#,
+        recipients.iter().map(|r| r.amount).sum::<Decimal>(),
+        intent.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    lock_stock(&mut tx, &cart_items, payload.cart_id, None).await?;
+    reserve_lines(
+        &mut tx,
+        payload.cart_id,
+        Some(intent_id),
+        &cart_items,
+        expires_at,
+    )
+    .await?;
+
+    let mut items = Vec::with_capacity(cart_items.len());
+    for item in cart_items {
+        let item = sqlx::query_as!(
+            PaymentIntentItem,
+            r#"
+            INSERT INTO payment_intent_items (
+                id, payment_intent_id, product_id, store_id, quantity, unit_price, currency,
+                list_price, exchange_rate, fee_rate
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING
+                id, payment_intent_id, product_id, store_id, quantity, unit_price, currency,
+                list_price, exchange_rate
+            "#,
+            Uuid::new_v4(),
+            intent_id,
+            item.product_id,
+            item.store_id,
+            item.quantity,
+            item.price,
+            item.currency,
+            item.list_price,
+            item.exchange_rate,
+            item.fee_rate
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        items.push(item);
+    }
+
+    tx.commit().await?;
+
+    let payment_requests = payment_requests(&intent, &recipients)?;
+    Ok(PaymentIntentResponse {
+        intent,
+        recipients,
+        items,
+        payment_requests,
+    })
+}
+
+fn payment_requests(
+    intent: &PaymentIntent,
+    recipients: &[PaymentIntentRecipient],
+) -> Result<Vec<PaymentRequest>> {
+    let token = find_token(&intent.token_symbol)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported payment token: {}", intent.token_symbol))?;
+    let chain_id = chain_id();
+
+    recipients
+        .iter()
+        .map(|recipient| {
+            Ok(PaymentRequest {
+                recipient_id: recipient.id,
+                recipient_address: recipient.recipient_address.clone(),
+                amount: recipient.amount,
+                uri: payment_uri(
+                    token,
+                    &recipient.recipient_address,
+                    recipient.amount,
+                    chain_id,
+                )?,
+                qr_code_url: format!(
+                    "/payment-intents/{}/qr?recipient_id={}",
+                    intent.id, recipient.id
+                ),
+            })
+        })
+        .collect()
+}
+
+/// How long an intent stays payable, from `PAYMENT_INTENT_TTL_SECS`. An
+/// intent never outlives the price quotes it was priced at, so a shorter
+/// `PRICE_QUOTE_TTL_SECS`, or an earlier expiry from the price source, cuts
+/// it short.
+fn payment_intent_ttl_secs() -> i64 {
+    std::env::var("PAYMENT_INTENT_TTL_SECS")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(900)
+}
+
+/// Adds a recipient to an intent, asking for `amount` plus a few base units
+/// of "dust" so that the transfer can be matched back to exactly one pending
+/// intent for the same recipient. A unique index on pending amounts settles
+/// races with concurrent intents; a taken amount is retried with new dust.
+async fn insert_payment_recipient(
+    tx: &mut PgConnection,
+    payment_intent_id: Uuid,
+    store_id: Option<Uuid>,
+    token: &Token,
+    recipient_address: &str,
+    amount: Decimal,
+) -> Result<PaymentIntentRecipient> {
+    for _ in 0..10 {
+        let random = Uuid::new_v4();
+        let bytes = random.as_bytes();
+        let dust = i64::from(u16::from_be_bytes([bytes[0], bytes[1]])) % 9999 + 1;
+        let candidate = amount + Decimal::new(dust, token.decimals);
+
+        let recipient = sqlx::query_as!(
+            PaymentIntentRecipient,
+            r#"
+            INSERT INTO payment_intent_recipients (
+                id, payment_intent_id, store_id, recipient_address, token_address, amount
+            )
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING
+            RETURNING id, payment_intent_id, store_id, recipient_address, amount, paid_transaction_hash
+            "#,
+            Uuid::new_v4(),
+            payment_intent_id,
+            store_id,
+            recipient_address,
+            token.address,
+            candidate
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(recipient) = recipient {
+            return Ok(recipient);
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "Could not allocate a unique payment amount, try again"
+    ))
+}
+
+pub async fn get_payment_intent(
+    db: &PgPool,
+    intent_id: Uuid,
+) -> Result<Option<PaymentIntentResponse>> {
+    let intent = sqlx::query_as!(
+        PaymentIntent,
+        r#"
+        SELECT
+            id, reference, user_id, cart_id, buyer_address, token_symbol, token_address,
+            total_amount, status, transaction_hash, expires_at, created_at
+        FROM payment_intents
+        WHERE id = $1
+        "#,
+        intent_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    let Some(intent) = intent else {
+        return Ok(None);
+    };
+
+    let recipients = sqlx::query_as!(
+        PaymentIntentRecipient,
+        r#"
+        SELECT id, payment_intent_id, store_id, recipient_address, amount, paid_transaction_hash
+        FROM payment_intent_recipients
+        WHERE payment_intent_id = $1
+        "#,
+        intent_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let items = sqlx::query_as!(
+        PaymentIntentItem,
+        r#"
+        SELECT
+            id, payment_intent_id, product_id, store_id, quantity, unit_price, currency,
+            list_price, exchange_rate
+        FROM payment_intent_items
+        WHERE payment_intent_id = $1
+        "#,
+        intent_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let payment_requests = payment_requests(&intent, &recipients)?;
+    Ok(Some(PaymentIntentResponse {
+        intent,
+        recipients,
+        items,
+        payment_requests,
+    }))
+}
+
+async fn get_open_payment_intent(
+    pool: &PgPool,
+    intent_id: Uuid,
+    user_id: Uuid,
+    cart_id: Uuid,
+) -> Result<PaymentIntentResponse> {
+    let intent = get_payment_intent(pool, intent_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Payment intent not found"))?;
+
+    if intent.intent.user_id != user_id || intent.intent.cart_id != cart_id {
+        return Err(anyhow::anyhow!(
+            "Payment intent does not belong to this cart"
+        ));
+    }
+    if intent.intent.status != "pending" {
+        return Err(anyhow::anyhow!(
+            "Payment intent is {}",
+            intent.intent.status
+        ));
+    }
+    if intent.intent.expires_at <= chrono::Utc::now() {
+        return Err(anyhow::anyhow!("Payment intent has expired"));
+    }
+
+    Ok(intent)
+}
+
+/// Marks pending intents past their expiry as expired and releases the
+/// stock they were holding back from other buyers.
+pub async fn expire_payment_intents(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let expired = sqlx::query_scalar!(
+        r#"
+        WITH expired AS (
+            UPDATE payment_intents
+            SET status = 'expired'
+            WHERE status = 'pending' AND expires_at <= now()
+            RETURNING id
+        ), released AS (
+            UPDATE stock_reservations r
+            SET status = 'released'
+            FROM expired
+            WHERE r.payment_intent_id = expired.id AND r.status = 'active'
+        )
+        SELECT COUNT(*) as "count!" FROM expired
+        "#
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(expired as u64)
+}
+
+fn reservation_ttl_secs() -> i64 {
+    std::env::var("RESERVATION_TTL_SECS")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(900)
+}
+
+/// Replaces the cart's checkout holds with holds on `lines` until
+/// `expires_at`. The caller must have locked the products with `lock_stock`.
+async fn reserve_lines(
+    tx: &mut PgConnection,
+    cart_id: Uuid,
+    payment_intent_id: Option<Uuid>,
+    lines: &[CheckoutLine],
+    expires_at: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<StockReservation>, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE stock_reservations
+        SET status = 'released'
+        WHERE cart_id = $1 AND payment_intent_id IS NULL AND status = 'active'
+        "#,
+        cart_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let mut reservations = Vec::with_capacity(lines.len());
+    for line in lines {
+        let reservation = sqlx::query_as!(
+            StockReservation,
+            r#"
+            INSERT INTO stock_reservations (product_id, cart_id, payment_intent_id, quantity, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, product_id, cart_id, payment_intent_id, quantity, status, expires_at, created_at
+            "#,
+            line.product_id,
+            cart_id,
+            payment_intent_id,
+            line.quantity,
+            expires_at
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        reservations.push(reservation);
+    }
+
+    Ok(reservations)
+}
+
+/// Holds the cart's stock for `RESERVATION_TTL_SECS` while its buyer pays.
+/// Calling it again replaces the cart's holds and restarts the clock.
+pub async fn reserve_cart_stock(pool: &PgPool, cart_id: Uuid) -> Result<Vec<StockReservation>> {
+    let mut tx = pool.begin().await?;
+    let lines = cart_lines(&mut *tx, cart_id).await?;
+    if lines.is_empty() {
+        return Err(anyhow::anyhow!("Cart is empty"));
+    }
+
+    lock_stock(&mut tx, &lines, cart_id, None).await?;
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(reservation_ttl_secs());
+    let reservations = reserve_lines(&mut tx, cart_id, None, &lines, expires_at).await?;
+    tx.commit().await?;
+
+    Ok(reservations)
+}
+
+/// Releases holds whose time has run out. Expired holds already stop
+/// counting against stock; this keeps the table's active set small.
+pub async fn release_expired_reservations(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE stock_reservations
+        SET status = 'released'
+        WHERE status = 'active' AND expires_at <= now()
+        "#
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn get_indexer_cursor(db: &PgPool, name: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT last_block FROM indexer_cursors WHERE name = $1
+        "#,
+        name
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn set_indexer_cursor(db: &PgPool, name: &str, block: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO indexer_cursors (name, last_block)
+        VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET last_block = EXCLUDED.last_block, updated_at = now()
+        "#,
+        name,
+        block
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_store_wallets(db: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT owner_address as "address!" FROM stores
+        UNION
+        SELECT payout_address FROM stores WHERE payout_address IS NOT NULL
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Whether `wallet` is the login or payout wallet of a store owned by `owner_address`.
+pub async fn is_store_wallet(
+    db: &PgPool,
+    owner_address: &str,
+    wallet: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM stores
+            WHERE owner_address = $1
+            AND (lower(owner_address) = lower($2) OR lower(payout_address) = lower($2))
+        ) as "exists!"
+        "#,
+        owner_address,
+        wallet
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Stores a transfer seen on chain. Returns `None` when the log was already
+/// recorded by an earlier run.
+/// Records an indexed transfer. Returns `None` if it was recorded before, or
+/// if its transaction was already claimed as a payment through checkout, so
+/// payments that are settled do not land in the reconciliation queue.
+pub async fn record_chain_transfer(
+    db: &PgPool,
+    transfer: NewChainTransfer,
+) -> Result<Option<ChainTransfer>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransfer,
+        r#"
+        INSERT INTO chain_transfers (
+            id, transaction_hash, log_index, block_number, token_address,
+            from_address, to_address, amount
+        )
+        SELECT $1, $2, $3, $4, $5, $6, $7, $8
+        WHERE NOT EXISTS (
+            SELECT 1 FROM payment_transactions WHERE transaction_hash = lower($2::VARCHAR)
+        )
+        ON CONFLICT (transaction_hash, log_index) DO NOTHING
+        RETURNING
+            id, transaction_hash, log_index, block_number, token_address, from_address,
+            to_address, amount, status, payment_intent_id, order_id, note, created_at
+        "#,
+        Uuid::new_v4(),
+        transfer.transaction_hash,
+        transfer.log_index,
+        transfer.block_number,
+        transfer.token_address,
+        transfer.from_address,
+        transfer.to_address,
+        transfer.amount
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Tries to pair an incoming transfer with an open payment intent first and a
+/// pending order second. Only the buyer's own transfers count, and the
+/// transaction is claimed as the payment in the same database transaction
+/// that settles it, so it cannot be replayed through checkout. Anything left
+/// over stays `unmatched` for the seller to reconcile by hand.
+pub async fn match_chain_transfer(pool: &PgPool, transfer: &ChainTransfer) -> Result<String> {
+    let token = find_token_by_address(&transfer.token_address)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported token: {}", transfer.token_address))?;
+    let mut tx = pool.begin().await?;
+
+    let recipient = sqlx::query!(
+        r#"
+        UPDATE payment_intent_recipients
+        SET paid_transaction_hash = $1
+        WHERE id = (
+            SELECT r.id
+            FROM payment_intent_recipients r
+            JOIN payment_intents pi ON r.payment_intent_id = pi.id
+            WHERE lower(r.recipient_address) = lower($2) AND r.amount = $3
+            AND lower(pi.token_address) = lower($4)
+            AND lower(pi.buyer_address) = lower($5)
+            AND pi.status = 'pending' AND r.paid_transaction_hash IS NULL
+            ORDER BY pi.created_at
+            LIMIT 1
+            FOR UPDATE OF r SKIP LOCKED
+        )
+        RETURNING payment_intent_id
+        "#,
+        transfer.transaction_hash,
+        transfer.to_address,
+        transfer.amount,
+        transfer.token_address,
+        transfer.from_address
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(recipient) = recipient {
+        let intent_id = recipient.payment_intent_id;
+        let intent = sqlx::query!(
+            r#"
+            SELECT
+                user_id,
+                (
+                    SELECT COUNT(*)
+                    FROM payment_intent_recipients
+                    WHERE payment_intent_id = $1 AND paid_transaction_hash IS NULL
+                ) as "outstanding!"
+            FROM payment_intents
+            WHERE id = $1
+            "#,
+            intent_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Settling runs in a savepoint, so a failure still records the match.
+        let mut settle = sqlx::Connection::begin(&mut *tx).await?;
+        let settled = async {
+            claim_payment_transaction(
+                &mut settle,
+                &transfer.transaction_hash,
+                token,
+                Some(intent.user_id),
+                Some(intent_id),
+            )
+            .await?;
+            if intent.outstanding == 0 {
+                complete_intent_in(&mut settle, intent_id, &transfer.transaction_hash).await?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        let (status, note) = match settled {
+            Ok(()) => {
+                settle.commit().await?;
+                ("matched", None)
+            }
+            Err(e) => {
+                settle.rollback().await?;
+                ("unmatched", Some(e.to_string()))
+            }
+        };
+
+        sqlx::query!(
+            r#"
+            UPDATE chain_transfers
+            SET status = $1, payment_intent_id = $2, note = $3
+            WHERE id = $4
+            "#,
+            status,
+            intent_id,
+            note,
+            transfer.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        return Ok(status.to_string());
+    }
+
+    // Direct orders are priced in cUSD. The store's transfer must be its share
+    // after the platform fee, and the fee must reach the treasury from the
+    // buyer in the same transaction; the indexer records both before matching.
+    if transfer.token_address.eq_ignore_ascii_case(CUSD.address) {
+        let treasury = treasury_address().unwrap_or_default();
+        let order = sqlx::query!(
+            r#"
+            UPDATE orders
+            SET payment_status = 'confirmed', transaction_hash = $1
+            WHERE id = (
+                SELECT o.id
+                FROM orders o
+                WHERE lower(o.payout_address) = lower($2) AND o.amount - o.platform_fee = $3
+                AND lower(o.buyer_address) = lower($4)
+                AND o.payment_status = 'pending' AND o.transaction_hash IS NULL
+                AND (o.platform_fee = 0 OR EXISTS (
+                    SELECT 1
+                    FROM chain_transfers f
+                    WHERE f.transaction_hash = $1 AND lower(f.to_address) = lower($5)
+                    AND lower(f.from_address) = lower($4) AND lower(f.token_address) = lower($6)
+                    AND f.amount = o.platform_fee AND f.status = 'unmatched'
+                ))
+                ORDER BY o.created_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id, platform_fee
+            "#,
+            transfer.transaction_hash,
+            transfer.to_address,
+            transfer.amount,
+            transfer.from_address,
+            treasury,
+            transfer.token_address
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(order) = order {
+            claim_payment_transaction(
+                &mut tx,
+                &transfer.transaction_hash,
+                token,
+                order.user_id,
+                None,
+            )
+            .await?;
+            record_order_event(
+                &mut *tx,
+                order.id,
+                "payment_status",
+                Some(PaymentStatus::Pending.as_str()),
+                PaymentStatus::Confirmed.as_str(),
+                "system",
+                Some(&format!("Paid by transfer {}", transfer.transaction_hash)),
+            )
+            .await?;
+            sqlx::query!(
+                r#"
+                UPDATE chain_transfers
+                SET status = 'matched', order_id = $1
+                WHERE id = $2 OR id = (
+                    SELECT id
+                    FROM chain_transfers
+                    WHERE transaction_hash = $3 AND lower(to_address) = lower($4)
+                    AND amount = $5 AND status = 'unmatched' AND $5 > 0
+                    LIMIT 1
+                )
+                "#,
+                order.id,
+                transfer.id,
+                transfer.transaction_hash,
+                treasury,
+                order.platform_fee
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            return Ok("matched".to_string());
+        }
+    }
+
+    Ok("unmatched".to_string())
+}
+
+pub async fn list_unmatched_transfers(
+    db: &PgPool,
+    wallet_address: &str,
+) -> Result<Vec<ChainTransfer>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransfer,
+        r#"
+        SELECT
+            id, transaction_hash, log_index, block_number, token_address, from_address,
+            to_address, amount, status, payment_intent_id, order_id, note, created_at
+        FROM chain_transfers
+        WHERE lower(to_address) = lower($1) AND status = 'unmatched'
+        ORDER BY block_number, log_index
+        "#,
+        wallet_address
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Transfers indexed from the given blocks that are still waiting for a match.
+pub async fn list_unmatched_transfers_in_blocks(
+    db: &PgPool,
+    from_block: i64,
+    to_block: i64,
+) -> Result<Vec<ChainTransfer>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransfer,
+        r#"
+        SELECT
+            id, transaction_hash, log_index, block_number, token_address, from_address,
+            to_address, amount, status, payment_intent_id, order_id, note, created_at
+        FROM chain_transfers
+        WHERE block_number BETWEEN $1 AND $2 AND status = 'unmatched'
+        ORDER BY block_number, log_index
+        "#,
+        from_block,
+        to_block
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_chain_transfer(
+    db: &PgPool,
+    transfer_id: Uuid,
+) -> Result<Option<ChainTransfer>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransfer,
+        r#"
+        SELECT
+            id, transaction_hash, log_index, block_number, token_address, from_address,
+            to_address, amount, status, payment_intent_id, order_id, note, created_at
+        FROM chain_transfers
+        WHERE id = $1
+        "#,
+        transfer_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Closes an item in the reconciliation queue. When the seller names the
+/// order the transfer paid for, that order is marked as paid as well.
+pub async fn resolve_chain_transfer(
+    pool: &PgPool,
+    transfer: &ChainTransfer,
+    payload: ResolveTransferRequest,
+) -> Result<ChainTransfer> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(order_id) = payload.order_id {
+        let token = find_token_by_address(&transfer.token_address)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported token: {}", transfer.token_address))?;
+        let order = sqlx::query!(
+            r#"
+            UPDATE orders
+            SET payment_status = 'confirmed', transaction_hash = $1
+            WHERE id = $2 AND lower(payout_address) = lower($3) AND payment_status = 'pending'
+            RETURNING user_id
+            "#,
+            transfer.transaction_hash,
+            order_id,
+            transfer.to_address
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| {
+            anyhow::anyhow!("Order {} is not a pending order of this store", order_id)
+        })?;
+        // Claimed with the order, so the transaction cannot also pay a checkout.
+        claim_payment_transaction(
+            &mut tx,
+            &transfer.transaction_hash,
+            token,
+            order.user_id,
+            None,
+        )
+        .await?;
+        record_order_event(
+            &mut *tx,
+            order_id,
+            "payment_status",
+            Some(PaymentStatus::Pending.as_str()),
+            PaymentStatus::Confirmed.as_str(),
+            &transfer.to_address,
+            Some(&format!(
+                "Matched to transfer {}",
+                transfer.transaction_hash
+            )),
+        )
+        .await?;
+    }
+
+    let transfer = sqlx::query_as!(
+        ChainTransfer,
+        r#"
+        UPDATE chain_transfers
+        SET status = 'resolved', order_id = COALESCE($1, order_id), note = COALESCE($2, note)
+        WHERE id = $3
+        RETURNING
+            id, transaction_hash, log_index, block_number, token_address, from_address,
+            to_address, amount, status, payment_intent_id, order_id, note, created_at
+        "#,
+        payload.order_id,
+        payload.note,
+        transfer.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(transfer)
+}
+
+pub async fn get_order_by_id(db: &PgPool, order_id: Uuid) -> Result<Option<Order>, sqlx::Error> {
+    sqlx::query_as!(
+        Order,
+        r#"
+        SELECT
+            id, order_id, store_id, user_id, buyer_address, seller_address,
+            amount, status as "status: OrderStatus",
+            payment_status as "payment_status: PaymentStatus", transaction_hash, platform_fee, currency, list_amount,
+            exchange_rate, receipt_token_id, payout_address, created_at, updated_at
+        FROM orders
+        WHERE id = $1
+        "#,
+        order_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list_order_items(db: &PgPool, order_id: Uuid) -> Result<Vec<OrderItem>, sqlx::Error> {
+    sqlx::query_as!(
+        OrderItem,
+        r#"
+        SELECT
+            id, order_id, product_id, quantity, unit_price, list_price, currency, exchange_rate,
+            amount, platform_fee
+        FROM order_items
+        WHERE order_id = $1
+        ORDER BY created_at, id
+        "#,
+        order_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// An order with its line items, its history and its shipments.
+pub async fn get_order_details(db: &PgPool, order: Order) -> Result<OrderDetails, sqlx::Error> {
+    let items = list_order_items(db, order.id).await?;
+    let events = list_order_events(db, order.id).await?;
+    let shipments = list_order_shipments(db, order.id).await?;
+
+    Ok(OrderDetails {
+        order,
+        items,
+        events,
+        shipments,
+    })
+}
+
+/// Sends some or all of what is left of an order and moves it to `shipped`
+/// if it was still pending.
+pub async fn create_shipment(
+    pool: &PgPool,
+    order_id: Uuid,
+    payload: &CreateShipmentRequest,
+    created_by: &str,
+) -> Result<ShipmentDetails> {
+    let carrier = payload.carrier.trim().to_ascii_lowercase();
+    if carrier.is_empty() || carrier.len() > 32 {
+        return Err(anyhow::anyhow!("Carrier must be 1 to 32 characters"));
+    }
+    let tracking_code = payload.tracking_code.trim();
+    if tracking_code.is_empty()
+        || tracking_code.len() > 64
+        || !tracking_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(anyhow::anyhow!(
+            "Tracking code must be 1 to 64 letters, digits or dashes"
+        ));
+    }
+    let template = payload
+        .tracking_url_template
+        .as_deref()
+        .or_else(|| default_tracking_url_template(&carrier));
+    let tracking_url = template
+        .map(|template| tracking_url(template, tracking_code))
+        .transpose()?;
+
+    let mut tx = pool.begin().await?;
+
+    let status = sqlx::query_scalar!(
+        r#"SELECT status as "status: OrderStatus" FROM orders WHERE id = $1 FOR UPDATE"#,
+        order_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Order not found"))?;
+    if !matches!(status, OrderStatus::Pending | OrderStatus::Shipped) {
+        return Err(anyhow::anyhow!("Order is {} and cannot be shipped", status));
+    }
+
+    // What each item still has to ship
+    let unshipped: HashMap<Uuid, i32> = sqlx::query!(
+        r#"
+        SELECT oi.id, oi.quantity - COALESCE(SUM(si.quantity), 0)::INTEGER as "remaining!"
+        FROM order_items oi
+        LEFT JOIN shipment_items si ON si.order_item_id = oi.id
+        WHERE oi.order_id = $1
+        GROUP BY oi.id
+        "#,
+        order_id
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|item| (item.id, item.remaining))
+    .collect();
+
+    let mut lines: HashMap<Uuid, i32> = HashMap::new();
+    if payload.items.is_empty() {
+        lines.extend(unshipped.iter().filter(|(_, remaining)| **remaining > 0));
+    }
+    for item in &payload.items {
+        if item.quantity < 1 {
+            return Err(anyhow::anyhow!("Shipped quantities must be at least 1"));
+        }
+        *lines.entry(item.order_item_id).or_default() += item.quantity;
+    }
+    if lines.is_empty() {
+        return Err(anyhow::anyhow!("Order has nothing left to ship"));
+    }
+    for (order_item_id, quantity) in &lines {
+        let remaining = unshipped
+            .get(order_item_id)
+            .ok_or_else(|| anyhow::anyhow!("Item {} is not in this order", order_item_id))?;
+        if quantity > remaining {
+            return Err(anyhow::anyhow!(
+                "Only {} of item {} are left to ship",
+                remaining,
+                order_item_id
+            ));
+        }
+    }
+
+    let taken = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM shipments WHERE carrier = $1 AND tracking_code = $2)",
+        carrier,
+        tracking_code
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if taken == Some(true) {
+        return Err(anyhow::anyhow!("Tracking code is already in use"));
+    }
+
+    let shipment = sqlx::query_as!(
+        Shipment,
+        r#"
+        INSERT INTO shipments (
+            order_id, carrier, tracking_code, tracking_url, estimated_delivery, created_by
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING
+            id, order_id, carrier, tracking_code, tracking_url, status, estimated_delivery,
+            delivered_at, created_by, created_at, updated_at
+        "#,
+        order_id,
+        carrier,
+        tracking_code,
+        tracking_url,
+        payload.estimated_delivery,
+        created_by
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let mut items = Vec::with_capacity(lines.len());
+    for (order_item_id, quantity) in lines {
+        let item = sqlx::query_as!(
+            ShipmentItem,
+            r#"
+            INSERT INTO shipment_items (shipment_id, order_item_id, quantity)
+            VALUES ($1, $2, $3)
+            RETURNING shipment_id, order_item_id, quantity
+            "#,
+            shipment.id,
+            order_item_id,
+            quantity
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        items.push(item);
+    }
+    items.sort_by_key(|item| item.order_item_id);
+
+    if status == OrderStatus::Pending {
+        let note = format!("Shipped with {} {}", carrier, tracking_code);
+        set_order_status(
+            &mut tx,
+            order_id,
+            OrderStatus::Shipped,
+            created_by,
+            Some(&note),
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(ShipmentDetails { shipment, items })
+}
+
+/// An order's shipments, oldest first.
+pub async fn list_order_shipments(
+    db: &PgPool,
+    order_id: Uuid,
+) -> Result<Vec<ShipmentDetails>, sqlx::Error> {
+    let shipments = sqlx::query_as!(
+        Shipment,
+        r#"
+        SELECT
+            id, order_id, carrier, tracking_code, tracking_url, status, estimated_delivery,
+            delivered_at, created_by, created_at, updated_at
+        FROM shipments
+        WHERE order_id = $1
+        ORDER BY created_at, id
+        "#,
+        order_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut items = sqlx::query_as!(
+        ShipmentItem,
+        r#"
+        SELECT si.shipment_id, si.order_item_id, si.quantity
+        FROM shipment_items si
+        JOIN shipments s ON s.id = si.shipment_id
+        WHERE s.order_id = $1
+        ORDER BY si.order_item_id
+        "#,
+        order_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(shipments
+        .into_iter()
+        .map(|shipment| {
+            let (shipped, rest) = items
+                .drain(..)
+                .partition(|item| item.shipment_id == shipment.id);
+            items = rest;
+            ShipmentDetails {
+                shipment,
+                items: shipped,
+            }
+        })
+        .collect())
+}
+
+/// Shipments the carrier has not delivered yet, least recently checked first.
+pub async fn list_in_transit_shipments(db: &PgPool) -> Result<Vec<Shipment>, sqlx::Error> {
+    sqlx::query_as!(
+        Shipment,
+        r#"
+        SELECT
+            id, order_id, carrier, tracking_code, tracking_url, status, estimated_delivery,
+            delivered_at, created_by, created_at, updated_at
+        FROM shipments
+        WHERE status = 'in_transit'
+        ORDER BY updated_at
+        LIMIT 100
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Marks a shipment delivered. Returns `None` if it was no longer in transit,
+/// otherwise whether that was the order's last parcel, i.e. every item has
+/// shipped and nothing is still in transit.
+pub async fn mark_shipment_delivered(
+    db: &PgPool,
+    shipment: &Shipment,
+) -> Result<Option<bool>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE shipments
+        SET status = 'delivered', delivered_at = now()
+        WHERE id = $1 AND status = 'in_transit'
+        "#,
+        shipment.id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Ok(None);
+    }
+
+    let complete = sqlx::query_scalar!(
+        r#"
+        SELECT
+            NOT EXISTS (
+                SELECT 1 FROM shipments
+                WHERE order_id = $1 AND status = 'in_transit'
+            )
+            AND NOT EXISTS (
+                SELECT 1
+                FROM order_items oi
+                LEFT JOIN shipment_items si ON si.order_item_id = oi.id
+                WHERE oi.order_id = $1
+                GROUP BY oi.id, oi.quantity
+                HAVING oi.quantity > COALESCE(SUM(si.quantity), 0)
+            ) as "complete!"
+        "#,
+        shipment.order_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(complete))
+}
+
+pub async fn get_escrow_for_order(
+    db: &PgPool,
+    order_id: Uuid,
+) -> Result<Option<Escrow>, sqlx::Error> {
+    sqlx::query_as!(
+        Escrow,
+        r#"
+        SELECT
+            id, order_id, contract_address, buyer_address, seller_address, token_address,
+            amount, status, deposit_transaction_hash, release_transaction_hash,
+            refund_transaction_hash, release_after, created_at, updated_at
+        FROM escrows
+        WHERE order_id = $1
+        "#,
+        order_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Verifies a buyer's deposit into the escrow contract for a pending order
+/// and records the escrow. The order counts as paid, but the seller only
+/// gets the funds once the escrow is released.
+pub async fn open_escrow<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    order: &Order,
+    transaction_hash: &str,
+) -> Result<Escrow> {
+    let contract =
+        escrow_contract().ok_or_else(|| anyhow::anyhow!("Escrow payments are not enabled"))?;
+    if order.payment_status != PaymentStatus::Pending {
+        return Err(anyhow::anyhow!(
+            "Order payment is already {}",
+            order.payment_status
+        ));
+    }
+
+    let deposit = find_escrow_deposit(web3, contract, transaction_hash, order.id).await?;
+    let token = find_token_by_address(&format!("{:?}", deposit.token))
+        .filter(|token| !token.native)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported escrow token {:?}", deposit.token))?;
+
+    if !format!("{:?}", deposit.buyer).eq_ignore_ascii_case(&order.buyer_address) {
+        return Err(anyhow::anyhow!("Invalid buyer address"));
+    }
+    if !format!("{:?}", deposit.seller).eq_ignore_ascii_case(&order.payout_address) {
+        return Err(anyhow::anyhow!("Invalid seller address"));
+    }
+    if deposit.amount != to_base_units(order.amount, token.decimals)? {
+        return Err(anyhow::anyhow!("Invalid payment amount"));
+    }
+
+    let escrow = NewEscrow {
+        order_id: order.id,
+        contract_address: format!("{:?}", contract),
+        buyer_address: order.buyer_address.clone(),
+        seller_address: order.payout_address.clone(),
+        token_address: token.address.to_string(),
+        amount: order.amount,
+        deposit_transaction_hash: transaction_hash.to_lowercase(),
+        release_after: chrono::DateTime::from_timestamp(deposit.release_after as i64, 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid escrow release time"))?,
+    };
+
+    let mut tx = pool.begin().await?;
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE orders
+        SET payment_status = 'escrowed', transaction_hash = $1
+        WHERE id = $2 AND payment_status = 'pending'
+        "#,
+        escrow.deposit_transaction_hash,
+        order.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() != 1 {
+        return Err(anyhow::anyhow!(
+            "Order {} is no longer pending payment",
+            order.id
+        ));
+    }
+    record_order_event(
+        &mut *tx,
+        order.id,
+        "payment_status",
+        Some(PaymentStatus::Pending.as_str()),
+        PaymentStatus::Escrowed.as_str(),
+        &order.buyer_address,
+        Some(&format!("Deposited in escrow by {}", transaction_hash)),
+    )
+    .await?;
+
+    let escrow = sqlx::query_as!(
+        Escrow,
+        r#"
+        INSERT INTO escrows (
+            order_id, contract_address, buyer_address, seller_address, token_address,
+            amount, deposit_transaction_hash, release_after
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING
+            id, order_id, contract_address, buyer_address, seller_address, token_address,
+            amount, status, deposit_transaction_hash, release_transaction_hash,
+            refund_transaction_hash, release_after, created_at, updated_at
+        "#,
+        escrow.order_id,
+        escrow.contract_address,
+        escrow.buyer_address,
+        escrow.seller_address,
+        escrow.token_address,
+        escrow.amount,
+        escrow.deposit_transaction_hash,
+        escrow.release_after
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(escrow)
+}
+
+/// Pays a funded escrow out to the seller. `function` is the contract call
+/// to use: `release` on delivery, `releaseExpired` once the timeout passed.
+pub async fn release_escrow<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    escrow: &Escrow,
+    function: &str,
+) -> Result<Escrow> {
+    settle_escrow(
+        pool,
+        web3,
+        escrow,
+        function,
+        "released",
+        PaymentStatus::Confirmed,
+    )
+    .await
+}
+
+/// Returns a funded escrow to the buyer.
+pub async fn refund_escrow<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    escrow: &Escrow,
+) -> Result<Escrow> {
+    settle_escrow(
+        pool,
+        web3,
+        escrow,
+        "refund",
+        "refunded",
+        PaymentStatus::Refunded,
+    )
+    .await
+}
+
+async fn settle_escrow<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    escrow: &Escrow,
+    function: &str,
+    escrow_status: &str,
+    payment_status: PaymentStatus,
+) -> Result<Escrow> {
+    let contract = Address::from_str(escrow.contract_address.trim_start_matches("0x"))?;
+    let buyer = Address::from_str(escrow.buyer_address.trim_start_matches("0x"))?;
+
+    // Take the escrow out of 'funded' first so only one caller sends the transaction.
+    let claimed = sqlx::query!(
+        "UPDATE escrows SET status = 'settling' WHERE id = $1 AND status = 'funded'",
+        escrow.id
+    )
+    .execute(pool)
+    .await?;
+    if claimed.rows_affected() != 1 {
+        return Err(anyhow::anyhow!("Escrow is not funded"));
+    }
+
+    let transaction_hash =
+        match send_escrow_call(web3, contract, function, buyer, escrow.order_id).await {
+            Ok(transaction_hash) => transaction_hash,
+            Err(e) => {
+                sqlx::query!(
+                    "UPDATE escrows SET status = 'funded' WHERE id = $1 AND status = 'settling'",
+                    escrow.id
+                )
+                .execute(pool)
+                .await?;
+                return Err(e);
+            }
+        };
+
+    let mut tx = pool.begin().await?;
+
+    let escrow = sqlx::query_as!(
+        Escrow,
+        r#"
+        UPDATE escrows
+        SET
+            status = $1::VARCHAR,
+            release_transaction_hash = CASE WHEN $1 = 'released' THEN $2 ELSE release_transaction_hash END,
+            refund_transaction_hash = CASE WHEN $1 = 'refunded' THEN $2 ELSE refund_transaction_hash END
+        WHERE id = $3
+        RETURNING
+            id, order_id, contract_address, buyer_address, seller_address, token_address,
+            amount, status, deposit_transaction_hash, release_transaction_hash,
+            refund_transaction_hash, release_after, created_at, updated_at
+        "#,
+        escrow_status,
+        transaction_hash,
+        escrow.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    set_payment_status(
+        &mut tx,
+        escrow.order_id,
+        payment_status,
+        "system",
+        Some(&format!("Escrow {} by {}", escrow_status, transaction_hash)),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(escrow)
+}
+
+/// Funded escrows whose timeout has passed and can be released by anyone.
+pub async fn list_expired_escrows(db: &PgPool) -> Result<Vec<Escrow>, sqlx::Error> {
+    sqlx::query_as!(
+        Escrow,
+        r#"
+        SELECT
+            id, order_id, contract_address, buyer_address, seller_address, token_address,
+            amount, status, deposit_transaction_hash, release_transaction_hash,
+            refund_transaction_hash, release_after, created_at, updated_at
+        FROM escrows
+        WHERE status = 'funded' AND release_after <= now()
+        ORDER BY release_after
+        LIMIT 50
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// The token an order was paid in, falling back to cUSD for orders paid
+/// before payment transactions were recorded.
+pub async fn get_order_payment_token(db: &PgPool, order: &Order) -> Result<&'static Token> {
+    let Some(transaction_hash) = &order.transaction_hash else {
+        return Ok(&CUSD);
+    };
+    let symbol = sqlx::query_scalar!(
+        "SELECT token_symbol FROM payment_transactions WHERE transaction_hash = lower($1)",
+        transaction_hash
+    )
+    .fetch_optional(db)
+    .await?;
+
+    match symbol {
+        Some(symbol) => {
+            find_token(&symbol).ok_or_else(|| anyhow::anyhow!("Unknown payment token {}", symbol))
+        }
+        None => Ok(&CUSD),
+    }
+}
+
+/// Looks up an order's payment on chain: whether it was mined and
+/// succeeded, how deep it is, and what it paid the order's payout address.
+pub async fn get_order_payment<T: web3::Transport>(
+    db: &PgPool,
+    web3: &Web3<T>,
+    order: &Order,
+) -> Result<OrderPayment> {
+    let token = get_order_payment_token(db, order).await?;
+    let mut payment = OrderPayment {
+        order_id: order.id,
+        transaction_hash: order.transaction_hash.clone(),
+        status: "unpaid".to_string(),
+        confirmations: 0,
+        required_confirmations: min_confirmations(),
+        block_number: None,
+        block_timestamp: None,
+        token_symbol: token.symbol.to_string(),
+        token_address: token.address.to_string(),
+        amount: None,
+        payer: None,
+        chain_id: chain_id(),
+        transaction_url: order.transaction_hash.as_deref().and_then(transaction_url),
+        payer_url: None,
+        token_url: Some(token.address)
+            .filter(|_| !token.native)
+            .and_then(token_url),
+    };
+    let Some(transaction_hash) = &order.transaction_hash else {
+        return Ok(payment);
+    };
+
+    let hash = H256::from_str(transaction_hash.trim_start_matches("0x"))
+        .map_err(|_| anyhow::anyhow!("Invalid transaction hash: {}", transaction_hash))?;
+    let Some(transaction) = web3.eth().transaction(TransactionId::Hash(hash)).await? else {
+        payment.status = "not_found".to_string();
+        return Ok(payment);
+    };
+    if let Some(from) = transaction.from {
+        let payer = format!("{:?}", from);
+        payment.payer_url = address_url(&payer);
+        payment.payer = Some(payer);
+    }
+
+    let receipt = web3.eth().transaction_receipt(hash).await?;
+    let Some((receipt, block_number)) = receipt.and_then(|receipt| {
+        receipt
+            .block_number
+            .map(|block_number| (receipt, block_number))
+    }) else {
+        payment.status = "pending".to_string();
+        return Ok(payment);
+    };
+    payment.block_number = Some(block_number.as_u64());
+    let head = web3.eth().block_number().await?;
+    payment.confirmations = head.saturating_sub(block_number).as_u64() + 1;
+    payment.block_timestamp = web3
+        .eth()
+        .block(BlockId::Number(BlockNumber::Number(block_number)))
+        .await?
+        .and_then(|block| chrono::DateTime::from_timestamp(block.timestamp.as_u64() as i64, 0));
+
+    if receipt.status != Some(1.into()) {
+        payment.status = "failed".to_string();
+        return Ok(payment);
+    }
+    payment.status = if payment.confirmations >= payment.required_confirmations {
+        "confirmed".to_string()
+    } else {
+        "pending".to_string()
+    };
+
+    let payout = Address::from_str(order.payout_address.trim_start_matches("0x")).ok();
+    let paid = if token.native {
+        Some(transaction.value).filter(|_| transaction.to.is_some() && transaction.to == payout)
+    } else {
+        let token_address = Address::from_str(token.address.trim_start_matches("0x"))?;
+        let transfer_topic =
+            H256::from_str("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")?;
+        receipt
+            .logs
+            .iter()
+            .filter(|log| log.address == token_address && log.topics.len() == 3)
+            .filter(|log| log.topics[0] == transfer_topic)
+            .filter(|log| Some(Address::from_slice(&log.topics[2][12..])) == payout)
+            .map(|log| U256::from_big_endian(&log.data.0))
+            .reduce(|total, amount| total.saturating_add(amount))
+    };
+    payment.amount = paid
+        .map(|amount| from_base_units(amount, token.decimals))
+        .transpose()?;
+
+    Ok(payment)
+}
+
+/// Opens a refund inside the caller's transaction. `amount` defaults to
+/// whatever has not been refunded yet.
+async fn insert_refund(
+    tx: &mut PgConnection,
+    order: &Order,
+    requested_by: &str,
+    amount: Option<Decimal>,
+    reason: Option<&str>,
+    token: &Token,
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Refund> {
+    // Lock the order so concurrent refunds cannot exceed what was paid.
+    sqlx::query!("SELECT id FROM orders WHERE id = $1 FOR UPDATE", order.id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let refunded = sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(SUM(amount), 0) as "refunded!"
+        FROM refunds
+        WHERE order_id = $1 AND status != 'rejected'
+        "#,
+        order.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let remaining = order.amount - refunded;
+    let amount = amount.unwrap_or(remaining);
+    if amount <= Decimal::zero() || amount > remaining {
+        return Err(anyhow::anyhow!(
+            "Refund amount must be between 0 and {}",
+            remaining
+        ));
+    }
+
+    let refund = sqlx::query_as!(
+        Refund,
+        r#"
+        INSERT INTO refunds (order_id, requested_by, amount, reason, token_symbol, due_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING
+            id, order_id, requested_by, amount, reason, status, token_symbol,
+            transaction_hash, due_at, created_at, updated_at
+        "#,
+        order.id,
+        requested_by,
+        amount,
+        reason,
+        token.symbol,
+        due_at
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    Ok(refund)
+}
+
+/// Opens a refund against a paid order, for at most what is still unrefunded.
+pub async fn create_refund(
+    pool: &PgPool,
+    order: &Order,
+    requested_by: &str,
+    payload: &CreateRefundRequest,
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Refund> {
+    if !order.payment_status.is_refundable() {
+        return Err(anyhow::anyhow!(
+            "Order with payment status {} cannot be refunded",
+            order.payment_status
+        ));
+    }
+    let token = get_order_payment_token(pool, order).await?;
+
+    let mut tx = pool.begin().await?;
+    let refund = insert_refund(
+        &mut tx,
+        order,
+        requested_by,
+        payload.amount,
+        payload.reason.as_deref(),
+        token,
+        due_at,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(refund)
+}
+
+/// When a refund the seller owes must be sent by: `REFUND_WINDOW_SECS` from now.
+fn refund_deadline() -> chrono::DateTime<chrono::Utc> {
+    let window = std::env::var("REFUND_WINDOW_SECS")
+        .ok()
+        .and_then(|window| window.parse().ok())
+        .unwrap_or(7 * 24 * 60 * 60);
+    chrono::Utc::now() + chrono::Duration::seconds(window)
+}
+
+/// Opens a refund of everything not yet refunded on a cancelled order, which
+/// the seller has until `refund_deadline` to send and cannot reject.
+pub async fn require_cancellation_refund(
+    pool: &PgPool,
+    order: &Order,
+    requested_by: &str,
+) -> Result<Refund> {
+    let payload = CreateRefundRequest {
+        amount: None,
+        reason: Some("Order cancelled".to_string()),
+        transaction_hash: None,
+    };
+    create_refund(pool, order, requested_by, &payload, Some(refund_deadline())).await
+}
+
+pub async fn get_refund(db: &PgPool, refund_id: Uuid) -> Result<Option<Refund>, sqlx::Error> {
+    sqlx::query_as!(
+        Refund,
+        r#"
+        SELECT
+            id, order_id, requested_by, amount, reason, status, token_symbol,
+            transaction_hash, due_at, created_at, updated_at
+        FROM refunds
+        WHERE id = $1
+        "#,
+        refund_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list_order_refunds(db: &PgPool, order_id: Uuid) -> Result<Vec<Refund>, sqlx::Error> {
+    sqlx::query_as!(
+        Refund,
+        r#"
+        SELECT
+            id, order_id, requested_by, amount, reason, status, token_symbol,
+            transaction_hash, due_at, created_at, updated_at
+        FROM refunds
+        WHERE order_id = $1
+        ORDER BY created_at
+        "#,
+        order_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Verifies the seller sent the refund back to the buyer on-chain, then
+/// marks it completed and moves the order to `refunded` or `partially_refunded`.
+pub async fn complete_refund<T: web3::Transport>(
+    pool: &PgPool,
+    web3: &Web3<T>,
+    order: &Order,
+    refund: &Refund,
+    transaction_hash: &str,
+) -> Result<Refund> {
+    let token = find_token(&refund.token_symbol)
+        .ok_or_else(|| anyhow::anyhow!("Unknown refund token {}", refund.token_symbol))?;
+    verify_transfer(
+        web3,
+        transaction_hash,
+        token,
+        Some(&order.payout_address),
+        &order.buyer_address,
+        refund.amount,
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("Refund verification failed: {}", e))?;
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("SELECT id FROM orders WHERE id = $1 FOR UPDATE", order.id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let refund = sqlx::query_as!(
+        Refund,
+        r#"
+        UPDATE refunds
+        SET status = 'completed', transaction_hash = lower($1)
+        WHERE id = $2 AND status IN ('requested', 'overdue')
+        RETURNING
+            id, order_id, requested_by, amount, reason, status, token_symbol,
+            transaction_hash, due_at, created_at, updated_at
+        "#,
+        transaction_hash,
+        refund.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Refund is not awaiting a transaction"))?;
+
+    let refunded = sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(SUM(amount), 0) as "refunded!"
+        FROM refunds
+        WHERE order_id = $1 AND status = 'completed'
+        "#,
+        order.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let payment_status = if refunded >= order.amount {
+        PaymentStatus::Refunded
+    } else {
+        PaymentStatus::PartiallyRefunded
+    };
+    set_payment_status(
+        &mut tx,
+        order.id,
+        payment_status,
+        "system",
+        Some(&format!("Refund {} completed", refund.id)),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(refund)
+}
+
+pub async fn reject_refund(db: &PgPool, refund_id: Uuid) -> Result<Option<Refund>, sqlx::Error> {
+    sqlx::query_as!(
+        Refund,
+        r#"
+        UPDATE refunds
+        SET status = 'rejected'
+        WHERE id = $1 AND status = 'requested' AND due_at IS NULL
+        RETURNING
+            id, order_id, requested_by, amount, reason, status, token_symbol,
+            transaction_hash, due_at, created_at, updated_at
+        "#,
+        refund_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Marks refunds the seller did not send before their deadline as overdue.
+/// They can still be completed.
+pub async fn mark_overdue_refunds(db: &PgPool) -> Result<Vec<Refund>, sqlx::Error> {
+    sqlx::query_as!(
+        Refund,
+        r#"
+        UPDATE refunds
+        SET status = 'overdue'
+        WHERE status = 'requested' AND due_at <= now()
+        RETURNING
+            id, order_id, requested_by, amount, reason, status, token_symbol,
+            transaction_hash, due_at, created_at, updated_at
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+const RETURN_REASONS: [&str; 5] = [
+    "damaged",
+    "wrong_item",
+    "not_as_described",
+    "missing_parts",
+    "other",
+];
+
+/// Most photos a buyer can attach to a return.
+pub const MAX_RETURN_PHOTOS: usize = 5;
+
+/// Opens a return for part of a delivered order line. Items already in a
+/// return that was not turned down cannot be returned again.
+pub async fn create_return_request(
+    pool: &PgPool,
+    order: &Order,
+    payload: &CreateReturnRequest,
+    evidence_cids: &[String],
+    requested_by: &str,
+) -> Result<ReturnRequest> {
+    if !RETURN_REASONS.contains(&payload.reason_code.as_str()) {
+        return Err(anyhow::anyhow!(
+            "Invalid reason code. Must be one of: {}",
+            RETURN_REASONS.join(", ")
+        ));
+    }
+    if payload.quantity < 1 {
+        return Err(anyhow::anyhow!("Quantity must be at least 1"));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let status = sqlx::query_scalar!(
+        r#"SELECT status as "status: OrderStatus" FROM orders WHERE id = $1 FOR UPDATE"#,
+        order.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if status != OrderStatus::Delivered {
+        return Err(anyhow::anyhow!("Only delivered orders can be returned"));
+    }
+
+    let item = sqlx::query!(
+        r#"
+        SELECT
+            oi.quantity,
+            (
+                SELECT COALESCE(SUM(r.quantity), 0)
+                FROM return_requests r
+                WHERE r.order_item_id = oi.id
+                AND NOT (r.status = 'resolved' AND r.refund_id IS NULL)
+            )::INTEGER as "returned!"
+        FROM order_items oi
+        WHERE oi.id = $1 AND oi.order_id = $2
+        "#,
+        payload.order_item_id,
+        order.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Item is not in this order"))?;
+    let returnable = item.quantity - item.returned;
+    if payload.quantity > returnable {
+        return Err(anyhow::anyhow!(
+            "Only {} of this item can be returned",
+            returnable
+        ));
+    }
+
+    let request = sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        INSERT INTO return_requests (
+            order_id, order_item_id, quantity, reason_code, details, evidence_cids, requested_by
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        "#,
+        order.id,
+        payload.order_item_id,
+        payload.quantity,
+        payload.reason_code,
+        payload.details,
+        evidence_cids,
+        requested_by
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(request)
+}
+
+pub async fn get_return_request(
+    db: &PgPool,
+    return_id: Uuid,
+) -> Result<Option<ReturnRequest>, sqlx::Error> {
+    sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        SELECT
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        FROM return_requests
+        WHERE id = $1
+        "#,
+        return_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list_order_returns(
+    db: &PgPool,
+    order_id: Uuid,
+) -> Result<Vec<ReturnRequest>, sqlx::Error> {
+    sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        SELECT
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        FROM return_requests
+        WHERE order_id = $1
+        ORDER BY created_at
+        "#,
+        order_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Open disputes, longest waiting first.
+pub async fn list_disputed_returns(db: &PgPool) -> Result<Vec<ReturnRequest>, sqlx::Error> {
+    sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        SELECT
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        FROM return_requests
+        WHERE status = 'disputed'
+        ORDER BY updated_at
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Locks a return that must be in one of `statuses` to be acted on.
+async fn lock_return_request(
+    tx: &mut PgConnection,
+    return_id: Uuid,
+    statuses: &[&str],
+) -> Result<ReturnRequest> {
+    let request = sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        SELECT
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        FROM return_requests
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        return_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Return not found"))?;
+    if !statuses.contains(&request.status.as_str()) {
+        return Err(anyhow::anyhow!("Return is already {}", request.status));
+    }
+    Ok(request)
+}
+
+/// Does what settling a return in the buyer's favour means inside the
+/// caller's transaction: opens the refund the seller owes for it, and puts
+/// the items back in stock when `restock` is set. Returns the refund and
+/// ledger entry to link to the return.
+async fn settle_return(
+    tx: &mut PgConnection,
+    order: &Order,
+    request: &ReturnRequest,
+    token: &Token,
+    amount: Option<Decimal>,
+    restock: bool,
+    actor: &str,
+) -> Result<(Uuid, Option<Uuid>)> {
+    if !order.payment_status.is_refundable() {
+        return Err(anyhow::anyhow!(
+            "Order with payment status {} cannot be refunded",
+            order.payment_status
+        ));
+    }
+    let item = sqlx::query!(
+        "SELECT product_id, quantity, amount FROM order_items WHERE id = $1",
+        request.order_item_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    // The returned items' share of what the line cost
+    let amount = amount.unwrap_or_else(|| {
+        (item.amount * Decimal::from(request.quantity) / Decimal::from(item.quantity)).round_dp(2)
+    });
+    let reason = format!("Return {}", request.id);
+    let refund = insert_refund(
+        tx,
+        order,
+        actor,
+        Some(amount),
+        Some(&reason),
+        token,
+        Some(refund_deadline()),
+    )
+    .await?;
+
+    let movement = if restock {
+        let movement = record_stock_movement(
+            tx,
+            item.product_id,
+            request.quantity,
+            "refund",
+            Some(order.id),
+            Some(&reason),
+            Some(actor),
+        )
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Stock could not be restored"))?;
+        Some(movement.id)
+    } else {
+        None
+    };
+
+    Ok((refund.id, movement))
+}
+
+/// The seller accepting a return: the buyer is owed a refund and the items
+/// can go back in stock.
+pub async fn accept_return(
+    pool: &PgPool,
+    order: &Order,
+    return_id: Uuid,
+    payload: &AcceptReturnRequest,
+    actor: &str,
+) -> Result<ReturnRequest> {
+    let token = get_order_payment_token(pool, order).await?;
+
+    let mut tx = pool.begin().await?;
+    let request = lock_return_request(&mut tx, return_id, &["requested"]).await?;
+    let (refund_id, movement_id) = settle_return(
+        &mut tx,
+        order,
+        &request,
+        token,
+        payload.amount,
+        payload.restock,
+        actor,
+    )
+    .await?;
+    let request = sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        UPDATE return_requests
+        SET status = 'accepted', seller_note = $1, resolved_by = $2, refund_id = $3,
+            inventory_movement_id = $4
+        WHERE id = $5
+        RETURNING
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        "#,
+        payload.note,
+        actor,
+        refund_id,
+        movement_id,
+        request.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(request)
+}
+
+pub async fn reject_return(
+    pool: &PgPool,
+    return_id: Uuid,
+    note: Option<&str>,
+    actor: &str,
+) -> Result<ReturnRequest> {
+    let mut tx = pool.begin().await?;
+    lock_return_request(&mut tx, return_id, &["requested"]).await?;
+    let request = sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        UPDATE return_requests
+        SET status = 'rejected', seller_note = $1, resolved_by = $2
+        WHERE id = $3
+        RETURNING
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        "#,
+        note,
+        actor,
+        return_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(request)
+}
+
+/// Turns a return the seller rejected or has not answered into a dispute.
+pub async fn escalate_return(
+    pool: &PgPool,
+    return_id: Uuid,
+    reason: &str,
+) -> Result<ReturnRequest> {
+    let mut tx = pool.begin().await?;
+    lock_return_request(&mut tx, return_id, &["requested", "rejected"]).await?;
+    let request = sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        UPDATE return_requests
+        SET status = 'disputed', dispute_reason = $1
+        WHERE id = $2
+        RETURNING
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        "#,
+        reason,
+        return_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(request)
+}
+
+/// An admin's ruling on a dispute. Ruling for the buyer settles it like an
+/// accepted return; ruling for the seller closes it with nothing owed.
+pub async fn resolve_return(
+    pool: &PgPool,
+    order: &Order,
+    return_id: Uuid,
+    payload: &ResolveReturnRequest,
+    actor: &str,
+) -> Result<ReturnRequest> {
+    let token = get_order_payment_token(pool, order).await?;
+
+    let mut tx = pool.begin().await?;
+    let request = lock_return_request(&mut tx, return_id, &["disputed"]).await?;
+    let (refund_id, movement_id) = if payload.refund {
+        let (refund_id, movement_id) = settle_return(
+            &mut tx,
+            order,
+            &request,
+            token,
+            payload.amount,
+            payload.restock,
+            actor,
+        )
+        .await?;
+        (Some(refund_id), movement_id)
+    } else {
+        (None, None)
+    };
+    let request = sqlx::query_as!(
+        ReturnRequest,
+        r#"
+        UPDATE return_requests
+        SET status = 'resolved', resolution_note = $1, resolved_by = $2, refund_id = $3,
+            inventory_movement_id = $4
+        WHERE id = $5
+        RETURNING
+            id, order_id, order_item_id, quantity, reason_code, details, evidence_cids, status,
+            requested_by, seller_note, dispute_reason, resolution_note, resolved_by, refund_id,
+            inventory_movement_id, created_at, updated_at
+        "#,
+        payload.note,
+        actor,
+        refund_id,
+        movement_id,
+        request.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(request)
+}
+
+/// Adds a transaction for the backend wallet to send. Returns `None` when an
+/// equivalent transaction is already queued, e.g. a second receipt for an order.
+pub async fn queue_chain_transaction(
+    db: &PgPool,
+    kind: &str,
+    order_id: Option<Uuid>,
+    to_address: &str,
+    data: Option<String>,
+) -> Result<Option<ChainTransaction>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransaction,
+        r#"
+        INSERT INTO chain_transactions (kind, order_id, to_address, data)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+        RETURNING
+            id, kind, order_id, to_address, data, status, nonce, transaction_hash,
+            transaction_hashes, attempts, last_error, next_attempt_at, sent_at, created_at
+        "#,
+        kind,
+        order_id,
+        to_address,
+        data
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Queues minting the order's receipt NFT to its buyer, if receipts are enabled.
+pub async fn queue_receipt_mint(
+    db: &PgPool,
+    order_id: Uuid,
+) -> Result<Option<ChainTransaction>, sqlx::Error> {
+    let Some(contract) = receipt_contract() else {
+        return Ok(None);
+    };
+    queue_chain_transaction(
+        db,
+        "mint_receipt",
+        Some(order_id),
+        &format!("{:?}", contract),
+        None,
+    )
+    .await
+}
+
+/// The oldest queued transaction that is due to be (re)tried.
+pub async fn next_queued_transaction(db: &PgPool) -> Result<Option<ChainTransaction>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransaction,
+        r#"
+        SELECT
+            id, kind, order_id, to_address, data, status, nonce, transaction_hash,
+            transaction_hashes, attempts, last_error, next_attempt_at, sent_at, created_at
+        FROM chain_transactions
+        WHERE status = 'queued' AND next_attempt_at <= now()
+        ORDER BY created_at
+        LIMIT 1
+        "#
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list_sent_transactions(db: &PgPool) -> Result<Vec<ChainTransaction>, sqlx::Error> {
+    sqlx::query_as!(
+        ChainTransaction,
+        r#"
+        SELECT
+            id, kind, order_id, to_address, data, status, nonce, transaction_hash,
+            transaction_hashes, attempts, last_error, next_attempt_at, sent_at, created_at
+        FROM chain_transactions
+        WHERE status = 'sent'
+        ORDER BY nonce
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Highest nonce the backend wallet has used, so a node that forgot a pending
+/// transaction does not get the same nonce handed out twice.
+pub async fn max_used_nonce(db: &PgPool) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT MAX(nonce) FROM chain_transactions WHERE status IN ('sent', 'confirmed')"
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn set_transaction_data(db: &PgPool, id: Uuid, data: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE chain_transactions SET data = $1 WHERE id = $2",
+        data,
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Records a signed transaction under its nonce before it is broadcast, adding
+/// its hash to the ones checked for a receipt.
+pub async fn mark_transaction_sent(
+    db: &PgPool,
+    id: Uuid,
+    nonce: i64,
+    transaction_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE chain_transactions
+        SET status = 'sent', nonce = $1, transaction_hash = $2,
+            transaction_hashes = array_append(transaction_hashes, $2), sent_at = now(),
+            attempts = attempts + 1, last_error = NULL
+        WHERE id = $3
+        "#,
+        nonce,
+        transaction_hash,
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn mark_transaction_confirmed(db: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE chain_transactions SET status = 'confirmed' WHERE id = $1",
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Gives up on a sent transaction without freeing its nonce for a resend,
+/// since whatever was broadcast under it may yet be mined.
+pub async fn mark_transaction_dropped(
+    db: &PgPool,
+    id: Uuid,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE chain_transactions SET status = 'dropped', last_error = $1 WHERE id = $2",
+        error,
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Records a failed attempt. The transaction goes back in the queue after
+/// `retry_at` or, once `max_attempts` is reached, is given up on. Its nonce and
+/// hashes are dropped, so only call this once none of them can still be mined.
+pub async fn mark_transaction_attempt_failed(
+    db: &PgPool,
+    id: Uuid,
+    error: &str,
+    retry_at: chrono::DateTime<chrono::Utc>,
+    max_attempts: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE chain_transactions
+        SET attempts = attempts + 1, last_error = $1, nonce = NULL, transaction_hash = NULL,
+            transaction_hashes = '{}',
+            status = CASE WHEN attempts + 1 >= $2 THEN 'failed' ELSE 'queued' END,
+            next_attempt_at = $3
+        WHERE id = $4
+        "#,
+        error,
+        max_attempts,
+        retry_at,
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_order_receipt_token(
+    db: &PgPool,
+    order_id: Uuid,
+    token_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE orders SET receipt_token_id = $1 WHERE id = $2",
+        token_id,
+        order_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Everything a receipt NFT describes about an order.
+pub async fn get_receipt_metadata(
+    db: &PgPool,
+    order_id: Uuid,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT o.id, o.buyer_address, o.amount, o.currency, o.list_amount, o.transaction_hash,
+            o.created_at, s.store_name,
+            (
+                SELECT string_agg(p.product_name, ', ' ORDER BY oi.created_at, oi.id)
+                FROM order_items oi
+                JOIN products p ON oi.product_id = p.id
+                WHERE oi.order_id = o.id
+            ) as "product_name!",
+            (
+                SELECT p.image_cid
+                FROM order_items oi
+                JOIN products p ON oi.product_id = p.id
+                WHERE oi.order_id = o.id AND p.image_cid IS NOT NULL
+                ORDER BY oi.created_at, oi.id
+                LIMIT 1
+            ) as image_cid
+        FROM orders o
+        JOIN stores s ON o.store_id = s.id
+        WHERE o.id = $1
+        "#,
+        order_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| {
+        serde_json::json!({
+            "name": format!("{} receipt", row.store_name),
+            "description": format!("Proof of purchase of {} from {}", row.product_name, row.store_name),
+            "image": row.image_cid.map(|cid| format!("ipfs://{}", cid)),
+            "attributes": [
+                { "trait_type": "Order", "value": row.id.to_string() },
+                { "trait_type": "Store", "value": row.store_name },
+                { "trait_type": "Product", "value": row.product_name },
+                { "trait_type": "Price", "value": format!("{} {}", row.list_amount, row.currency) },
+                { "trait_type": "Paid", "value": row.amount.to_string() },
+                { "trait_type": "Buyer", "value": row.buyer_address },
+                { "trait_type": "Transaction", "value": row.transaction_hash },
+                { "trait_type": "Purchased", "value": row.created_at.to_rfc3339() },
+            ]
+        })
+    }))
+}
+
+pub async fn add_product_token_gate(
+    db: &PgPool,
+    product_id: Uuid,
+    payload: CreateProductTokenGateRequest,
+) -> Result<ProductTokenGate, sqlx::Error> {
+    sqlx::query_as!(
+        ProductTokenGate,
+        r#"
+        INSERT INTO product_token_gates (product_id, contract_address, token_id, discount_bps)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, product_id, contract_address, token_id, discount_bps, created_at
+        "#,
+        product_id,
+        payload.contract_address,
+        payload.token_id,
+        payload.discount_bps
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn list_product_token_gates(
+    db: &PgPool,
+    product_ids: &[Uuid],
+) -> Result<Vec<ProductTokenGate>, sqlx::Error> {
+    sqlx::query_as!(
+        ProductTokenGate,
+        r#"
+        SELECT id, product_id, contract_address, token_id, discount_bps, created_at
+        FROM product_token_gates
+        WHERE product_id = ANY($1)
+        ORDER BY created_at
+        "#,
+        product_ids
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn delete_product_token_gate(
+    db: &PgPool,
+    product_id: Uuid,
+    gate_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let deleted = sqlx::query!(
+        "DELETE FROM product_token_gates WHERE id = $1 AND product_id = $2",
+        gate_id,
+        product_id
+    )
+    .execute(db)
+    .await?;
+    Ok(deleted.rows_affected() == 1)
+}
+
+/// Whether `holder` satisfies at least one of `gates`.
+async fn holds_any_gate<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    cache: &TokenGateCache,
+    gates: &[&ProductTokenGate],
+    holder: &str,
+) -> Result<bool> {
+    for gate in gates {
+        let token_id = gate.token_id.map(|id| id as u64);
+        if cache
+            .holds(web3, &gate.contract_address, token_id, holder)
+            .await?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `holder` may buy the product. Products without access rules are open to everyone.
+pub async fn can_access_product<T: web3::Transport>(
+    db: &PgPool,
+    web3: &web3::Web3<T>,
+    cache: &TokenGateCache,
+    product_id: Uuid,
+    holder: &str,
+) -> Result<bool> {
+    let gates = list_product_token_gates(db, &[product_id]).await?;
+    let access: Vec<&ProductTokenGate> = gates
+        .iter()
+        .filter(|gate| gate.discount_bps.is_none())
+        .collect();
+    Ok(access.is_empty() || holds_any_gate(web3, cache, &access, holder).await?)
+}
+
+/// Rejects lines the buyer is not allowed to buy and lowers the listed price of
+/// those they hold a discount for, taking the best discount that applies.
+async fn apply_token_gates<T: web3::Transport>(
+    db: &PgPool,
+    web3: &web3::Web3<T>,
+    cache: &TokenGateCache,
+    holder: &str,
+    lines: &mut [CheckoutLine],
+) -> Result<()> {
+    let product_ids: Vec<Uuid> = lines.iter().map(|line| line.product_id).collect();
+    let gates = list_product_token_gates(db, &product_ids).await?;
+    if gates.is_empty() {
+        return Ok(());
+    }
+
+    for line in lines.iter_mut() {
+        let (access, discounts): (Vec<&ProductTokenGate>, Vec<&ProductTokenGate>) = gates
+            .iter()
+            .filter(|gate| gate.product_id == line.product_id)
+            .partition(|gate| gate.discount_bps.is_none());
+
+        if !access.is_empty() && !holds_any_gate(web3, cache, &access, holder).await? {
+            return Err(anyhow::anyhow!(
+                "Product {} is only available to token holders",
+                line.product_id
+            ));
+        }
+
+        let mut discount_bps = 0;
+        for gate in discounts {
+            let bps = gate.discount_bps.unwrap_or(0);
+            if bps > discount_bps && holds_any_gate(web3, cache, &[gate], holder).await? {
+                discount_bps = bps;
+            }
+        }
+        if discount_bps > 0 {
+            line.list_price = (line.list_price * Decimal::from(10_000 - discount_bps)
+                / Decimal::from(10_000))
+            .round_dp(2);
+        }
+    }
+
+    Ok(())
+}
+
+/// The message a store owner signs to send payouts to `payout_address`.
+/// It names the store and the time, so it cannot be replayed elsewhere or later.
+pub fn payout_address_message(
+    store_id: Uuid,
+    payout_address: Option<&str>,
+    issued_at: i64,
+) -> String {
+    format!(
+        "Change JES payout address\nStore: {}\nPayout address: {}\nIssued at: {}",
+        store_id,
+        payout_address.unwrap_or("owner wallet"),
+        issued_at
+    )
+}
+
+/// Points the store's payouts at a new wallet and records the signed request.
+/// Each signature can only be used once.
+pub async fn change_payout_address(
+    pool: &PgPool,
+    store: &Store,
+    payout_address: Option<&str>,
+    message: &str,
+    signature: &str,
+) -> Result<Store> {
+    let mut tx = pool.begin().await?;
+
+    let recorded = sqlx::query!(
+        r#"
+        INSERT INTO payout_address_changes (
+            store_id, previous_address, payout_address, signed_by, message, signature
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (signature) DO NOTHING
+        "#,
+        store.id,
+        store.payout_address,
+        payout_address,
+        store.owner_address,
+        message,
+        signature.to_lowercase()
+    )
+    .execute(&mut *tx)
+    .await?;
+    if recorded.rows_affected() != 1 {
+        return Err(anyhow::anyhow!("Signature has already been used"));
+    }
+
+    sqlx::query!(
+        "UPDATE stores SET payout_address = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2",
+        payout_address,
+        store.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(get_store_by_id(pool, store.id).await?)
+}
+
+pub async fn list_payout_address_changes(
+    db: &PgPool,
+    store_id: Uuid,
+) -> Result<Vec<PayoutAddressChange>, sqlx::Error> {
+    sqlx::query_as!(
+        PayoutAddressChange,
+        r#"
+        SELECT
+            id, store_id, previous_address, payout_address, signed_by, message, signature,
+            created_at
+        FROM payout_address_changes
+        WHERE store_id = $1
+        ORDER BY created_at DESC
+        "#,
+        store_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+fn idempotency_ttl_secs() -> i64 {
+    std::env::var("IDEMPOTENCY_TTL_SECS")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(24 * 60 * 60)
+}
+
+/// How long a claimed key stays locked to a request that has not answered
+/// yet. A first attempt that crashed frees the key once this runs out.
+fn idempotency_lease_secs() -> i64 {
+    std::env::var("IDEMPOTENCY_LEASE_SECS")
+        .ok()
+        .and_then(|lease| lease.parse().ok())
+        .unwrap_or(120)
+}
+
+/// Claims an idempotency key for a request about to run. Returns `false`
+/// when the caller already used the key and it has not expired, in which
+/// case the saved record decides the answer. An unanswered claim whose
+/// lease ran out is taken over, as its request never finished.
+pub async fn claim_idempotency_key(
+    db: &PgPool,
+    owner: &str,
+    idempotency_key: &str,
+    request_path: &str,
+    fingerprint: &str,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now();
+    let expires_at = now + chrono::Duration::seconds(idempotency_ttl_secs());
+    let locked_until = now + chrono::Duration::seconds(idempotency_lease_secs());
+    let claimed = sqlx::query_scalar!(
+        r#"
+        INSERT INTO idempotency_keys (
+            owner, idempotency_key, request_path, fingerprint, expires_at, locked_until
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (owner, idempotency_key) DO UPDATE
+        SET request_path = EXCLUDED.request_path,
+            fingerprint = EXCLUDED.fingerprint,
+            status_code = NULL,
+            content_type = NULL,
+            response_body = NULL,
+            created_at = now(),
+            expires_at = EXCLUDED.expires_at,
+            locked_until = EXCLUDED.locked_until
+        WHERE idempotency_keys.expires_at <= now()
+        OR (
+            idempotency_keys.status_code IS NULL
+            AND idempotency_keys.fingerprint = EXCLUDED.fingerprint
+            AND idempotency_keys.locked_until <= now()
+        )
+        RETURNING owner
+        "#,
+        owner,
+        idempotency_key,
+        request_path,
+        fingerprint,
+        expires_at,
+        locked_until
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(claimed.is_some())
+}
+
+pub async fn get_idempotency_record(
+    db: &PgPool,
+    owner: &str,
+    idempotency_key: &str,
+) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        IdempotencyRecord,
+        r#"
+        SELECT
+            owner, idempotency_key, request_path, fingerprint, status_code, content_type,
+            response_body, created_at, expires_at, locked_until
+        FROM idempotency_keys
+        WHERE owner = $1 AND idempotency_key = $2
+        "#,
+        owner,
+        idempotency_key
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Saves the first response to a claimed key, for retries to get back.
+pub async fn save_idempotent_response(
+    db: &PgPool,
+    owner: &str,
+    idempotency_key: &str,
+    status_code: i32,
+    content_type: Option<&str>,
+    response_body: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE idempotency_keys
+        SET status_code = $1, content_type = $2, response_body = $3, locked_until = NULL
+        WHERE owner = $4 AND idempotency_key = $5
+        "#,
+        status_code,
+        content_type,
+        response_body,
+        owner,
+        idempotency_key
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Gives up a claimed key, so a retry runs the request again.
+pub async fn release_idempotency_key(
+    db: &PgPool,
+    owner: &str,
+    idempotency_key: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM idempotency_keys WHERE owner = $1 AND idempotency_key = $2",
+        owner,
+        idempotency_key
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn purge_expired_idempotency_keys(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let purged = sqlx::query!("DELETE FROM idempotency_keys WHERE expires_at <= now()")
+        .execute(db)
+        .await?
+        .rows_affected();
+    Ok(purged)
+}
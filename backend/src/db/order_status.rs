@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Where an order is in fulfilment. Orders start `Pending` and can only move
+/// forward; `Delivered` and `Cancelled` are final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    Pending,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub const ALL: [OrderStatus; 4] = [
+        OrderStatus::Pending,
+        OrderStatus::Shipped,
+        OrderStatus::Delivered,
+        OrderStatus::Cancelled,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Delivered => "delivered",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn can_transition_to(self, next: OrderStatus) -> bool {
+        matches!(
+            (self, next),
+            (OrderStatus::Pending, OrderStatus::Shipped)
+                | (OrderStatus::Pending, OrderStatus::Cancelled)
+                | (OrderStatus::Shipped, OrderStatus::Delivered)
+        )
+    }
+}
+
+/// Where the money for an order is. `Escrowed` payments end up `Confirmed`
+/// when released to the seller or `Refunded` when returned to the buyer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    Pending,
+    Confirmed,
+    Escrowed,
+    PartiallyRefunded,
+    Refunded,
+}
+
+impl PaymentStatus {
+    pub const ALL: [PaymentStatus; 5] = [
+        PaymentStatus::Pending,
+        PaymentStatus::Confirmed,
+        PaymentStatus::Escrowed,
+        PaymentStatus::PartiallyRefunded,
+        PaymentStatus::Refunded,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Confirmed => "confirmed",
+            PaymentStatus::Escrowed => "escrowed",
+            PaymentStatus::PartiallyRefunded => "partially_refunded",
+            PaymentStatus::Refunded => "refunded",
+        }
+    }
+
+    pub fn can_transition_to(self, next: PaymentStatus) -> bool {
+        matches!(
+            (self, next),
+            (PaymentStatus::Pending, PaymentStatus::Confirmed)
+                | (PaymentStatus::Pending, PaymentStatus::Escrowed)
+                | (PaymentStatus::Escrowed, PaymentStatus::Confirmed)
+                | (PaymentStatus::Escrowed, PaymentStatus::Refunded)
+                | (PaymentStatus::Confirmed, PaymentStatus::PartiallyRefunded)
+                | (PaymentStatus::Confirmed, PaymentStatus::Refunded)
+                | (PaymentStatus::PartiallyRefunded, PaymentStatus::Refunded)
+        )
+    }
+
+    /// Whether the buyer has paid and still has money in the order to refund.
+    pub fn is_refundable(self) -> bool {
+        matches!(
+            self,
+            PaymentStatus::Confirmed | PaymentStatus::PartiallyRefunded
+        )
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OrderStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid status. Must be one of: {}",
+                    OrderStatus::ALL.map(OrderStatus::as_str).join(", ")
+                )
+            })
+    }
+}
+
+impl FromStr for PaymentStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PaymentStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid payment status. Must be one of: {}",
+                    PaymentStatus::ALL.map(PaymentStatus::as_str).join(", ")
+                )
+            })
+    }
+}
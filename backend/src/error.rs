@@ -1,6 +1,7 @@
 use axum::http::StatusCode;
 use std::error::Error;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum AppError {
     Database(sqlx::Error),
@@ -46,7 +47,7 @@ impl axum::response::IntoResponse for AppError {
 }
 
 impl From<(StatusCode, String)> for AppError {
-    fn from((status, message): (StatusCode, String)) -> Self {
+    fn from((_status, message): (StatusCode, String)) -> Self {
         AppError::Ipfs(message)
     }
 }
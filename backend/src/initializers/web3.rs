@@ -1,8 +1,27 @@
+use crate::utils::failover::{FailoverTransport, RpcConfig};
+use crate::utils::transport::ChainTransport;
 use std::env;
 
-pub fn initialize_web3() -> web3::Web3<web3::transports::Http> {
-    let transport =
-        web3::transports::Http::new(&env::var("WEB3_PROVIDER").expect("WEB3_PROVIDER must be set"))
-            .expect("Failed to initialize Web3 transport");
-    web3::Web3::new(transport)
+/// Connects to every provider in `WEB3_PROVIDERS` (comma separated, primary
+/// first), falling back to the single `WEB3_PROVIDER`.
+pub fn initialize_web3() -> web3::Web3<ChainTransport> {
+    let urls = env::var("WEB3_PROVIDERS")
+        .or_else(|_| env::var("WEB3_PROVIDER"))
+        .expect("WEB3_PROVIDERS or WEB3_PROVIDER must be set");
+    let providers = urls
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            let transport =
+                web3::transports::Http::new(url).expect("Failed to initialize Web3 transport");
+            // Keep API keys in paths and query strings out of logs.
+            let name = reqwest::Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| "unknown".to_string());
+            (name, transport)
+        })
+        .collect();
+    web3::Web3::new(FailoverTransport::new(providers, RpcConfig::from_env()).into())
 }
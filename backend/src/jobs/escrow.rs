@@ -0,0 +1,32 @@
+use crate::db::operations::{list_expired_escrows, release_escrow};
+use crate::state::AppState;
+use crate::utils::escrow::escrow_contract;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Periodically pays out escrows whose release timeout has passed.
+pub fn spawn_escrow_releaser(state: Arc<AppState>) {
+    if escrow_contract().is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let escrows = match list_expired_escrows(&state.db.pool).await {
+                Ok(escrows) => escrows,
+                Err(e) => {
+                    error!("Failed to list expired escrows: {}", e);
+                    continue;
+                }
+            };
+            for escrow in escrows {
+                match release_escrow(&state.db.pool, &state.web3, &escrow, "releaseExpired").await {
+                    Ok(_) => info!("Released expired escrow for order {}", escrow.order_id),
+                    Err(e) => error!("Failed to release escrow {}: {}", escrow.id, e),
+                }
+            }
+        }
+    });
+}
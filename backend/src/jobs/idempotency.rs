@@ -0,0 +1,19 @@
+use crate::db::operations::purge_expired_idempotency_keys;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Periodically forgets idempotency keys past their retention window.
+pub fn spawn_idempotency_key_purger(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            match purge_expired_idempotency_keys(&pool).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired idempotency keys", purged),
+                Err(e) => error!("Failed to purge idempotency keys: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,7 @@
+pub mod escrow;
+pub mod idempotency;
+pub mod payment_intents;
+pub mod refunds;
+pub mod shipments;
+pub mod transfer_indexer;
+pub mod tx_sender;
@@ -0,0 +1,25 @@
+use crate::db::operations::{expire_payment_intents, release_expired_reservations};
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Periodically expires stale payment intents and releases stock holds
+/// whose time has run out.
+pub fn spawn_payment_intent_sweeper(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            match expire_payment_intents(&pool).await {
+                Ok(0) => {}
+                Ok(expired) => info!("Expired {} payment intents", expired),
+                Err(e) => error!("Failed to expire payment intents: {}", e),
+            }
+            match release_expired_reservations(&pool).await {
+                Ok(0) => {}
+                Ok(released) => info!("Released {} expired stock reservations", released),
+                Err(e) => error!("Failed to release stock reservations: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,26 @@
+use crate::db::operations::mark_overdue_refunds;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Periodically flags refunds owed on cancelled orders that the seller has
+/// not sent by their deadline.
+pub fn spawn_refund_deadline_checker(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            match mark_overdue_refunds(&pool).await {
+                Ok(refunds) => {
+                    for refund in refunds {
+                        warn!(
+                            "Refund {} for order {} is overdue",
+                            refund.id, refund.order_id
+                        );
+                    }
+                }
+                Err(e) => error!("Failed to check refund deadlines: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,74 @@
+use crate::db::models::OrderStatus;
+use crate::db::operations::{
+    get_order_by_id, get_store_by_id, list_in_transit_shipments, mark_shipment_delivered,
+};
+use crate::routes::user_handler::apply_order_status;
+use crate::state::AppState;
+use crate::utils::carriers::{carrier_tracker_from_env, CarrierTracker, TrackingStatus};
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Periodically asks the carrier about parcels in transit.
+pub fn spawn_shipment_tracker(state: Arc<AppState>) {
+    let Some(tracker) = carrier_tracker_from_env() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            match track_shipments(&state, tracker.as_ref()).await {
+                Ok(0) => {}
+                Ok(delivered) => info!("{} shipments were delivered", delivered),
+                Err(e) => error!("Failed to track shipments: {}", e),
+            }
+        }
+    });
+}
+
+/// Marks the parcels the carrier has delivered, and delivers an order once
+/// its last parcel arrives. Returns how many parcels were delivered.
+pub async fn track_shipments(state: &AppState, tracker: &dyn CarrierTracker) -> Result<usize> {
+    let mut delivered = 0;
+    for shipment in list_in_transit_shipments(&state.db.pool).await? {
+        match tracker
+            .status(&shipment.carrier, &shipment.tracking_code)
+            .await
+        {
+            Ok(TrackingStatus::Delivered) => {}
+            Ok(TrackingStatus::InTransit) => continue,
+            Err(e) => {
+                error!("Failed to track shipment {}: {}", shipment.id, e);
+                continue;
+            }
+        }
+        let Some(complete) = mark_shipment_delivered(&state.db.pool, &shipment).await? else {
+            continue;
+        };
+        delivered += 1;
+        if !complete {
+            continue;
+        }
+
+        let Some(order) = get_order_by_id(&state.db.pool, shipment.order_id).await? else {
+            continue;
+        };
+        if order.status != OrderStatus::Shipped {
+            continue;
+        }
+        let store = get_store_by_id(&state.db.pool, order.store_id).await?;
+        apply_order_status(
+            state,
+            order.id,
+            OrderStatus::Delivered,
+            "system",
+            Some("Delivered by carrier"),
+            store.mint_receipts,
+        )
+        .await
+        .map_err(|(_, e)| anyhow!(e))?;
+    }
+    Ok(delivered)
+}
@@ -0,0 +1,151 @@
+use crate::db::models::NewChainTransfer;
+use crate::db::operations::{
+    get_indexer_cursor, list_store_wallets, list_unmatched_transfers_in_blocks,
+    match_chain_transfer, record_chain_transfer, set_indexer_cursor,
+};
+use crate::state::AppState;
+use crate::utils::fees::treasury_address;
+use crate::utils::tokens::{from_base_units, TOKENS};
+use anyhow::Result;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use web3::types::{Address, BlockNumber, FilterBuilder, H256, U256, U64};
+
+const CURSOR_NAME: &str = "token_transfers";
+const TRANSFER_TOPIC: &str = "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Polls `eth_getLogs` for registry token transfers into store wallets and the
+/// platform treasury, and settles the payment intents and orders they pay for. Native CELO moves
+/// without logs, so those payments still go through `/checkout`.
+pub fn spawn_transfer_indexer(state: Arc<AppState>) {
+    let poll_interval = Duration::from_secs(env_u64("INDEXER_POLL_SECS", 15));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(poll_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = index_transfers(&state).await {
+                error!("Transfer indexer failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Processes one batch of blocks after the stored cursor.
+pub async fn index_transfers(state: &AppState) -> Result<()> {
+    let pool = &state.db.pool;
+    let confirmations = env_u64("INDEXER_CONFIRMATIONS", 3);
+    let batch_size = env_u64("INDEXER_BATCH_SIZE", 1000).max(1);
+
+    let head = state.web3.eth().block_number().await?.as_u64();
+    let safe_head = head.saturating_sub(confirmations);
+
+    let last_block = match get_indexer_cursor(pool, CURSOR_NAME).await? {
+        Some(block) => block as u64,
+        None => match std::env::var("INDEXER_START_BLOCK") {
+            Ok(start) => start.parse::<u64>()?.saturating_sub(1),
+            Err(_) => safe_head,
+        },
+    };
+
+    let from_block = last_block + 1;
+    if from_block > safe_head {
+        return Ok(());
+    }
+    let to_block = safe_head.min(from_block + batch_size - 1);
+
+    let mut wallets = list_store_wallets(pool).await?;
+    wallets.extend(treasury_address());
+    let wallet_topics: Vec<H256> = wallets
+        .iter()
+        .filter_map(|wallet| Address::from_str(wallet).ok())
+        .map(H256::from)
+        .collect();
+
+    if !wallet_topics.is_empty() {
+        let token_addresses = TOKENS
+            .iter()
+            .filter(|token| !token.native)
+            .filter_map(|token| Address::from_str(token.address).ok())
+            .collect();
+        let filter = FilterBuilder::default()
+            .address(token_addresses)
+            .topics(
+                Some(vec![H256::from_str(TRANSFER_TOPIC)?]),
+                None,
+                Some(wallet_topics),
+                None,
+            )
+            .from_block(BlockNumber::Number(U64::from(from_block)))
+            .to_block(BlockNumber::Number(U64::from(to_block)))
+            .build();
+
+        let logs = state.web3.eth().logs(filter).await?;
+        for log in logs {
+            let (Some(transaction_hash), Some(block_number), Some(log_index)) =
+                (log.transaction_hash, log.block_number, log.log_index)
+            else {
+                continue;
+            };
+            if log.topics.len() != 3 {
+                continue;
+            }
+            let Some(token) = TOKENS
+                .iter()
+                .find(|token| Address::from_str(token.address).ok() == Some(log.address))
+            else {
+                continue;
+            };
+
+            let amount = match from_base_units(U256::from_big_endian(&log.data.0), token.decimals) {
+                Ok(amount) => amount,
+                Err(e) => {
+                    warn!("Skipping transfer in {:?}: {}", transaction_hash, e);
+                    continue;
+                }
+            };
+
+            let transfer = NewChainTransfer {
+                transaction_hash: format!("{:?}", transaction_hash),
+                log_index: log_index.as_u64() as i64,
+                block_number: block_number.as_u64() as i64,
+                token_address: format!("{:?}", log.address),
+                from_address: format!("{:?}", Address::from(log.topics[1])),
+                to_address: format!("{:?}", Address::from(log.topics[2])),
+                amount,
+            };
+
+            record_chain_transfer(pool, transfer).await?;
+        }
+    }
+
+    // Everything in the batch is recorded before any of it is matched, so an
+    // order can see its fee transfer whatever order the logs came in. Until
+    // the cursor moves past these blocks, a failed batch retries every
+    // transfer in them that is still unmatched, not just the new ones.
+    for transfer in
+        list_unmatched_transfers_in_blocks(pool, from_block as i64, to_block as i64).await?
+    {
+        let status = match_chain_transfer(pool, &transfer).await?;
+        info!(
+            "Indexed {} transfer of {} to {} in {}: {}",
+            transfer.token_address,
+            transfer.amount,
+            transfer.to_address,
+            transfer.transaction_hash,
+            status
+        );
+    }
+
+    set_indexer_cursor(pool, CURSOR_NAME, to_block as i64).await?;
+
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use crate::db::models::ChainTransaction;
+use crate::db::operations::{
+    get_order_by_id, get_receipt_metadata, list_sent_transactions, mark_transaction_attempt_failed,
+    mark_transaction_confirmed, mark_transaction_dropped, mark_transaction_sent, max_used_nonce,
+    next_queued_transaction, set_order_receipt_token, set_transaction_data,
+};
+use crate::state::AppState;
+use crate::utils::ipfs::upload_json_to_ipfs;
+use crate::utils::receipts::{minted_token_id, safe_mint_calldata};
+use crate::utils::signer::Signer;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use web3::signing::Key;
+use web3::types::{Address, BlockNumber, Bytes, CallRequest, TransactionParameters, H256, U256};
+
+/// Sends queued transactions from the backend wallet one at a time, assigning
+/// nonces in order, and retries them with backoff until they are mined.
+///
+/// `TX_SENDER_KEY` must be the key of the MiniPay contract's owner: `safeMint`
+/// is `onlyOwner`, so receipt mints from any other wallet revert.
+pub fn spawn_tx_sender(state: Arc<AppState>) {
+    let signer = match Signer::from_env("TX_SENDER_KEY") {
+        Ok(signer) => signer,
+        Err(_) => return,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            if let Err(e) = process_queue(&state, &signer).await {
+                error!("Transaction sender failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Attempts before a transaction that cannot be sent is marked failed, or one
+/// that keeps going unmined is dropped.
+fn max_attempts() -> i32 {
+    std::env::var("TX_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5)
+}
+
+/// How long a sent transaction may stay unmined before it is resent with a higher gas price.
+fn resend_after() -> chrono::Duration {
+    let secs = std::env::var("TX_RESEND_AFTER_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(180);
+    chrono::Duration::seconds(secs)
+}
+
+/// Highest gas price a resend may offer, as a percentage of the node's price.
+fn max_gas_price_percent() -> u64 {
+    std::env::var("TX_MAX_GAS_PRICE_PERCENT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(200)
+}
+
+/// 30s, 1m, 2m, ... capped at an hour.
+fn backoff(attempts: i32) -> chrono::Duration {
+    chrono::Duration::seconds((30i64 << attempts.clamp(0, 7)).min(3600))
+}
+
+pub(crate) async fn process_queue(state: &AppState, signer: &Signer) -> Result<()> {
+    for tx in list_sent_transactions(&state.db.pool).await? {
+        if let Err(e) = check_sent(state, signer, &tx).await {
+            warn!("Failed to check transaction {}: {}", tx.id, e);
+        }
+    }
+
+    while let Some(tx) = next_queued_transaction(&state.db.pool).await? {
+        if let Err(e) = send_queued(state, signer, &tx).await {
+            warn!("Failed to send {} transaction {}: {}", tx.kind, tx.id, e);
+            mark_transaction_attempt_failed(
+                &state.db.pool,
+                tx.id,
+                &e.to_string(),
+                Utc::now() + backoff(tx.attempts),
+                max_attempts(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_queued(state: &AppState, signer: &Signer, tx: &ChainTransaction) -> Result<()> {
+    let data = match &tx.data {
+        Some(data) => data.clone(),
+        None => {
+            let data = format!("0x{}", hex::encode(prepare(state, tx).await?));
+            set_transaction_data(&state.db.pool, tx.id, &data).await?;
+            data
+        }
+    };
+
+    // A transaction keeps the nonce it was first signed with, so a retry can
+    // only ever replace it, never land alongside it.
+    let nonce = match tx.nonce {
+        Some(nonce) => U256::from(nonce),
+        None => next_nonce(state, signer).await?,
+    };
+
+    let hash = send_with_nonce(state, signer, tx, &data, nonce, 100).await?;
+    info!("Sent {} transaction {} as {}", tx.kind, tx.id, hash);
+    Ok(())
+}
+
+async fn next_nonce(state: &AppState, signer: &Signer) -> Result<U256> {
+    // The node can forget pending transactions, so never reuse a nonce we handed out.
+    let pending = state
+        .web3
+        .eth()
+        .transaction_count(signer.address(), Some(BlockNumber::Pending))
+        .await?;
+    Ok(match max_used_nonce(&state.db.pool).await? {
+        Some(used) => pending.max(U256::from(used + 1)),
+        None => pending,
+    })
+}
+
+async fn check_sent(state: &AppState, signer: &Signer, tx: &ChainTransaction) -> Result<()> {
+    let nonce = tx
+        .nonce
+        .ok_or_else(|| anyhow!("Sent transaction has no nonce"))?;
+
+    // Each resend replaced the last under the same nonce, so any of them may be the one mined.
+    let mut mined = None;
+    for hash in &tx.transaction_hashes {
+        let receipt = state
+            .web3
+            .eth()
+            .transaction_receipt(H256::from_str(hash.trim_start_matches("0x"))?)
+            .await?;
+        if let Some(receipt) = receipt {
+            mined = Some((hash, receipt));
+            break;
+        }
+    }
+
+    match mined {
+        Some((hash, receipt)) if receipt.status == Some(1.into()) => {
+            mark_transaction_confirmed(&state.db.pool, tx.id).await?;
+            if tx.kind == "mint_receipt" {
+                let contract = Address::from_str(tx.to_address.trim_start_matches("0x"))?;
+                if let (Some(order_id), Some(token_id)) =
+                    (tx.order_id, minted_token_id(&receipt, contract))
+                {
+                    set_order_receipt_token(&state.db.pool, order_id, token_id as i64).await?;
+                }
+            }
+            info!("Transaction {} confirmed in {}", tx.id, hash);
+        }
+        Some(_) => {
+            mark_transaction_attempt_failed(
+                &state.db.pool,
+                tx.id,
+                "Transaction reverted",
+                Utc::now() + backoff(tx.attempts),
+                max_attempts(),
+            )
+            .await?;
+        }
+        None => {
+            let confirmed = state
+                .web3
+                .eth()
+                .transaction_count(signer.address(), Some(BlockNumber::Latest))
+                .await?;
+            let stuck = tx
+                .sent_at
+                .is_some_and(|sent_at| sent_at + resend_after() < Utc::now());
+
+            if confirmed > U256::from(nonce) {
+                // The nonce is used up. Give the node time to serve the receipt,
+                // then take it that another transaction used the nonce.
+                if stuck {
+                    let error = format!("Nonce {} was used by another transaction", nonce);
+                    mark_transaction_dropped(&state.db.pool, tx.id, &error).await?;
+                    warn!("Dropped transaction {}: {}", tx.id, error);
+                }
+                return Ok(());
+            }
+
+            if stuck && tx.attempts >= max_attempts() {
+                // It may still be mined, so it is never resent under a new nonce.
+                let error = format!("Not mined after {} attempts", tx.attempts);
+                mark_transaction_dropped(&state.db.pool, tx.id, &error).await?;
+                warn!("Dropped transaction {}: {}", tx.id, error);
+            } else if let (true, Some(data)) = (stuck, &tx.data) {
+                // Replace it under the same nonce, paying 20% more for each
+                // attempt so far, up to the gas price cap.
+                let bump = (100 + 20 * tx.attempts.max(1) as u64).min(max_gas_price_percent());
+                let hash =
+                    send_with_nonce(state, signer, tx, data, U256::from(nonce), bump).await?;
+                info!("Resent transaction {} as {}", tx.id, hash);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the calldata for kinds that are queued without it.
+async fn prepare(state: &AppState, tx: &ChainTransaction) -> Result<Vec<u8>> {
+    match tx.kind.as_str() {
+        "mint_receipt" => {
+            let order_id = tx
+                .order_id
+                .ok_or_else(|| anyhow!("Receipt mint has no order"))?;
+            let order = get_order_by_id(&state.db.pool, order_id)
+                .await?
+                .ok_or_else(|| anyhow!("Order not found"))?;
+            let metadata = get_receipt_metadata(&state.db.pool, order_id)
+                .await?
+                .ok_or_else(|| anyhow!("Order not found"))?;
+            let cid = upload_json_to_ipfs(state, &format!("receipt-{}", order.order_id), &metadata)
+                .await
+                .map_err(|e| anyhow!("Failed to pin receipt metadata: {}", e))?;
+            safe_mint_calldata(&order.buyer_address, &format!("ipfs://{}", cid))
+        }
+        kind => Err(anyhow!("Nothing to send for {} transaction", kind)),
+    }
+}
+
+/// Signs the transaction under `nonce`, records it and then broadcasts it.
+/// The hash is saved first so a broadcast the node took but failed to answer
+/// is still watched, and is later replaced under the same nonce if it never lands.
+async fn send_with_nonce(
+    state: &AppState,
+    signer: &Signer,
+    tx: &ChainTransaction,
+    data: &str,
+    nonce: U256,
+    gas_price_percent: u64,
+) -> Result<String> {
+    let to = Address::from_str(tx.to_address.trim_start_matches("0x"))?;
+    let data = Bytes(hex::decode(data.trim_start_matches("0x"))?);
+
+    let gas = state
+        .web3
+        .eth()
+        .estimate_gas(
+            CallRequest {
+                from: Some(signer.address()),
+                to: Some(to),
+                data: Some(data.clone()),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+    let gas_price = state.web3.eth().gas_price().await? * gas_price_percent / 100;
+
+    let transaction = TransactionParameters {
+        nonce: Some(nonce),
+        to: Some(to),
+        data,
+        gas: gas * 120 / 100,
+        gas_price: Some(gas_price),
+        ..Default::default()
+    };
+    let signed = state
+        .web3
+        .accounts()
+        .sign_transaction(transaction, signer.clone())
+        .await?;
+    let hash = format!("{:?}", signed.transaction_hash);
+    mark_transaction_sent(&state.db.pool, tx.id, nonce.as_u64() as i64, &hash).await?;
+
+    if let Err(e) = state
+        .web3
+        .eth()
+        .send_raw_transaction(signed.raw_transaction)
+        .await
+    {
+        warn!(
+            "Broadcasting transaction {} as {} failed, will resend: {}",
+            tx.id, hash, e
+        );
+    }
+
+    Ok(hash)
+}
@@ -2,11 +2,13 @@ use crate::routes::store_handler::*;
 use axum::{
     http::{header, HeaderName, Method},
     middleware,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Router,
 };
 use db::{models::*, operations::*};
-use log::info;
+use log::{info, warn};
+use routes::payment_handler::*;
+use routes::return_handler::*;
 use routes::user_handler::*;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -15,11 +17,13 @@ use crate::authentication::authentication::*;
 use initializers::{database::initialize_database, web3::initialize_web3};
 mod authentication;
 mod db;
+mod jobs;
 mod routes;
 mod state;
 mod utils;
 use state::{AppState, AppStateDb};
 mod error;
+mod payment_test;
 mod store_test;
 mod user_test;
 use http::HeaderValue;
@@ -37,8 +41,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         pinata_api_key: std::env::var("PINATA_API_KEY").expect("PINATA_API_KEY must be set"),
         pinata_secret_key: std::env::var("PINATA_SECRET_KEY")
             .expect("PINATA_SECRET_KEY must be set"),
+        price_source: utils::pricing::price_source_from_env().expect("PRICE_TABLE must be valid"),
+        token_gates: Arc::new(utils::token_gates::TokenGateCache::from_env()),
     });
 
+    // Token addresses, payment URIs and explorer links all follow CHAIN_ID.
+    match state.web3.eth().chain_id().await {
+        Ok(rpc_chain_id) if rpc_chain_id.as_u64() != utils::chain::chain_id() => warn!(
+            "RPC is on chain {} but CHAIN_ID is {}; payments will not verify",
+            rpc_chain_id,
+            utils::chain::chain_id()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Could not read the RPC chain id: {}", e),
+    }
+
+    jobs::payment_intents::spawn_payment_intent_sweeper(db.clone());
+    jobs::transfer_indexer::spawn_transfer_indexer(state.clone());
+    jobs::escrow::spawn_escrow_releaser(state.clone());
+    jobs::refunds::spawn_refund_deadline_checker(db.clone());
+    jobs::idempotency::spawn_idempotency_key_purger(db.clone());
+    jobs::shipments::spawn_shipment_tracker(state.clone());
+    jobs::tx_sender::spawn_tx_sender(state.clone());
+
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::predicate(
             |origin: &HeaderValue, _req_parts| {
@@ -70,13 +95,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             header::AUTHORIZATION,
             header::ACCEPT,
             HeaderName::from_static("x-requested-with"),
+            routes::idempotency::IDEMPOTENCY_KEY,
         ])
         .allow_credentials(true);
 
     let auth_layer = middleware::from_fn_with_state(state.clone(), auth_middleware);
+    // Layered inside auth_layer, which must run first
+    let idempotency_layer =
+        middleware::from_fn_with_state(state.clone(), routes::idempotency::idempotency_middleware);
 
     let app = Router::new()
         .route("/", get(|| async { "JES SaaS Backend is running!" }))
+        .route("/health/rpc", get(rpc_health_handler))
         .route("/login", post(login_handler))
         .route("/register", post(register_user_handler))
         .route(
@@ -93,16 +123,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "/stores/:id/orders",
             get(get_store_orders_handler).layer(auth_layer.clone()),
         )
+        .route(
+            "/stores/:id/orders/status",
+            post(bulk_update_order_status_handler).layer(auth_layer.clone()),
+        )
         .route(
             "/stores/:id",
             put(update_store_handler)
                 .delete(delete_store_handler)
                 .layer(auth_layer.clone()),
         )
+        .route(
+            "/stores/:id/payout-address",
+            put(update_payout_address_handler)
+                .get(list_payout_address_changes_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/stores/:id/payout-address/message",
+            get(payout_address_message_handler).layer(auth_layer.clone()),
+        )
         .route("/products/:id/quantity", get(get_product_quantity_handler))
+        .route(
+            "/products/:id/inventory",
+            get(get_inventory_ledger_handler)
+                .post(record_inventory_movement_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/products/:id/token-gates",
+            post(add_product_token_gate_handler)
+                .get(list_product_token_gates_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/products/:id/token-gates/:gate_id",
+            delete(delete_product_token_gate_handler).layer(auth_layer.clone()),
+        )
         .route(
             "/create_orders",
-            post(create_order_handler).layer(auth_layer.clone()),
+            post(create_order_handler)
+                .layer(idempotency_layer.clone())
+                .layer(auth_layer.clone()),
         )
         .route("/stores", get(get_all_stores_handler))
         .route(
@@ -112,7 +174,120 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .layer(auth_layer.clone()),
         )
         .route("/store/:store_id", get(get_store_by_id_handler))
-        .route("/checkout", post(checkout_handler).layer(auth_layer))
+        .route(
+            "/payments/precheck",
+            get(payment_precheck_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/payment-intents",
+            post(create_payment_intent_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/payment-intents/:id",
+            get(get_payment_intent_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/payment-intents/:id/qr",
+            get(payment_intent_qr_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/stores/:id/unmatched-transfers",
+            get(list_unmatched_transfers_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/transfers/:id/resolve",
+            post(resolve_transfer_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders",
+            get(list_buyer_orders_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id",
+            get(get_order_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/status",
+            put(update_order_status_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/shipments",
+            post(create_shipment_handler)
+                .get(list_shipments_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/returns",
+            post(create_return_handler)
+                .get(list_returns_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/returns/:id/accept",
+            post(accept_return_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/returns/:id/reject",
+            post(reject_return_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/returns/:id/dispute",
+            post(escalate_return_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/disputes",
+            get(list_disputes_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/disputes/:id/resolve",
+            post(resolve_dispute_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/cancel",
+            post(cancel_order_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/escrow",
+            post(deposit_escrow_handler)
+                .get(get_escrow_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/escrow/confirm",
+            post(confirm_escrow_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/payment",
+            get(get_order_payment_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/events",
+            get(list_order_events_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/orders/:id/refunds",
+            post(create_refund_handler)
+                .get(list_refunds_handler)
+                .layer(auth_layer.clone()),
+        )
+        .route(
+            "/refunds/:id/complete",
+            post(complete_refund_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/refunds/:id/reject",
+            post(reject_refund_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/checkout/reserve",
+            post(reserve_stock_handler).layer(auth_layer.clone()),
+        )
+        .route(
+            "/checkout",
+            post(checkout_handler)
+                .layer(idempotency_layer)
+                .layer(auth_layer),
+        )
         .with_state(state)
         .layer(cors);
 
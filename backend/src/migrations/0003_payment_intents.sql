@@ -0,0 +1,60 @@
+-- Create payment_intents table
+CREATE TABLE IF NOT EXISTS payment_intents (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    reference VARCHAR(32) NOT NULL UNIQUE,
+    user_id UUID NOT NULL,
+    cart_id UUID NOT NULL,
+    buyer_address VARCHAR(42) NOT NULL,
+    token_symbol VARCHAR(16) NOT NULL,
+    token_address VARCHAR(42) NOT NULL,
+    total_amount NUMERIC(36, 18) NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    transaction_hash VARCHAR,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT payment_intents_user_id_fkey FOREIGN KEY (user_id)
+    REFERENCES users (id) ON DELETE CASCADE,
+    CONSTRAINT payment_intents_cart_id_fkey FOREIGN KEY (cart_id)
+    REFERENCES cart (id) ON DELETE CASCADE
+);
+
+-- One row per seller wallet that has to be paid for an intent
+CREATE TABLE IF NOT EXISTS payment_intent_recipients (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    payment_intent_id UUID NOT NULL,
+    store_id UUID NOT NULL,
+    recipient_address VARCHAR(42) NOT NULL,
+    amount NUMERIC(36, 18) NOT NULL,
+    CONSTRAINT payment_intent_recipients_intent_id_fkey FOREIGN KEY (payment_intent_id)
+    REFERENCES payment_intents (id) ON DELETE CASCADE,
+    CONSTRAINT payment_intent_recipients_store_id_fkey FOREIGN KEY (store_id)
+    REFERENCES stores (id) ON DELETE CASCADE
+);
+
+-- Snapshot of the cart at the time the intent was created
+CREATE TABLE IF NOT EXISTS payment_intent_items (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    payment_intent_id UUID NOT NULL,
+    product_id UUID NOT NULL,
+    store_id UUID NOT NULL,
+    quantity INTEGER NOT NULL,
+    unit_price NUMERIC(18, 2) NOT NULL,
+    CONSTRAINT payment_intent_items_intent_id_fkey FOREIGN KEY (payment_intent_id)
+    REFERENCES payment_intents (id) ON DELETE CASCADE,
+    CONSTRAINT payment_intent_items_product_id_fkey FOREIGN KEY (product_id)
+    REFERENCES products (id) ON DELETE CASCADE,
+    CONSTRAINT payment_intent_items_quantity_check CHECK (quantity > 0)
+);
+
+CREATE TRIGGER update_payment_intents_timestamp
+BEFORE UPDATE ON payment_intents
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_payment_intents_user_id ON payment_intents (user_id);
+CREATE INDEX IF NOT EXISTS idx_payment_intents_status_expires_at ON payment_intents (status, expires_at);
+CREATE INDEX IF NOT EXISTS idx_payment_intent_recipients_intent_id ON payment_intent_recipients (payment_intent_id);
+CREATE INDEX IF NOT EXISTS idx_payment_intent_recipients_address_amount ON payment_intent_recipients (recipient_address, amount);
+CREATE INDEX IF NOT EXISTS idx_payment_intent_items_intent_id ON payment_intent_items (payment_intent_id);
+CREATE INDEX IF NOT EXISTS idx_payment_intent_items_product_id ON payment_intent_items (product_id);
@@ -0,0 +1,41 @@
+-- Last block processed by each background indexer
+CREATE TABLE IF NOT EXISTS indexer_cursors (
+    name VARCHAR(64) PRIMARY KEY,
+    last_block BIGINT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp
+);
+
+-- Every token transfer seen going to a store wallet
+CREATE TABLE IF NOT EXISTS chain_transfers (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    transaction_hash VARCHAR(66) NOT NULL,
+    log_index BIGINT NOT NULL,
+    block_number BIGINT NOT NULL,
+    token_address VARCHAR(42) NOT NULL,
+    from_address VARCHAR(42) NOT NULL,
+    to_address VARCHAR(42) NOT NULL,
+    amount NUMERIC(36, 18) NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'unmatched',
+    payment_intent_id UUID,
+    order_id UUID,
+    note TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT chain_transfers_tx_log_key UNIQUE (transaction_hash, log_index),
+    CONSTRAINT chain_transfers_payment_intent_id_fkey FOREIGN KEY (payment_intent_id)
+    REFERENCES payment_intents (id) ON DELETE SET NULL,
+    CONSTRAINT chain_transfers_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE SET NULL
+);
+
+-- Track which recipient of an intent has been paid, and by which transaction
+ALTER TABLE payment_intent_recipients
+ADD COLUMN IF NOT EXISTS paid_transaction_hash VARCHAR(66);
+
+CREATE TRIGGER update_chain_transfers_timestamp
+BEFORE UPDATE ON chain_transfers
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_chain_transfers_status ON chain_transfers (status);
+CREATE INDEX IF NOT EXISTS idx_chain_transfers_to_address ON chain_transfers (lower(to_address));
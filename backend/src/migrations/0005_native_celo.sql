@@ -0,0 +1,16 @@
+-- Let each store opt in to being paid in native CELO
+ALTER TABLE stores
+ADD COLUMN IF NOT EXISTS accepts_native_celo BOOLEAN NOT NULL DEFAULT false;
+
+-- Transactions that have already paid for a checkout, so none is used twice
+CREATE TABLE IF NOT EXISTS payment_transactions (
+    transaction_hash VARCHAR(66) PRIMARY KEY,
+    token_symbol VARCHAR(16) NOT NULL,
+    user_id UUID,
+    payment_intent_id UUID,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT payment_transactions_user_id_fkey FOREIGN KEY (user_id)
+    REFERENCES users (id) ON DELETE SET NULL,
+    CONSTRAINT payment_transactions_payment_intent_id_fkey FOREIGN KEY (payment_intent_id)
+    REFERENCES payment_intents (id) ON DELETE SET NULL
+);
@@ -0,0 +1,28 @@
+-- Funds held by the JesEscrow contract for a single order
+CREATE TABLE IF NOT EXISTS escrows (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    contract_address VARCHAR(42) NOT NULL,
+    buyer_address VARCHAR(42) NOT NULL,
+    seller_address VARCHAR(42) NOT NULL,
+    token_address VARCHAR(42) NOT NULL,
+    amount NUMERIC(36, 18) NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'funded',
+    deposit_transaction_hash VARCHAR(66) NOT NULL,
+    release_transaction_hash VARCHAR(66),
+    refund_transaction_hash VARCHAR(66),
+    release_after TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT escrows_order_id_key UNIQUE (order_id),
+    CONSTRAINT escrows_deposit_transaction_hash_key UNIQUE (deposit_transaction_hash),
+    CONSTRAINT escrows_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER update_escrows_timestamp
+BEFORE UPDATE ON escrows
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_escrows_status_release_after ON escrows (status, release_after);
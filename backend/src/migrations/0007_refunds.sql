@@ -0,0 +1,24 @@
+-- Money going back from a seller to a buyer for an order
+CREATE TABLE IF NOT EXISTS refunds (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    requested_by VARCHAR(42) NOT NULL,
+    amount NUMERIC(18, 2) NOT NULL,
+    reason TEXT,
+    status VARCHAR NOT NULL DEFAULT 'requested',
+    token_symbol VARCHAR(16) NOT NULL,
+    transaction_hash VARCHAR(66),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT refunds_amount_check CHECK (amount > 0),
+    CONSTRAINT refunds_transaction_hash_key UNIQUE (transaction_hash),
+    CONSTRAINT refunds_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER update_refunds_timestamp
+BEFORE UPDATE ON refunds
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_refunds_order_id ON refunds (order_id);
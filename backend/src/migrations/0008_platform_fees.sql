@@ -0,0 +1,14 @@
+-- Per-store overrides of the platform fee, NULL means the platform default
+ALTER TABLE stores
+ADD COLUMN IF NOT EXISTS platform_fee_bps INTEGER,
+ADD COLUMN IF NOT EXISTS platform_fee_flat NUMERIC(18, 2),
+ADD CONSTRAINT stores_platform_fee_bps_check CHECK (platform_fee_bps BETWEEN 0 AND 10000),
+ADD CONSTRAINT stores_platform_fee_flat_check CHECK (platform_fee_flat >= 0);
+
+-- The platform's share of each order, for reporting
+ALTER TABLE orders
+ADD COLUMN IF NOT EXISTS platform_fee NUMERIC(18, 2) NOT NULL DEFAULT 0;
+
+-- The treasury is paid alongside the stores, as a recipient without a store
+ALTER TABLE payment_intent_recipients
+ALTER COLUMN store_id DROP NOT NULL;
@@ -0,0 +1,21 @@
+-- Currency each product is priced in; prices are converted to the payment token at checkout
+ALTER TABLE products
+ADD COLUMN IF NOT EXISTS currency VARCHAR(3) NOT NULL DEFAULT 'USD';
+
+-- The listed price and the rate it was converted at, per intent line
+ALTER TABLE payment_intent_items
+ADD COLUMN IF NOT EXISTS currency VARCHAR(3) NOT NULL DEFAULT 'USD',
+ADD COLUMN IF NOT EXISTS list_price NUMERIC(18, 2),
+ADD COLUMN IF NOT EXISTS exchange_rate NUMERIC(36, 18) NOT NULL DEFAULT 1;
+
+UPDATE payment_intent_items SET list_price = unit_price WHERE list_price IS NULL;
+ALTER TABLE payment_intent_items ALTER COLUMN list_price SET NOT NULL;
+
+-- What the buyer was charged in the listing currency and at which rate
+ALTER TABLE orders
+ADD COLUMN IF NOT EXISTS currency VARCHAR(3) NOT NULL DEFAULT 'USD',
+ADD COLUMN IF NOT EXISTS list_amount NUMERIC(18, 2),
+ADD COLUMN IF NOT EXISTS exchange_rate NUMERIC(36, 18) NOT NULL DEFAULT 1;
+
+UPDATE orders SET list_amount = amount WHERE list_amount IS NULL;
+ALTER TABLE orders ALTER COLUMN list_amount SET NOT NULL;
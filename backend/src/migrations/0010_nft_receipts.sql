@@ -0,0 +1,35 @@
+-- Stores that opt in get an NFT receipt minted to the buyer on delivery
+ALTER TABLE stores
+ADD COLUMN IF NOT EXISTS mint_receipts BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE orders
+ADD COLUMN IF NOT EXISTS receipt_token_id BIGINT;
+
+-- Transactions the backend wallet sends, in nonce order, with retries
+CREATE TABLE IF NOT EXISTS chain_transactions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    kind VARCHAR(32) NOT NULL,
+    order_id UUID,
+    to_address VARCHAR(42) NOT NULL,
+    data TEXT,
+    status VARCHAR NOT NULL DEFAULT 'queued',
+    nonce BIGINT,
+    transaction_hash VARCHAR(66),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    sent_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT chain_transactions_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE SET NULL
+);
+
+CREATE TRIGGER update_chain_transactions_timestamp
+BEFORE UPDATE ON chain_transactions
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_chain_transactions_status ON chain_transactions (status, next_attempt_at);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_chain_transactions_receipt_order
+ON chain_transactions (order_id) WHERE kind = 'mint_receipt';
@@ -0,0 +1,23 @@
+-- Products reserved for, or discounted to, holders of an NFT.
+-- A NULL token_id matches any token from the contract; a NULL discount_bps
+-- makes the rule an access rule rather than a discount.
+CREATE TABLE IF NOT EXISTS product_token_gates (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL,
+    contract_address VARCHAR(42) NOT NULL,
+    token_id BIGINT,
+    discount_bps INTEGER,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT product_token_gates_token_id_check CHECK (token_id >= 0),
+    CONSTRAINT product_token_gates_discount_bps_check CHECK (discount_bps BETWEEN 1 AND 10000),
+    CONSTRAINT product_token_gates_product_id_fkey FOREIGN KEY (product_id)
+    REFERENCES products (id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER update_product_token_gates_timestamp
+BEFORE UPDATE ON product_token_gates
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_product_token_gates_product_id ON product_token_gates (product_id);
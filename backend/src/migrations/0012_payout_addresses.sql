@@ -0,0 +1,29 @@
+-- Where a store gets paid, when that is not the wallet it logs in with
+ALTER TABLE stores
+ADD COLUMN IF NOT EXISTS payout_address VARCHAR(42);
+
+-- The address each order was paid to, kept if the store later changes it
+ALTER TABLE orders
+ADD COLUMN IF NOT EXISTS payout_address VARCHAR(42);
+
+UPDATE orders SET payout_address = seller_address WHERE payout_address IS NULL;
+
+ALTER TABLE orders
+ALTER COLUMN payout_address SET NOT NULL;
+
+-- Every payout address change, with the owner signature that authorised it
+CREATE TABLE IF NOT EXISTS payout_address_changes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    store_id UUID NOT NULL,
+    previous_address VARCHAR(42),
+    payout_address VARCHAR(42),
+    signed_by VARCHAR(42) NOT NULL,
+    message TEXT NOT NULL,
+    signature VARCHAR(132) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT payout_address_changes_signature_key UNIQUE (signature),
+    CONSTRAINT payout_address_changes_store_id_fkey FOREIGN KEY (store_id)
+    REFERENCES stores (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_payout_address_changes_store_id ON payout_address_changes (store_id);
@@ -0,0 +1,30 @@
+-- Every change to a product's stock, as a signed quantity. products.quantity is
+-- the running total of these entries and is only changed alongside one.
+CREATE TABLE IF NOT EXISTS inventory_movements (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL,
+    quantity_change INTEGER NOT NULL,
+    reason VARCHAR(20) NOT NULL,
+    order_id UUID,
+    note TEXT,
+    created_by VARCHAR(42),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT inventory_movements_quantity_change_check CHECK (quantity_change <> 0),
+    CONSTRAINT inventory_movements_reason_check
+    CHECK (reason IN ('sale', 'restock', 'adjustment', 'refund', 'reservation')),
+    CONSTRAINT inventory_movements_product_id_fkey FOREIGN KEY (product_id)
+    REFERENCES products (id) ON DELETE CASCADE,
+    CONSTRAINT inventory_movements_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_inventory_movements_product_id
+ON inventory_movements (product_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_inventory_movements_order_id ON inventory_movements (order_id);
+
+-- Open the ledger for existing products at their current stock
+INSERT INTO inventory_movements (product_id, quantity_change, reason, note)
+SELECT p.id, p.quantity, 'adjustment', 'Opening balance'
+FROM products p
+WHERE p.quantity <> 0
+AND NOT EXISTS (SELECT 1 FROM inventory_movements m WHERE m.product_id = p.id);
@@ -0,0 +1,42 @@
+-- Stock held for a cart while its buyer pays. Active holds that have not
+-- expired are subtracted from what other buyers can take.
+CREATE TABLE IF NOT EXISTS stock_reservations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL,
+    cart_id UUID NOT NULL,
+    payment_intent_id UUID,
+    quantity INTEGER NOT NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'active',
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT stock_reservations_quantity_check CHECK (quantity > 0),
+    CONSTRAINT stock_reservations_status_check
+    CHECK (status IN ('active', 'released', 'consumed')),
+    CONSTRAINT stock_reservations_product_id_fkey FOREIGN KEY (product_id)
+    REFERENCES products (id) ON DELETE CASCADE,
+    CONSTRAINT stock_reservations_cart_id_fkey FOREIGN KEY (cart_id)
+    REFERENCES cart (id) ON DELETE CASCADE,
+    CONSTRAINT stock_reservations_payment_intent_id_fkey FOREIGN KEY (payment_intent_id)
+    REFERENCES payment_intents (id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER update_stock_reservations_timestamp
+BEFORE UPDATE ON stock_reservations
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_stock_reservations_active_product_id
+ON stock_reservations (product_id) WHERE status = 'active';
+CREATE INDEX IF NOT EXISTS idx_stock_reservations_active_cart_id
+ON stock_reservations (cart_id) WHERE status = 'active';
+CREATE INDEX IF NOT EXISTS idx_stock_reservations_active_expires_at
+ON stock_reservations (expires_at) WHERE status = 'active';
+
+-- Pending payment intents used to hold stock implicitly; give them real holds
+INSERT INTO stock_reservations (product_id, cart_id, payment_intent_id, quantity, expires_at)
+SELECT pii.product_id, pi.cart_id, pi.id, pii.quantity, pi.expires_at
+FROM payment_intent_items pii
+JOIN payment_intents pi ON pii.payment_intent_id = pi.id
+WHERE pi.status = 'pending' AND pi.expires_at > now()
+AND NOT EXISTS (SELECT 1 FROM stock_reservations r WHERE r.payment_intent_id = pi.id);
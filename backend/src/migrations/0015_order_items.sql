@@ -0,0 +1,48 @@
+-- Orders become a header per store per checkout; what was bought lives in
+-- order_items, priced as it was at the time of purchase.
+CREATE TABLE IF NOT EXISTS order_items (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    product_id UUID NOT NULL,
+    quantity INTEGER NOT NULL,
+    -- Per unit, in the payment token
+    unit_price NUMERIC(18, 2) NOT NULL,
+    -- Per unit, in the currency the product was listed in
+    list_price NUMERIC(18, 2) NOT NULL,
+    currency VARCHAR(3) NOT NULL DEFAULT 'USD',
+    exchange_rate NUMERIC(36, 18) NOT NULL DEFAULT 1,
+    amount NUMERIC(18, 2) NOT NULL,
+    platform_fee NUMERIC(18, 2) NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT order_items_quantity_check CHECK (quantity > 0),
+    CONSTRAINT order_items_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE CASCADE,
+    CONSTRAINT order_items_product_id_fkey FOREIGN KEY (product_id)
+    REFERENCES products (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_order_items_order_id ON order_items (order_id);
+CREATE INDEX IF NOT EXISTS idx_order_items_product_id ON order_items (product_id);
+
+-- Existing orders each bought one product; their sale in the ledger says how many
+INSERT INTO order_items (
+    order_id, product_id, quantity, unit_price, list_price, currency, exchange_rate,
+    amount, platform_fee, created_at
+)
+SELECT o.id, o.product_id, q.quantity, round(o.amount / q.quantity, 2),
+    round(o.list_amount / q.quantity, 2), o.currency, o.exchange_rate, o.amount,
+    o.platform_fee, o.created_at
+FROM orders o
+CROSS JOIN LATERAL (
+    SELECT GREATEST(COALESCE((
+        SELECT -SUM(m.quantity_change)
+        FROM inventory_movements m
+        WHERE m.order_id = o.id AND m.reason = 'sale'
+    ), 1), 1)::INT4 AS quantity
+) q
+WHERE NOT EXISTS (SELECT 1 FROM order_items oi WHERE oi.order_id = o.id);
+
+ALTER TABLE orders
+DROP COLUMN IF EXISTS product_id;
+
+CREATE INDEX IF NOT EXISTS idx_orders_transaction_hash ON orders (transaction_hash);
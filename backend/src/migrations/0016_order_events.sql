@@ -0,0 +1,34 @@
+-- Every change to an order's status or payment status, oldest first
+CREATE TABLE IF NOT EXISTS order_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    -- 'status' or 'payment_status'
+    field VARCHAR(16) NOT NULL,
+    from_status VARCHAR(32),
+    to_status VARCHAR(32) NOT NULL,
+    -- Wallet of whoever made the change, or 'system'
+    actor VARCHAR NOT NULL,
+    note TEXT,
+    -- Wall-clock time, so events written in one transaction keep their order
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT clock_timestamp(),
+    CONSTRAINT order_events_field_check CHECK (field IN ('status', 'payment_status')),
+    CONSTRAINT order_events_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_order_events_order_id ON order_events (order_id, created_at);
+
+ALTER TABLE orders
+ADD CONSTRAINT orders_status_check
+CHECK (status IN ('pending', 'shipped', 'delivered', 'cancelled')),
+ADD CONSTRAINT orders_payment_status_check
+CHECK (payment_status IN ('pending', 'confirmed', 'escrowed', 'partially_refunded', 'refunded'));
+
+-- Orders placed before history was kept start from where they are now
+INSERT INTO order_events (order_id, field, to_status, actor, note, created_at)
+SELECT o.id, f.field, f.to_status, 'system', 'Recorded before order history was kept', o.created_at
+FROM orders o
+CROSS JOIN LATERAL (
+    VALUES ('status', o.status), ('payment_status', o.payment_status)
+) AS f (field, to_status)
+WHERE NOT EXISTS (SELECT 1 FROM order_events e WHERE e.order_id = o.id);
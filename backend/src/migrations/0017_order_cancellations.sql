@@ -0,0 +1,13 @@
+-- Cancelled orders put their stock back through the ledger
+ALTER TABLE inventory_movements
+DROP CONSTRAINT IF EXISTS inventory_movements_reason_check;
+ALTER TABLE inventory_movements
+ADD CONSTRAINT inventory_movements_reason_check
+CHECK (reason IN ('sale', 'restock', 'adjustment', 'refund', 'reservation', 'cancellation'));
+
+-- Refunds owed for a cancelled order must be sent by the seller before due_at;
+-- those still unpaid afterwards are marked overdue
+ALTER TABLE refunds ADD COLUMN IF NOT EXISTS due_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_refunds_due_at ON refunds (due_at)
+WHERE status = 'requested' AND due_at IS NOT NULL;
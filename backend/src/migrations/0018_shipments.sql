@@ -0,0 +1,44 @@
+-- Parcels sent for an order. An order can go out in several shipments, each
+-- carrying some of its items
+CREATE TABLE IF NOT EXISTS shipments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    carrier VARCHAR(32) NOT NULL,
+    tracking_code VARCHAR(64) NOT NULL,
+    -- The carrier's tracking page for this parcel
+    tracking_url TEXT,
+    status VARCHAR(16) NOT NULL DEFAULT 'in_transit',
+    estimated_delivery TIMESTAMP WITH TIME ZONE,
+    delivered_at TIMESTAMP WITH TIME ZONE,
+    created_by VARCHAR(42) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT shipments_status_check CHECK (status IN ('in_transit', 'delivered')),
+    CONSTRAINT shipments_carrier_tracking_code_key UNIQUE (carrier, tracking_code),
+    CONSTRAINT shipments_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER update_shipments_timestamp
+BEFORE UPDATE ON shipments
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_shipments_order_id ON shipments (order_id);
+CREATE INDEX IF NOT EXISTS idx_shipments_in_transit ON shipments (updated_at)
+WHERE status = 'in_transit';
+
+-- How many of each order item went in a shipment
+CREATE TABLE IF NOT EXISTS shipment_items (
+    shipment_id UUID NOT NULL,
+    order_item_id UUID NOT NULL,
+    quantity INTEGER NOT NULL,
+    PRIMARY KEY (shipment_id, order_item_id),
+    CONSTRAINT shipment_items_quantity_check CHECK (quantity > 0),
+    CONSTRAINT shipment_items_shipment_id_fkey FOREIGN KEY (shipment_id)
+    REFERENCES shipments (id) ON DELETE CASCADE,
+    CONSTRAINT shipment_items_order_item_id_fkey FOREIGN KEY (order_item_id)
+    REFERENCES order_items (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_shipment_items_order_item_id ON shipment_items (order_item_id);
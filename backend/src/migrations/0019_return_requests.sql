@@ -0,0 +1,48 @@
+-- A buyer asking to send back some of a delivered order line. The seller
+-- accepts or rejects it; a rejected or stalled request can be escalated to a
+-- dispute, which an admin resolves
+CREATE TABLE IF NOT EXISTS return_requests (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    order_item_id UUID NOT NULL,
+    quantity INTEGER NOT NULL,
+    reason_code VARCHAR(32) NOT NULL,
+    details TEXT,
+    -- IPFS CIDs of the photos the buyer sent
+    evidence_cids TEXT[] NOT NULL DEFAULT '{}',
+    status VARCHAR(16) NOT NULL DEFAULT 'requested',
+    requested_by VARCHAR(42) NOT NULL,
+    seller_note TEXT,
+    dispute_reason TEXT,
+    resolution_note TEXT,
+    -- Wallet of the seller or admin who settled the request
+    resolved_by VARCHAR(42),
+    -- What settling it did: the refund owed and the stock put back, if any
+    refund_id UUID,
+    inventory_movement_id UUID,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    CONSTRAINT return_requests_quantity_check CHECK (quantity > 0),
+    CONSTRAINT return_requests_reason_code_check
+    CHECK (reason_code IN ('damaged', 'wrong_item', 'not_as_described', 'missing_parts', 'other')),
+    CONSTRAINT return_requests_status_check
+    CHECK (status IN ('requested', 'accepted', 'rejected', 'disputed', 'resolved')),
+    CONSTRAINT return_requests_order_id_fkey FOREIGN KEY (order_id)
+    REFERENCES orders (id) ON DELETE CASCADE,
+    CONSTRAINT return_requests_order_item_id_fkey FOREIGN KEY (order_item_id)
+    REFERENCES order_items (id) ON DELETE CASCADE,
+    CONSTRAINT return_requests_refund_id_fkey FOREIGN KEY (refund_id)
+    REFERENCES refunds (id) ON DELETE SET NULL,
+    CONSTRAINT return_requests_inventory_movement_id_fkey FOREIGN KEY (inventory_movement_id)
+    REFERENCES inventory_movements (id) ON DELETE SET NULL
+);
+
+CREATE TRIGGER update_return_requests_timestamp
+BEFORE UPDATE ON return_requests
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
+
+CREATE INDEX IF NOT EXISTS idx_return_requests_order_id ON return_requests (order_id);
+CREATE INDEX IF NOT EXISTS idx_return_requests_order_item_id ON return_requests (order_item_id);
+CREATE INDEX IF NOT EXISTS idx_return_requests_disputed ON return_requests (updated_at)
+WHERE status = 'disputed';
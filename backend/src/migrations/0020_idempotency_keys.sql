@@ -0,0 +1,19 @@
+-- Responses saved against a client's Idempotency-Key, so a retried request
+-- gets the first answer instead of being run again
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    -- Wallet that sent the request; keys are only unique per caller
+    owner VARCHAR(42) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    request_path TEXT NOT NULL,
+    -- keccak256 of the method, path and body, hex encoded
+    fingerprint VARCHAR(64) NOT NULL,
+    -- Empty while the first request is still running
+    status_code INTEGER,
+    content_type TEXT,
+    response_body BYTEA,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (owner, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_expires_at ON idempotency_keys (expires_at);
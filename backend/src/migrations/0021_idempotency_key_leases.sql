@@ -0,0 +1,3 @@
+-- A claim still in progress is only held until its lease runs out, so a
+-- request whose first attempt crashed can be retried before the key expires
+ALTER TABLE idempotency_keys ADD COLUMN IF NOT EXISTS locked_until TIMESTAMP WITH TIME ZONE;
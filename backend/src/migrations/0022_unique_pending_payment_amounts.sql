@@ -0,0 +1,34 @@
+-- Copy each intent's token and status onto its recipients, so that no two
+-- pending intents can ask a wallet for the same amount of the same token
+ALTER TABLE payment_intent_recipients
+ADD COLUMN IF NOT EXISTS token_address VARCHAR(42),
+ADD COLUMN IF NOT EXISTS intent_status VARCHAR NOT NULL DEFAULT 'pending';
+
+UPDATE payment_intent_recipients r
+SET token_address = pi.token_address, intent_status = pi.status
+FROM payment_intents pi
+WHERE r.payment_intent_id = pi.id;
+
+ALTER TABLE payment_intent_recipients
+ALTER COLUMN token_address SET NOT NULL;
+
+CREATE OR REPLACE FUNCTION sync_payment_intent_recipient_status()
+RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE payment_intent_recipients
+    SET intent_status = NEW.status
+    WHERE payment_intent_id = NEW.id;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sync_payment_intent_recipient_status
+AFTER UPDATE OF status ON payment_intents
+FOR EACH ROW
+WHEN (OLD.status IS DISTINCT FROM NEW.status)
+EXECUTE FUNCTION sync_payment_intent_recipient_status();
+
+-- The dust added to each amount is what matches a transfer to its intent
+CREATE UNIQUE INDEX IF NOT EXISTS idx_payment_intent_recipients_pending_amount
+ON payment_intent_recipients (lower(recipient_address), lower(token_address), amount)
+WHERE intent_status = 'pending';
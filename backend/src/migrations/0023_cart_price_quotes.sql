@@ -0,0 +1,13 @@
+-- The rate each currency in a cart was last quoted at for a payment token,
+-- so a direct checkout is verified against the amount the buyer was shown
+CREATE TABLE IF NOT EXISTS cart_price_quotes (
+    cart_id UUID NOT NULL,
+    token_symbol VARCHAR(16) NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    rate NUMERIC(36, 18) NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (cart_id, token_symbol, currency),
+    CONSTRAINT cart_price_quotes_cart_id_fkey FOREIGN KEY (cart_id)
+    REFERENCES cart (id) ON DELETE CASCADE
+);
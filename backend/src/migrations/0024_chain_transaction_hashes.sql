@@ -0,0 +1,8 @@
+-- Every hash broadcast for a transaction, since a resend under the same nonce
+-- gets a new hash and any one of them may be the one that is mined
+ALTER TABLE chain_transactions
+ADD COLUMN IF NOT EXISTS transaction_hashes VARCHAR(66)[] NOT NULL DEFAULT '{}';
+
+UPDATE chain_transactions
+SET transaction_hashes = ARRAY[transaction_hash]
+WHERE transaction_hash IS NOT NULL AND transaction_hashes = '{}';
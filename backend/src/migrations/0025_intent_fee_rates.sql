@@ -0,0 +1,4 @@
+-- The rate the flat platform fee, set in the default currency, was converted
+-- into the payment token at, so orders placed from an intent charge the same fee
+ALTER TABLE payment_intent_items
+ADD COLUMN IF NOT EXISTS fee_rate NUMERIC(36, 18) NOT NULL DEFAULT 1;
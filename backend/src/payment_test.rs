@@ -0,0 +1,2154 @@
+#[cfg(test)]
+mod payment_tests {
+    use crate::authentication::authentication::{auth_middleware, Claims};
+    use crate::db::models::{
+        CreateOrderRequest, CreatePaymentIntentRequest, CreateProductTokenGateRequest,
+        CreateRefundRequest, CreateShipmentRequest, NewChainTransfer, OrderListQuery, OrderStatus,
+        PaymentPrecheckQuery, PaymentStatus, ShipmentItemRequest,
+    };
+    use crate::db::operations::{
+        add_product_token_gate, change_payout_address, create_order, create_payment_intent,
+        create_refund, create_shipment, get_escrow_for_order, get_order_by_id, get_order_details,
+        get_payment_intent, get_store_by_id, get_user_by_wallet, list_order_refunds, list_orders,
+        list_unmatched_transfers, match_chain_transfer, next_queued_transaction, open_escrow,
+        payment_precheck, payout_address_message, queue_chain_transaction, record_chain_transfer,
+        set_indexer_cursor, update_order_status,
+    };
+    use crate::jobs::shipments::track_shipments;
+    use crate::jobs::transfer_indexer::index_transfers;
+    use crate::jobs::tx_sender::process_queue;
+    use crate::routes::payment_handler::*;
+    use crate::routes::return_handler::*;
+    use crate::routes::user_handler::apply_order_status;
+    use crate::state::{AppState, AppStateDb};
+    use crate::utils::carriers::MockCarrier;
+    use crate::utils::eip191::recover_signer;
+    use crate::utils::escrow::{escrow_calldata, escrow_order_key};
+    use crate::utils::fake_chain::FakeChain;
+    use crate::utils::pricing::StaticPriceSource;
+    use crate::utils::receipts::safe_mint_calldata;
+    use crate::utils::signer::Signer;
+    use crate::utils::token_gates::TokenGateCache;
+    use crate::utils::tokens::{to_base_units, CELO, CUSD};
+    use axum::{
+        http::StatusCode,
+        routing::{get, post},
+        Router,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use reqwest::Client;
+    use serde_json::{json, Value};
+    use serial_test::serial;
+    use sqlx::types::Decimal;
+    use sqlx::{Executor, PgPool};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+    use web3::signing::Key;
+    use web3::types::{Address, H256};
+
+    async fn cleanup_test_db(pool: &PgPool) -> Result<(), sqlx::Error> {
+        pool.execute(
+            "TRUNCATE TABLE users, cart, cart_items, orders, products, stores RESTART IDENTITY CASCADE"
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn setup_test_app() -> (Router, PgPool, Arc<AppState>) {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPool::connect(&database_url)
+            .await
+            .expect("Failed to connect to database");
+
+        cleanup_test_db(&pool)
+            .await
+            .expect("Failed to clean test database");
+
+        let state = Arc::new(AppState {
+            db: AppStateDb { pool: pool.clone() },
+            web3: web3::Web3::new(FakeChain::new().into()),
+            pinata_client: reqwest::Client::new(),
+            pinata_api_key: std::env::var("PINATA_API_KEY").unwrap_or_default(),
+            pinata_secret_key: std::env::var("PINATA_SECRET_KEY").unwrap_or_default(),
+            price_source: Arc::new(StaticPriceSource::new([
+                ("USD".to_string(), "CELO".to_string(), Decimal::new(2, 0)),
+                ("NGN".to_string(), "cUSD".to_string(), Decimal::new(65, 5)),
+            ])),
+            token_gates: Arc::new(TokenGateCache::new(Duration::from_secs(60))),
+        });
+
+        let app = Router::new()
+            .route("/payment-intents", post(create_payment_intent_handler))
+            .route("/payment-intents/:id", get(get_payment_intent_handler))
+            .route("/payment-intents/:id/qr", get(payment_intent_qr_handler))
+            .route(
+                "/orders/:id/returns",
+                post(create_return_handler).get(list_returns_handler),
+            )
+            .route("/returns/:id/accept", post(accept_return_handler))
+            .route("/returns/:id/reject", post(reject_return_handler))
+            .route("/returns/:id/dispute", post(escalate_return_handler))
+            .route("/disputes", get(list_disputes_handler))
+            .route("/disputes/:id/resolve", post(resolve_dispute_handler))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            ))
+            .with_state(state.clone());
+
+        (app, pool, state)
+    }
+
+    fn generate_jwt(wallet_address: &str, role: &str) -> String {
+        let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+        let claims = Claims {
+            sub: wallet_address.to_string(),
+            exp: (SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 3600) as usize,
+            role: role.to_string(),
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_ref()),
+        )
+        .expect("Failed to generate JWT")
+    }
+
+    /// Inserts a buyer with a cart holding `quantity` units of a 9.99 product
+    /// that has `stock` units left. Returns (buyer address, cart id, product id).
+    async fn seed_cart(pool: &PgPool, quantity: i32, stock: i32) -> (String, Uuid, Uuid) {
+        let buyer_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
+        let seller_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
+        let user_id = Uuid::new_v4();
+        let store_id = Uuid::new_v4();
+        let product_id = Uuid::new_v4();
+        let cart_id = Uuid::new_v4();
+
+        let mut tx = pool.begin().await.expect("Failed to start transaction");
+        tx.execute(sqlx::query!(
+            "INSERT INTO users (id, wallet_address, user_name) VALUES ($1, $2, $3)",
+            user_id,
+            &buyer_address,
+            "Buyer"
+        ))
+        .await
+        .expect("Failed to insert test user");
+        tx.execute(sqlx::query!(
+            "INSERT INTO stores (id, store_name, owner_address) VALUES ($1, $2, $3)",
+            store_id,
+            "Test Store",
+            &seller_address
+        ))
+        .await
+        .expect("Failed to insert test store");
+        tx.execute(sqlx::query!(
+            "INSERT INTO products (id, store_id, product_name, price, quantity) VALUES ($1, $2, $3, $4, $5)",
+            product_id,
+            store_id,
+            "Test Product",
+            Decimal::new(999, 2),
+            stock
+        ))
+        .await
+        .expect("Failed to insert test product");
+        tx.execute(sqlx::query!(
+            "INSERT INTO cart (id, user_id) VALUES ($1, $2)",
+            cart_id,
+            user_id
+        ))
+        .await
+        .expect("Failed to insert test cart");
+        tx.execute(sqlx::query!(
+            "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, $4)",
+            Uuid::new_v4(),
+            cart_id,
+            product_id,
+            quantity
+        ))
+        .await
+        .expect("Failed to insert test cart item");
+        tx.commit().await.expect("Failed to commit transaction");
+
+        (buyer_address, cart_id, product_id)
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_create_payment_intent() {
+        let (app, pool, _state) = setup_test_app().await;
+        let client = Client::new();
+        let server_addr = "http://localhost:3014";
+
+        let server_task = tokio::spawn(async move {
+            axum::serve(
+                tokio::net::TcpListener::bind("0.0.0.0:3014").await.unwrap(),
+                app,
+            )
+            .await
+            .unwrap();
+        });
+
+        let (buyer_address, cart_id, _product_id) = seed_cart(&pool, 2, 10).await;
+        let token = generate_jwt(&buyer_address, "user");
+
+        let response = client
+            .post(format!("{}/payment-intents", server_addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({ "cart_id": cart_id, "buyer_address": buyer_address }))
+            .send()
+            .await
+            .expect("Failed to send POST /payment-intents");
+
+        let status = response.status();
+        if status != 200 {
+            let error_body = response.text().await.unwrap_or_default();
+            panic!(
+                "POST /payment-intents failed with status {}: {}",
+                status, error_body
+            );
+        }
+
+        let body: Value = response
+            .json()
+            .await
+            .expect("Failed to parse POST /payment-intents response");
+        assert_eq!(body["status"], "pending");
+        assert_eq!(body["token_symbol"], "cUSD");
+        assert!(body["reference"].as_str().unwrap().starts_with("JES-"));
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+
+        let recipients = body["recipients"].as_array().unwrap();
+        assert_eq!(recipients.len(), 1);
+        let amount = Decimal::from_str(recipients[0]["amount"].as_str().unwrap()).unwrap();
+        assert!(amount > Decimal::new(1998, 2), "Amount should carry dust");
+        assert!(
+            amount < Decimal::new(1999, 2),
+            "Dust should stay below a cent"
+        );
+        let duplicate = sqlx::query!(
+            r#"
+            INSERT INTO payment_intent_recipients (
+                payment_intent_id, store_id, recipient_address, token_address, amount
+            )
+            SELECT payment_intent_id, store_id, upper(recipient_address), token_address, amount
+            FROM payment_intent_recipients
+            "#
+        )
+        .execute(&pool)
+        .await;
+        assert!(
+            duplicate.is_err(),
+            "No two pending intents may ask a wallet for the same amount"
+        );
+
+        let intent_id = body["id"].as_str().unwrap();
+        let response = client
+            .get(format!("{}/payment-intents/{}", server_addr, intent_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .expect("Failed to send GET /payment-intents/:id");
+        assert_eq!(response.status(), 200);
+
+        let other_token = generate_jwt("0x0000000000000000000000000000000000000001", "user");
+        let response = client
+            .get(format!("{}/payment-intents/{}", server_addr, intent_id))
+            .header("Authorization", format!("Bearer {}", other_token))
+            .send()
+            .await
+            .expect("Failed to send GET /payment-intents/:id");
+        assert_ne!(
+            response.status(),
+            200,
+            "Other users must not see the intent"
+        );
+
+        let requests = body["payment_requests"].as_array().unwrap();
+        assert_eq!(requests.len(), 1);
+        let uri = requests[0]["uri"].as_str().unwrap();
+        let base_units = to_base_units(amount, CUSD.decimals).unwrap();
+        assert_eq!(
+            uri,
+            format!(
+                "ethereum:{}@44787/transfer?address={}&uint256={}",
+                CUSD.address,
+                recipients[0]["recipient_address"].as_str().unwrap(),
+                base_units
+            )
+        );
+
+        let response = client
+            .get(format!(
+                "{}{}&format=png",
+                server_addr,
+                requests[0]["qr_code_url"].as_str().unwrap()
+            ))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .expect("Failed to send GET /payment-intents/:id/qr");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        let png = response.bytes().await.unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+
+        let response = client
+            .get(format!("{}/payment-intents/{}/qr", server_addr, intent_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .expect("Failed to send GET /payment-intents/:id/qr");
+        assert_eq!(response.status(), 200);
+        assert!(response.text().await.unwrap().contains("<svg"));
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_payment_intent_reserves_stock() {
+        let (app, pool, _state) = setup_test_app().await;
+        let client = Client::new();
+        let server_addr = "http://localhost:3015";
+
+        let server_task = tokio::spawn(async move {
+            axum::serve(
+                tokio::net::TcpListener::bind("0.0.0.0:3015").await.unwrap(),
+                app,
+            )
+            .await
+            .unwrap();
+        });
+
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 2, 3).await;
+        let token = generate_jwt(&buyer_address, "user");
+        let payload = json!({ "cart_id": cart_id, "buyer_address": buyer_address });
+
+        let response = client
+            .post(format!("{}/payment-intents", server_addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&payload)
+            .send()
+            .await
+            .expect("Failed to send POST /payment-intents");
+        assert_eq!(response.status(), 200);
+
+        // Only one unit is left unreserved, so a second intent must be refused.
+        let response = client
+            .post(format!("{}/payment-intents", server_addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&payload)
+            .send()
+            .await
+            .expect("Failed to send POST /payment-intents");
+        assert_eq!(response.status(), 400);
+
+        sqlx::query!(
+            "UPDATE payment_intents SET expires_at = now() - interval '1 minute' WHERE cart_id = $1",
+            cart_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to backdate payment intent");
+        let expired = crate::db::operations::expire_payment_intents(&pool)
+            .await
+            .expect("Failed to expire payment intents");
+        assert_eq!(expired, 1);
+
+        let response = client
+            .post(format!("{}/payment-intents", server_addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&payload)
+            .send()
+            .await
+            .expect("Failed to send POST /payment-intents");
+        assert_eq!(
+            response.status(),
+            200,
+            "Expired intent should release stock of product {}",
+            product_id
+        );
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_direct_order_is_priced_by_the_store_and_paid_with_its_fee() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, _cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let store = sqlx::query!(
+            "SELECT s.id, s.owner_address FROM stores s JOIN products p ON p.store_id = s.id WHERE p.id = $1",
+            product_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch store");
+        let request = |store_id: Uuid| CreateOrderRequest {
+            store_id,
+            product_id,
+            user_id: user.id,
+            buyer_address: buyer_address.clone(),
+            quantity: 1,
+        };
+
+        let err = create_order(&pool, state.price_source.as_ref(), request(Uuid::new_v4()))
+            .await
+            .expect_err("The product must belong to the store");
+        assert!(err.to_string().contains("does not belong"), "{}", err);
+
+        let treasury = "0x00000000000000000000000000000000000fee01";
+        // SAFETY: tests touching platform fee settings are serialised.
+        unsafe {
+            std::env::set_var("PLATFORM_TREASURY_ADDRESS", treasury);
+            std::env::set_var("PLATFORM_FEE_BPS", "250");
+        }
+        let order = create_order(&pool, state.price_source.as_ref(), request(store.id))
+            .await
+            .expect("Failed to create order")
+            .order;
+        // 9.99 at 2.5% is 0.25, so the store is owed 9.74.
+        assert_eq!(order.amount, Decimal::new(999, 2));
+        assert_eq!(order.platform_fee, Decimal::new(25, 2));
+        assert_eq!(order.payout_address, store.owner_address);
+
+        let hash = format!("0x{}", "44".repeat(32));
+        let record = |log_index: i64, to_address: &str, amount: Decimal| {
+            record_chain_transfer(
+                &pool,
+                NewChainTransfer {
+                    transaction_hash: hash.clone(),
+                    log_index,
+                    block_number: 1,
+                    token_address: CUSD.address.to_string(),
+                    from_address: buyer_address.clone(),
+                    to_address: to_address.to_string(),
+                    amount,
+                },
+            )
+        };
+        let share = record(0, &store.owner_address, Decimal::new(974, 2))
+            .await
+            .expect("Failed to record transfer")
+            .expect("Transfer should be new");
+        let unpaid_fee = match_chain_transfer(&pool, &share).await;
+        record(1, treasury, Decimal::new(25, 2))
+            .await
+            .expect("Failed to record transfer")
+            .expect("Transfer should be new");
+        let paid = match_chain_transfer(&pool, &share).await;
+        unsafe {
+            std::env::remove_var("PLATFORM_TREASURY_ADDRESS");
+            std::env::remove_var("PLATFORM_FEE_BPS");
+        }
+
+        assert_eq!(
+            unpaid_fee.expect("Failed to match transfer"),
+            "unmatched",
+            "The store's share alone does not pay the order"
+        );
+        assert_eq!(paid.expect("Failed to match transfer"), "matched");
+        let order = get_order_by_id(&pool, order.id)
+            .await
+            .expect("Failed to fetch order")
+            .expect("Order should exist");
+        assert_eq!(order.payment_status, PaymentStatus::Confirmed);
+        let statuses = sqlx::query_scalar!(
+            "SELECT status FROM chain_transfers WHERE transaction_hash = $1",
+            hash
+        )
+        .fetch_all(&pool)
+        .await
+        .expect("Failed to fetch transfers");
+        assert!(statuses.iter().all(|status| status == "matched"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_transfer_matches_payment_intent() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 2, 10).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            CreatePaymentIntentRequest {
+                cart_id,
+                buyer_address: buyer_address.clone(),
+                token: "cUSD".to_string(),
+            },
+        )
+        .await
+        .expect("Failed to create payment intent");
+        let recipient = &intent.recipients[0];
+
+        let stray = record_chain_transfer(
+            &pool,
+            NewChainTransfer {
+                transaction_hash: format!("0x{}", "11".repeat(32)),
+                log_index: 0,
+                block_number: 1,
+                token_address: CUSD.address.to_string(),
+                from_address: buyer_address.clone(),
+                to_address: recipient.recipient_address.clone(),
+                amount: Decimal::new(1998, 2),
+            },
+        )
+        .await
+        .expect("Failed to record transfer")
+        .expect("Transfer should be new");
+        let status = match_chain_transfer(&pool, &stray)
+            .await
+            .expect("Failed to match transfer");
+        assert_eq!(status, "unmatched", "Amount without dust must not match");
+
+        let stranger = record_chain_transfer(
+            &pool,
+            NewChainTransfer {
+                transaction_hash: format!("0x{}", "12".repeat(32)),
+                log_index: 0,
+                block_number: 1,
+                token_address: CUSD.address.to_string(),
+                from_address: format!("0x{}", "99".repeat(20)),
+                to_address: recipient.recipient_address.clone(),
+                amount: recipient.amount,
+            },
+        )
+        .await
+        .expect("Failed to record transfer")
+        .expect("Transfer should be new");
+        let status = match_chain_transfer(&pool, &stranger)
+            .await
+            .expect("Failed to match transfer");
+        assert_eq!(
+            status, "unmatched",
+            "Only the buyer's transfer pays the intent"
+        );
+
+        let payment = NewChainTransfer {
+            transaction_hash: format!("0x{}", "22".repeat(32)),
+            log_index: 3,
+            block_number: 2,
+            token_address: CUSD.address.to_string(),
+            from_address: buyer_address.clone(),
+            to_address: recipient.recipient_address.to_uppercase(),
+            amount: recipient.amount,
+        };
+        let transfer = record_chain_transfer(&pool, payment)
+            .await
+            .expect("Failed to record transfer")
+            .expect("Transfer should be new");
+        let status = match_chain_transfer(&pool, &transfer)
+            .await
+            .expect("Failed to match transfer");
+        assert_eq!(status, "matched");
+        let claimed = sqlx::query_scalar!(
+            "SELECT payment_intent_id FROM payment_transactions WHERE transaction_hash = $1",
+            format!("0x{}", "22".repeat(32))
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("The matched transaction must be claimed");
+        assert_eq!(claimed, Some(intent.intent.id));
+
+        let intent = get_payment_intent(&pool, intent.intent.id)
+            .await
+            .expect("Failed to fetch payment intent")
+            .expect("Payment intent should exist");
+        assert_eq!(intent.intent.status, "completed");
+
+        let orders = sqlx::query!(
+            r#"
+            SELECT o.payment_status, o.transaction_hash, oi.quantity
+            FROM orders o
+            JOIN order_items oi ON oi.order_id = o.id
+            WHERE oi.product_id = $1
+            "#,
+            product_id
+        )
+        .fetch_all(&pool)
+        .await
+        .expect("Failed to fetch orders");
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].payment_status, "confirmed");
+        assert_eq!(orders[0].transaction_hash, Some(transfer.transaction_hash));
+        assert_eq!(orders[0].quantity, 2);
+
+        let unmatched = list_unmatched_transfers(&pool, &recipient.recipient_address)
+            .await
+            .expect("Failed to list unmatched transfers");
+        let mut unmatched: Vec<Uuid> = unmatched.into_iter().map(|t| t.id).collect();
+        unmatched.sort();
+        let mut expected = vec![stray.id, stranger.id];
+        expected.sort();
+        assert_eq!(unmatched, expected);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_indexer_retries_unmatched_transfers_and_skips_claimed_hashes() {
+        let (_app, pool, state) = setup_test_app().await;
+        let chain = FakeChain::new();
+        let state = AppState {
+            db: AppStateDb { pool: pool.clone() },
+            web3: web3::Web3::new(chain.clone().into()),
+            pinata_client: state.pinata_client.clone(),
+            pinata_api_key: String::new(),
+            pinata_secret_key: String::new(),
+            price_source: state.price_source.clone(),
+            token_gates: state.token_gates.clone(),
+        };
+        let (_, cart_id, _) = seed_cart(&pool, 1, 10).await;
+        // The fake chain needs real 20-byte addresses for its logs.
+        let buyer_address = format!("0x{}", "44".repeat(20));
+        let seller_address = format!("0x{}", "55".repeat(20));
+        sqlx::query!("UPDATE users SET wallet_address = $1", &buyer_address)
+            .execute(&pool)
+            .await
+            .expect("Failed to update buyer");
+        sqlx::query!("UPDATE stores SET owner_address = $1", &seller_address)
+            .execute(&pool)
+            .await
+            .expect("Failed to update store");
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            CreatePaymentIntentRequest {
+                cart_id,
+                buyer_address: buyer_address.clone(),
+                token: "cUSD".to_string(),
+            },
+        )
+        .await
+        .expect("Failed to create payment intent");
+        let recipient = &intent.recipients[0];
+
+        let block = chain.block_number();
+        let hash = chain.erc20_transfer(
+            &CUSD,
+            &buyer_address,
+            &recipient.recipient_address,
+            recipient.amount,
+        );
+        chain.mine(3);
+        set_indexer_cursor(&pool, "token_transfers", block as i64 - 1)
+            .await
+            .expect("Failed to set cursor");
+
+        // An earlier run recorded the log and then failed before matching it.
+        let recorded = record_chain_transfer(
+            &pool,
+            NewChainTransfer {
+                transaction_hash: hash.clone(),
+                log_index: 0,
+                block_number: block as i64,
+                token_address: CUSD.address.to_string(),
+                from_address: buyer_address.clone(),
+                to_address: recipient.recipient_address.clone(),
+                amount: recipient.amount,
+            },
+        )
+        .await
+        .expect("Failed to record transfer")
+        .expect("Transfer should be new");
+
+        index_transfers(&state)
+            .await
+            .expect("Failed to index transfers");
+        let status = sqlx::query_scalar!(
+            "SELECT status FROM chain_transfers WHERE id = $1",
+            recorded.id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch transfer");
+        assert_eq!(status, "matched", "The retry must match the recorded row");
+        let intent = get_payment_intent(&pool, intent.intent.id)
+            .await
+            .expect("Failed to fetch payment intent")
+            .expect("Payment intent should exist");
+        assert_eq!(intent.intent.status, "completed");
+
+        // Once the payment has claimed the hash, no other log of it is recorded.
+        let skipped = record_chain_transfer(
+            &pool,
+            NewChainTransfer {
+                transaction_hash: hash.clone(),
+                log_index: 1,
+                block_number: block as i64,
+                token_address: CUSD.address.to_string(),
+                from_address: buyer_address.clone(),
+                to_address: recipient.recipient_address.clone(),
+                amount: recipient.amount,
+            },
+        )
+        .await
+        .expect("Failed to record transfer");
+        assert!(skipped.is_none(), "A claimed hash must be skipped");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_native_celo_requires_store_opt_in() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+
+        let request = || CreatePaymentIntentRequest {
+            cart_id,
+            buyer_address: buyer_address.clone(),
+            token: CELO.symbol.to_string(),
+        };
+
+        let err = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            request(),
+        )
+        .await
+        .expect_err("Store has not opted in to CELO");
+        assert!(err.to_string().contains("does not accept CELO"));
+
+        sqlx::query!(
+            "UPDATE stores SET accepts_native_celo = true WHERE id = (SELECT store_id FROM products WHERE id = $1)",
+            product_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to opt store in");
+
+        // SAFETY: tests touching platform fee settings are serialised.
+        unsafe {
+            std::env::set_var(
+                "PLATFORM_TREASURY_ADDRESS",
+                "0x00000000000000000000000000000000000fee01",
+            );
+            std::env::set_var("PLATFORM_FEE_BPS", "250");
+        }
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            request(),
+        )
+        .await;
+        unsafe {
+            std::env::remove_var("PLATFORM_TREASURY_ADDRESS");
+            std::env::remove_var("PLATFORM_FEE_BPS");
+        }
+        let intent = intent.expect("Failed to create CELO payment intent");
+        assert_eq!(intent.intent.token_symbol, "CELO");
+        assert_eq!(intent.intent.token_address, CELO.address);
+        // A native transfer has one recipient, so there is no treasury leg.
+        assert_eq!(intent.recipients.len(), 1);
+        assert!(intent.recipients[0].store_id.is_some());
+        assert_eq!(
+            intent.recipients[0].amount.round_dp(2),
+            Decimal::new(1998, 2)
+        );
+    }
+
+    #[test]
+    fn test_escrow_keys_and_arbiter_signer() {
+        let order_id = Uuid::parse_str("0123456789abcdef0123456789abcdef").unwrap();
+        assert_eq!(
+            format!("{:?}", escrow_order_key(order_id)),
+            "0x000000000000000000000000000000000123456789abcdef0123456789abcdef"
+        );
+
+        // Escrows are kept per buyer, so calls name the buyer as well as the order.
+        let buyer = Address::from_low_u64_be(0xb0b);
+        let calldata = escrow_calldata("releaseExpired", buyer, order_id);
+        assert_eq!(
+            &calldata[..4],
+            &web3::signing::keccak256(b"releaseExpired(address,bytes32)")[..4]
+        );
+        assert_eq!(&calldata[4..36], H256::from(buyer).as_bytes());
+        assert_eq!(&calldata[36..], escrow_order_key(order_id).as_bytes());
+
+        let signer =
+            Signer::from_hex("0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+                .expect("Failed to load signer");
+        assert_eq!(
+            format!("{:?}", signer.address()),
+            "0x2c7536e3605d9c16a7a3d7b1898e529396a65c23"
+        );
+        assert!(Signer::from_hex("0x1234").is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_escrow_is_opened_then_refunded_or_released() {
+        let (_app, pool, state) = setup_test_app().await;
+        let chain = FakeChain::new();
+        chain.mine_sent_transactions();
+        let state = AppState {
+            db: AppStateDb { pool: pool.clone() },
+            web3: web3::Web3::new(chain.clone().into()),
+            pinata_client: state.pinata_client.clone(),
+            pinata_api_key: String::new(),
+            pinata_secret_key: String::new(),
+            price_source: state.price_source.clone(),
+            token_gates: state.token_gates.clone(),
+        };
+        let (_, _, product_id) = seed_cart(&pool, 1, 10).await;
+        // The fake chain needs real 20-byte addresses for its logs.
+        let buyer_address = format!("0x{}", "44".repeat(20));
+        let seller_address = format!("0x{}", "55".repeat(20));
+        sqlx::query!("UPDATE users SET wallet_address = $1", &buyer_address)
+            .execute(&pool)
+            .await
+            .expect("Failed to update buyer");
+        let store_id = sqlx::query_scalar!(
+            "UPDATE stores SET owner_address = $1 RETURNING id",
+            &seller_address
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to update store");
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let buyer = Address::from_str(buyer_address.trim_start_matches("0x")).unwrap();
+        let place_order = || async {
+            create_order(
+                &pool,
+                state.price_source.as_ref(),
+                CreateOrderRequest {
+                    store_id,
+                    product_id,
+                    user_id: user.id,
+                    buyer_address: buyer_address.clone(),
+                    quantity: 1,
+                },
+            )
+            .await
+            .expect("Failed to create order")
+            .order
+        };
+        let contract = "0x00000000000000000000000000000000000e5c40";
+        let deposit = |order_id: Uuid, amount: Decimal| {
+            chain.escrow_deposit(
+                contract,
+                order_id,
+                &buyer_address,
+                &seller_address,
+                &CUSD,
+                amount,
+            )
+        };
+        let sent_call = |function: &str, order_id: Uuid| {
+            let calldata = escrow_calldata(function, buyer, order_id);
+            chain.sent_transactions().last().is_some_and(|raw| {
+                raw.0
+                    .windows(calldata.len())
+                    .any(|window| window == calldata)
+            })
+        };
+        // SAFETY: tests touching escrow settings are serialised.
+        unsafe {
+            std::env::set_var("ESCROW_CONTRACT_ADDRESS", contract);
+            std::env::set_var(
+                "ESCROW_ARBITER_KEY",
+                "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318",
+            );
+        }
+
+        let cancelled = place_order().await;
+        let short = deposit(cancelled.id, cancelled.amount - Decimal::new(1, 2));
+        let short = open_escrow(&pool, &state.web3, &cancelled, &short).await;
+        let escrow = open_escrow(
+            &pool,
+            &state.web3,
+            &cancelled,
+            &deposit(cancelled.id, cancelled.amount),
+        )
+        .await;
+        chain.fail_next("eth_sendRawTransaction", "node unavailable");
+        let failed_refund = apply_order_status(
+            &state,
+            cancelled.id,
+            OrderStatus::Cancelled,
+            &seller_address,
+            None,
+            false,
+        )
+        .await;
+        let after_failure = get_order_by_id(&pool, cancelled.id).await;
+        let escrow_after_failure = get_escrow_for_order(&pool, cancelled.id).await;
+        let refunded = apply_order_status(
+            &state,
+            cancelled.id,
+            OrderStatus::Cancelled,
+            &seller_address,
+            None,
+            false,
+        )
+        .await;
+        let refund_sent = sent_call("refund", cancelled.id);
+
+        let delivered = place_order().await;
+        open_escrow(
+            &pool,
+            &state.web3,
+            &delivered,
+            &deposit(delivered.id, delivered.amount),
+        )
+        .await
+        .expect("Failed to open escrow");
+        update_order_status(
+            &pool,
+            delivered.id,
+            OrderStatus::Shipped,
+            &seller_address,
+            None,
+        )
+        .await
+        .expect("Failed to ship order");
+        let released = apply_order_status(
+            &state,
+            delivered.id,
+            OrderStatus::Delivered,
+            &seller_address,
+            None,
+            false,
+        )
+        .await;
+        let release_sent = sent_call("release", delivered.id);
+        unsafe {
+            std::env::remove_var("ESCROW_CONTRACT_ADDRESS");
+            std::env::remove_var("ESCROW_ARBITER_KEY");
+        }
+
+        let err = short.expect_err("A short deposit must not open the escrow");
+        assert!(
+            err.to_string().contains("Invalid payment amount"),
+            "{}",
+            err
+        );
+        let escrow = escrow.expect("Failed to open escrow");
+        assert_eq!(escrow.status, "funded");
+        assert_eq!(escrow.amount, cancelled.amount);
+
+        let (status, message) = failed_refund.expect_err("The refund call was made to fail");
+        assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", message);
+        let after_failure = after_failure
+            .expect("Failed to fetch order")
+            .expect("Order should exist");
+        assert_eq!(
+            after_failure.status,
+            OrderStatus::Pending,
+            "An unrefunded order must not be cancelled"
+        );
+        assert_eq!(after_failure.payment_status, PaymentStatus::Escrowed);
+        let escrow_after_failure = escrow_after_failure
+            .expect("Failed to fetch escrow")
+            .expect("Escrow should exist");
+        assert_eq!(escrow_after_failure.status, "funded");
+
+        let refunded = refunded.expect("Failed to cancel order");
+        assert_eq!(refunded.status, OrderStatus::Cancelled);
+        assert_eq!(refunded.payment_status, PaymentStatus::Refunded);
+        let escrow = get_escrow_for_order(&pool, cancelled.id)
+            .await
+            .expect("Failed to fetch escrow")
+            .expect("Escrow should exist");
+        assert_eq!(escrow.status, "refunded");
+        assert!(escrow.refund_transaction_hash.is_some());
+        assert!(refund_sent, "The arbiter must call refund for the buyer");
+
+        let released = released.expect("Failed to deliver order");
+        assert_eq!(released.status, OrderStatus::Delivered);
+        assert_eq!(released.payment_status, PaymentStatus::Confirmed);
+        let escrow = get_escrow_for_order(&pool, delivered.id)
+            .await
+            .expect("Failed to fetch escrow")
+            .expect("Escrow should exist");
+        assert_eq!(escrow.status, "released");
+        assert!(escrow.release_transaction_hash.is_some());
+        assert!(release_sent, "The arbiter must call release for the buyer");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_refunds_cannot_exceed_order_amount() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, _cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let store = sqlx::query!(
+            "SELECT s.id, s.owner_address FROM stores s JOIN products p ON p.store_id = s.id WHERE p.id = $1",
+            product_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch store");
+
+        let order = create_order(
+            &pool,
+            state.price_source.as_ref(),
+            CreateOrderRequest {
+                store_id: store.id,
+                product_id,
+                user_id: user.id,
+                buyer_address: buyer_address.clone(),
+                quantity: 2,
+            },
+        )
+        .await
+        .expect("Failed to create order")
+        .order;
+
+        let request = |amount: Option<Decimal>| CreateRefundRequest {
+            amount,
+            reason: Some("Damaged".to_string()),
+            transaction_hash: None,
+        };
+
+        create_refund(&pool, &order, &buyer_address, &request(None), None)
+            .await
+            .expect_err("Unpaid orders cannot be refunded");
+
+        sqlx::query!(
+            "UPDATE orders SET payment_status = 'confirmed' WHERE id = $1",
+            order.id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to mark order paid");
+        let order = get_order_by_id(&pool, order.id)
+            .await
+            .expect("Failed to fetch order")
+            .expect("Order should exist");
+
+        let partial = create_refund(
+            &pool,
+            &order,
+            &buyer_address,
+            &request(Some(Decimal::new(500, 2))),
+            None,
+        )
+        .await
+        .expect("Failed to request partial refund");
+        assert_eq!(partial.status, "requested");
+        assert_eq!(partial.token_symbol, "cUSD");
+
+        create_refund(
+            &pool,
+            &order,
+            &buyer_address,
+            &request(Some(Decimal::new(1600, 2))),
+            None,
+        )
+        .await
+        .expect_err("Refunds must not exceed the order amount");
+
+        let rest = create_refund(&pool, &order, &buyer_address, &request(None), None)
+            .await
+            .expect("Failed to request remaining refund");
+        assert_eq!(rest.amount, Decimal::new(1498, 2));
+
+        let refunds = list_order_refunds(&pool, order.id)
+            .await
+            .expect("Failed to list refunds");
+        assert_eq!(refunds.len(), 2);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_payment_intent_splits_platform_fee() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, _product_id) = seed_cart(&pool, 2, 10).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+
+        let treasury = "0x00000000000000000000000000000000000fee01";
+        // SAFETY: tests touching platform fee settings are serialised.
+        unsafe {
+            std::env::set_var("PLATFORM_TREASURY_ADDRESS", treasury);
+            std::env::set_var("PLATFORM_FEE_BPS", "250");
+            std::env::set_var("PLATFORM_FEE_FLAT", "0.10");
+        }
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            CreatePaymentIntentRequest {
+                cart_id,
+                buyer_address: buyer_address.clone(),
+                token: "cUSD".to_string(),
+            },
+        )
+        .await;
+        unsafe {
+            std::env::remove_var("PLATFORM_TREASURY_ADDRESS");
+            std::env::remove_var("PLATFORM_FEE_BPS");
+            std::env::remove_var("PLATFORM_FEE_FLAT");
+        }
+        let intent = intent.expect("Failed to create payment intent");
+
+        // 2 x 9.99 = 19.98; 2.5% of that is 0.4995, plus 0.10 flat, is 0.60.
+        assert_eq!(intent.recipients.len(), 2);
+        let store = intent
+            .recipients
+            .iter()
+            .find(|r| r.store_id.is_some())
+            .expect("Store recipient missing");
+        let platform = intent
+            .recipients
+            .iter()
+            .find(|r| r.store_id.is_none())
+            .expect("Treasury recipient missing");
+        assert_eq!(platform.recipient_address, treasury);
+        assert_eq!(platform.amount.round_dp(2), Decimal::new(60, 2));
+        assert_eq!(store.amount.round_dp(2), Decimal::new(1938, 2));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_payment_intent_converts_fiat_prices() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 2, 10).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let request = || CreatePaymentIntentRequest {
+            cart_id,
+            buyer_address: buyer_address.clone(),
+            token: "cUSD".to_string(),
+        };
+
+        sqlx::query!(
+            "UPDATE products SET currency = 'KES' WHERE id = $1",
+            product_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to reprice product");
+        let err = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            request(),
+        )
+        .await
+        .expect_err("There is no KES rate");
+        assert!(err.to_string().contains("Cannot price KES in cUSD"));
+
+        sqlx::query!(
+            "UPDATE products SET currency = 'NGN', price = 15000 WHERE id = $1",
+            product_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to reprice product");
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            request(),
+        )
+        .await
+        .expect("Failed to create payment intent");
+
+        // 15000 NGN at 0.00065 is 9.75 cUSD each.
+        let item = &intent.items[0];
+        assert_eq!(item.currency, "NGN");
+        assert_eq!(item.list_price, Decimal::new(15000, 0));
+        assert_eq!(item.exchange_rate, Decimal::new(65, 5));
+        assert_eq!(item.unit_price, Decimal::new(975, 2));
+        assert_eq!(
+            intent.recipients[0].amount.round_dp(2),
+            Decimal::new(1950, 2)
+        );
+
+        // The intent cannot outlive the quote it was priced with.
+        let quote_ttl = chrono::Duration::seconds(300);
+        assert!(intent.intent.expires_at <= chrono::Utc::now() + quote_ttl);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_receipt_mint_is_queued_once_per_order() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, _cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let store = sqlx::query!(
+            "SELECT s.id, s.owner_address FROM stores s JOIN products p ON p.store_id = s.id WHERE p.id = $1",
+            product_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch store");
+        let order = create_order(
+            &pool,
+            state.price_source.as_ref(),
+            CreateOrderRequest {
+                store_id: store.id,
+                product_id,
+                user_id: user.id,
+                buyer_address: buyer_address.clone(),
+                quantity: 1,
+            },
+        )
+        .await
+        .expect("Failed to create order")
+        .order;
+
+        let contract = "0x00000000000000000000000000000000000000aa";
+        let queued = queue_chain_transaction(&pool, "mint_receipt", Some(order.id), contract, None)
+            .await
+            .expect("Failed to queue mint")
+            .expect("First mint should be queued");
+        assert_eq!(queued.status, "queued");
+        assert_eq!(queued.attempts, 0);
+
+        let again = queue_chain_transaction(&pool, "mint_receipt", Some(order.id), contract, None)
+            .await
+            .expect("Failed to queue mint");
+        assert!(again.is_none(), "An order only gets one receipt");
+
+        let next = next_queued_transaction(&pool)
+            .await
+            .expect("Failed to fetch queue")
+            .expect("Mint should be due");
+        assert_eq!(next.id, queued.id);
+
+        // safeMint(address,string)
+        let calldata =
+            safe_mint_calldata(contract, "ipfs://mock_cid").expect("Failed to encode safeMint");
+        assert_eq!(&calldata[..4], &[0xd2, 0x04, 0xc4, 0x5e]);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_failed_broadcast_is_resent_under_the_same_nonce() {
+        let (_app, pool, state) = setup_test_app().await;
+        let chain = FakeChain::new();
+        let state = AppState {
+            db: AppStateDb { pool: pool.clone() },
+            web3: web3::Web3::new(chain.clone().into()),
+            pinata_client: state.pinata_client.clone(),
+            pinata_api_key: String::new(),
+            pinata_secret_key: String::new(),
+            price_source: state.price_source.clone(),
+            token_gates: state.token_gates.clone(),
+        };
+        let signer =
+            Signer::from_hex("0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+                .expect("Failed to load signer");
+
+        let contract = "0x00000000000000000000000000000000000000aa";
+        let data =
+            safe_mint_calldata(contract, "ipfs://mock_cid").expect("Failed to encode safeMint");
+        let queued = queue_chain_transaction(
+            &pool,
+            "mint_receipt",
+            None,
+            contract,
+            Some(format!("0x{}", hex::encode(data))),
+        )
+        .await
+        .expect("Failed to queue mint")
+        .expect("Mint should be queued");
+        let fetch = || async {
+            sqlx::query!(
+                "SELECT status, nonce, transaction_hashes FROM chain_transactions WHERE id = $1",
+                queued.id
+            )
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch transaction")
+        };
+
+        // The node may have taken the transaction before the error, so it stays sent under its nonce.
+        chain.fail_next("eth_sendRawTransaction", "connection reset");
+        process_queue(&state, &signer)
+            .await
+            .expect("Failed to process queue");
+        let sent = fetch().await;
+        assert_eq!(sent.status, "sent");
+        assert_eq!(sent.nonce, Some(0));
+        assert_eq!(sent.transaction_hashes.len(), 1);
+
+        sqlx::query!(
+            "UPDATE chain_transactions SET sent_at = now() - interval '1 hour' WHERE id = $1",
+            queued.id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to age transaction");
+        process_queue(&state, &signer)
+            .await
+            .expect("Failed to process queue");
+        let resent = fetch().await;
+        assert_eq!(resent.status, "sent");
+        assert_eq!(
+            resent.nonce,
+            Some(0),
+            "A resend must replace, not add, a mint"
+        );
+        assert_eq!(resent.transaction_hashes.len(), 2);
+        assert_eq!(resent.transaction_hashes[0], sent.transaction_hashes[0]);
+
+        // The fake chain never mines it, yet the nonce is now used, so once
+        // the node has had time the row is dropped rather than left sent.
+        sqlx::query!(
+            "UPDATE chain_transactions SET sent_at = now() - interval '1 hour' WHERE id = $1",
+            queued.id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to age transaction");
+        process_queue(&state, &signer)
+            .await
+            .expect("Failed to process queue");
+        let dropped = fetch().await;
+        assert_eq!(dropped.status, "dropped");
+        assert_eq!(dropped.nonce, Some(0));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_unmined_transaction_is_dropped_after_max_attempts() {
+        let (_app, pool, state) = setup_test_app().await;
+        let chain = FakeChain::new();
+        let state = AppState {
+            db: AppStateDb { pool: pool.clone() },
+            web3: web3::Web3::new(chain.clone().into()),
+            pinata_client: state.pinata_client.clone(),
+            pinata_api_key: String::new(),
+            pinata_secret_key: String::new(),
+            price_source: state.price_source.clone(),
+            token_gates: state.token_gates.clone(),
+        };
+        let signer =
+            Signer::from_hex("0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+                .expect("Failed to load signer");
+
+        let contract = "0x00000000000000000000000000000000000000aa";
+        let data =
+            safe_mint_calldata(contract, "ipfs://mock_cid").expect("Failed to encode safeMint");
+        let queued = queue_chain_transaction(
+            &pool,
+            "mint_receipt",
+            None,
+            contract,
+            Some(format!("0x{}", hex::encode(data))),
+        )
+        .await
+        .expect("Failed to queue mint")
+        .expect("Mint should be queued");
+
+        chain.fail_next("eth_sendRawTransaction", "connection reset");
+        process_queue(&state, &signer)
+            .await
+            .expect("Failed to process queue");
+        sqlx::query!(
+            "UPDATE chain_transactions SET sent_at = now() - interval '1 hour' WHERE id = $1",
+            queued.id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to age transaction");
+
+        // SAFETY: tests touching transaction sender settings are serialised.
+        unsafe {
+            std::env::set_var("TX_MAX_ATTEMPTS", "1");
+        }
+        let processed = process_queue(&state, &signer).await;
+        unsafe {
+            std::env::remove_var("TX_MAX_ATTEMPTS");
+        }
+        processed.expect("Failed to process queue");
+
+        let row = sqlx::query!(
+            "SELECT status, last_error, transaction_hashes FROM chain_transactions WHERE id = $1",
+            queued.id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch transaction");
+        assert_eq!(row.status, "dropped");
+        assert_eq!(
+            row.last_error.as_deref(),
+            Some("Not mined after 1 attempts")
+        );
+        assert_eq!(row.transaction_hashes.len(), 1, "It must not be resent");
+    }
+
+    /// A chain where `holder` owns `token_ids` of the MiniPay contract at `contract`.
+    fn nft_holder_chain(contract: &str, token_ids: &[u64]) -> FakeChain {
+        let chain = FakeChain::new();
+        chain.respond_to_call(
+            contract,
+            "balanceOf(address)",
+            ethabi::encode(&[ethabi::Token::Uint(token_ids.len().into())]),
+        );
+        chain.respond_to_call(
+            contract,
+            "getNFTsByAddress(address)",
+            ethabi::encode(&[ethabi::Token::Array(
+                token_ids
+                    .iter()
+                    .map(|id| ethabi::Token::Uint((*id).into()))
+                    .collect(),
+            )]),
+        );
+        chain
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_token_gates_restrict_and_discount_products() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let holder = "0x00000000000000000000000000000000000000bb";
+        let request = || CreatePaymentIntentRequest {
+            cart_id,
+            buyer_address: holder.to_string(),
+            token: "cUSD".to_string(),
+        };
+
+        let contract = "0x00000000000000000000000000000000000000aa";
+        for (token_id, discount_bps) in [(None, None), (Some(7), Some(2000)), (Some(8), Some(5000))]
+        {
+            add_product_token_gate(
+                &pool,
+                product_id,
+                CreateProductTokenGateRequest {
+                    contract_address: contract.to_string(),
+                    token_id,
+                    discount_bps,
+                },
+            )
+            .await
+            .expect("Failed to add token gate");
+        }
+
+        let outsider = web3::Web3::new(nft_holder_chain(contract, &[]));
+        let err = create_payment_intent(
+            &pool,
+            &outsider,
+            state.price_source.as_ref(),
+            &TokenGateCache::new(Duration::from_secs(60)),
+            user.id,
+            request(),
+        )
+        .await
+        .expect_err("Only holders may buy the product");
+        assert!(err.to_string().contains("only available to token holders"));
+
+        let chain = nft_holder_chain(contract, &[3, 7]);
+        let member = web3::Web3::new(chain.clone());
+        let cache = TokenGateCache::new(Duration::from_secs(60));
+        let intent = create_payment_intent(
+            &pool,
+            &member,
+            state.price_source.as_ref(),
+            &cache,
+            user.id,
+            request(),
+        )
+        .await
+        .expect("Failed to create payment intent");
+
+        // Holding token 7 takes 20% off 9.99; token 8 would have given 50%.
+        assert_eq!(intent.items[0].list_price, Decimal::new(799, 2));
+        assert_eq!(chain.requests().len(), 3);
+
+        assert!(cache
+            .holds(&member, contract, Some(7), holder)
+            .await
+            .expect("Failed to check holdings"));
+        assert_eq!(chain.requests().len(), 3, "Holdings should be cached");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_payout_address_change_requires_owner_signature() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+
+        let owner =
+            Signer::from_hex("0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+                .expect("Failed to load signer");
+        let store_id = sqlx::query_scalar!(
+            "UPDATE stores SET owner_address = $1 FROM products p WHERE p.store_id = stores.id AND p.id = $2 RETURNING stores.id",
+            format!("{:?}", owner.address()),
+            product_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to set store owner");
+        let store = get_store_by_id(&pool, store_id)
+            .await
+            .expect("Failed to fetch store");
+        assert_eq!(store.payout_address, None);
+
+        let payout = "0x00000000000000000000000000000000000000cc";
+        let issued_at = chrono::Utc::now().timestamp();
+        let message = payout_address_message(store_id, Some(payout), issued_at);
+        let signature = owner
+            .sign_message(web3::signing::hash_message(&message).as_bytes())
+            .expect("Failed to sign");
+        let signature = format!(
+            "0x{}{}{:02x}",
+            hex::encode(signature.r),
+            hex::encode(signature.s),
+            signature.v + 27
+        );
+
+        assert_eq!(
+            recover_signer(&message, &signature).expect("Failed to recover signer"),
+            owner.address()
+        );
+        let other_message = payout_address_message(store_id, Some(payout), issued_at + 1);
+        assert_ne!(
+            recover_signer(&other_message, &signature).expect("Failed to recover signer"),
+            owner.address()
+        );
+
+        let store = change_payout_address(&pool, &store, Some(payout), &message, &signature)
+            .await
+            .expect("Failed to change payout address");
+        assert_eq!(store.payout_address.as_deref(), Some(payout));
+        change_payout_address(&pool, &store, Some(payout), &message, &signature)
+            .await
+            .expect_err("Signatures cannot be replayed");
+
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            CreatePaymentIntentRequest {
+                cart_id,
+                buyer_address: buyer_address.clone(),
+                token: "cUSD".to_string(),
+            },
+        )
+        .await
+        .expect("Failed to create payment intent");
+        assert_eq!(intent.recipients[0].recipient_address, payout);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_payment_precheck_reports_shortfalls() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, _product_id) = seed_cart(&pool, 2, 10).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch buyer")
+            .expect("Buyer not found");
+        let wallet = "0x00000000000000000000000000000000000000bb";
+
+        let chain = FakeChain::new();
+        let web3 = web3::Web3::new(chain.clone());
+        let balance = |amount: i64| {
+            ethabi::encode(&[ethabi::Token::Uint(
+                to_base_units(Decimal::new(amount, 2), 18).unwrap(),
+            )])
+        };
+        chain.respond_to_call(CUSD.address, "balanceOf(address)", balance(1000));
+        chain.set_native_balance(wallet, Decimal::new(1, 6));
+        let precheck = |cart_id: Option<Uuid>, payment_intent_id: Option<Uuid>, token: &str| {
+            payment_precheck(
+                &pool,
+                &web3,
+                state.price_source.as_ref(),
+                &state.token_gates,
+                user.id,
+                PaymentPrecheckQuery {
+                    buyer_address: wallet.to_string(),
+                    cart_id,
+                    payment_intent_id,
+                    token: token.to_string(),
+                },
+            )
+        };
+
+        // 2 x 9.99 cUSD against 10 cUSD, and 1 gwei x 65k gas against 0.000001 CELO.
+        let check = precheck(Some(cart_id), None, "cUSD")
+            .await
+            .expect("Failed to precheck cart");
+        assert_eq!(check.amount_due, Decimal::new(1998, 2));
+        assert_eq!(check.token_balance, Decimal::new(10, 0));
+        assert_eq!(check.shortfall, Decimal::new(998, 2));
+        assert_eq!(check.transfers, 1);
+        assert_eq!(check.estimated_network_fee, Decimal::new(65, 6));
+        assert_eq!(check.fee_shortfall, Decimal::new(64, 6));
+        assert_eq!(check.escrow_allowance, None);
+        assert!(!check.can_afford);
+
+        // An intent is checked against what is still unpaid on it.
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            CreatePaymentIntentRequest {
+                cart_id,
+                buyer_address: buyer_address.clone(),
+                token: "cUSD".to_string(),
+            },
+        )
+        .await
+        .expect("Failed to create payment intent");
+        chain.respond_to_call(CUSD.address, "balanceOf(address)", balance(2500));
+        chain.set_native_balance(wallet, Decimal::new(1, 2));
+        let check = precheck(None, Some(intent.intent.id), "CELO")
+            .await
+            .expect("Failed to precheck intent");
+        assert_eq!(check.token_symbol, "cUSD");
+        assert_eq!(check.amount_due, intent.intent.total_amount);
+        assert!(check.shortfall.is_zero());
+        assert!(check.can_afford);
+
+        // CELO pays the fee out of the same balance. 2 x 9.99 USD at 2 CELO each.
+        chain.set_native_balance(wallet, Decimal::new(3996, 2));
+        let check = precheck(Some(cart_id), None, "CELO")
+            .await
+            .expect("Failed to precheck native payment");
+        assert_eq!(check.amount_due, Decimal::new(3996, 2));
+        assert_eq!(check.estimated_network_fee, Decimal::new(21, 6));
+        assert_eq!(check.shortfall, Decimal::new(21, 6));
+        assert!(check.fee_shortfall.is_zero());
+        assert!(!check.can_afford);
+
+        precheck(None, None, "cUSD")
+            .await
+            .expect_err("A cart or intent is required");
+        chain.fail_next("eth_getBalance", "connection reset");
+        let err = precheck(Some(cart_id), None, "cUSD")
+            .await
+            .expect_err("RPC failures are reported");
+        assert_eq!(payment_error(err).0, 502);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_paid_intent_creates_one_order_per_store() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, cart_id, product_id) = seed_cart(&pool, 2, 10).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let store_id =
+            sqlx::query_scalar!("SELECT store_id FROM products WHERE id = $1", product_id)
+                .fetch_one(&pool)
+                .await
+                .expect("Failed to fetch store");
+        let other_store_id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO stores (id, store_name, owner_address) VALUES ($1, 'Other Store', $2)",
+            other_store_id,
+            format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()))
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to insert second store");
+        // A second product from the same store and one from another store.
+        for (store_id, price, quantity) in [
+            (store_id, Decimal::new(500, 2), 1),
+            (other_store_id, Decimal::new(1250, 2), 3),
+        ] {
+            let product_id = Uuid::new_v4();
+            sqlx::query!(
+                "INSERT INTO products (id, store_id, product_name, price, quantity) VALUES ($1, $2, 'Extra', $3, 10)",
+                product_id,
+                store_id,
+                price
+            )
+            .execute(&pool)
+            .await
+            .expect("Failed to insert product");
+            sqlx::query!(
+                "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, $4)",
+                Uuid::new_v4(),
+                cart_id,
+                product_id,
+                quantity
+            )
+            .execute(&pool)
+            .await
+            .expect("Failed to fill cart");
+        }
+
+        let intent = create_payment_intent(
+            &pool,
+            &state.web3,
+            state.price_source.as_ref(),
+            &state.token_gates,
+            user.id,
+            CreatePaymentIntentRequest {
+                cart_id,
+                buyer_address: buyer_address.clone(),
+                token: "cUSD".to_string(),
+            },
+        )
+        .await
+        .expect("Failed to create payment intent");
+        // One batched transaction pays both stores. The indexer records every
+        // log of a block before matching any, since matching claims the hash.
+        let mut transfers = Vec::new();
+        for (log_index, recipient) in intent.recipients.iter().enumerate() {
+            let transfer = record_chain_transfer(
+                &pool,
+                NewChainTransfer {
+                    transaction_hash: format!("0x{}", "33".repeat(32)),
+                    log_index: log_index as i64,
+                    block_number: 1,
+                    token_address: CUSD.address.to_string(),
+                    from_address: buyer_address.clone(),
+                    to_address: recipient.recipient_address.clone(),
+                    amount: recipient.amount,
+                },
+            )
+            .await
+            .expect("Failed to record transfer")
+            .expect("Transfer should be new");
+            transfers.push(transfer);
+        }
+        for transfer in &transfers {
+            let status = match_chain_transfer(&pool, transfer)
+                .await
+                .expect("Failed to match transfer");
+            assert_eq!(status, "matched");
+        }
+        let page = list_orders(&pool, None, Some(user.id), &OrderListQuery::default())
+            .await
+            .expect("Failed to list orders");
+        let mut orders = Vec::new();
+        for order in page.orders {
+            orders.push(
+                get_order_details(&pool, order)
+                    .await
+                    .expect("Failed to fetch order details"),
+            );
+        }
+
+        assert_eq!(orders.len(), 2);
+        let first = orders
+            .iter()
+            .find(|order| order.order.store_id == store_id)
+            .expect("First store should have an order");
+        assert_eq!(first.items.len(), 2);
+        // 2 x 9.99 + 1 x 5.00
+        assert_eq!(first.order.amount, Decimal::new(2498, 2));
+        let second = orders
+            .iter()
+            .find(|order| order.order.store_id == other_store_id)
+            .expect("Second store should have an order");
+        assert_eq!(second.items.len(), 1);
+        assert_eq!(second.items[0].quantity, 3);
+        assert_eq!(second.items[0].unit_price, Decimal::new(1250, 2));
+        assert_eq!(second.order.amount, Decimal::new(3750, 2));
+        assert!(orders.iter().all(
+            |order| order.order.payment_status == PaymentStatus::Confirmed
+                && order.order.order_id.starts_with("ORD-")
+        ));
+
+        let stock = sqlx::query_scalar!("SELECT quantity FROM products WHERE id = $1", product_id)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch stock");
+        assert_eq!(stock, 8);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_partial_shipments_deliver_the_order_when_all_arrive() {
+        let (_app, pool, state) = setup_test_app().await;
+        let (buyer_address, _cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let store = sqlx::query!(
+            "SELECT s.id, s.owner_address FROM stores s JOIN products p ON p.store_id = s.id WHERE p.id = $1",
+            product_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch store");
+        let placed = create_order(
+            &pool,
+            state.price_source.as_ref(),
+            CreateOrderRequest {
+                store_id: store.id,
+                product_id,
+                user_id: user.id,
+                buyer_address: buyer_address.clone(),
+                quantity: 3,
+            },
+        )
+        .await
+        .expect("Failed to create order");
+        let order = placed.order;
+        let item_id = placed.items[0].id;
+        let shipment = |carrier: &str, code: &str, template: Option<&str>, quantity: i32| {
+            CreateShipmentRequest {
+                carrier: carrier.to_string(),
+                tracking_code: code.to_string(),
+                tracking_url_template: template.map(str::to_string),
+                estimated_delivery: Some(chrono::Utc::now() + chrono::Duration::days(3)),
+                items: if quantity > 0 {
+                    vec![ShipmentItemRequest {
+                        order_item_id: item_id,
+                        quantity,
+                    }]
+                } else {
+                    Vec::new()
+                },
+            }
+        };
+        let seller = store.owner_address.as_str();
+
+        create_shipment(&pool, order.id, &shipment("dhl", "JD01", None, 4), seller)
+            .await
+            .expect_err("Cannot ship more than was ordered");
+        create_shipment(
+            &pool,
+            order.id,
+            &shipment("acme", "A1", Some("ftp://acme/{tracking_code}"), 1),
+            seller,
+        )
+        .await
+        .expect_err("Tracking templates must be web pages");
+
+        let first = create_shipment(&pool, order.id, &shipment("DHL", "JD01", None, 1), seller)
+            .await
+            .expect("Failed to ship part of the order");
+        assert_eq!(first.shipment.carrier, "dhl");
+        assert_eq!(
+            first.shipment.tracking_url.as_deref(),
+            Some("https://www.dhl.com/global-en/home/tracking.html?tracking-id=JD01")
+        );
+        assert_eq!(first.items[0].quantity, 1);
+        let shipped = get_order_by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(shipped.status, OrderStatus::Shipped);
+
+        let rest = create_shipment(
+            &pool,
+            order.id,
+            &shipment(
+                "acme",
+                "A-2",
+                Some("https://acme.test/t/{tracking_code}"),
+                0,
+            ),
+            seller,
+        )
+        .await
+        .expect("Failed to ship the rest");
+        assert_eq!(rest.items[0].quantity, 2, "Empty items ship what is left");
+        assert_eq!(
+            rest.shipment.tracking_url.as_deref(),
+            Some("https://acme.test/t/A-2")
+        );
+        create_shipment(&pool, order.id, &shipment("ups", "1Z", None, 0), seller)
+            .await
+            .expect_err("Nothing is left to ship");
+
+        let carrier = MockCarrier::new();
+        assert_eq!(track_shipments(&state, &carrier).await.unwrap(), 0);
+        carrier.deliver("dhl", "JD01");
+        assert_eq!(track_shipments(&state, &carrier).await.unwrap(), 1);
+        let waiting = get_order_by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(
+            waiting.status,
+            OrderStatus::Shipped,
+            "One parcel is still on its way"
+        );
+
+        carrier.deliver("acme", "A-2");
+        assert_eq!(track_shipments(&state, &carrier).await.unwrap(), 1);
+        let details = get_order_details(
+            &pool,
+            get_order_by_id(&pool, order.id).await.unwrap().unwrap(),
+        )
+        .await
+        .expect("Failed to fetch order details");
+        assert_eq!(details.order.status, OrderStatus::Delivered);
+        assert_eq!(details.shipments.len(), 2);
+        assert!(details
+            .shipments
+            .iter()
+            .all(|shipment| shipment.shipment.status == "delivered"
+                && shipment.shipment.delivered_at.is_some()));
+        let last = details.events.last().unwrap();
+        assert_eq!(
+            (last.to_status.as_str(), last.actor.as_str()),
+            ("delivered", "system")
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_returns_are_accepted_or_disputed_and_settled() {
+        let (app, pool, state) = setup_test_app().await;
+        let client = Client::new();
+        let (buyer_address, _cart_id, product_id) = seed_cart(&pool, 1, 5).await;
+        let user = get_user_by_wallet(&pool, &buyer_address)
+            .await
+            .expect("Failed to fetch user")
+            .expect("User should exist");
+        let store = sqlx::query!(
+            "SELECT s.id, s.owner_address FROM stores s JOIN products p ON p.store_id = s.id WHERE p.id = $1",
+            product_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch store");
+        let placed = create_order(
+            &pool,
+            state.price_source.as_ref(),
+            CreateOrderRequest {
+                store_id: store.id,
+                product_id,
+                user_id: user.id,
+                buyer_address: buyer_address.clone(),
+                quantity: 3,
+            },
+        )
+        .await
+        .expect("Failed to create order");
+        let order_id = placed.order.id;
+        let item_id = placed.items[0].id;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_task = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        let buyer = generate_jwt(&buyer_address, "user");
+        let seller = generate_jwt(&store.owner_address, "store");
+        let admin = generate_jwt("0x00000000000000000000000000000000000000ad", "admin");
+        let post_as = |token: &str, path: String, body: Value| {
+            client
+                .post(format!("{}{}", server_addr, path))
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&body)
+                .send()
+        };
+        let returns_path = format!("/orders/{}/returns", order_id);
+        let damaged = json!({
+            "order_item_id": item_id,
+            "quantity": 1,
+            "reason_code": "damaged",
+            "details": "Box arrived crushed",
+            "photos": ["data:image/jpeg;base64,/9j/4AAQ"]
+        });
+
+        let response = post_as(&buyer, returns_path.clone(), damaged.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            400,
+            "Only delivered orders can be returned"
+        );
+        sqlx::query!(
+            "UPDATE orders SET status = 'delivered', payment_status = 'confirmed' WHERE id = $1",
+            order_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to mark order delivered");
+        let mut bogus = damaged.clone();
+        bogus["reason_code"] = json!("changed_my_mind");
+        let response = post_as(&buyer, returns_path.clone(), bogus).await.unwrap();
+        assert_eq!(response.status(), 400);
+
+        let response = post_as(&buyer, returns_path.clone(), damaged)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let first: Value = response.json().await.unwrap();
+        assert_eq!(first["status"], "requested");
+        assert_eq!(first["evidence_cids"], json!(["mock_cid"]));
+        let first_id = first["id"].as_str().unwrap().to_string();
+
+        let response = post_as(
+            &buyer,
+            format!("/returns/{}/accept", first_id),
+            json!({ "restock": true }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 403, "Only the seller accepts returns");
+        let response = post_as(
+            &seller,
+            format!("/returns/{}/accept", first_id),
+            json!({ "restock": true, "note": "Sorry about that" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let accepted: Value = response.json().await.unwrap();
+        assert_eq!(accepted["status"], "accepted");
+        assert!(accepted["inventory_movement_id"].is_string());
+        let stock = sqlx::query_scalar!("SELECT quantity FROM products WHERE id = $1", product_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stock, 3, "The returned item is back in stock");
+
+        let wrong_item = json!({
+            "order_item_id": item_id,
+            "quantity": 3,
+            "reason_code": "wrong_item"
+        });
+        let response = post_as(&buyer, returns_path.clone(), wrong_item)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            400,
+            "One of the three is already returned"
+        );
+        let response = post_as(
+            &buyer,
+            returns_path.clone(),
+            json!({ "order_item_id": item_id, "quantity": 2, "reason_code": "wrong_item" }),
+        )
+        .await
+        .unwrap();
+        let second: Value = response.json().await.unwrap();
+        let second_id = second["id"].as_str().unwrap().to_string();
+        let response = post_as(
+            &seller,
+            format!("/returns/{}/reject", second_id),
+            json!({ "note": "Matches the listing" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = post_as(
+            &seller,
+            format!("/returns/{}/dispute", second_id),
+            json!({ "reason": "No" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 403, "Sellers cannot open disputes");
+        let response = post_as(
+            &buyer,
+            format!("/returns/{}/dispute", second_id),
+            json!({ "reason": "The photos show a different model" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let disputes = |token: &str| {
+            client
+                .get(format!("{}/disputes", server_addr))
+                .header("Authorization", format!("Bearer {}", token))
+                .send()
+        };
+        assert_eq!(disputes(&buyer).await.unwrap().status(), 403);
+        let open: Value = disputes(&admin).await.unwrap().json().await.unwrap();
+        assert_eq!(open.as_array().unwrap().len(), 1);
+        assert_eq!(open[0]["id"], second_id);
+
+        let response = post_as(
+            &admin,
+            format!("/disputes/{}/resolve", second_id),
+            json!({ "refund": true, "note": "Wrong model shipped" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let resolved: Value = response.json().await.unwrap();
+        assert_eq!(resolved["status"], "resolved");
+        assert!(resolved["inventory_movement_id"].is_null());
+
+        let refunds = list_order_refunds(&pool, order_id)
+            .await
+            .expect("Failed to list refunds");
+        let amounts: Vec<(String, Decimal)> = refunds
+            .iter()
+            .map(|refund| (refund.id.to_string(), refund.amount))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (
+                    accepted["refund_id"].as_str().unwrap().to_string(),
+                    Decimal::new(999, 2)
+                ),
+                (
+                    resolved["refund_id"].as_str().unwrap().to_string(),
+                    Decimal::new(1998, 2)
+                ),
+            ]
+        );
+        assert!(refunds.iter().all(|refund| refund.due_at.is_some()));
+
+        server_task.abort();
+    }
+}
@@ -0,0 +1,154 @@
+use crate::authentication::authentication::Claims;
+use crate::db::models::IdempotencyRecord;
+use crate::db::operations::{
+    claim_idempotency_key, get_idempotency_record, release_idempotency_key,
+    save_idempotent_response,
+};
+use crate::state::AppState;
+use axum::{
+    body::{to_bytes, Body},
+    extract::State,
+    http::{header, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::error;
+use web3::signing::keccak256;
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on responses replayed from an earlier request with the same key.
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Largest request body that is fingerprinted, matching axum's `Json` limit.
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Makes a route safe to retry. A request carrying an `Idempotency-Key` runs
+/// once per caller and key; retries within the retention window get the
+/// first response back, and reusing the key for a different request is
+/// refused. Must run after `auth_middleware`, as keys belong to the caller.
+pub async fn idempotency_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(next.run(req).await);
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|key| !key.is_empty() && key.len() <= 255)
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Idempotency-Key must be 1 to 255 visible characters".to_string(),
+            )
+        })?
+        .to_string();
+    let owner = req
+        .extensions()
+        .get::<Claims>()
+        .map(|claims| claims.sub.to_ascii_lowercase())
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Not signed in".to_string()))?;
+
+    let (parts, body) = req.into_parts();
+    let body = to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let path = parts.uri.path().to_string();
+    let fingerprint = hex::encode(keccak256(
+        &[
+            parts.method.as_str().as_bytes(),
+            b" ",
+            path.as_bytes(),
+            b"\n",
+            &body,
+        ]
+        .concat(),
+    ));
+
+    let pool = &state.db.pool;
+    let claimed = claim_idempotency_key(pool, &owner, &key, &path, &fingerprint)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !claimed {
+        let record = get_idempotency_record(pool, &owner, &key)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return replay(record, &fingerprint);
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            let _ = release_idempotency_key(pool, &owner, &key).await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    };
+
+    // Server errors may be transient, so the request is left free to retry.
+    let saved = if parts.status.is_server_error() {
+        release_idempotency_key(pool, &owner, &key).await
+    } else {
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        save_idempotent_response(
+            pool,
+            &owner,
+            &key,
+            i32::from(parts.status.as_u16()),
+            content_type,
+            &body,
+        )
+        .await
+    };
+    if let Err(e) = saved {
+        error!("Failed to save response for idempotency key {}: {}", key, e);
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// The answer to a retry: the saved response, or why there is none to give.
+fn replay(
+    record: Option<IdempotencyRecord>,
+    fingerprint: &str,
+) -> Result<Response, (StatusCode, String)> {
+    let in_progress = || {
+        (
+            StatusCode::CONFLICT,
+            "A request with this Idempotency-Key is still being processed".to_string(),
+        )
+    };
+    // A record that vanished was released by a failed first attempt.
+    let record = record.ok_or_else(in_progress)?;
+    if record.fingerprint != fingerprint {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency-Key was already used for a different request".to_string(),
+        ));
+    }
+    let status = record
+        .status_code
+        .and_then(|status| u16::try_from(status).ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(in_progress)?;
+
+    let mut response = (status, record.response_body.unwrap_or_default()).into_response();
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_TYPE);
+    if let Some(content_type) = record
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    Ok(response)
+}
@@ -1,2 +1,5 @@
+pub mod idempotency;
+pub mod payment_handler;
+pub mod return_handler;
 pub mod store_handler;
 pub mod user_handler;
@@ -0,0 +1,530 @@
+use crate::authentication::authentication::Claims;
+use crate::db::models::{
+    ChainTransfer, CompleteRefundRequest, CreatePaymentIntentRequest, CreateRefundRequest, Escrow,
+    EscrowDepositRequest, Order, OrderEvent, OrderPayment, PaymentIntentResponse, PaymentPrecheck,
+    PaymentPrecheckQuery, PaymentQrQuery, Refund, ResolveTransferRequest,
+};
+use crate::db::operations::{
+    complete_refund, create_payment_intent, create_refund, get_cart, get_chain_transfer,
+    get_escrow_for_order, get_order_by_id, get_order_payment, get_payment_intent, get_refund,
+    get_store_by_id, get_user_by_wallet, is_store_wallet, list_order_events, list_order_refunds,
+    list_unmatched_transfers, open_escrow, payment_precheck, reject_refund, release_escrow,
+    resolve_chain_transfer,
+};
+use crate::state::AppState;
+use crate::utils::failover::ProviderHealth;
+use crate::utils::qr::{qr_png, qr_svg};
+use axum::{
+    extract::{Extension, Json, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_macros::debug_handler;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[debug_handler]
+pub async fn create_payment_intent_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreatePaymentIntentRequest>,
+) -> Result<Json<PaymentIntentResponse>, (StatusCode, String)> {
+    if claims.role != "user" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only users can create payment intents".to_string(),
+        ));
+    }
+    if claims.sub != payload.buyer_address {
+        return Err((StatusCode::FORBIDDEN, "Invalid buyer address".to_string()));
+    }
+
+    let user = get_user_by_wallet(&state.db.pool, &claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+
+    let cart = get_cart(&state.db.pool, user.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Cart not found".to_string()))?;
+
+    if cart.id != payload.cart_id {
+        return Err((StatusCode::BAD_REQUEST, "Invalid cart ID".to_string()));
+    }
+
+    let intent = create_payment_intent(
+        &state.db.pool,
+        &state.web3,
+        state.price_source.as_ref(),
+        &state.token_gates,
+        user.id,
+        payload,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(intent))
+}
+
+#[debug_handler]
+pub async fn get_payment_intent_handler(
+    State(state): State<Arc<AppState>>,
+    Path(intent_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<PaymentIntentResponse>, (StatusCode, String)> {
+    let user = get_user_by_wallet(&state.db.pool, &claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+
+    let intent = get_payment_intent(&state.db.pool, intent_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                "Payment intent not found".to_string(),
+            )
+        })?;
+
+    if intent.intent.user_id != user.id {
+        return Err((StatusCode::FORBIDDEN, "Not your payment intent".to_string()));
+    }
+
+    Ok(Json(intent))
+}
+
+/// Tells a buyer whether their wallet can cover a cart or payment intent
+/// before they send a payment.
+#[debug_handler]
+pub async fn payment_precheck_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<PaymentPrecheckQuery>,
+) -> Result<Json<PaymentPrecheck>, (StatusCode, String)> {
+    if claims.sub != query.buyer_address {
+        return Err((StatusCode::FORBIDDEN, "Invalid buyer address".to_string()));
+    }
+
+    let user = get_user_by_wallet(&state.db.pool, &claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+
+    if let Some(cart_id) = query.cart_id.filter(|_| query.payment_intent_id.is_none()) {
+        let cart = get_cart(&state.db.pool, user.id)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if cart.is_none_or(|cart| cart.id != cart_id) {
+            return Err((StatusCode::BAD_REQUEST, "Invalid cart ID".to_string()));
+        }
+    }
+
+    let precheck = payment_precheck(
+        &state.db.pool,
+        &state.web3,
+        state.price_source.as_ref(),
+        &state.token_gates,
+        user.id,
+        query,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(precheck))
+}
+
+/// Renders the EIP-681 request for one recipient of an intent as a QR code.
+#[debug_handler]
+pub async fn payment_intent_qr_handler(
+    State(state): State<Arc<AppState>>,
+    Path(intent_id): Path<Uuid>,
+    Query(query): Query<PaymentQrQuery>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, (StatusCode, String)> {
+    let Json(intent) =
+        get_payment_intent_handler(State(state), Path(intent_id), Extension(claims)).await?;
+
+    let request = match query.recipient_id {
+        Some(recipient_id) => intent
+            .payment_requests
+            .iter()
+            .find(|request| request.recipient_id == recipient_id),
+        None if intent.payment_requests.len() == 1 => intent.payment_requests.first(),
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "recipient_id is required when an intent has several recipients".to_string(),
+            ));
+        }
+    }
+    .ok_or_else(|| (StatusCode::NOT_FOUND, "Recipient not found".to_string()))?;
+
+    let response = match query.format.as_deref().unwrap_or("svg") {
+        "svg" => {
+            let svg = qr_svg(&request.uri)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response()
+        }
+        "png" => {
+            let png = qr_png(&request.uri)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            ([(header::CONTENT_TYPE, "image/png")], png).into_response()
+        }
+        format => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported QR format: {}", format),
+            ));
+        }
+    };
+    Ok(response)
+}
+
+#[debug_handler]
+pub async fn list_unmatched_transfers_handler(
+    State(state): State<Arc<AppState>>,
+    Path(store_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<ChainTransfer>>, (StatusCode, String)> {
+    if claims.role != "store" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only store owners can reconcile transfers".to_string(),
+        ));
+    }
+
+    let store = get_store_by_id(&state.db.pool, store_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "Store not found".to_string()))?;
+
+    if store.owner_address != claims.sub {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+
+    let payout_address = store
+        .payout_address
+        .as_deref()
+        .unwrap_or(&store.owner_address);
+    let transfers = list_unmatched_transfers(&state.db.pool, payout_address)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(transfers))
+}
+
+#[debug_handler]
+pub async fn resolve_transfer_handler(
+    State(state): State<Arc<AppState>>,
+    Path(transfer_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<ResolveTransferRequest>,
+) -> Result<Json<ChainTransfer>, (StatusCode, String)> {
+    if claims.role != "store" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only store owners can reconcile transfers".to_string(),
+        ));
+    }
+
+    let transfer = get_chain_transfer(&state.db.pool, transfer_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Transfer not found".to_string()))?;
+
+    let owned = is_store_wallet(&state.db.pool, &claims.sub, &transfer.to_address)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !owned {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+    if transfer.status != "unmatched" {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Transfer is already {}", transfer.status),
+        ));
+    }
+
+    let transfer = resolve_chain_transfer(&state.db.pool, &transfer, payload)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(transfer))
+}
+
+/// Database failures are server errors and RPC failures are upstream ones;
+/// anything else coming out of the payment operations is a problem with the
+/// buyer's request.
+pub(crate) fn payment_error(e: anyhow::Error) -> (StatusCode, String) {
+    if e.downcast_ref::<sqlx::Error>().is_some() {
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    } else if e.downcast_ref::<web3::Error>().is_some() {
+        (StatusCode::BAD_GATEWAY, e.to_string())
+    } else {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+}
+
+#[debug_handler]
+pub async fn deposit_escrow_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<EscrowDepositRequest>,
+) -> Result<Json<Escrow>, (StatusCode, String)> {
+    let order = get_buyer_order(&state, order_id, &claims).await?;
+
+    let escrow = open_escrow(
+        &state.db.pool,
+        &state.web3,
+        &order,
+        &payload.transaction_hash,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(escrow))
+}
+
+#[debug_handler]
+pub async fn get_escrow_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Escrow>, (StatusCode, String)> {
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+
+    if !order.buyer_address.eq_ignore_ascii_case(&claims.sub)
+        && !order.seller_address.eq_ignore_ascii_case(&claims.sub)
+    {
+        return Err((StatusCode::FORBIDDEN, "Not your order".to_string()));
+    }
+
+    let escrow = get_escrow_for_order(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order has no escrow".to_string()))?;
+    Ok(Json(escrow))
+}
+
+/// The buyer confirms the goods arrived, releasing the escrow to the seller.
+#[debug_handler]
+pub async fn confirm_escrow_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Escrow>, (StatusCode, String)> {
+    get_buyer_order(&state, order_id, &claims).await?;
+
+    let escrow = get_escrow_for_order(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order has no escrow".to_string()))?;
+
+    let escrow = release_escrow(&state.db.pool, &state.web3, &escrow, "release")
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(escrow))
+}
+
+async fn get_buyer_order(
+    state: &AppState,
+    order_id: Uuid,
+    claims: &Claims,
+) -> Result<Order, (StatusCode, String)> {
+    if claims.role != "user" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only buyers can manage escrow".to_string(),
+        ));
+    }
+
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+
+    if !order.buyer_address.eq_ignore_ascii_case(&claims.sub) {
+        return Err((StatusCode::FORBIDDEN, "Not your order".to_string()));
+    }
+
+    Ok(order)
+}
+
+/// Buyers request a refund; sellers may also refund straight away by
+/// including the transaction that sent the money back.
+#[debug_handler]
+pub async fn create_refund_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateRefundRequest>,
+) -> Result<Json<Refund>, (StatusCode, String)> {
+    let order = get_party_order(&state, order_id, &claims).await?;
+    let is_seller = order.seller_address.eq_ignore_ascii_case(&claims.sub);
+    if payload.transaction_hash.is_some() && !is_seller {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only the seller can submit a refund transaction".to_string(),
+        ));
+    }
+
+    let refund = create_refund(&state.db.pool, &order, &claims.sub, &payload, None)
+        .await
+        .map_err(payment_error)?;
+
+    let Some(transaction_hash) = &payload.transaction_hash else {
+        return Ok(Json(refund));
+    };
+    let refund = complete_refund(
+        &state.db.pool,
+        &state.web3,
+        &order,
+        &refund,
+        transaction_hash,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(refund))
+}
+
+/// Live on-chain status of an order's payment, for its buyer or seller.
+#[debug_handler]
+pub async fn get_order_payment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<OrderPayment>, (StatusCode, String)> {
+    let order = get_party_order(&state, order_id, &claims).await?;
+
+    let payment = get_order_payment(&state.db.pool, &state.web3, &order)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(payment))
+}
+
+/// The order's status history, oldest first, for its buyer or seller.
+#[debug_handler]
+pub async fn list_order_events_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<OrderEvent>>, (StatusCode, String)> {
+    get_party_order(&state, order_id, &claims).await?;
+
+    let events = list_order_events(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(events))
+}
+
+#[debug_handler]
+pub async fn list_refunds_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<Refund>>, (StatusCode, String)> {
+    get_party_order(&state, order_id, &claims).await?;
+
+    let refunds = list_order_refunds(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(refunds))
+}
+
+#[debug_handler]
+pub async fn complete_refund_handler(
+    State(state): State<Arc<AppState>>,
+    Path(refund_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CompleteRefundRequest>,
+) -> Result<Json<Refund>, (StatusCode, String)> {
+    let (order, refund) = get_seller_refund(&state, refund_id, &claims).await?;
+
+    let refund = complete_refund(
+        &state.db.pool,
+        &state.web3,
+        &order,
+        &refund,
+        &payload.transaction_hash,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(refund))
+}
+
+#[debug_handler]
+pub async fn reject_refund_handler(
+    State(state): State<Arc<AppState>>,
+    Path(refund_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Refund>, (StatusCode, String)> {
+    get_seller_refund(&state, refund_id, &claims).await?;
+
+    // Refunds owed on a cancelled order have a deadline and cannot be turned down.
+    let refund = reject_refund(&state.db.pool, refund_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Refund cannot be rejected".to_string(),
+            )
+        })?;
+    Ok(Json(refund))
+}
+
+/// An order the caller bought or sold.
+pub(crate) async fn get_party_order(
+    state: &AppState,
+    order_id: Uuid,
+    claims: &Claims,
+) -> Result<Order, (StatusCode, String)> {
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+
+    if !order.buyer_address.eq_ignore_ascii_case(&claims.sub)
+        && !order.seller_address.eq_ignore_ascii_case(&claims.sub)
+    {
+        return Err((StatusCode::FORBIDDEN, "Not your order".to_string()));
+    }
+
+    Ok(order)
+}
+
+async fn get_seller_refund(
+    state: &AppState,
+    refund_id: Uuid,
+    claims: &Claims,
+) -> Result<(Order, Refund), (StatusCode, String)> {
+    let refund = get_refund(&state.db.pool, refund_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Refund not found".to_string()))?;
+    let order = get_order_by_id(&state.db.pool, refund.order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+
+    if claims.role != "store" || !order.seller_address.eq_ignore_ascii_case(&claims.sub) {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+
+    Ok((order, refund))
+}
+
+/// Reports each RPC provider's health. Responds 503 when none is usable, so it
+/// can double as a readiness probe.
+#[debug_handler]
+pub async fn rpc_health_handler(
+    State(state): State<Arc<AppState>>,
+) -> (StatusCode, Json<Vec<ProviderHealth>>) {
+    let providers = state.web3.transport().provider_health();
+    let status = if providers.is_empty() || providers.iter().any(|provider| provider.healthy) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(providers))
+}
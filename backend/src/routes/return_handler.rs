@@ -0,0 +1,201 @@
+use crate::authentication::authentication::Claims;
+use crate::db::models::{
+    AcceptReturnRequest, CreateReturnRequest, EscalateReturnRequest, Order, RejectReturnRequest,
+    ResolveReturnRequest, ReturnRequest,
+};
+use crate::db::operations::{
+    accept_return, create_return_request, escalate_return, get_order_by_id, get_return_request,
+    list_disputed_returns, list_order_returns, reject_return, resolve_return, MAX_RETURN_PHOTOS,
+};
+use crate::routes::payment_handler::{get_party_order, payment_error};
+use crate::state::AppState;
+use crate::utils::ipfs::upload_to_ipfs;
+use axum::{
+    extract::{Extension, Json, Path, State},
+    http::StatusCode,
+};
+use axum_macros::debug_handler;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// The buyer reporting a wrong or damaged item on a delivered order, with
+/// photos uploaded to IPFS as evidence.
+#[debug_handler]
+pub async fn create_return_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateReturnRequest>,
+) -> Result<Json<ReturnRequest>, (StatusCode, String)> {
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .filter(|order| order.buyer_address.eq_ignore_ascii_case(&claims.sub))
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+    if payload.photos.len() > MAX_RETURN_PHOTOS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("At most {} photos can be attached", MAX_RETURN_PHOTOS),
+        ));
+    }
+
+    let mut evidence_cids = Vec::with_capacity(payload.photos.len());
+    for photo in &payload.photos {
+        let cid = upload_to_ipfs(&state, photo)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        evidence_cids.push(cid);
+    }
+
+    let request = create_return_request(
+        &state.db.pool,
+        &order,
+        &payload,
+        &evidence_cids,
+        &claims.sub,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(request))
+}
+
+/// An order's returns, oldest first, for its buyer or seller.
+#[debug_handler]
+pub async fn list_returns_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<ReturnRequest>>, (StatusCode, String)> {
+    get_party_order(&state, order_id, &claims).await?;
+
+    let returns = list_order_returns(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(returns))
+}
+
+/// The seller taking a return back. The buyer is owed a refund, due within
+/// the refund window, and the items can go back in stock.
+#[debug_handler]
+pub async fn accept_return_handler(
+    State(state): State<Arc<AppState>>,
+    Path(return_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<AcceptReturnRequest>,
+) -> Result<Json<ReturnRequest>, (StatusCode, String)> {
+    let order = get_return_order(&state, return_id).await?;
+    if claims.role != "store" || !order.seller_address.eq_ignore_ascii_case(&claims.sub) {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+
+    let request = accept_return(&state.db.pool, &order, return_id, &payload, &claims.sub)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(request))
+}
+
+#[debug_handler]
+pub async fn reject_return_handler(
+    State(state): State<Arc<AppState>>,
+    Path(return_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<RejectReturnRequest>,
+) -> Result<Json<ReturnRequest>, (StatusCode, String)> {
+    let order = get_return_order(&state, return_id).await?;
+    if claims.role != "store" || !order.seller_address.eq_ignore_ascii_case(&claims.sub) {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+
+    let request = reject_return(
+        &state.db.pool,
+        return_id,
+        payload.note.as_deref(),
+        &claims.sub,
+    )
+    .await
+    .map_err(payment_error)?;
+    Ok(Json(request))
+}
+
+/// Takes a return the seller rejected or has not answered to an admin. The
+/// buyer or an admin can escalate.
+#[debug_handler]
+pub async fn escalate_return_handler(
+    State(state): State<Arc<AppState>>,
+    Path(return_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<EscalateReturnRequest>,
+) -> Result<Json<ReturnRequest>, (StatusCode, String)> {
+    let order = get_return_order(&state, return_id).await?;
+    if claims.role != "admin" && !order.buyer_address.eq_ignore_ascii_case(&claims.sub) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only the buyer or an admin can open a dispute".to_string(),
+        ));
+    }
+    if payload.reason.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "A dispute needs a reason".to_string(),
+        ));
+    }
+
+    let request = escalate_return(&state.db.pool, return_id, payload.reason.trim())
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(request))
+}
+
+/// Open disputes, longest waiting first.
+#[debug_handler]
+pub async fn list_disputes_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<ReturnRequest>>, (StatusCode, String)> {
+    require_admin(&claims)?;
+
+    let disputes = list_disputed_returns(&state.db.pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(disputes))
+}
+
+#[debug_handler]
+pub async fn resolve_dispute_handler(
+    State(state): State<Arc<AppState>>,
+    Path(return_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<ResolveReturnRequest>,
+) -> Result<Json<ReturnRequest>, (StatusCode, String)> {
+    require_admin(&claims)?;
+    let order = get_return_order(&state, return_id).await?;
+
+    let request = resolve_return(&state.db.pool, &order, return_id, &payload, &claims.sub)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(request))
+}
+
+fn require_admin(claims: &Claims) -> Result<(), (StatusCode, String)> {
+    if claims.role != "admin" {
+        return Err((StatusCode::FORBIDDEN, "Admins only".to_string()));
+    }
+    Ok(())
+}
+
+/// The order a return belongs to.
+async fn get_return_order(
+    state: &AppState,
+    return_id: Uuid,
+) -> Result<Order, (StatusCode, String)> {
+    let request = get_return_request(&state.db.pool, return_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Return not found".to_string()))?;
+    let order = get_order_by_id(&state.db.pool, request.order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+
+    Ok(order)
+}
@@ -1,16 +1,27 @@
 use crate::authentication::authentication::Claims;
+use crate::db::models::{
+    CreateProductTokenGateRequest, InventoryLedger, InventoryMovement, OrderListQuery, OrderPage,
+    PayoutAddressChange, PayoutAddressMessage, PayoutAddressMessageQuery, ProductTokenGate,
+    RecordInventoryMovementRequest, UpdatePayoutAddressRequest,
+};
 use crate::db::operations::{
-    delete_store, get_all_stores, get_store_by_id, get_store_orders, update_store,
+    add_product_token_gate, adjust_inventory, change_payout_address, delete_product_token_gate,
+    delete_store, get_all_stores, get_inventory_ledger, get_store_by_id, list_orders,
+    list_payout_address_changes, list_product_token_gates, payout_address_message, update_store,
 };
+use crate::routes::payment_handler::payment_error;
 use crate::state::AppState;
+use crate::utils::eip191::recover_signer;
+use crate::utils::pricing::normalize_currency;
 use crate::{add_product, create_store, get_product_quantity, list_products, Product};
-use crate::{AddProductRequest, CreateStoreRequest, Order, Store};
+use crate::{AddProductRequest, CreateStoreRequest, Store};
 use axum::http::StatusCode;
 use axum::{
-    extract::{Extension, Path, State},
+    extract::{Extension, Path, Query, State},
     Json,
 };
 use axum_macros::debug_handler;
+use std::str::FromStr;
 use std::sync::Arc;
 use tracing::{debug, info};
 use uuid::Uuid;
@@ -18,7 +29,7 @@ use uuid::Uuid;
 #[debug_handler]
 pub async fn create_store_handler(
     State(state): State<Arc<AppState>>,
-    Extension(claims): Extension<Claims>,
+    Extension(_claims): Extension<Claims>,
     Json(payload): Json<CreateStoreRequest>,
 ) -> Result<Json<Store>, (StatusCode, String)> {
     // if claims.role != "store" {
@@ -52,7 +63,7 @@ pub async fn add_product_handler(
     State(state): State<Arc<AppState>>,
     Path(store_id): Path<Uuid>,
     Extension(claims): Extension<Claims>,
-    Json(payload): Json<AddProductRequest>,
+    Json(mut payload): Json<AddProductRequest>,
 ) -> Result<Json<Product>, (StatusCode, String)> {
     if claims.role != "store" {
         return Err((
@@ -75,6 +86,12 @@ pub async fn add_product_handler(
         return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
     }
 
+    if let Some(currency) = payload.currency.as_deref() {
+        let currency =
+            normalize_currency(currency).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        payload.currency = Some(currency);
+    }
+
     let image_cid = if let Some(image) = payload.image.as_ref() {
         Some(
             crate::utils::ipfs::upload_to_ipfs(&state, image)
@@ -139,7 +156,8 @@ pub async fn get_store_orders_handler(
     State(state): State<Arc<AppState>>,
     Path(store_id): Path<Uuid>,
     Extension(claims): Extension<Claims>,
-) -> Result<Json<Vec<Order>>, (StatusCode, String)> {
+    Query(query): Query<OrderListQuery>,
+) -> Result<Json<OrderPage>, (StatusCode, String)> {
     if claims.role != "store" {
         return Err((
             StatusCode::FORBIDDEN,
@@ -161,10 +179,10 @@ pub async fn get_store_orders_handler(
         return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
     }
 
-    let orders = get_store_orders(&state.db.pool, store_id)
+    let page = list_orders(&state.db.pool, Some(store_id), None, &query)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(orders))
+        .map_err(payment_error)?;
+    Ok(Json(page))
 }
 
 #[debug_handler]
@@ -264,3 +282,272 @@ pub async fn get_store_by_id_handler(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     Ok(Json(store))
 }
+
+/// Makes sure the caller owns the store selling `product_id`.
+async fn authorize_product_owner(
+    state: &AppState,
+    claims: &Claims,
+    product_id: Uuid,
+) -> Result<(), (StatusCode, String)> {
+    if claims.role != "store" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only store owners can manage products".to_string(),
+        ));
+    }
+
+    let store = sqlx::query!(
+        r#"
+        SELECT s.owner_address FROM products p JOIN stores s ON p.store_id = s.id WHERE p.id = $1
+        "#,
+        product_id
+    )
+    .fetch_one(&state.db.pool)
+    .await
+    .map_err(|_| (StatusCode::NOT_FOUND, "Product not found".to_string()))?;
+
+    if store.owner_address != claims.sub {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+    Ok(())
+}
+
+/// The product's stock ledger, so owners can see where every unit went.
+#[debug_handler]
+pub async fn get_inventory_ledger_handler(
+    State(state): State<Arc<AppState>>,
+    Path(product_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<InventoryLedger>, (StatusCode, String)> {
+    authorize_product_owner(&state, &claims, product_id).await?;
+
+    let ledger = get_inventory_ledger(&state.db.pool, product_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Product not found".to_string()))?;
+    Ok(Json(ledger))
+}
+
+#[debug_handler]
+pub async fn record_inventory_movement_handler(
+    State(state): State<Arc<AppState>>,
+    Path(product_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<RecordInventoryMovementRequest>,
+) -> Result<Json<InventoryMovement>, (StatusCode, String)> {
+    authorize_product_owner(&state, &claims, product_id).await?;
+
+    // Sales, refunds and reservations are only ever written by the system.
+    match payload.reason.as_str() {
+        "restock" if payload.quantity_change > 0 => {}
+        "restock" => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "A restock must add stock".to_string(),
+            ))
+        }
+        "adjustment" if payload.quantity_change != 0 => {}
+        "adjustment" => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "An adjustment must change stock".to_string(),
+            ))
+        }
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Reason must be restock or adjustment".to_string(),
+            ))
+        }
+    }
+
+    let movement = adjust_inventory(&state.db.pool, product_id, &payload, &claims.sub)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(movement))
+}
+
+#[debug_handler]
+pub async fn add_product_token_gate_handler(
+    State(state): State<Arc<AppState>>,
+    Path(product_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateProductTokenGateRequest>,
+) -> Result<Json<ProductTokenGate>, (StatusCode, String)> {
+    authorize_product_owner(&state, &claims, product_id).await?;
+
+    if ethabi::Address::from_str(payload.contract_address.trim_start_matches("0x")).is_err() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Invalid contract address".to_string(),
+        ));
+    }
+    if payload.token_id.is_some_and(|id| id < 0) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid token id".to_string()));
+    }
+    if payload
+        .discount_bps
+        .is_some_and(|bps| !(1..=10_000).contains(&bps))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Discount must be between 1 and 10000 basis points".to_string(),
+        ));
+    }
+
+    let gate = add_product_token_gate(&state.db.pool, product_id, payload)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(gate))
+}
+
+#[debug_handler]
+pub async fn list_product_token_gates_handler(
+    State(state): State<Arc<AppState>>,
+    Path(product_id): Path<Uuid>,
+) -> Result<Json<Vec<ProductTokenGate>>, (StatusCode, String)> {
+    let gates = list_product_token_gates(&state.db.pool, &[product_id])
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(gates))
+}
+
+#[debug_handler]
+pub async fn delete_product_token_gate_handler(
+    State(state): State<Arc<AppState>>,
+    Path((product_id, gate_id)): Path<(Uuid, Uuid)>,
+    Extension(claims): Extension<Claims>,
+) -> Result<(), (StatusCode, String)> {
+    authorize_product_owner(&state, &claims, product_id).await?;
+
+    let deleted = delete_product_token_gate(&state.db.pool, product_id, gate_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !deleted {
+        return Err((StatusCode::NOT_FOUND, "Token gate not found".to_string()));
+    }
+    Ok(())
+}
+
+/// Loads the store, making sure the caller owns it.
+async fn get_owned_store(
+    state: &AppState,
+    claims: &Claims,
+    store_id: Uuid,
+) -> Result<Store, (StatusCode, String)> {
+    if claims.role != "store" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only store owners can manage payouts".to_string(),
+        ));
+    }
+
+    let store = get_store_by_id(&state.db.pool, store_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "Store not found".to_string()))?;
+
+    if store.owner_address != claims.sub {
+        return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
+    }
+    Ok(store)
+}
+
+/// How long a signed payout change request stays valid.
+fn payout_signature_ttl_secs() -> i64 {
+    std::env::var("PAYOUT_SIGNATURE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(600)
+}
+
+fn validate_payout_address(payout_address: Option<&str>) -> Result<(), (StatusCode, String)> {
+    match payout_address {
+        Some(address)
+            if !address.starts_with("0x")
+                || ethabi::Address::from_str(address.trim_start_matches("0x")).is_err() =>
+        {
+            Err((
+                StatusCode::BAD_REQUEST,
+                "Invalid payout address".to_string(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[debug_handler]
+pub async fn payout_address_message_handler(
+    State(state): State<Arc<AppState>>,
+    Path(store_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<PayoutAddressMessageQuery>,
+) -> Result<Json<PayoutAddressMessage>, (StatusCode, String)> {
+    let store = get_owned_store(&state, &claims, store_id).await?;
+    validate_payout_address(query.payout_address.as_deref())?;
+
+    let issued_at = chrono::Utc::now().timestamp();
+    Ok(Json(PayoutAddressMessage {
+        message: payout_address_message(store.id, query.payout_address.as_deref(), issued_at),
+        issued_at,
+    }))
+}
+
+#[debug_handler]
+pub async fn update_payout_address_handler(
+    State(state): State<Arc<AppState>>,
+    Path(store_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<UpdatePayoutAddressRequest>,
+) -> Result<Json<Store>, (StatusCode, String)> {
+    let store = get_owned_store(&state, &claims, store_id).await?;
+    let payout_address = payload.payout_address.as_deref();
+    validate_payout_address(payout_address)?;
+
+    let age = chrono::Utc::now().timestamp() - payload.issued_at;
+    if !(-60..=payout_signature_ttl_secs()).contains(&age) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Signed message has expired".to_string(),
+        ));
+    }
+
+    // The JWT alone is not enough to redirect funds; the owner wallet must sign.
+    let message = payout_address_message(store.id, payout_address, payload.issued_at);
+    let signer = recover_signer(&message, &payload.signature)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if !format!("{:?}", signer).eq_ignore_ascii_case(&store.owner_address) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Message was not signed by the store owner".to_string(),
+        ));
+    }
+
+    let store = change_payout_address(
+        &state.db.pool,
+        &store,
+        payout_address,
+        &message,
+        &payload.signature,
+    )
+    .await
+    .map_err(payment_error)?;
+    info!(
+        "Store {} now pays out to {}",
+        store.id,
+        payout_address.unwrap_or(&store.owner_address)
+    );
+    Ok(Json(store))
+}
+
+#[debug_handler]
+pub async fn list_payout_address_changes_handler(
+    State(state): State<Arc<AppState>>,
+    Path(store_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<PayoutAddressChange>>, (StatusCode, String)> {
+    let store = get_owned_store(&state, &claims, store_id).await?;
+    let changes = list_payout_address_changes(&state.db.pool, store.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(changes))
+}
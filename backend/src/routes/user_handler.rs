@@ -1,17 +1,21 @@
 use crate::authentication::authentication::Claims;
 use crate::db::models::*;
 use crate::db::models::{
-    AddToCartRequest, CartItem, CheckoutRequest, CreateOrderRequest, Order, RegisterUserRequest,
-    User,
+    AddToCartRequest, CartItem, CheckoutRequest, CreateOrderRequest, RegisterUserRequest, User,
 };
 use crate::db::operations::{
-    add_to_cart, calculate_cart_total, checkout, create_order, get_cart, get_user_by_wallet,
-    list_cart_items, register_user, update_order_status,
+    add_to_cart, calculate_cart_total, can_access_product, checkout, create_order, create_shipment,
+    get_cart, get_escrow_for_order, get_order_by_id, get_order_details, get_user_by_wallet,
+    list_cart_items, list_order_shipments, list_orders, queue_receipt_mint, refund_escrow,
+    register_user, release_escrow, require_cancellation_refund, reserve_cart_stock,
+    update_order_status,
 };
+use crate::routes::payment_handler::{get_party_order, payment_error};
 use crate::state::AppState;
+use crate::utils::tokens::{to_base_units, Token};
 use anyhow::{anyhow, Result};
 use axum::{
-    extract::{Extension, Json, Path, State},
+    extract::{Extension, Json, Path, Query, State},
     http::StatusCode,
 };
 use axum_macros::debug_handler;
@@ -19,7 +23,8 @@ use ethabi::ethereum_types::Address;
 use sqlx::types::Decimal;
 use std::str::FromStr;
 use std::sync::Arc;
-use web3::types::{H256, U256};
+use uuid::Uuid;
+use web3::types::{TransactionId, H256, U256};
 
 #[debug_handler]
 pub async fn register_user_handler(
@@ -50,6 +55,27 @@ pub async fn add_to_cart_handler(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
         .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
 
+    let allowed = can_access_product(
+        &state.db.pool,
+        &state.web3,
+        &state.token_gates,
+        payload.product_id,
+        &claims.sub,
+    )
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Could not check token holdings: {}", e),
+        )
+    })?;
+    if !allowed {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "This product is only available to token holders".to_string(),
+        ));
+    }
+
     let cart_item = add_to_cart(&state.db.pool, user.id, payload)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
@@ -100,7 +126,7 @@ pub async fn checkout_handler(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
     Json(payload): Json<CheckoutRequest>,
-) -> Result<Json<Order>, (StatusCode, String)> {
+) -> Result<Json<CheckoutSummary>, (StatusCode, String)> {
     if claims.role != "user" {
         return Err((StatusCode::FORBIDDEN, "Only users can checkout".to_string()));
     }
@@ -122,18 +148,91 @@ pub async fn checkout_handler(
         return Err((StatusCode::BAD_REQUEST, "Invalid cart ID".to_string()));
     }
 
-    let order = checkout(&state.db.pool, &state.web3, payload, user.id)
+    let summary = checkout(
+        &state.db.pool,
+        &state.web3,
+        state.price_source.as_ref(),
+        &state.token_gates,
+        payload,
+        user.id,
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(summary))
+}
+
+/// Holds the buyer's cart stock while they pay, so nobody else can take it.
+#[debug_handler]
+pub async fn reserve_stock_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<ReserveStockRequest>,
+) -> Result<Json<Vec<StockReservation>>, (StatusCode, String)> {
+    if claims.role != "user" {
+        return Err((StatusCode::FORBIDDEN, "Only users can checkout".to_string()));
+    }
+
+    let user = get_user_by_wallet(&state.db.pool, &claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+
+    let cart = get_cart(&state.db.pool, user.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Cart not found".to_string()))?;
+
+    if cart.id != payload.cart_id {
+        return Err((StatusCode::BAD_REQUEST, "Invalid cart ID".to_string()));
+    }
+
+    let reservations = reserve_cart_stock(&state.db.pool, cart.id)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .map_err(payment_error)?;
 
-    Ok(Json(order))
+    Ok(Json(reservations))
 }
 
+/// Checks that the buyer's own transaction paid the seller, so nobody else
+/// can claim a payment they happen to see on chain.
 pub async fn verify_payment<T: web3::Transport>(
     web3: &web3::Web3<T>,
     tx_hash: &str,
+    token: &Token,
+    buyer_address: &str,
     expected_seller_address: &str,
     expected_amount: Decimal,
+) -> anyhow::Result<()> {
+    verify_transfer(
+        web3,
+        tx_hash,
+        token,
+        Some(buyer_address),
+        expected_seller_address,
+        expected_amount,
+    )
+    .await
+}
+
+/// Blocks a payment must be buried under, counting its own, from
+/// `PAYMENT_MIN_CONFIRMATIONS`.
+pub fn min_confirmations() -> u64 {
+    std::env::var("PAYMENT_MIN_CONFIRMATIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Checks that `tx_hash` moved exactly `expected_amount` of `token` to
+/// `expected_recipient`, and from `expected_sender` when one is given.
+pub async fn verify_transfer<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    tx_hash: &str,
+    token: &Token,
+    expected_sender: Option<&str>,
+    expected_recipient: &str,
+    expected_amount: Decimal,
 ) -> anyhow::Result<()> {
     let tx_hash = H256::from_str(tx_hash.trim_start_matches("0x"))?;
     let receipt = web3
@@ -146,31 +245,78 @@ pub async fn verify_payment<T: web3::Transport>(
         return Err(anyhow!("Transaction failed"));
     }
 
-    // Verify cUSD contract
-    let cusd_contract = Address::from_str("0x874069Fa1Eb16D44d622BC6Cf16451f9B2bE0855")?;
-    if receipt.to != Some(cusd_contract) {
-        return Err(anyhow!("Transaction not sent to cUSD contract"));
+    let min_confirmations = min_confirmations();
+    let mined_in = receipt
+        .block_number
+        .ok_or_else(|| anyhow!("Transaction is still pending"))?;
+    let head = web3.eth().block_number().await?;
+    let confirmations = head.saturating_sub(mined_in).as_u64() + 1;
+    if confirmations < min_confirmations {
+        return Err(anyhow!(
+            "Transaction has {} of {} required confirmations",
+            confirmations,
+            min_confirmations
+        ));
     }
 
+    let expected_sender = expected_sender.map(Address::from_str).transpose()?;
+    let expected_recipient = Address::from_str(expected_recipient)?;
+    let expected_amount_wei = to_base_units(expected_amount, token.decimals)?;
+
+    // Native CELO transfers emit no logs, so check the transaction itself.
+    if token.native {
+        let transaction = web3
+            .eth()
+            .transaction(TransactionId::Hash(tx_hash))
+            .await?
+            .ok_or_else(|| anyhow!("Transaction not found"))?;
+        if expected_sender.is_some_and(|sender| transaction.from != Some(sender)) {
+            return Err(anyhow!("Invalid sender address"));
+        }
+        if transaction.to != Some(expected_recipient) {
+            return Err(anyhow!("Invalid recipient address"));
+        }
+        if transaction.value != expected_amount_wei {
+            return Err(anyhow!("Invalid payment amount"));
+        }
+        return Ok(());
+    }
+
+    let token_contract = Address::from_str(token.address)?;
     let transfer_topic =
         H256::from_str("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")?;
-    let log = receipt
+    let transfers: Vec<_> = receipt
         .logs
         .iter()
-        .find(|log| log.topics.get(0) == Some(&transfer_topic))
-        .ok_or_else(|| anyhow!("Transfer event not found"))?;
+        .filter(|log| log.address == token_contract)
+        .filter(|log| log.topics.first() == Some(&transfer_topic) && log.topics.len() == 3)
+        .collect();
+    if transfers.is_empty() {
+        return Err(anyhow!("Transfer event not found"));
+    }
 
-    let seller_address = Address::from_slice(&log.topics[2][12..]);
-    if seller_address != Address::from_str(expected_seller_address)? {
-        return Err(anyhow!("Invalid seller address"));
+    let from_sender: Vec<_> = transfers
+        .into_iter()
+        .filter(|log| {
+            expected_sender.is_none_or(|sender| Address::from_slice(&log.topics[1][12..]) == sender)
+        })
+        .collect();
+    if from_sender.is_empty() {
+        return Err(anyhow!("Invalid sender address"));
     }
 
-    let amount = U256::from_big_endian(&log.data.0);
-    let expected_amount_wei = U256::from_dec_str(&format!(
-        "{}000000000000000000", // Convert Decimal to Wei (18 decimals)
-        expected_amount.to_string().replace(".", "")
-    ))?;
-    if amount != expected_amount_wei {
+    let to_recipient: Vec<_> = from_sender
+        .into_iter()
+        .filter(|log| Address::from_slice(&log.topics[2][12..]) == expected_recipient)
+        .collect();
+    if to_recipient.is_empty() {
+        return Err(anyhow!("Invalid recipient address"));
+    }
+
+    if !to_recipient
+        .iter()
+        .any(|log| U256::from_big_endian(&log.data.0) == expected_amount_wei)
+    {
         return Err(anyhow!("Invalid payment amount"));
     }
 
@@ -182,7 +328,7 @@ pub async fn create_order_handler(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
     Json(mut payload): Json<CreateOrderRequest>,
-) -> Result<Json<Order>, (StatusCode, String)> {
+) -> Result<Json<OrderDetails>, (StatusCode, String)> {
     if claims.role != "user" {
         return Err((
             StatusCode::FORBIDDEN,
@@ -200,19 +346,21 @@ pub async fn create_order_handler(
 
     payload.user_id = user.id;
 
-    let order = create_order(&state.db.pool, payload)
+    let order = create_order(&state.db.pool, state.price_source.as_ref(), payload)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .map_err(payment_error)?;
     Ok(Json(order))
 }
 
-#[debug_handler]
-pub async fn update_order_status_handler(
-    State(state): State<Arc<AppState>>,
-    Path(order_id): Path<String>,
-    Extension(claims): Extension<Claims>,
-    Json(payload): Json<UpdateOrderStatusRequest>,
-) -> Result<(), (StatusCode, String)> {
+/// Most orders one bulk status update may touch.
+const MAX_BULK_ORDERS: usize = 100;
+
+/// Checks that the caller owns the store, returning whether it mints receipts.
+async fn authorize_store_owner(
+    state: &AppState,
+    store_id: Uuid,
+    claims: &Claims,
+) -> Result<bool, (StatusCode, String)> {
     if claims.role != "store" {
         return Err((
             StatusCode::FORBIDDEN,
@@ -220,21 +368,11 @@ pub async fn update_order_status_handler(
         ));
     }
 
-    let order = sqlx::query!(
-        r#"
-        SELECT store_id FROM orders WHERE order_id = $1
-        "#,
-        order_id
-    )
-    .fetch_one(&state.db.pool)
-    .await
-    .map_err(|e| (StatusCode::NOT_FOUND, format!("Order not found: {}", e)))?;
-
     let store = sqlx::query!(
         r#"
-        SELECT owner_address FROM stores WHERE id = $1
+        SELECT owner_address, mint_receipts FROM stores WHERE id = $1
         "#,
-        order.store_id
+        store_id
     )
     .fetch_one(&state.db.pool)
     .await
@@ -244,17 +382,276 @@ pub async fn update_order_status_handler(
         return Err((StatusCode::FORBIDDEN, "Not store owner".to_string()));
     }
 
-    let valid_statuses = ["pending", "shipped", "delivered", "cancelled"];
-    if !valid_statuses.contains(&payload.status.as_str()) {
+    Ok(store.mint_receipts)
+}
+
+/// Moves an order to `status` and settles what that means for the money:
+/// escrowed funds go to the seller on delivery and back to the buyer on
+/// cancellation, other paid cancellations are refunded, and deliveries can
+/// mint a receipt.
+pub(crate) async fn apply_order_status(
+    state: &AppState,
+    order_id: Uuid,
+    status: OrderStatus,
+    actor: &str,
+    note: Option<&str>,
+    mint_receipts: bool,
+) -> Result<Order, (StatusCode, String)> {
+    let current = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+    if !current.status.can_transition_to(status) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Order cannot move from {} to {}", current.status, status),
+        ));
+    }
+
+    // The escrow is settled before the status changes, so a failed contract
+    // call leaves the order where it was. A cancelled order whose refund
+    // failed would otherwise keep a funded escrow that `releaseExpired`
+    // later pays to the seller.
+    let escrow = get_escrow_for_order(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .filter(|escrow| escrow.status == "funded");
+    if let Some(escrow) = &escrow {
+        let settled = match status {
+            OrderStatus::Delivered => {
+                release_escrow(&state.db.pool, &state.web3, escrow, "release")
+                    .await
+                    .map(drop)
+            }
+            OrderStatus::Cancelled => refund_escrow(&state.db.pool, &state.web3, escrow)
+                .await
+                .map(drop),
+            _ => Ok(()),
+        };
+        settled.map_err(|e| {
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("Escrow not settled: {}", e),
+            )
+        })?;
+    }
+
+    let order = update_order_status(&state.db.pool, order_id, status, actor, note)
+        .await
+        .map_err(payment_error)?;
+
+    if escrow.is_none() && status == OrderStatus::Cancelled && order.payment_status.is_refundable()
+    {
+        // Paid orders owe the buyer whatever has not been refunded yet.
+        require_cancellation_refund(&state.db.pool, &order, actor)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+
+    // The mint is sent in the background; a repeated delivery does not queue a second one.
+    if status == OrderStatus::Delivered && mint_receipts {
+        queue_receipt_mint(&state.db.pool, order.id)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+
+    // Settling an escrow changes the payment status, so read the order back.
+    get_order_by_id(&state.db.pool, order.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))
+}
+
+#[debug_handler]
+pub async fn update_order_status_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<UpdateOrderStatusRequest>,
+) -> Result<Json<Order>, (StatusCode, String)> {
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+    let mint_receipts = authorize_store_owner(&state, order.store_id, &claims).await?;
+    let status =
+        OrderStatus::from_str(&payload.status).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let order = apply_order_status(
+        &state,
+        order.id,
+        status,
+        &claims.sub,
+        payload.note.as_deref(),
+        mint_receipts,
+    )
+    .await?;
+    Ok(Json(order))
+}
+
+/// Lets the buyer cancel an order that has not shipped yet. The stock goes
+/// back on sale, and anything paid is refunded from escrow or owed by the
+/// seller within the refund window.
+#[debug_handler]
+pub async fn cancel_order_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CancelOrderRequest>,
+) -> Result<Json<Order>, (StatusCode, String)> {
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .filter(|order| order.buyer_address.eq_ignore_ascii_case(&claims.sub))
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+    if order.status != OrderStatus::Pending {
         return Err((
             StatusCode::BAD_REQUEST,
-            "Invalid status. Must be one of: pending, shipped, delivered, cancelled".to_string(),
+            format!("Order is {} and can no longer be cancelled", order.status),
         ));
     }
 
-    update_order_status(&state.db.pool, &order_id, &payload.status)
+    let order = apply_order_status(
+        &state,
+        order.id,
+        OrderStatus::Cancelled,
+        &claims.sub,
+        payload.reason.as_deref(),
+        false,
+    )
+    .await?;
+    Ok(Json(order))
+}
+
+/// Attaches a parcel to an order. The first one moves the order to shipped.
+#[debug_handler]
+pub async fn create_shipment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateShipmentRequest>,
+) -> Result<Json<ShipmentDetails>, (StatusCode, String)> {
+    let order = get_order_by_id(&state.db.pool, order_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Order not found".to_string()))?;
+    authorize_store_owner(&state, order.store_id, &claims).await?;
+
+    let shipment = create_shipment(&state.db.pool, order.id, &payload, &claims.sub)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(shipment))
+}
+
+/// An order's shipments, oldest first, for its buyer or seller.
+#[debug_handler]
+pub async fn list_shipments_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<ShipmentDetails>>, (StatusCode, String)> {
+    get_party_order(&state, order_id, &claims).await?;
+
+    let shipments = list_order_shipments(&state.db.pool, order_id)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(shipments))
+}
 
-    Ok(())
+/// Applies one status change to many of a store's orders. Each order is
+/// updated on its own, so one that cannot move does not hold back the rest.
+#[debug_handler]
+pub async fn bulk_update_order_status_handler(
+    State(state): State<Arc<AppState>>,
+    Path(store_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<BulkUpdateOrderStatusRequest>,
+) -> Result<Json<Vec<BulkOrderStatusResult>>, (StatusCode, String)> {
+    let mint_receipts = authorize_store_owner(&state, store_id, &claims).await?;
+    let status =
+        OrderStatus::from_str(&payload.status).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if payload.order_ids.is_empty() || payload.order_ids.len() > MAX_BULK_ORDERS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Between 1 and {} orders can be updated at once",
+                MAX_BULK_ORDERS
+            ),
+        ));
+    }
+
+    let mut results = Vec::with_capacity(payload.order_ids.len());
+    for order_id in payload.order_ids {
+        let order = get_order_by_id(&state.db.pool, order_id)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .filter(|order| order.store_id == store_id);
+        let result = match order {
+            Some(order) => apply_order_status(
+                &state,
+                order.id,
+                status,
+                &claims.sub,
+                payload.note.as_deref(),
+                mint_receipts,
+            )
+            .await
+            .map_err(|(_, e)| e),
+            None => Err("Order not found".to_string()),
+        };
+        results.push(match result {
+            Ok(order) => BulkOrderStatusResult {
+                order_id,
+                order: Some(order),
+                error: None,
+            },
+            Err(error) => BulkOrderStatusResult {
+                order_id,
+                order: None,
+                error: Some(error),
+            },
+        });
+    }
+
+    Ok(Json(results))
+}
+
+/// The caller's own orders, newest first by default.
+#[debug_handler]
+pub async fn list_buyer_orders_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<OrderListQuery>,
+) -> Result<Json<OrderPage>, (StatusCode, String)> {
+    if claims.role != "user" {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only users have order history".to_string(),
+        ));
+    }
+
+    let user = get_user_by_wallet(&state.db.pool, &claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+
+    let page = list_orders(&state.db.pool, None, Some(user.id), &query)
+        .await
+        .map_err(payment_error)?;
+    Ok(Json(page))
+}
+
+/// An order with its items and history, for its buyer or seller.
+#[debug_handler]
+pub async fn get_order_handler(
+    State(state): State<Arc<AppState>>,
+    Path(order_id): Path<Uuid>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<OrderDetails>, (StatusCode, String)> {
+    let order = get_party_order(&state, order_id, &claims).await?;
+
+    let details = get_order_details(&state.db.pool, order)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(details))
 }
@@ -1,14 +1,20 @@
+use crate::utils::pricing::PriceSource;
+use crate::utils::token_gates::TokenGateCache;
+use crate::utils::transport::ChainTransport;
 use reqwest::Client;
-use web3::{transports::Http, Web3};
+use std::sync::Arc;
+use web3::Web3;
 
 use sqlx::PgPool;
 #[derive(Clone)]
 pub struct AppState {
     pub db: AppStateDb,
-    pub web3: Web3<Http>,
+    pub web3: Web3<ChainTransport>,
     pub pinata_client: Client,
     pub pinata_api_key: String,
     pub pinata_secret_key: String,
+    pub price_source: Arc<dyn PriceSource>,
+    pub token_gates: Arc<TokenGateCache>,
 }
 
 #[derive(Clone)]
@@ -4,6 +4,9 @@ mod store_tests {
     use crate::db::models::*;
     use crate::routes::store_handler::*;
     use crate::state::{AppState, AppStateDb};
+    use crate::utils::fake_chain::FakeChain;
+    use crate::utils::pricing::StaticPriceSource;
+    use crate::utils::token_gates::TokenGateCache;
     use axum::{
         routing::{get, post, put},
         Router,
@@ -40,11 +43,13 @@ mod store_tests {
 
         let state = Arc::new(AppState {
             db: AppStateDb { pool: pool.clone() },
-            web3: web3::Web3::new(web3::transports::Http::new("http://localhost:8545").unwrap()),
+            web3: web3::Web3::new(FakeChain::new().into()),
             pinata_client: reqwest::Client::new(),
             pinata_api_key: std::env::var("PINATA_API_KEY").expect("PINATA_API_KEY must be set"),
             pinata_secret_key: std::env::var("PINATA_SECRET_KEY")
                 .expect("PINATA_SECRET_KEY must be set"),
+            price_source: Arc::new(StaticPriceSource::new([])),
+            token_gates: Arc::new(TokenGateCache::from_env()),
         });
 
         let app = Router::new()
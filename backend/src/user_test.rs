@@ -1,479 +1,1647 @@
-// #[cfg(test)]
-// mod user_test {
-//     use super::*;
-//     use crate::authentication::authentication::{auth_middleware, Claims};
-//     use crate::db::models::*;
-//     use crate::routes::store_handler::*;
-//     use crate::state::{AppState, AppStateDb};
-//     use axum::{
-//         routing::{get, post, put},
-//         Router,
-//     };
-//     use jsonwebtoken::{encode, EncodingKey, Header};
-//     use reqwest::Client;
-//     use serde_json::{json, Value};
-//     use sqlx::types::Decimal;
-//     use sqlx::{Executor, PgPool};
-//     use std::sync::Arc;
-//     use std::time::{SystemTime, UNIX_EPOCH};
-//     use uuid::Uuid;
-//     use web3::{
-//         transports::Http,
-//         types::{Address, Log, TransactionReceipt, H256, U256},
-//         Transport, Web3,
-//     };
-//
-//     // Mock Web3 transport for verify_payment
-//     #[derive(Clone)]
-//     struct MockWeb3Transport {
-//         should_succeed: bool,
-//         seller_address: String,
-//         amount: Decimal,
-//     }
-//
-//     #[async_trait::async_trait]
-//     impl Transport for MockWeb3Transport {
-//         type Out = futures::future::Ready<web3::Result<serde_json::Value>>;
-//
-//         fn prepare(
-//             &self,
-//             _method: &str,
-//             _params: Vec<serde_json::Value>,
-//         ) -> (usize, serde_json::Value) {
-//             (0, serde_json::Value::Null)
-//         }
-//
-//         fn execute(&self, _id: usize, _request: serde_json::Value) -> Self::Out {
-//             let receipt = if self.should_succeed {
-//                 let transfer_topic = H256::from_str(
-//                     "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
-//                 )
-//                 .unwrap();
-//                 let seller_address = Address::from_str(&self.seller_address).unwrap();
-//                 let amount_wei = U256::from_dec_str(&format!(
-//                     "{}000000000000000000",
-//                     self.amount.to_string().replace(".", "")
-//                 ))
-//                 .unwrap();
-//                 let mut amount_bytes = [0u8; 32];
-//                 amount_wei.to_big_endian(&mut amount_bytes);
-//
-//                 TransactionReceipt {
-//                     status: Some(1.into()),
-//                     to: Some(
-//                         Address::from_str("0x874069Fa1Eb16D44d622BC6Cf16451f9B2bE0855").unwrap(),
-//                     ),
-//                     logs: vec![Log {
-//                         topics: vec![
-//                             transfer_topic,
-//                             H256::zero(),
-//                             H256::from_low_u64_be(0), // From address (not checked)
-//                             H256::from_slice(&seller_address.as_bytes()[12..]), // To address
-//                         ],
-//                         data: amount_bytes.into(),
-//                         ..Default::default()
-//                     }],
-//                     ..Default::default()
-//                 }
-//             } else {
-//                 TransactionReceipt {
-//                     status: Some(0.into()),
-//                     ..Default::default()
-//                 }
-//             };
-//
-//             futures::future::ready(Ok(serde_json::to_value(receipt).unwrap()))
-//         }
-//     }
-//
-//     async fn cleanup_test_db(pool: &PgPool) -> Result<(), sqlx::Error> {
-//         pool.execute(
-//             "TRUNCATE TABLE users, cart, cart_items, orders, products, stores RESTART IDENTITY CASCADE"
-//         )
-//         .await?;
-//         Ok(())
-//     }
-//
-//     async fn setup_test_app(
-//         mock_payment_success: bool,
-//         seller_address: &str,
-//         amount: Decimal,
-//     ) -> (Router, PgPool, Arc<AppState>) {
-//         dotenv::dotenv().ok();
-//         let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-//         let pool = PgPool::connect(&database_url)
-//             .await
-//             .expect("Failed to connect to database");
-//
-//         cleanup_test_db(&pool)
-//             .await
-//             .expect("Failed to clean test database");
-//
-//         let state = Arc::new(AppState {
-//             db: AppStateDb { pool: pool.clone() },
-//             web3: Web3::new(MockWeb3Transport {
-//                 should_succeed: mock_payment_success,
-//                 seller_address: seller_address.to_string(),
-//                 amount,
-//             }),
-//             pinata_client: reqwest::Client::new(),
-//             pinata_api_key: std::env::var("PINATA_API_KEY").unwrap_or_default(),
-//             pinata_secret_key: std::env::var("PINATA_SECRET_KEY").unwrap_or_default(),
-//         });
-//
-//         let app = Router::new()
-//             .route("/stores", post(create_store_handler))
-//             .route(
-//                 "/stores/:id",
-//                 put(update_store_handler).delete(delete_store_handler),
-//             )
-//             .route(
-//                 "/stores/:id/products",
-//                 post(add_product_handler).get(list_products_handler),
-//             )
-//             .route("/stores/:id/orders", get(get_store_orders_handler))
-//             .route("/products/:id/quantity", get(get_product_quantity_handler))
-//             .route("/users/register", post(register_user_handler))
-//             .route("/users/login", post(login_handler))
-//             .route("/cart", post(add_to_cart_handler).get(get_cart_handler))
-//             .route("/checkout", post(checkout_handler))
-//             .route("/orders", post(create_order_handler))
-//             .layer(axum::middleware::from_fn_with_state(
-//                 state.clone(),
-//                 auth_middleware,
-//             ))
-//             .with_state(state.clone());
-//
-//         (app, pool, state)
-//     }
-//
-//     async fn generate_jwt(wallet_address: &str, role: &str) -> String {
-//         let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-//         let claims = Claims {
-//             sub: wallet_address.to_string(),
-//             exp: (SystemTime::now()
-//                 .duration_since(UNIX_EPOCH)
-//                 .unwrap()
-//                 .as_secs()
-//                 + 3600) as usize,
-//             role: role.to_string(),
-//         };
-//         encode(
-//             &Header::default(),
-//             &claims,
-//             &EncodingKey::from_secret(secret.as_ref()),
-//         )
-//         .expect("Failed to generate JWT")
-//     }
-//
-//     #[tokio::test]
-//     async fn test_store_handlers() {
-//         let (app, pool, _state) = setup_test_app(true, "", Decimal::zero()).await;
-//         let client = Client::new();
-//         let server_addr = "http://localhost:3009";
-//
-//         let server_task = tokio::spawn(async move {
-//             axum::serve(
-//                 tokio::net::TcpListener::bind("0.0.0.0:3009").await.unwrap(),
-//                 app,
-//             )
-//             .await
-//             .unwrap();
-//         });
-//
-//         let mut tx = pool.begin().await.expect("Failed to start transaction");
-//
-//         let wallet_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
-//         let user_id = Uuid::new_v4();
-//         let email = format!("store_{}@example.com", user_id);
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO users (id, wallet_address, email, user_name) VALUES ($1, $2, $3, $4)",
-//             user_id,
-//             &wallet_address,
-//             &email,
-//             "Store Owner"
-//         ))
-//         .await
-//         .expect("Failed to insert test user");
-//
-//         let token = generate_jwt(&wallet_address, "store").await;
-//
-//         let create_payload = json!({
-//             "store_name": "Test Store",
-//             "description": "Store Description",
-//             "owner_address": wallet_address,
-//             "image": "data:image/jpeg;base64,SGVsbG8gV29ybGQ="
-//         });
-//
-//         let response = client
-//             .post(format!("{}/stores", server_addr))
-//             .header("Content-Type", "application/json")
-//             .header("Authorization", format!("Bearer {}", token))
-//             .json(&create_payload)
-//             .send()
-//             .await
-//             .expect("Failed to send POST /stores");
-//
-//         assert_eq!(response.status(), 200, "POST /stores should return 200 OK");
-//
-//         let body: Value = response
-//             .json()
-//             .await
-//             .expect("Failed to parse POST /stores response");
-//         assert_eq!(body["store_name"], "Test Store");
-//         assert_eq!(body["owner_address"], wallet_address);
-//
-//         let store_id = body["id"].as_str().expect("Store ID should be a string");
-//         let store_id_uuid = Uuid::parse_str(store_id).expect("Invalid store ID format");
-//
-//         // Test update store
-//         let update_payload = json!({
-//             "store_name": "Updated Store",
-//             "description": "Updated Description",
-//             "owner_address": wallet_address,
-//             "image": null
-//         });
-//
-//         let response = client
-//             .put(format!("{}/stores/{}", server_addr, store_id))
-//             .header("Content-Type", "application/json")
-//             .header("Authorization", format!("Bearer {}", token))
-//             .json(&update_payload)
-//             .send()
-//             .await
-//             .expect("Failed to send PUT /stores/:id");
-//
-//         assert_eq!(
-//             response.status(),
-//             200,
-//             "PUT /stores/:id should return 200 OK"
-//         );
-//
-//         tx.rollback().await.expect("Failed to rollback transaction");
-//         server_task.abort();
-//     }
-//
-//     #[tokio::test]
-//     async fn test_product_handlers() {
-//         let (app, pool, _state) = setup_test_app(true, "", Decimal::zero()).await;
-//         let client = Client::new();
-//         let server_addr = "http://localhost:3010";
-//
-//         let server_task = tokio::spawn(async move {
-//             axum::serve(
-//                 tokio::net::TcpListener::bind("0.0.0.0:3010").await.unwrap(),
-//                 app,
-//             )
-//             .await
-//             .unwrap();
-//         });
-//
-//         let mut tx = pool.begin().await.expect("Failed to start transaction");
-//
-//         let wallet_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
-//         let user_id = Uuid::new_v4();
-//         let email = format!("store_{}@example.com", user_id);
-//         let store_id = Uuid::new_v4();
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO users (id, wallet_address, email, user_name) VALUES ($1, $2, $3, $4)",
-//             user_id,
-//             &wallet_address,
-//             &email,
-//             "Store Owner"
-//         ))
-//         .await
-//         .expect("Failed to insert test user");
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO stores (id, store_name, owner_address, share_link) VALUES ($1, $2, $3, $4)",
-//             store_id,
-//             "Test Store",
-//             &wallet_address,
-//             "https://test.store"
-//         ))
-//         .await
-//         .expect("Failed to insert test store");
-//
-//         tx.commit().await.expect("Failed to commit transaction");
-//
-//         let tx = pool.begin().await.expect("Failed to start transaction");
-//
-//         let token = generate_jwt(&wallet_address, "store").await;
-//
-//         let product_payload = json!({
-//             "product_name": "Test Product",
-//             "description": "Test Description",
-//             "price": 10.99,
-//             "quantity": 100,
-//             "image": "data:image/jpeg;base64,SGVsbG8gV29ybGQ="
-//         });
-//
-//         let response = client
-//             .post(format!("{}/stores/{}/products", server_addr, store_id))
-//             .header("Content-Type", "application/json")
-//             .header("Authorization", format!("Bearer {}", token))
-//             .json(&product_payload)
-//             .send()
-//             .await
-//             .expect("Failed to send POST request");
-//
-//         assert_eq!(response.status(), 200, "POST should return 200 OK");
-//
-//         tx.rollback().await.expect("Failed to rollback transaction");
-//         server_task.abort();
-//     }
-//
-//     #[tokio::test]
-//     async fn test_register_user_handler() {
-//         let (app, pool, _state) = setup_test_app(true, "", Decimal::zero()).await;
-//         let client = Client::new();
-//         let server_addr = "http://localhost:3013";
-//
-//         let server_task = tokio::spawn(async move {
-//             axum::serve(
-//                 tokio::net::TcpListener::bind("0.0.0.0:3013").await.unwrap(),
-//                 app,
-//             )
-//             .await
-//             .unwrap();
-//         });
-//
-//         let mut tx = pool.begin().await.expect("Failed to start transaction");
-//
-//         let wallet_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
-//         let email = format!("user_{}@example.com", Uuid::new_v4());
-//
-//         let payload = json!({
-//             "wallet_address": wallet_address,
-//             "email": email,
-//             "user_name": "Test User",
-//             "phone_number": "+1234567890",
-//             "house_address": "123 Test St"
-//         });
-//
-//         let response = client
-//             .post(format!("{}/users/register", server_addr))
-//             .header("Content-Type", "application/json")
-//             .json(&payload)
-//             .send()
-//             .await
-//             .expect("Failed to send POST /users/register");
-//
-//         assert_eq!(
-//             response.status(),
-//             200,
-//             "POST /users/register should return 200 OK"
-//         );
-//
-//         tx.rollback().await.expect("Failed to rollback transaction");
-//         server_task.abort();
-//     }
-//
-//     #[tokio::test]
-//     async fn test_checkout_handler() {
-//         let wallet_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
-//         let seller_address = format!("0x{}", hex::encode(Uuid::new_v4().as_bytes()));
-//         let total = Decimal::new(1998, 2); // 2 * 9.99
-//         let (app, pool, _state) = setup_test_app(true, &seller_address, total).await;
-//         let client = Client::new();
-//         let server_addr = "http://localhost:3017";
-//
-//         let server_task = tokio::spawn(async move {
-//             axum::serve(
-//                 tokio::net::TcpListener::bind("0.0.0.0:3017").await.unwrap(),
-//                 app,
-//             )
-//             .await
-//             .unwrap();
-//         });
-//
-//         let mut tx = pool.begin().await.expect("Failed to start transaction");
-//
-//         let user_id = Uuid::new_v4();
-//         let email = format!("user_{}@example.com", user_id);
-//         let store_id = Uuid::new_v4();
-//         let product_id = Uuid::new_v4();
-//         let cart_id = Uuid::new_v4();
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO users (id, wallet_address, email, user_name) VALUES ($1, $2, $3, $4)",
-//             user_id,
-//             &wallet_address,
-//             &email,
-//             "Test User"
-//         ))
-//         .await
-//         .expect("Failed to insert test user");
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO stores (id, store_name, owner_address, share_link) VALUES ($1, $2, $3, $4)",
-//             store_id,
-//             "Test Store",
-//             &seller_address,
-//             "https://test.store"
-//         ))
-//         .await
-//         .expect("Failed to insert test store");
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO products (id, store_id, product_name, price, quantity) VALUES ($1, $2, $3, $4, $5)",
-//             product_id,
-//             store_id,
-//             "Test Product",
-//             Decimal::new(999, 2),
-//             10
-//         ))
-//         .await
-//         .expect("Failed to insert test product");
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO cart (id, user_id) VALUES ($1, $2)",
-//             cart_id,
-//             user_id
-//         ))
-//         .await
-//         .expect("Failed to insert test cart");
-//
-//         tx.execute(sqlx::query!(
-//             "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, $4)",
-//             Uuid::new_v4(),
-//             cart_id,
-//             product_id,
-//             2
-//         ))
-//         .await
-//         .expect("Failed to insert test cart item");
-//
-//         tx.commit().await.expect("Failed to commit transaction");
-//
-//         let tx = pool.begin().await.expect("Failed to start transaction");
-//
-//         let token = generate_jwt(&wallet_address, "user").await;
-//
-//         let payload = json!({
-//             "cart_id": cart_id,
-//             "buyer_address": wallet_address,
-//             "payment_type": "cUSD",
-//             "transaction_hash": "0x1234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef"
-//         });
-//
-//         let response = client
-//             .post(format!("{}/checkout", server_addr))
-//             .header("Content-Type", "application/json")
-//             .header("Authorization", format!("Bearer {}", token))
-//             .json(&payload)
-//             .send()
-//             .await
-//             .expect("Failed to send POST /checkout");
-//
-//         assert_eq!(
-//             response.status(),
-//             200,
-//             "POST /checkout should return 200 OK"
-//         );
-//
-//         tx.rollback().await.expect("Failed to rollback transaction");
-//         server_task.abort();
-//     }
-// }
+#[cfg(test)]
+mod user_tests {
+    use crate::authentication::authentication::{auth_middleware, Claims};
+    use crate::db::models::{
+        CheckoutRequest, CreateOrderRequest, OrderStatus, PaymentStatus,
+        RecordInventoryMovementRequest,
+    };
+    use crate::db::operations::{
+        adjust_inventory, checkout, create_order, get_inventory_ledger, get_order_payment,
+        get_product_quantity, list_order_events, list_order_refunds, mark_overdue_refunds,
+        reject_refund, release_expired_reservations, reserve_cart_stock, update_order_status,
+    };
+    use crate::routes::idempotency::idempotency_middleware;
+    use crate::routes::user_handler::*;
+    use crate::state::{AppState, AppStateDb};
+    use crate::utils::failover::{FailoverTransport, RpcConfig};
+    use crate::utils::fake_chain::FakeChain;
+    use crate::utils::pricing::StaticPriceSource;
+    use crate::utils::token_gates::TokenGateCache;
+    use crate::utils::tokens::{CELO, CUSD};
+    use axum::{
+        routing::{get, post, put},
+        Router,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use reqwest::Client;
+    use serde_json::{json, Value};
+    use serial_test::serial;
+    use sqlx::types::Decimal;
+    use sqlx::{Executor, PgPool};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+    use web3::types::Transaction;
+
+    const BUYER: &str = "0x1111111111111111111111111111111111111111";
+    const SELLER: &str = "0x2222222222222222222222222222222222222222";
+
+    async fn cleanup_test_db(pool: &PgPool) -> Result<(), sqlx::Error> {
+        pool.execute(
+            "TRUNCATE TABLE users, cart, cart_items, orders, products, stores, payment_transactions, idempotency_keys RESTART IDENTITY CASCADE"
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// An app wired to a fake chain, so payments can be scripted without a node.
+    async fn setup_test_app() -> (Router, PgPool, FakeChain) {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPool::connect(&database_url)
+            .await
+            .expect("Failed to connect to database");
+
+        cleanup_test_db(&pool)
+            .await
+            .expect("Failed to clean test database");
+
+        let chain = FakeChain::new();
+        let state = Arc::new(AppState {
+            db: AppStateDb { pool: pool.clone() },
+            web3: web3::Web3::new(chain.clone().into()),
+            pinata_client: reqwest::Client::new(),
+            pinata_api_key: std::env::var("PINATA_API_KEY").unwrap_or_default(),
+            pinata_secret_key: std::env::var("PINATA_SECRET_KEY").unwrap_or_default(),
+            price_source: Arc::new(StaticPriceSource::new([])),
+            token_gates: Arc::new(TokenGateCache::new(Duration::from_secs(60))),
+        });
+
+        let app = Router::new()
+            .route("/users/register", post(register_user_handler))
+            .route(
+                "/checkout",
+                post(checkout_handler)
+                    .layer(axum::middleware::from_fn_with_state(
+                        state.clone(),
+                        idempotency_middleware,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        state.clone(),
+                        auth_middleware,
+                    )),
+            )
+            .route(
+                "/orders",
+                get(list_buyer_orders_handler).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth_middleware,
+                )),
+            )
+            .route(
+                "/orders/:id",
+                get(get_order_handler).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth_middleware,
+                )),
+            )
+            .route(
+                "/orders/:id/status",
+                put(update_order_status_handler).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth_middleware,
+                )),
+            )
+            .route(
+                "/orders/:id/cancel",
+                post(cancel_order_handler).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth_middleware,
+                )),
+            )
+            .route(
+                "/stores/:id/orders/status",
+                post(bulk_update_order_status_handler).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth_middleware,
+                )),
+            )
+            .with_state(state);
+
+        (app, pool, chain)
+    }
+
+    fn generate_jwt(wallet_address: &str, role: &str) -> String {
+        let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+        let claims = Claims {
+            sub: wallet_address.to_string(),
+            exp: (SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 3600) as usize,
+            role: role.to_string(),
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_ref()),
+        )
+        .expect("Failed to generate JWT")
+    }
+
+    /// Inserts the buyer, the seller's store and a cart holding `quantity` of a
+    /// 9.99 product. Returns the cart id.
+    async fn seed_cart(pool: &PgPool, quantity: i32) -> Uuid {
+        let user_id = Uuid::new_v4();
+        let store_id = Uuid::new_v4();
+        let product_id = Uuid::new_v4();
+        let cart_id = Uuid::new_v4();
+
+        let mut tx = pool.begin().await.expect("Failed to start transaction");
+        tx.execute(sqlx::query!(
+            "INSERT INTO users (id, wallet_address, user_name) VALUES ($1, $2, $3)",
+            user_id,
+            BUYER,
+            "Test User"
+        ))
+        .await
+        .expect("Failed to insert test user");
+        tx.execute(sqlx::query!(
+            "INSERT INTO stores (id, store_name, owner_address) VALUES ($1, $2, $3)",
+            store_id,
+            "Test Store",
+            SELLER
+        ))
+        .await
+        .expect("Failed to insert test store");
+        tx.execute(sqlx::query!(
+            "INSERT INTO products (id, store_id, product_name, price, quantity) VALUES ($1, $2, $3, $4, $5)",
+            product_id,
+            store_id,
+            "Test Product",
+            Decimal::new(999, 2),
+            10
+        ))
+        .await
+        .expect("Failed to insert test product");
+        tx.execute(sqlx::query!(
+            "INSERT INTO cart (id, user_id) VALUES ($1, $2)",
+            cart_id,
+            user_id
+        ))
+        .await
+        .expect("Failed to insert test cart");
+        tx.execute(sqlx::query!(
+            "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, $4)",
+            Uuid::new_v4(),
+            cart_id,
+            product_id,
+            quantity
+        ))
+        .await
+        .expect("Failed to insert test cart item");
+        tx.commit().await.expect("Failed to commit transaction");
+
+        cart_id
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_register_user_handler() {
+        let (app, _pool, _chain) = setup_test_app().await;
+        let client = Client::new();
+        let server_addr = "http://localhost:3013";
+
+        let server_task = tokio::spawn(async move {
+            axum::serve(
+                tokio::net::TcpListener::bind("0.0.0.0:3013").await.unwrap(),
+                app,
+            )
+            .await
+            .unwrap();
+        });
+
+        let payload = json!({
+            "wallet_address": BUYER,
+            "email": format!("user_{}@example.com", Uuid::new_v4()),
+            "user_name": "Test User",
+            "phone_number": "+1234567890",
+            "house_address": "123 Test St"
+        });
+
+        let response = client
+            .post(format!("{}/users/register", server_addr))
+            .json(&payload)
+            .send()
+            .await
+            .expect("Failed to send POST /users/register");
+
+        assert_eq!(
+            response.status(),
+            200,
+            "POST /users/register should return 200 OK"
+        );
+        let body: Value = response.json().await.expect("Failed to parse response");
+        assert_eq!(body["wallet_address"], BUYER);
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_checkout_handler() {
+        let (app, pool, chain) = setup_test_app().await;
+        let client = Client::new();
+        let server_addr = "http://localhost:3017";
+
+        let server_task = tokio::spawn(async move {
+            axum::serve(
+                tokio::net::TcpListener::bind("0.0.0.0:3017").await.unwrap(),
+                app,
+            )
+            .await
+            .unwrap();
+        });
+
+        let cart_id = seed_cart(&pool, 2).await;
+        let token = generate_jwt(BUYER, "user");
+        // 2 x 9.99
+        let transaction_hash = chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(1998, 2));
+        let checkout = |transaction_hash: String| {
+            client
+                .post(format!("{}/checkout", server_addr))
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&json!({
+                    "cart_id": cart_id,
+                    "buyer_address": BUYER,
+                    "payment_type": "cUSD",
+                    "transaction_hash": transaction_hash
+                }))
+                .send()
+        };
+
+        let underpaid = chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(999, 2));
+        let response = checkout(underpaid)
+            .await
+            .expect("Failed to send POST /checkout");
+        assert_ne!(response.status(), 200, "Underpayments must be rejected");
+        let body = response.text().await.expect("Failed to read response");
+        assert!(body.contains("Invalid payment amount"), "{}", body);
+
+        // Another wallet's payment of the same amount cannot be claimed.
+        let someone_elses = chain.erc20_transfer(
+            &CUSD,
+            "0x3333333333333333333333333333333333333333",
+            SELLER,
+            Decimal::new(1998, 2),
+        );
+        let response = checkout(someone_elses)
+            .await
+            .expect("Failed to send POST /checkout");
+        assert_ne!(
+            response.status(),
+            200,
+            "Other buyers' payments must be rejected"
+        );
+        let body = response.text().await.expect("Failed to read response");
+        assert!(body.contains("Invalid sender address"), "{}", body);
+
+        let response = checkout(transaction_hash.clone())
+            .await
+            .expect("Failed to send POST /checkout");
+        assert_eq!(
+            response.status(),
+            200,
+            "POST /checkout should return 200 OK"
+        );
+        let summary: Value = response
+            .json()
+            .await
+            .expect("Failed to parse checkout summary");
+        assert_eq!(summary["transaction_hash"], transaction_hash);
+        assert_eq!(summary["total_amount"], "19.98");
+        assert_eq!(summary["item_count"], 2);
+        let order = &summary["orders"][0];
+        assert_eq!(order["payment_status"], "confirmed");
+        assert_eq!(order["transaction_hash"], transaction_hash);
+        assert_eq!(order["items"][0]["quantity"], 2);
+        assert_eq!(order["items"][0]["unit_price"], "9.99");
+
+        // The same payment cannot be used for a second checkout.
+        let product_id = sqlx::query_scalar!("SELECT id FROM products")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch product");
+        sqlx::query!(
+            "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, 2)",
+            Uuid::new_v4(),
+            cart_id,
+            product_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to refill cart");
+        let response = checkout(transaction_hash)
+            .await
+            .expect("Failed to send POST /checkout");
+        assert_ne!(response.status(), 200, "Replayed payments must be rejected");
+        let body = response.text().await.expect("Failed to read response");
+        assert!(body.contains("already been used"), "{}", body);
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_verify_payment_against_fake_chain() {
+        let chain = FakeChain::new();
+        let web3 = web3::Web3::new(chain.clone());
+        let amount = Decimal::new(1998, 2);
+
+        let paid = chain.erc20_transfer(&CUSD, BUYER, SELLER, amount);
+        verify_payment(&web3, &paid, &CUSD, BUYER, SELLER, amount)
+            .await
+            .expect("Payment should verify");
+
+        let err = verify_payment(&web3, &paid, &CUSD, BUYER, SELLER, Decimal::new(2000, 2))
+            .await
+            .expect_err("Amount must match");
+        assert_eq!(err.to_string(), "Invalid payment amount");
+        let err = verify_payment(&web3, &paid, &CUSD, BUYER, BUYER, amount)
+            .await
+            .expect_err("Recipient must match");
+        assert_eq!(err.to_string(), "Invalid recipient address");
+        let err = verify_payment(&web3, &paid, &CELO, BUYER, SELLER, amount)
+            .await
+            .expect_err("A cUSD transfer does not pay in CELO");
+        assert_eq!(err.to_string(), "Invalid recipient address");
+
+        let err = verify_payment(&web3, &paid, &CUSD, SELLER, SELLER, amount)
+            .await
+            .expect_err("Sender must match");
+        assert_eq!(err.to_string(), "Invalid sender address");
+
+        let native = chain.native_transfer(BUYER, SELLER, amount);
+        verify_payment(&web3, &native, &CELO, BUYER, SELLER, amount)
+            .await
+            .expect("Native payment should verify");
+        let err = verify_payment(&web3, &native, &CELO, SELLER, SELLER, amount)
+            .await
+            .expect_err("Sender must match");
+        assert_eq!(err.to_string(), "Invalid sender address");
+
+        let reverted = chain.reverted_transaction(BUYER, SELLER);
+        let err = verify_payment(&web3, &reverted, &CUSD, BUYER, SELLER, amount)
+            .await
+            .expect_err("Reverted transactions pay nothing");
+        assert_eq!(err.to_string(), "Transaction failed");
+
+        let unknown = format!("0x{}", "ab".repeat(32));
+        let err = verify_payment(&web3, &unknown, &CUSD, BUYER, SELLER, amount)
+            .await
+            .expect_err("Unknown transactions pay nothing");
+        assert_eq!(err.to_string(), "Transaction not found");
+
+        let pending = chain.add_transaction(Transaction::default(), 1, None, vec![]);
+        let err = verify_payment(&web3, &pending, &CUSD, BUYER, SELLER, amount)
+            .await
+            .expect_err("Pending transactions are not final");
+        assert_eq!(err.to_string(), "Transaction is still pending");
+
+        chain.fail_next("eth_getTransactionReceipt", "connection reset");
+        let err = verify_payment(&web3, &paid, &CUSD, BUYER, SELLER, amount)
+            .await
+            .expect_err("RPC failures are reported");
+        assert!(err.to_string().contains("connection reset"));
+        verify_payment(&web3, &paid, &CUSD, BUYER, SELLER, amount)
+            .await
+            .expect("Failures only affect the scripted call");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_verify_payment_waits_for_confirmations() {
+        let chain = FakeChain::new();
+        let web3 = web3::Web3::new(chain.clone());
+        let amount = Decimal::new(500, 2);
+        let paid = chain.erc20_transfer(&CUSD, BUYER, SELLER, amount);
+
+        // SAFETY: serial tests are the only ones that read this variable.
+        unsafe { std::env::set_var("PAYMENT_MIN_CONFIRMATIONS", "3") };
+        let unconfirmed = verify_payment(&web3, &paid, &CUSD, BUYER, SELLER, amount).await;
+        chain.mine(2);
+        let confirmed = verify_payment(&web3, &paid, &CUSD, BUYER, SELLER, amount).await;
+        unsafe { std::env::remove_var("PAYMENT_MIN_CONFIRMATIONS") };
+
+        assert_eq!(
+            unconfirmed
+                .expect_err("One block is not enough")
+                .to_string(),
+            "Transaction has 1 of 3 required confirmations"
+        );
+        confirmed.expect("Three blocks are enough");
+        assert_eq!(
+            chain
+                .requests()
+                .iter()
+                .filter(|method| *method == "eth_blockNumber")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpc_failover_retries_and_health() {
+        let primary = FakeChain::new();
+        let backup = FakeChain::new();
+        backup.set_block_number(101);
+        let transport = FailoverTransport::new(
+            vec![
+                ("primary".to_string(), primary.clone()),
+                ("backup".to_string(), backup.clone()),
+            ],
+            RpcConfig {
+                timeout: Duration::from_millis(50),
+                max_retries: 1,
+                backoff: Duration::from_millis(1),
+                unhealthy_after: 2,
+                cooldown: Duration::from_secs(60),
+            },
+        );
+        let web3 = web3::Web3::new(transport.clone());
+
+        // Healthy primary serves everything.
+        assert_eq!(web3.eth().block_number().await.unwrap().as_u64(), 100);
+
+        // Transport errors and timeouts fail over to the backup.
+        primary.fail_next("eth_blockNumber", "connection reset");
+        assert_eq!(web3.eth().block_number().await.unwrap().as_u64(), 101);
+        primary.stall_next("eth_blockNumber");
+        assert_eq!(web3.eth().block_number().await.unwrap().as_u64(), 101);
+
+        // Two failures in a row take the primary out of rotation.
+        let health = transport.health();
+        assert!(!health[0].healthy);
+        assert_eq!(health[0].failures, 2);
+        assert_eq!(
+            health[0].last_error.as_deref(),
+            Some("Timed out after 50ms")
+        );
+        assert!(health[1].healthy);
+        let primary_calls = primary.requests().len();
+        assert_eq!(web3.eth().block_number().await.unwrap().as_u64(), 101);
+        assert_eq!(primary.requests().len(), primary_calls);
+
+        // Node errors are answers, not outages, and are not retried.
+        let backup_calls = backup.requests().len();
+        assert!(web3.eth().accounts().await.is_err());
+        assert_eq!(backup.requests().len(), backup_calls + 1);
+        assert!(transport.health()[1].healthy);
+
+        // With every provider down the call retries, then gives up.
+        backup.fail_next("eth_blockNumber", "down");
+        backup.fail_next("eth_blockNumber", "down");
+        primary.fail_next("eth_blockNumber", "down");
+        primary.fail_next("eth_blockNumber", "down");
+        let err = web3
+            .eth()
+            .block_number()
+            .await
+            .expect_err("All providers fail");
+        assert!(err.to_string().contains("down"));
+        assert!(transport.health().iter().all(|provider| !provider.healthy));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_order_payment_status_and_explorer_links() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let cart_id = seed_cart(&pool, 2).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        let transaction_hash = chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(1998, 2));
+
+        let mut order = checkout(
+            &pool,
+            &web3,
+            &StaticPriceSource::new([]),
+            &TokenGateCache::new(Duration::from_secs(60)),
+            CheckoutRequest {
+                cart_id,
+                buyer_address: BUYER.to_string(),
+                payment_type: "cUSD".to_string(),
+                transaction_hash: transaction_hash.clone(),
+                payment_intent_id: None,
+            },
+            user_id,
+        )
+        .await
+        .expect("Checkout failed")
+        .orders
+        .remove(0)
+        .order;
+
+        chain.mine(4);
+        let payment = get_order_payment(&pool, &web3, &order)
+            .await
+            .expect("Failed to fetch payment");
+        assert_eq!(payment.status, "confirmed");
+        assert_eq!(payment.confirmations, 5);
+        assert_eq!(payment.block_number, Some(100));
+        assert_eq!(
+            payment.block_timestamp.map(|at| at.timestamp() as u64),
+            Some(FakeChain::block_timestamp(100))
+        );
+        assert_eq!(payment.token_symbol, "cUSD");
+        assert_eq!(payment.amount, Some(Decimal::new(1998, 2)));
+        assert_eq!(payment.payer.as_deref(), Some(BUYER));
+        assert_eq!(
+            payment.transaction_url,
+            Some(format!(
+                "https://alfajores.celoscan.io/tx/{}",
+                transaction_hash
+            ))
+        );
+        assert_eq!(
+            payment.payer_url,
+            Some(format!("https://alfajores.celoscan.io/address/{}", BUYER))
+        );
+        assert_eq!(
+            payment.token_url,
+            Some(format!(
+                "https://alfajores.celoscan.io/token/{}",
+                CUSD.address
+            ))
+        );
+
+        // SAFETY: tests touching chain settings are serialised.
+        unsafe { std::env::set_var("CHAIN_ID", "42220") };
+        let mainnet = get_order_payment(&pool, &web3, &order).await;
+        unsafe { std::env::remove_var("CHAIN_ID") };
+        let mainnet = mainnet.expect("Failed to fetch payment");
+        assert_eq!(mainnet.chain_id, 42220);
+        assert_eq!(
+            mainnet.transaction_url,
+            Some(format!("https://celoscan.io/tx/{}", transaction_hash)),
+            "Explorer links follow the configured chain"
+        );
+
+        order.transaction_hash = Some(chain.reverted_transaction(BUYER, SELLER));
+        let payment = get_order_payment(&pool, &web3, &order)
+            .await
+            .expect("Failed to fetch payment");
+        assert_eq!(payment.status, "failed");
+        assert_eq!(payment.amount, None);
+
+        order.transaction_hash = Some(format!("0x{}", "cd".repeat(32)));
+        let payment = get_order_payment(&pool, &web3, &order)
+            .await
+            .expect("Failed to fetch payment");
+        assert_eq!(payment.status, "not_found");
+
+        order.transaction_hash = None;
+        let payment = get_order_payment(&pool, &web3, &order)
+            .await
+            .expect("Failed to fetch payment");
+        assert_eq!(payment.status, "unpaid");
+        assert_eq!(payment.transaction_url, None);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_concurrent_checkouts_cannot_oversell() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let other_buyer = "0x3333333333333333333333333333333333333333";
+        let cart_id = seed_cart(&pool, 1).await;
+        let product_id = sqlx::query_scalar!("UPDATE products SET quantity = 1 RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to set stock");
+        let other_cart_id = Uuid::new_v4();
+        let other_user_id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO users (id, wallet_address, user_name) VALUES ($1, $2, 'Other')",
+            other_user_id,
+            other_buyer
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to insert second buyer");
+        sqlx::query!(
+            "INSERT INTO cart (id, user_id) VALUES ($1, $2)",
+            other_cart_id,
+            other_user_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to insert second cart");
+        sqlx::query!(
+            "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, 1)",
+            Uuid::new_v4(),
+            other_cart_id,
+            product_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to fill second cart");
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+
+        // Both buyers have paid for the last unit.
+        let prices = StaticPriceSource::new([]);
+        let gates = TokenGateCache::new(Duration::from_secs(60));
+        let buy = |buyer: &str, cart_id: Uuid, user_id: Uuid| {
+            let transaction_hash = chain.erc20_transfer(&CUSD, buyer, SELLER, Decimal::new(999, 2));
+            checkout(
+                &pool,
+                &web3,
+                &prices,
+                &gates,
+                CheckoutRequest {
+                    cart_id,
+                    buyer_address: buyer.to_string(),
+                    payment_type: "cUSD".to_string(),
+                    transaction_hash,
+                    payment_intent_id: None,
+                },
+                user_id,
+            )
+        };
+        let (first, second) = tokio::join!(
+            buy(BUYER, cart_id, user_id),
+            buy(other_buyer, other_cart_id, other_user_id)
+        );
+
+        assert!(
+            first.is_ok() != second.is_ok(),
+            "Exactly one checkout should win"
+        );
+        let err = first.err().or(second.err()).unwrap();
+        assert!(err.to_string().contains("Insufficient stock"), "{}", err);
+
+        let stock = sqlx::query_scalar!("SELECT quantity FROM products WHERE id = $1", product_id)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch stock");
+        assert_eq!(stock, 0);
+        let orders = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM orders"#)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to count orders");
+        assert_eq!(orders, 1);
+        // The losing payment is not claimed, so it can still be refunded or reused.
+        let claimed =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM payment_transactions"#)
+                .fetch_one(&pool)
+                .await
+                .expect("Failed to count payments");
+        assert_eq!(claimed, 1);
+        let cart_items = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM cart_items"#)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to count cart items");
+        assert_eq!(cart_items, 1);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_checkout_pays_every_store_and_charges_the_flat_fee_per_order() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let cart_id = seed_cart(&pool, 2).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        let store_id = sqlx::query_scalar!("SELECT id FROM stores")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch store");
+        let other_seller = "0x3333333333333333333333333333333333333333";
+        let other_store_id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO stores (id, store_name, owner_address) VALUES ($1, 'Other Store', $2)",
+            other_store_id,
+            other_seller
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to insert second store");
+        // A second line from the first store and one from another store.
+        for (store_id, price) in [
+            (store_id, Decimal::new(500, 2)),
+            (other_store_id, Decimal::new(1250, 2)),
+        ] {
+            let product_id = Uuid::new_v4();
+            sqlx::query!(
+                "INSERT INTO products (id, store_id, product_name, price, quantity) VALUES ($1, $2, 'Extra', $3, 10)",
+                product_id,
+                store_id,
+                price
+            )
+            .execute(&pool)
+            .await
+            .expect("Failed to insert product");
+            sqlx::query!(
+                "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, 1)",
+                Uuid::new_v4(),
+                cart_id,
+                product_id
+            )
+            .execute(&pool)
+            .await
+            .expect("Failed to fill cart");
+        }
+
+        let treasury = "0x00000000000000000000000000000000000fee01";
+        // SAFETY: tests touching platform fee settings are serialised.
+        unsafe {
+            std::env::set_var("PLATFORM_TREASURY_ADDRESS", treasury);
+            std::env::set_var("PLATFORM_FEE_FLAT", "0.10");
+        }
+        let prices = StaticPriceSource::new([]);
+        let gates = TokenGateCache::new(Duration::from_secs(60));
+        let pay = |transaction_hash: String| {
+            checkout(
+                &pool,
+                &web3,
+                &prices,
+                &gates,
+                CheckoutRequest {
+                    cart_id,
+                    buyer_address: BUYER.to_string(),
+                    payment_type: "cUSD".to_string(),
+                    transaction_hash,
+                    payment_intent_id: None,
+                },
+                user_id,
+            )
+        };
+        // 2 x 9.99 + 5.00 from the first store and 12.50 from the second,
+        // less one 0.10 fee on each order.
+        let first_store_only = chain.erc20_batch_transfer(
+            &CUSD,
+            BUYER,
+            &[
+                (SELLER, Decimal::new(2488, 2)),
+                (treasury, Decimal::new(20, 2)),
+            ],
+        );
+        let unpaid = pay(first_store_only).await;
+        let paid = chain.erc20_batch_transfer(
+            &CUSD,
+            BUYER,
+            &[
+                (SELLER, Decimal::new(2488, 2)),
+                (other_seller, Decimal::new(1240, 2)),
+                (treasury, Decimal::new(20, 2)),
+            ],
+        );
+        let summary = pay(paid).await;
+        unsafe {
+            std::env::remove_var("PLATFORM_TREASURY_ADDRESS");
+            std::env::remove_var("PLATFORM_FEE_FLAT");
+        }
+
+        let err = unpaid.expect_err("Every store must be paid its share");
+        assert!(err.to_string().contains("Invalid recipient address"));
+        let summary = summary.expect("Checkout failed");
+        assert_eq!(summary.orders.len(), 2);
+        assert_eq!(summary.platform_fee, Decimal::new(20, 2));
+        let first = summary
+            .orders
+            .iter()
+            .find(|order| order.order.store_id == store_id)
+            .expect("First store should have an order");
+        assert_eq!(first.order.platform_fee, Decimal::new(10, 2));
+        assert_eq!(
+            first
+                .items
+                .iter()
+                .map(|item| item.platform_fee)
+                .sum::<Decimal>(),
+            Decimal::new(10, 2),
+            "The flat fee is charged once per order, not per line"
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_native_celo_checkout_leaves_the_fee_on_the_order() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let cart_id = seed_cart(&pool, 2).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        sqlx::query!("UPDATE stores SET accepts_native_celo = true")
+            .execute(&pool)
+            .await
+            .expect("Failed to opt store in");
+
+        // SAFETY: tests touching platform fee settings are serialised.
+        unsafe {
+            std::env::set_var(
+                "PLATFORM_TREASURY_ADDRESS",
+                "0x00000000000000000000000000000000000fee01",
+            );
+            std::env::set_var("PLATFORM_FEE_BPS", "250");
+            std::env::set_var("PLATFORM_FEE_FLAT", "0.10");
+        }
+        // 2 x 9.99 USD at 2 CELO each; one transfer pays the store in full.
+        let summary = checkout(
+            &pool,
+            &web3,
+            &StaticPriceSource::new([("USD".to_string(), "CELO".to_string(), Decimal::new(2, 0))]),
+            &TokenGateCache::new(Duration::from_secs(60)),
+            CheckoutRequest {
+                cart_id,
+                buyer_address: BUYER.to_string(),
+                payment_type: "CELO".to_string(),
+                transaction_hash: chain.native_transfer(BUYER, SELLER, Decimal::new(3996, 2)),
+                payment_intent_id: None,
+            },
+            user_id,
+        )
+        .await;
+        unsafe {
+            std::env::remove_var("PLATFORM_TREASURY_ADDRESS");
+            std::env::remove_var("PLATFORM_FEE_BPS");
+            std::env::remove_var("PLATFORM_FEE_FLAT");
+        }
+
+        let summary = summary.expect("Native checkout failed");
+        assert_eq!(summary.orders.len(), 1);
+        let order = &summary.orders[0].order;
+        assert_eq!(order.amount, Decimal::new(3996, 2));
+        // 2.5% of 39.96 is 0.999, plus the 0.10 USD flat fee at 2 CELO is
+        // 0.20, is 1.20 CELO, owed by the store.
+        assert_eq!(order.platform_fee, Decimal::new(120, 2));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_checkout_is_verified_against_the_saved_price_quote() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let cart_id = seed_cart(&pool, 1).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        sqlx::query!("UPDATE stores SET accepts_native_celo = true")
+            .execute(&pool)
+            .await
+            .expect("Failed to opt store in");
+        // The buyer was quoted 3 CELO per USD; the rate has since moved to 2.
+        sqlx::query!(
+            r#"
+            INSERT INTO cart_price_quotes (cart_id, token_symbol, currency, rate, expires_at)
+            VALUES ($1, 'CELO', 'USD', 3, now() + interval '1 minute')
+            "#,
+            cart_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to save quote");
+        let prices =
+            StaticPriceSource::new([("USD".to_string(), "CELO".to_string(), Decimal::new(2, 0))]);
+        let gates = TokenGateCache::new(Duration::from_secs(60));
+        let pay = |amount: Decimal| {
+            checkout(
+                &pool,
+                &web3,
+                &prices,
+                &gates,
+                CheckoutRequest {
+                    cart_id,
+                    buyer_address: BUYER.to_string(),
+                    payment_type: "CELO".to_string(),
+                    transaction_hash: chain.native_transfer(BUYER, SELLER, amount),
+                    payment_intent_id: None,
+                },
+                user_id,
+            )
+        };
+
+        pay(Decimal::new(1998, 2))
+            .await
+            .expect_err("The live rate is not what the buyer was quoted");
+        let summary = pay(Decimal::new(2997, 2))
+            .await
+            .expect("Checkout at the quoted rate failed");
+        assert_eq!(summary.orders[0].order.exchange_rate, Decimal::new(3, 0));
+        let saved = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM cart_price_quotes WHERE cart_id = $1"#,
+            cart_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to count quotes");
+        assert_eq!(saved, 0, "A placed cart's quotes are spent");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_stock_changes_are_recorded_in_the_ledger() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let cart_id = seed_cart(&pool, 2).await;
+        let product = sqlx::query!("UPDATE products SET quantity = 0 RETURNING id, store_id")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to empty stock");
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        let stock = |change: i32, reason: &str| RecordInventoryMovementRequest {
+            quantity_change: change,
+            reason: reason.to_string(),
+            note: Some("Delivery".to_string()),
+        };
+
+        adjust_inventory(&pool, product.id, &stock(10, "restock"), SELLER)
+            .await
+            .expect("Failed to restock");
+
+        // Checkout takes what was bought, once.
+        let summary = checkout(
+            &pool,
+            &web3,
+            &StaticPriceSource::new([]),
+            &TokenGateCache::new(Duration::from_secs(60)),
+            CheckoutRequest {
+                cart_id,
+                buyer_address: BUYER.to_string(),
+                payment_type: "cUSD".to_string(),
+                transaction_hash: chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(1998, 2)),
+                payment_intent_id: None,
+            },
+            user_id,
+        )
+        .await
+        .expect("Checkout failed");
+
+        let prices = StaticPriceSource::new([]);
+        let direct_order = |quantity: i32| {
+            create_order(
+                &pool,
+                &prices,
+                CreateOrderRequest {
+                    store_id: product.store_id,
+                    product_id: product.id,
+                    user_id,
+                    buyer_address: BUYER.to_string(),
+                    quantity,
+                },
+            )
+        };
+        direct_order(3).await.expect("Failed to create order");
+        direct_order(6)
+            .await
+            .expect_err("Orders cannot take more than is in stock");
+        adjust_inventory(&pool, product.id, &stock(-6, "adjustment"), SELLER)
+            .await
+            .expect_err("Stock cannot go negative");
+
+        let ledger = get_inventory_ledger(&pool, product.id)
+            .await
+            .expect("Failed to fetch ledger")
+            .expect("Product not found");
+        assert_eq!(ledger.stock, 5);
+        assert_eq!(ledger.ledger_stock, 5);
+        assert!(ledger.in_sync);
+        let mut entries: Vec<(i32, &str)> = ledger
+            .movements
+            .iter()
+            .map(|movement| (movement.quantity_change, movement.reason.as_str()))
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec![(-3, "sale"), (-2, "sale"), (10, "restock")]);
+        let checkout_sale = ledger
+            .movements
+            .iter()
+            .find(|movement| movement.quantity_change == -2)
+            .unwrap();
+        assert_eq!(checkout_sale.order_id, Some(summary.orders[0].order.id));
+        let restock = ledger
+            .movements
+            .iter()
+            .find(|movement| movement.reason == "restock")
+            .unwrap();
+        assert_eq!(restock.created_by.as_deref(), Some(SELLER));
+        assert_eq!(restock.note.as_deref(), Some("Delivery"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_reserved_stock_is_held_until_it_expires() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let other_buyer = "0x3333333333333333333333333333333333333333";
+        let cart_id = seed_cart(&pool, 1).await;
+        let product_id = sqlx::query_scalar!("UPDATE products SET quantity = 1 RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to set stock");
+        let other_cart_id = Uuid::new_v4();
+        let other_user_id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO users (id, wallet_address, user_name) VALUES ($1, $2, 'Other')",
+            other_user_id,
+            other_buyer
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to insert second buyer");
+        sqlx::query!(
+            "INSERT INTO cart (id, user_id) VALUES ($1, $2)",
+            other_cart_id,
+            other_user_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to insert second cart");
+        sqlx::query!(
+            "INSERT INTO cart_items (id, cart_id, product_id, quantity) VALUES ($1, $2, $3, 1)",
+            Uuid::new_v4(),
+            other_cart_id,
+            product_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to fill second cart");
+        let prices = StaticPriceSource::new([]);
+        let gates = TokenGateCache::new(Duration::from_secs(60));
+        let buy = |buyer: &str, cart_id: Uuid, user_id: Uuid| {
+            let transaction_hash = chain.erc20_transfer(&CUSD, buyer, SELLER, Decimal::new(999, 2));
+            checkout(
+                &pool,
+                &web3,
+                &prices,
+                &gates,
+                CheckoutRequest {
+                    cart_id,
+                    buyer_address: buyer.to_string(),
+                    payment_type: "cUSD".to_string(),
+                    transaction_hash,
+                    payment_intent_id: None,
+                },
+                user_id,
+            )
+        };
+
+        let reservations = reserve_cart_stock(&pool, cart_id)
+            .await
+            .expect("Failed to reserve stock");
+        assert_eq!(reservations.len(), 1);
+        assert_eq!(reservations[0].quantity, 1);
+        assert_eq!(reservations[0].status, "active");
+        assert_eq!(get_product_quantity(&pool, product_id).await.unwrap(), 0);
+
+        // Someone else cannot take the held unit, even after paying for it.
+        let err = reserve_cart_stock(&pool, other_cart_id)
+            .await
+            .expect_err("Held stock cannot be reserved twice");
+        assert!(err.to_string().contains("Insufficient stock"), "{}", err);
+        let err = buy(other_buyer, other_cart_id, other_user_id)
+            .await
+            .expect_err("Held stock cannot be bought by another buyer");
+        assert!(err.to_string().contains("Insufficient stock"), "{}", err);
+
+        // Reserving again only renews the buyer's own hold.
+        reserve_cart_stock(&pool, cart_id)
+            .await
+            .expect("Failed to renew reservation");
+        let active = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM stock_reservations WHERE status = 'active'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to count reservations");
+        assert_eq!(active, 1);
+
+        sqlx::query!(
+            "UPDATE stock_reservations SET expires_at = now() - interval '1 minute' WHERE cart_id = $1",
+            cart_id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to backdate reservation");
+        assert_eq!(get_product_quantity(&pool, product_id).await.unwrap(), 1);
+        assert_eq!(release_expired_reservations(&pool).await.unwrap(), 1);
+
+        // Once the hold lapses the unit goes to whoever reserves and pays.
+        reserve_cart_stock(&pool, other_cart_id)
+            .await
+            .expect("Released stock should be reservable");
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        buy(BUYER, cart_id, user_id)
+            .await
+            .expect_err("The lapsed hold no longer protects the first buyer");
+        buy(other_buyer, other_cart_id, other_user_id)
+            .await
+            .expect("Checkout with a reservation failed");
+
+        let status = sqlx::query_scalar!(
+            "SELECT status FROM stock_reservations WHERE cart_id = $1",
+            other_cart_id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch reservation");
+        assert_eq!(status, "consumed");
+        assert_eq!(get_product_quantity(&pool, product_id).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_order_status_follows_the_state_machine() {
+        let (_app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let cart_id = seed_cart(&pool, 1).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+
+        let placed = checkout(
+            &pool,
+            &web3,
+            &StaticPriceSource::new([]),
+            &TokenGateCache::new(Duration::from_secs(60)),
+            CheckoutRequest {
+                cart_id,
+                buyer_address: BUYER.to_string(),
+                payment_type: "cUSD".to_string(),
+                transaction_hash: chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(999, 2)),
+                payment_intent_id: None,
+            },
+            user_id,
+        )
+        .await
+        .expect("Checkout failed")
+        .orders
+        .remove(0);
+        let order = placed.order;
+        assert_eq!(order.status, OrderStatus::Pending);
+        assert_eq!(order.payment_status, PaymentStatus::Confirmed);
+        assert_eq!(placed.events.len(), 2);
+
+        let shipped = update_order_status(
+            &pool,
+            order.id,
+            OrderStatus::Shipped,
+            SELLER,
+            Some("Tracking 123"),
+        )
+        .await
+        .expect("Pending orders can ship");
+        assert_eq!(shipped.status, OrderStatus::Shipped);
+        assert!(shipped.updated_at > order.updated_at);
+
+        let err = update_order_status(&pool, order.id, OrderStatus::Pending, SELLER, None)
+            .await
+            .expect_err("Orders cannot move backwards");
+        assert!(
+            err.to_string()
+                .contains("cannot move from shipped to pending"),
+            "{}",
+            err
+        );
+        update_order_status(&pool, order.id, OrderStatus::Delivered, SELLER, None)
+            .await
+            .expect("Shipped orders can be delivered");
+        update_order_status(&pool, order.id, OrderStatus::Cancelled, SELLER, None)
+            .await
+            .expect_err("Delivered orders are final");
+
+        let events = list_order_events(&pool, order.id)
+            .await
+            .expect("Failed to fetch events");
+        let timeline: Vec<(&str, Option<&str>, &str, &str)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.field.as_str(),
+                    event.from_status.as_deref(),
+                    event.to_status.as_str(),
+                    event.actor.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            timeline,
+            vec![
+                ("status", None, "pending", BUYER),
+                ("payment_status", None, "confirmed", BUYER),
+                ("status", Some("pending"), "shipped", SELLER),
+                ("status", Some("shipped"), "delivered", SELLER),
+            ]
+        );
+        assert_eq!(events[2].note.as_deref(), Some("Tracking 123"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_order_history_pages_filters_and_bulk_updates() {
+        let (app, pool, _chain) = setup_test_app().await;
+        let client = Client::new();
+        seed_cart(&pool, 1).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        let product = sqlx::query!("SELECT id, store_id FROM products")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch product");
+
+        let prices = StaticPriceSource::new([]);
+        let mut order_ids = Vec::new();
+        for quantity in 1..=3 {
+            let placed = create_order(
+                &pool,
+                &prices,
+                CreateOrderRequest {
+                    store_id: product.store_id,
+                    product_id: product.id,
+                    user_id,
+                    buyer_address: BUYER.to_string(),
+                    quantity,
+                },
+            )
+            .await
+            .expect("Failed to create order");
+            order_ids.push(placed.order.id);
+        }
+
+        // Bound before the server task starts, so requests cannot race it.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_task = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        let buyer = generate_jwt(BUYER, "user");
+        let seller = generate_jwt(SELLER, "store");
+        let list = |query: Vec<(&'static str, String)>| {
+            client
+                .get(format!("{}/orders", server_addr))
+                .header("Authorization", format!("Bearer {}", buyer))
+                .query(&query)
+                .send()
+        };
+        let order_ids_of = |page: &Value| -> Vec<String> {
+            page["orders"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|order| order["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let first: Value = list(vec![("limit", "2".to_string())])
+            .await
+            .expect("Failed to send GET /orders")
+            .json()
+            .await
+            .unwrap();
+        let cursor = first["next_cursor"]
+            .as_str()
+            .expect("A full page has a cursor")
+            .to_string();
+        let second: Value = list(vec![("limit", "2".to_string()), ("cursor", cursor)])
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(second["next_cursor"].is_null());
+        let mut seen = order_ids_of(&first);
+        seen.extend(order_ids_of(&second));
+        assert_eq!(seen.len(), 3);
+        assert_eq!(
+            seen,
+            order_ids
+                .iter()
+                .rev()
+                .map(Uuid::to_string)
+                .collect::<Vec<_>>(),
+            "Newest orders come first"
+        );
+
+        let by_amount: Value = list(vec![
+            ("sort", "amount".to_string()),
+            ("direction", "asc".to_string()),
+        ])
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+        let amounts: Vec<&str> = by_amount["orders"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|order| order["amount"].as_str().unwrap())
+            .collect();
+        assert_eq!(amounts, vec!["9.99", "19.98", "29.97"]);
+
+        let stranger = Uuid::new_v4();
+        let response = client
+            .post(format!(
+                "{}/stores/{}/orders/status",
+                server_addr, product.store_id
+            ))
+            .header("Authorization", format!("Bearer {}", seller))
+            .json(&json!({
+                "order_ids": [order_ids[0], order_ids[1], stranger],
+                "status": "shipped",
+                "note": "Batch 7"
+            }))
+            .send()
+            .await
+            .expect("Failed to send bulk status update");
+        assert_eq!(response.status(), 200);
+        let results: Value = response.json().await.unwrap();
+        assert_eq!(results[0]["order"]["status"], "shipped");
+        assert_eq!(results[1]["order"]["status"], "shipped");
+        assert_eq!(results[2]["order_id"], stranger.to_string());
+        assert_eq!(results[2]["error"], "Order not found");
+
+        let shipped: Value = list(vec![("status", "shipped".to_string())])
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(shipped["orders"].as_array().unwrap().len(), 2);
+        let future: Value = list(vec![("from", "2999-01-01T00:00:00Z".to_string())])
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(future["orders"].as_array().unwrap().is_empty());
+
+        let status_url = format!("{}/orders/{}/status", server_addr, order_ids[2]);
+        let response = client
+            .put(&status_url)
+            .header("Authorization", format!("Bearer {}", buyer))
+            .json(&json!({ "status": "shipped" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403, "Buyers cannot ship orders");
+        let response = client
+            .put(&status_url)
+            .header("Authorization", format!("Bearer {}", seller))
+            .json(&json!({ "status": "delivered" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "Pending orders must ship first");
+
+        let details: Value = client
+            .get(format!("{}/orders/{}", server_addr, order_ids[0]))
+            .header("Authorization", format!("Bearer {}", buyer))
+            .send()
+            .await
+            .expect("Failed to send GET /orders/:id")
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(details["status"], "shipped");
+        assert_eq!(details["items"][0]["quantity"], 1);
+        let last_event = details["events"].as_array().unwrap().last().unwrap();
+        assert_eq!(last_event["to_status"], "shipped");
+        assert_eq!(last_event["note"], "Batch 7");
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_buyer_cancellation_restores_stock_and_owes_a_refund() {
+        let (app, pool, chain) = setup_test_app().await;
+        let web3 = web3::Web3::new(chain.clone());
+        let client = Client::new();
+        let cart_id = seed_cart(&pool, 2).await;
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE wallet_address = $1", BUYER)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch buyer");
+        let product_id = sqlx::query_scalar!("SELECT id FROM products")
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to fetch product");
+
+        let order = checkout(
+            &pool,
+            &web3,
+            &StaticPriceSource::new([]),
+            &TokenGateCache::new(Duration::from_secs(60)),
+            CheckoutRequest {
+                cart_id,
+                buyer_address: BUYER.to_string(),
+                payment_type: "cUSD".to_string(),
+                transaction_hash: chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(1998, 2)),
+                payment_intent_id: None,
+            },
+            user_id,
+        )
+        .await
+        .expect("Checkout failed")
+        .orders
+        .remove(0)
+        .order;
+        assert_eq!(get_product_quantity(&pool, product_id).await.unwrap(), 8);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_task = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        let cancel = |wallet: &str| {
+            client
+                .post(format!("{}/orders/{}/cancel", server_addr, order.id))
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", generate_jwt(wallet, "user")),
+                )
+                .json(&json!({ "reason": "Ordered by mistake" }))
+                .send()
+        };
+
+        let response = cancel(SELLER).await.expect("Failed to send cancel");
+        assert_eq!(response.status(), 404, "Only the buyer can cancel");
+        let response = cancel(BUYER).await.expect("Failed to send cancel");
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "cancelled");
+        let response = cancel(BUYER).await.expect("Failed to send cancel");
+        assert_eq!(response.status(), 400, "Cancelled orders stay cancelled");
+
+        assert_eq!(get_product_quantity(&pool, product_id).await.unwrap(), 10);
+        let restored = sqlx::query!(
+            "SELECT quantity_change, created_by FROM inventory_movements WHERE order_id = $1 AND reason = 'cancellation'",
+            order.id
+        )
+        .fetch_one(&pool)
+        .await
+        .expect("Cancellation should be in the ledger");
+        assert_eq!(restored.quantity_change, 2);
+        assert_eq!(restored.created_by.as_deref(), Some(BUYER));
+
+        let refunds = list_order_refunds(&pool, order.id)
+            .await
+            .expect("Failed to list refunds");
+        assert_eq!(refunds.len(), 1);
+        let refund = &refunds[0];
+        assert_eq!(refund.amount, Decimal::new(1998, 2));
+        assert_eq!(refund.status, "requested");
+        assert!(refund.due_at.expect("Refund should have a deadline") > chrono::Utc::now());
+        assert!(
+            reject_refund(&pool, refund.id).await.unwrap().is_none(),
+            "Sellers cannot reject a refund they owe"
+        );
+
+        sqlx::query!(
+            "UPDATE refunds SET due_at = now() - interval '1 minute' WHERE id = $1",
+            refund.id
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to move the deadline");
+        let overdue = mark_overdue_refunds(&pool)
+            .await
+            .expect("Failed to mark overdue refunds");
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0].status, "overdue");
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_checkout_retries_with_an_idempotency_key_replay_the_first_response() {
+        let (app, pool, chain) = setup_test_app().await;
+        let client = Client::new();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_task = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let cart_id = seed_cart(&pool, 2).await;
+        let token = generate_jwt(BUYER, "user");
+        let checkout = |key: &'static str, transaction_hash: &str| {
+            client
+                .post(format!("{}/checkout", server_addr))
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Idempotency-Key", key)
+                .json(&json!({
+                    "cart_id": cart_id,
+                    "buyer_address": BUYER,
+                    "payment_type": "cUSD",
+                    "transaction_hash": transaction_hash
+                }))
+                .send()
+        };
+
+        let underpaid = chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(999, 2));
+        let first = checkout("retry-underpaid", &underpaid).await.unwrap();
+        assert_eq!(first.status(), 500);
+        let again = checkout("retry-underpaid", &underpaid).await.unwrap();
+        assert_eq!(again.status(), 500);
+        assert!(
+            again.headers().get("idempotent-replayed").is_none(),
+            "Server errors are not saved, so the retry runs again"
+        );
+
+        let transaction_hash = chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(1998, 2));
+        let first = checkout("checkout-1", &transaction_hash).await.unwrap();
+        assert_eq!(first.status(), 200);
+        assert!(first.headers().get("idempotent-replayed").is_none());
+        let summary: Value = first.json().await.unwrap();
+
+        let retry = checkout("checkout-1", &transaction_hash).await.unwrap();
+        assert_eq!(retry.status(), 200);
+        assert_eq!(retry.headers()["content-type"], "application/json");
+        assert_eq!(retry.headers()["idempotent-replayed"], "true");
+        let replayed: Value = retry.json().await.unwrap();
+        assert_eq!(replayed, summary);
+        let orders = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM orders"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(orders, 1, "A retry must not place the order again");
+
+        let response = checkout("checkout-1", &underpaid).await.unwrap();
+        assert_eq!(
+            response.status(),
+            422,
+            "A key cannot be reused for a different request"
+        );
+
+        // A first attempt that never answered holds the key only for its lease.
+        sqlx::query!(
+            r#"
+            INSERT INTO cart_items (id, cart_id, product_id, quantity)
+            SELECT $1, $2, id, 1 FROM products
+            "#,
+            Uuid::new_v4(),
+            cart_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let transaction_hash = chain.erc20_transfer(&CUSD, BUYER, SELLER, Decimal::new(999, 2));
+        let body = json!({
+            "cart_id": cart_id,
+            "buyer_address": BUYER,
+            "payment_type": "cUSD",
+            "transaction_hash": transaction_hash
+        });
+        let fingerprint = hex::encode(web3::signing::keccak256(
+            format!("POST /checkout\n{}", body).as_bytes(),
+        ));
+        sqlx::query!(
+            r#"
+            INSERT INTO idempotency_keys (
+                owner, idempotency_key, request_path, fingerprint, expires_at, locked_until
+            )
+            VALUES ($1, 'crashed', '/checkout', $2, now() + interval '1 day', now() + interval '1 minute')
+            "#,
+            BUYER,
+            fingerprint
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let crashed = || {
+            client
+                .post(format!("{}/checkout", server_addr))
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Idempotency-Key", "crashed")
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+                .send()
+        };
+        assert_eq!(crashed().await.unwrap().status(), 409);
+        sqlx::query!("UPDATE idempotency_keys SET locked_until = now() - interval '1 second' WHERE idempotency_key = 'crashed'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            crashed().await.unwrap().status(),
+            200,
+            "A claim whose lease ran out is retried"
+        );
+
+        server_task.abort();
+    }
+}
@@ -0,0 +1,69 @@
+use crate::utils::tokens::Token;
+use anyhow::{anyhow, Result};
+use web3::types::{Address, Bytes, CallRequest, U256};
+
+/// Gas a plain CELO transfer uses.
+const NATIVE_TRANSFER_GAS: u64 = 21_000;
+/// A generous limit for an ERC-20 `transfer`, which wallets usually set near this.
+const ERC20_TRANSFER_GAS: u64 = 65_000;
+
+/// Gas one payment transfer in `token` is expected to use.
+pub fn transfer_gas(token: &Token) -> U256 {
+    if token.native {
+        U256::from(NATIVE_TRANSFER_GAS)
+    } else {
+        U256::from(ERC20_TRANSFER_GAS)
+    }
+}
+
+async fn call_uint<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    contract: Address,
+    function: &str,
+    args: &[Address],
+) -> Result<U256> {
+    let params = vec![ethabi::ParamType::Address; args.len()];
+    let mut data = ethabi::short_signature(function, &params).to_vec();
+    data.extend(ethabi::encode(
+        &args
+            .iter()
+            .map(|address| ethabi::Token::Address(*address))
+            .collect::<Vec<_>>(),
+    ));
+
+    let result = web3
+        .eth()
+        .call(
+            CallRequest {
+                to: Some(contract),
+                data: Some(Bytes(data)),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+
+    ethabi::decode(&[ethabi::ParamType::Uint(256)], &result.0)?
+        .pop()
+        .and_then(|token| token.into_uint())
+        .ok_or_else(|| anyhow!("Malformed {} response", function))
+}
+
+/// ERC-20 `balanceOf(owner)`.
+pub async fn erc20_balance<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    token: Address,
+    owner: Address,
+) -> Result<U256> {
+    call_uint(web3, token, "balanceOf", &[owner]).await
+}
+
+/// ERC-20 `allowance(owner, spender)`.
+pub async fn erc20_allowance<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    token: Address,
+    owner: Address,
+    spender: Address,
+) -> Result<U256> {
+    call_uint(web3, token, "allowance", &[owner, spender]).await
+}
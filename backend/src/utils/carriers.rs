@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Placeholder for the parcel's code in a tracking URL template.
+pub const TRACKING_CODE_PLACEHOLDER: &str = "{tracking_code}";
+
+/// Where a parcel is, as far as its carrier knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackingStatus {
+    InTransit,
+    Delivered,
+}
+
+/// Somewhere to ask carriers about parcels. Delivery tracking only goes
+/// through this trait, so carriers can be swapped without touching orders.
+#[async_trait]
+pub trait CarrierTracker: Send + Sync {
+    async fn status(&self, carrier: &str, tracking_code: &str) -> Result<TrackingStatus>;
+}
+
+/// Tracking pages of the carriers we know.
+const TRACKING_URL_TEMPLATES: [(&str, &str); 4] = [
+    (
+        "dhl",
+        "https://www.dhl.com/global-en/home/tracking.html?tracking-id={tracking_code}",
+    ),
+    (
+        "fedex",
+        "https://www.fedex.com/fedextrack/?trknbr={tracking_code}",
+    ),
+    ("ups", "https://www.ups.com/track?tracknum={tracking_code}"),
+    (
+        "usps",
+        "https://tools.usps.com/go/TrackConfirmAction?tLabels={tracking_code}",
+    ),
+];
+
+pub fn default_tracking_url_template(carrier: &str) -> Option<&'static str> {
+    TRACKING_URL_TEMPLATES
+        .iter()
+        .find(|(code, _)| *code == carrier)
+        .map(|(_, template)| *template)
+}
+
+/// Fills a tracking code into a template, which must be an http(s) URL
+/// containing `{tracking_code}`.
+pub fn tracking_url(template: &str, tracking_code: &str) -> Result<String> {
+    if !(template.starts_with("https://") || template.starts_with("http://"))
+        || !template.contains(TRACKING_CODE_PLACEHOLDER)
+    {
+        return Err(anyhow!(
+            "Tracking URL template must be an http(s) URL containing {}",
+            TRACKING_CODE_PLACEHOLDER
+        ));
+    }
+    Ok(template.replace(TRACKING_CODE_PLACEHOLDER, tracking_code))
+}
+
+/// Asks an HTTP service about parcels: `GET {url}?carrier=dhl&tracking_code=...`
+/// answering `{"status": "in_transit"}` or `{"status": "delivered"}`.
+pub struct HttpCarrierTracker {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct HttpTracking {
+    status: TrackingStatus,
+}
+
+impl HttpCarrierTracker {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CarrierTracker for HttpCarrierTracker {
+    async fn status(&self, carrier: &str, tracking_code: &str) -> Result<TrackingStatus> {
+        let tracking: HttpTracking = self
+            .client
+            .get(&self.url)
+            .query(&[("carrier", carrier), ("tracking_code", tracking_code)])
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(tracking.status)
+    }
+}
+
+/// `HttpCarrierTracker` when `CARRIER_TRACKING_URL` is set. Without one,
+/// sellers mark orders delivered themselves.
+pub fn carrier_tracker_from_env() -> Option<Arc<dyn CarrierTracker>> {
+    match std::env::var("CARRIER_TRACKING_URL") {
+        Ok(url) if !url.is_empty() => Some(Arc::new(HttpCarrierTracker::new(url))),
+        _ => None,
+    }
+}
+
+/// A carrier for tests. Every parcel is in transit until `deliver` is
+/// called for it. Clones share the same parcels.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MockCarrier {
+    delivered: Arc<std::sync::Mutex<std::collections::HashSet<(String, String)>>>,
+}
+
+#[cfg(test)]
+impl MockCarrier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn deliver(&self, carrier: &str, tracking_code: &str) {
+        self.delivered
+            .lock()
+            .unwrap()
+            .insert((carrier.to_string(), tracking_code.to_string()));
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl CarrierTracker for MockCarrier {
+    async fn status(&self, carrier: &str, tracking_code: &str) -> Result<TrackingStatus> {
+        let key = (carrier.to_string(), tracking_code.to_string());
+        if self.delivered.lock().unwrap().contains(&key) {
+            Ok(TrackingStatus::Delivered)
+        } else {
+            Ok(TrackingStatus::InTransit)
+        }
+    }
+}
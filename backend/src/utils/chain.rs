@@ -0,0 +1,10 @@
+/// The chain the marketplace runs on. Token addresses, payment URIs and
+/// block explorer links all follow it. Alfajores, the testnet the addresses
+/// in `tokens` belong to, unless `CHAIN_ID` says otherwise (42220 for
+/// mainnet, with its token addresses).
+pub fn chain_id() -> u64 {
+    std::env::var("CHAIN_ID")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(44787)
+}
@@ -0,0 +1,23 @@
+use anyhow::{anyhow, Result};
+use web3::signing::{hash_message, recover};
+use web3::types::Address;
+
+/// The wallet that produced `signature` by signing `message` with
+/// `personal_sign`, as MiniPay and other wallets do.
+pub fn recover_signer(message: &str, signature: &str) -> Result<Address> {
+    let bytes = hex::decode(signature.trim_start_matches("0x"))
+        .map_err(|_| anyhow!("Signature is not valid hex"))?;
+    if bytes.len() != 65 {
+        return Err(anyhow!("Signature must be 65 bytes"));
+    }
+
+    // Wallets use either 0/1 or 27/28 for the recovery id.
+    let recovery_id = match bytes[64] {
+        v @ 0..=1 => v as i32,
+        v @ 27..=28 => (v - 27) as i32,
+        _ => return Err(anyhow!("Invalid signature recovery id")),
+    };
+
+    recover(hash_message(message).as_bytes(), &bytes[..64], recovery_id)
+        .map_err(|_| anyhow!("Invalid signature"))
+}
@@ -0,0 +1,28 @@
+use crate::utils::tokens::{to_base_units, Token};
+use anyhow::Result;
+use sqlx::types::Decimal;
+
+/// Builds an EIP-681 payment request that wallets such as MiniPay can open
+/// directly, with the amount in the token's base units.
+///
+/// ERC-20 tokens become a `transfer` call on the token contract; native CELO
+/// is a plain value transfer to the recipient.
+pub fn payment_uri(
+    token: &Token,
+    recipient: &str,
+    amount: Decimal,
+    chain_id: u64,
+) -> Result<String> {
+    let amount = to_base_units(amount, token.decimals)?;
+    if token.native {
+        Ok(format!(
+            "ethereum:{}@{}?value={}",
+            recipient, chain_id, amount
+        ))
+    } else {
+        Ok(format!(
+            "ethereum:{}@{}/transfer?address={}&uint256={}",
+            token.address, chain_id, recipient, amount
+        ))
+    }
+}
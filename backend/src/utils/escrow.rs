@@ -0,0 +1,151 @@
+use crate::utils::signer::Signer;
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+use web3::signing::{keccak256, Key};
+use web3::types::{Address, Bytes, CallRequest, TransactionParameters, H256, U256};
+
+const DEPOSITED_EVENT: &str = "Deposited(bytes32,address,address,address,uint256,uint64)";
+
+/// A `Deposited` event emitted by the JesEscrow contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscrowDeposit {
+    pub buyer: Address,
+    pub seller: Address,
+    pub token: Address,
+    pub amount: U256,
+    pub release_after: u64,
+}
+
+/// Address of the deployed JesEscrow contract, if escrow is enabled.
+pub fn escrow_contract() -> Option<Address> {
+    std::env::var("ESCROW_CONTRACT_ADDRESS")
+        .ok()
+        .and_then(|address| Address::from_str(address.trim_start_matches("0x")).ok())
+}
+
+/// The order id the contract is given: the order's UUID left-padded to 32 bytes.
+/// Escrows are stored under the buyer and this id, so calls name both.
+pub fn escrow_order_key(order_id: Uuid) -> H256 {
+    let mut key = [0u8; 32];
+    key[16..].copy_from_slice(order_id.as_bytes());
+    H256(key)
+}
+
+/// Finds the deposit for `order_id` in a successful transaction to the escrow contract.
+pub async fn find_escrow_deposit<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    contract: Address,
+    tx_hash: &str,
+    order_id: Uuid,
+) -> Result<EscrowDeposit> {
+    let tx_hash = H256::from_str(tx_hash.trim_start_matches("0x"))?;
+    let receipt = web3
+        .eth()
+        .transaction_receipt(tx_hash)
+        .await?
+        .ok_or_else(|| anyhow!("Transaction not found"))?;
+
+    if receipt.status != Some(1.into()) {
+        return Err(anyhow!("Transaction failed"));
+    }
+
+    let event_topic = H256(keccak256(DEPOSITED_EVENT.as_bytes()));
+    let order_key = escrow_order_key(order_id);
+    let log = receipt
+        .logs
+        .iter()
+        .filter(|log| log.address == contract && log.topics.len() == 4)
+        .find(|log| log.topics[0] == event_topic && log.topics[1] == order_key)
+        .ok_or_else(|| anyhow!("Escrow deposit for this order not found"))?;
+
+    let data = &log.data.0;
+    if data.len() != 96 {
+        return Err(anyhow!("Malformed escrow deposit event"));
+    }
+
+    Ok(EscrowDeposit {
+        buyer: Address::from(log.topics[2]),
+        seller: Address::from(log.topics[3]),
+        token: Address::from_slice(&data[12..32]),
+        amount: U256::from_big_endian(&data[32..64]),
+        release_after: U256::from_big_endian(&data[64..96]).low_u64(),
+    })
+}
+
+/// Encodes `function(address buyer, bytes32 orderId)` for the buyer's escrow of the order.
+pub fn escrow_calldata(function: &str, buyer: Address, order_id: Uuid) -> Vec<u8> {
+    let mut data = ethabi::short_signature(
+        function,
+        &[
+            ethabi::ParamType::Address,
+            ethabi::ParamType::FixedBytes(32),
+        ],
+    )
+    .to_vec();
+    data.extend(ethabi::encode(&[
+        ethabi::Token::Address(buyer),
+        ethabi::Token::FixedBytes(escrow_order_key(order_id).as_bytes().to_vec()),
+    ]));
+    data
+}
+
+/// Calls `function(address buyer, bytes32 orderId)` on the escrow contract
+/// from the arbiter wallet and waits for it to be mined.
+pub async fn send_escrow_call<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    contract: Address,
+    function: &str,
+    buyer: Address,
+    order_id: Uuid,
+) -> Result<String> {
+    let arbiter = Signer::from_env("ESCROW_ARBITER_KEY")?;
+
+    let data = escrow_calldata(function, buyer, order_id);
+
+    // Estimating first turns a call the contract would reject, such as
+    // releasing a disputed escrow, into an error before any gas is spent.
+    let gas = web3
+        .eth()
+        .estimate_gas(
+            CallRequest {
+                from: Some(arbiter.address()),
+                to: Some(contract),
+                data: Some(Bytes(data.clone())),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .map_err(|e| anyhow!("Escrow {} would fail: {}", function, e))?;
+
+    let transaction = TransactionParameters {
+        to: Some(contract),
+        data: Bytes(data),
+        gas: gas * 120 / 100,
+        ..Default::default()
+    };
+    let signed = web3
+        .accounts()
+        .sign_transaction(transaction, arbiter)
+        .await?;
+
+    let receipt = web3::confirm::send_raw_transaction_with_confirmation(
+        web3.transport().clone(),
+        signed.raw_transaction,
+        Duration::from_secs(1),
+        1,
+    )
+    .await?;
+
+    if receipt.status != Some(1.into()) {
+        return Err(anyhow!(
+            "Escrow {} reverted in {:?}",
+            function,
+            receipt.transaction_hash
+        ));
+    }
+
+    Ok(format!("{:?}", receipt.transaction_hash))
+}
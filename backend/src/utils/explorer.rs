@@ -0,0 +1,43 @@
+use crate::utils::chain::chain_id;
+
+/// Explorers for the chains the marketplace is deployed on.
+const DEFAULT_EXPLORERS: &[(u64, &str)] = &[
+    (42220, "https://celoscan.io"),
+    (44787, "https://alfajores.celoscan.io"),
+    (11142220, "https://celo-sepolia.blockscout.com"),
+];
+
+/// Base URL of the block explorer for `chain_id`. `BLOCK_EXPLORER_URLS`
+/// (`<chain id>=<url>,...`) adds chains or overrides the defaults.
+pub fn explorer_base_url(chain_id: u64) -> Option<String> {
+    let configured = std::env::var("BLOCK_EXPLORER_URLS").unwrap_or_default();
+    configured
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .find(|(id, _)| id.trim().parse() == Ok(chain_id))
+        .map(|(_, url)| url.trim().to_string())
+        .or_else(|| {
+            DEFAULT_EXPLORERS
+                .iter()
+                .find(|(id, _)| *id == chain_id)
+                .map(|(_, url)| url.to_string())
+        })
+        .map(|url| url.trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+}
+
+fn explorer_url(kind: &str, value: &str) -> Option<String> {
+    explorer_base_url(chain_id()).map(|base| format!("{}/{}/{}", base, kind, value))
+}
+
+pub fn transaction_url(transaction_hash: &str) -> Option<String> {
+    explorer_url("tx", transaction_hash)
+}
+
+pub fn address_url(address: &str) -> Option<String> {
+    explorer_url("address", address)
+}
+
+pub fn token_url(token_address: &str) -> Option<String> {
+    explorer_url("token", token_address)
+}
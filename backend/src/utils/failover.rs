@@ -0,0 +1,265 @@
+use jsonrpc_core::{Call, Value};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use web3::futures::future::BoxFuture;
+use web3::futures::FutureExt;
+use web3::{RequestId, Transport};
+
+/// Timeouts, retries and health thresholds for [`FailoverTransport`].
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    /// How long a single call to one provider may take.
+    pub timeout: Duration,
+    /// Extra passes over the provider list after the first one fails.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled for each one after it.
+    pub backoff: Duration,
+    /// Consecutive failures after which a provider is skipped.
+    pub unhealthy_after: u32,
+    /// How long an unhealthy provider is skipped before it is tried again.
+    pub cooldown: Duration,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_retries: 2,
+            backoff: Duration::from_millis(250),
+            unhealthy_after: 3,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RpcConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        Self {
+            timeout: var("RPC_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.timeout),
+            max_retries: var("RPC_MAX_RETRIES")
+                .map(|retries| retries as u32)
+                .unwrap_or(defaults.max_retries),
+            backoff: var("RPC_RETRY_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.backoff),
+            unhealthy_after: var("RPC_UNHEALTHY_AFTER")
+                .map(|failures| failures.max(1) as u32)
+                .unwrap_or(defaults.unhealthy_after),
+            cooldown: var("RPC_COOLDOWN_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.cooldown),
+        }
+    }
+}
+
+/// A point-in-time view of one provider, as served by `/health/rpc`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub name: String,
+    pub healthy: bool,
+    pub requests: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub last_latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    requests: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    last_latency_ms: Option<u64>,
+    last_error: Option<String>,
+    unhealthy_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Provider<T> {
+    name: String,
+    transport: T,
+    health: Mutex<HealthState>,
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    providers: Vec<Provider<T>>,
+    config: RpcConfig,
+}
+
+/// Sends each JSON-RPC call to the first healthy provider, moving on to the
+/// next when a call errors or times out. Providers are tried in the order
+/// they were configured, so the first one is the primary. A provider that
+/// fails `unhealthy_after` times in a row is skipped for `cooldown`; if every
+/// provider is unhealthy they are all tried anyway.
+///
+/// Errors returned by the node itself (reverts, bad params) are passed
+/// straight back, since another provider would give the same answer.
+#[derive(Debug, Clone)]
+pub struct FailoverTransport<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> FailoverTransport<T> {
+    /// `providers` pairs a display name with its transport. Names end up in
+    /// logs and on the health endpoint, so they should not contain API keys.
+    pub fn new(providers: Vec<(String, T)>, config: RpcConfig) -> Self {
+        assert!(
+            !providers.is_empty(),
+            "At least one RPC provider is required"
+        );
+        let providers = providers
+            .into_iter()
+            .map(|(name, transport)| Provider {
+                name,
+                transport,
+                health: Mutex::new(HealthState::default()),
+            })
+            .collect();
+        Self {
+            inner: Arc::new(Inner { providers, config }),
+        }
+    }
+
+    pub fn health(&self) -> Vec<ProviderHealth> {
+        let now = Instant::now();
+        self.inner
+            .providers
+            .iter()
+            .map(|provider| {
+                let health = provider.health.lock().unwrap();
+                ProviderHealth {
+                    name: provider.name.clone(),
+                    healthy: health.unhealthy_until.is_none_or(|until| until <= now),
+                    requests: health.requests,
+                    failures: health.failures,
+                    consecutive_failures: health.consecutive_failures,
+                    last_latency_ms: health.last_latency_ms,
+                    last_error: health.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl<T> Inner<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    /// Healthy providers first, each group in configured order.
+    fn candidates(&self) -> Vec<&Provider<T>> {
+        let now = Instant::now();
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = self.providers.iter().partition(|provider| {
+            provider
+                .health
+                .lock()
+                .unwrap()
+                .unhealthy_until
+                .is_none_or(|until| until <= now)
+        });
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    fn record_success(&self, provider: &Provider<T>, latency: Duration) {
+        let mut health = provider.health.lock().unwrap();
+        if health.unhealthy_until.is_some() {
+            info!("RPC provider {} recovered", provider.name);
+        }
+        health.requests += 1;
+        health.consecutive_failures = 0;
+        health.last_latency_ms = Some(latency.as_millis() as u64);
+        health.unhealthy_until = None;
+    }
+
+    fn record_failure(&self, provider: &Provider<T>, method: &str, error: &str) {
+        let mut health = provider.health.lock().unwrap();
+        health.requests += 1;
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(error.to_string());
+        warn!(
+            "RPC provider {} failed {}: {}",
+            provider.name, method, error
+        );
+        if health.consecutive_failures >= self.config.unhealthy_after {
+            if health.unhealthy_until.is_none() {
+                warn!(
+                    "RPC provider {} marked unhealthy after {} consecutive failures",
+                    provider.name, health.consecutive_failures
+                );
+            }
+            health.unhealthy_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+
+    async fn execute(self: Arc<Self>, method: String, params: Vec<Value>) -> web3::Result<Value> {
+        let mut last_error = web3::Error::Unreachable;
+        for attempt in 0..=self.config.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(self.config.backoff * 2u32.pow(attempt - 1)).await;
+            }
+            for provider in self.candidates() {
+                let (id, request) = provider.transport.prepare(&method, params.clone());
+                let started = Instant::now();
+                let error = match tokio::time::timeout(
+                    self.config.timeout,
+                    provider.transport.send(id, request),
+                )
+                .await
+                {
+                    Ok(Ok(value)) => {
+                        self.record_success(provider, started.elapsed());
+                        return Ok(value);
+                    }
+                    // The node answered; the request itself is at fault.
+                    Ok(Err(error @ web3::Error::Rpc(_))) => {
+                        self.record_success(provider, started.elapsed());
+                        return Err(error);
+                    }
+                    Ok(Err(error)) => error,
+                    Err(_) => web3::Error::Transport(web3::error::TransportError::Message(
+                        format!("Timed out after {:?}", self.config.timeout),
+                    )),
+                };
+                self.record_failure(provider, &method, &error.to_string());
+                last_error = error;
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl<T> Transport for FailoverTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        self.inner.providers[0].transport.prepare(method, params)
+    }
+
+    fn send(&self, _id: RequestId, request: Call) -> Self::Out {
+        let Call::MethodCall(call) = request else {
+            return async { Err(web3::Error::Internal) }.boxed();
+        };
+        let params = match call.params {
+            jsonrpc_core::Params::Array(params) => params,
+            jsonrpc_core::Params::Map(map) => vec![Value::Object(map)],
+            jsonrpc_core::Params::None => vec![],
+        };
+        self.inner.clone().execute(call.method, params).boxed()
+    }
+}
@@ -0,0 +1,463 @@
+use crate::utils::escrow::escrow_order_key;
+use crate::utils::tokens::{to_base_units, Token};
+use jsonrpc_core::{Call, Value};
+use serde_json::json;
+use sqlx::types::Decimal;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use web3::futures::future::{pending, ready, BoxFuture};
+use web3::futures::FutureExt;
+use web3::signing::keccak256;
+use web3::types::{Address, Block, Bytes, Log, Transaction, TransactionReceipt, H256, U256, U64};
+use web3::{RequestId, Transport};
+
+const TRANSFER_TOPIC: &str = "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+const DEPOSITED_EVENT: &str = "Deposited(bytes32,address,address,address,uint256,uint64)";
+
+/// An in-process chain for tests. It serves the receipts, transactions,
+/// contract calls and block height it has been scripted with, and can be told
+/// to fail the next call to a method. Clones share the same chain.
+#[derive(Debug, Clone, Default)]
+pub struct FakeChain {
+    state: Arc<Mutex<FakeChainState>>,
+}
+
+#[derive(Debug, Default)]
+struct FakeChainState {
+    block_number: u64,
+    transaction_count: u64,
+    receipts: HashMap<H256, TransactionReceipt>,
+    transactions: HashMap<H256, Transaction>,
+    contract_calls: HashMap<(Address, [u8; 4]), Vec<u8>>,
+    balances: HashMap<Address, U256>,
+    failures: HashMap<String, VecDeque<String>>,
+    stalls: HashMap<String, u32>,
+    requests: Vec<String>,
+    sent_transactions: Vec<Bytes>,
+    mine_sent: bool,
+}
+
+fn parse_address(address: &str) -> Address {
+    Address::from_str(address.trim_start_matches("0x")).expect("Invalid address")
+}
+
+fn address_topic(address: Address) -> H256 {
+    let mut topic = [0u8; 32];
+    topic[12..].copy_from_slice(address.as_bytes());
+    H256(topic)
+}
+
+impl FakeChain {
+    pub fn new() -> Self {
+        let chain = Self::default();
+        chain.set_block_number(100);
+        chain
+    }
+
+    /// Blocks are five seconds apart, like Celo's.
+    pub fn block_timestamp(block_number: u64) -> u64 {
+        1_700_000_000 + block_number * 5
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.state.lock().unwrap().block_number
+    }
+
+    pub fn set_block_number(&self, block_number: u64) {
+        self.state.lock().unwrap().block_number = block_number;
+    }
+
+    /// Advances the head, adding confirmations to everything already mined.
+    pub fn mine(&self, blocks: u64) {
+        self.state.lock().unwrap().block_number += blocks;
+    }
+
+    /// Adds a mined transaction and returns its hash. A `None` block leaves it pending.
+    pub fn add_transaction(
+        &self,
+        transaction: Transaction,
+        status: u64,
+        block_number: Option<u64>,
+        logs: Vec<Log>,
+    ) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.transaction_count += 1;
+        let hash = H256::from_low_u64_be(0xfa4e_0000 + state.transaction_count);
+        let block_number = block_number.map(U64::from);
+
+        let logs = logs
+            .into_iter()
+            .enumerate()
+            .map(|(index, log)| Log {
+                block_number,
+                transaction_hash: Some(hash),
+                log_index: Some(index.into()),
+                ..log
+            })
+            .collect();
+        let receipt = TransactionReceipt {
+            transaction_hash: hash,
+            block_number,
+            from: transaction.from.unwrap_or_default(),
+            to: transaction.to,
+            status: Some(status.into()),
+            logs,
+            ..Default::default()
+        };
+
+        state.receipts.insert(hash, receipt);
+        state.transactions.insert(
+            hash,
+            Transaction {
+                hash,
+                block_number,
+                ..transaction
+            },
+        );
+        format!("{:?}", hash)
+    }
+
+    /// A successful ERC-20 transfer mined in the current head block.
+    pub fn erc20_transfer(&self, token: &Token, from: &str, to: &str, amount: Decimal) -> String {
+        self.erc20_batch_transfer(token, from, &[(to, amount)])
+    }
+
+    /// One transaction that moves `token` to several recipients, as a
+    /// multicall or batching wallet would, mined in the current head block.
+    pub fn erc20_batch_transfer(
+        &self,
+        token: &Token,
+        from: &str,
+        transfers: &[(&str, Decimal)],
+    ) -> String {
+        let contract = parse_address(token.address);
+        let logs = transfers
+            .iter()
+            .map(|(to, amount)| {
+                let units = to_base_units(*amount, token.decimals).expect("Invalid amount");
+                let mut data = [0u8; 32];
+                units.to_big_endian(&mut data);
+                Log {
+                    address: contract,
+                    topics: vec![
+                        H256::from_str(TRANSFER_TOPIC).unwrap(),
+                        address_topic(parse_address(from)),
+                        address_topic(parse_address(to)),
+                    ],
+                    data: Bytes(data.to_vec()),
+                    block_hash: None,
+                    block_number: None,
+                    transaction_hash: None,
+                    transaction_index: None,
+                    log_index: None,
+                    transaction_log_index: None,
+                    log_type: None,
+                    removed: None,
+                }
+            })
+            .collect();
+        let transaction = Transaction {
+            from: Some(parse_address(from)),
+            to: Some(contract),
+            ..Default::default()
+        };
+        self.add_transaction(transaction, 1, Some(self.block_number()), logs)
+    }
+
+    /// A buyer's deposit of `amount` into the escrow contract for `order_id`,
+    /// mined in the current head block and releasable a week later.
+    pub fn escrow_deposit(
+        &self,
+        contract: &str,
+        order_id: Uuid,
+        from: &str,
+        seller: &str,
+        token: &Token,
+        amount: Decimal,
+    ) -> String {
+        let release_after = Self::block_timestamp(self.block_number()) + 7 * 24 * 60 * 60;
+        let mut data = [0u8; 96];
+        data[12..32].copy_from_slice(parse_address(token.address).as_bytes());
+        to_base_units(amount, token.decimals)
+            .expect("Invalid amount")
+            .to_big_endian(&mut data[32..64]);
+        U256::from(release_after).to_big_endian(&mut data[64..96]);
+        let log = Log {
+            address: parse_address(contract),
+            topics: vec![
+                H256(keccak256(DEPOSITED_EVENT.as_bytes())),
+                escrow_order_key(order_id),
+                address_topic(parse_address(from)),
+                address_topic(parse_address(seller)),
+            ],
+            data: Bytes(data.to_vec()),
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_index: None,
+            log_index: None,
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        };
+        let transaction = Transaction {
+            from: Some(parse_address(from)),
+            to: Some(parse_address(contract)),
+            ..Default::default()
+        };
+        self.add_transaction(transaction, 1, Some(self.block_number()), vec![log])
+    }
+
+    /// A successful transfer of the chain's native coin.
+    pub fn native_transfer(&self, from: &str, to: &str, amount: Decimal) -> String {
+        let transaction = Transaction {
+            from: Some(parse_address(from)),
+            to: Some(parse_address(to)),
+            value: to_base_units(amount, 18).expect("Invalid amount"),
+            ..Default::default()
+        };
+        self.add_transaction(transaction, 1, Some(self.block_number()), vec![])
+    }
+
+    /// A transaction that was mined but reverted.
+    pub fn reverted_transaction(&self, from: &str, to: &str) -> String {
+        let transaction = Transaction {
+            from: Some(parse_address(from)),
+            to: Some(parse_address(to)),
+            ..Default::default()
+        };
+        self.add_transaction(transaction, 0, Some(self.block_number()), vec![])
+    }
+
+    /// Answers `eth_call`s of `function` on `contract` with ABI-encoded `output`.
+    pub fn respond_to_call(&self, contract: &str, function: &str, output: Vec<u8>) {
+        let mut selector = [0u8; 4];
+        selector.copy_from_slice(&keccak256(function.as_bytes())[..4]);
+        self.state
+            .lock()
+            .unwrap()
+            .contract_calls
+            .insert((parse_address(contract), selector), output);
+    }
+
+    /// Sets the CELO balance `eth_getBalance` reports for `address`.
+    pub fn set_native_balance(&self, address: &str, amount: Decimal) {
+        let amount = to_base_units(amount, 18).expect("Invalid amount");
+        self.state
+            .lock()
+            .unwrap()
+            .balances
+            .insert(parse_address(address), amount);
+    }
+
+    /// Makes the next call to `method` fail with a transport error.
+    pub fn fail_next(&self, method: &str, message: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(method.to_string())
+            .or_default()
+            .push_back(message.to_string());
+    }
+
+    /// Makes the next call to `method` hang forever, as a dead node would.
+    pub fn stall_next(&self, method: &str) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .stalls
+            .entry(method.to_string())
+            .or_default() += 1;
+    }
+
+    /// Mines every raw transaction sent from now on in the head block, with a
+    /// successful receipt, as a dev node with automining would.
+    pub fn mine_sent_transactions(&self) {
+        self.state.lock().unwrap().mine_sent = true;
+    }
+
+    /// Raw transactions sent so far, in order.
+    pub fn sent_transactions(&self) -> Vec<Bytes> {
+        self.state.lock().unwrap().sent_transactions.clone()
+    }
+
+    /// JSON-RPC methods called so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    fn respond(&self, method: &str, params: &[Value]) -> web3::Result<Value> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(method.to_string());
+
+        if let Some(message) = state
+            .failures
+            .get_mut(method)
+            .and_then(|failures| failures.pop_front())
+        {
+            return Err(web3::Error::Transport(
+                web3::error::TransportError::Message(message),
+            ));
+        }
+
+        let hash_param = || -> web3::Result<H256> {
+            serde_json::from_value(params.first().cloned().unwrap_or_default())
+                .map_err(|e| web3::Error::Decoder(e.to_string()))
+        };
+
+        match method {
+            "eth_blockNumber" => Ok(json!(U64::from(state.block_number))),
+            "eth_chainId" => Ok(json!(U64::from(44787))),
+            "eth_gasPrice" => Ok(json!(U256::from(1_000_000_000u64))),
+            "eth_estimateGas" => Ok(json!(U256::from(100_000u64))),
+            "eth_getTransactionCount" => Ok(json!(U256::from(state.sent_transactions.len()))),
+            "eth_getBlockByNumber" => {
+                let number: U64 = serde_json::from_value(params[0].clone())
+                    .map_err(|e| web3::Error::Decoder(e.to_string()))?;
+                if number.as_u64() > state.block_number {
+                    return Ok(Value::Null);
+                }
+                Ok(json!(Block::<H256> {
+                    number: Some(number),
+                    timestamp: Self::block_timestamp(number.as_u64()).into(),
+                    ..Default::default()
+                }))
+            }
+            "eth_getBalance" => {
+                let address: Address = serde_json::from_value(params[0].clone())
+                    .map_err(|e| web3::Error::Decoder(e.to_string()))?;
+                Ok(json!(state
+                    .balances
+                    .get(&address)
+                    .copied()
+                    .unwrap_or_default()))
+            }
+            "eth_getTransactionReceipt" => Ok(json!(state.receipts.get(&hash_param()?))),
+            "eth_getTransactionByHash" => Ok(json!(state.transactions.get(&hash_param()?))),
+            "eth_sendRawTransaction" => {
+                let raw: Bytes = serde_json::from_value(params[0].clone())
+                    .map_err(|e| web3::Error::Decoder(e.to_string()))?;
+                let hash = H256(keccak256(&raw.0));
+                state.sent_transactions.push(raw);
+                if state.mine_sent {
+                    let receipt = TransactionReceipt {
+                        transaction_hash: hash,
+                        block_number: Some(state.block_number.into()),
+                        status: Some(1.into()),
+                        ..Default::default()
+                    };
+                    state.receipts.insert(hash, receipt);
+                }
+                Ok(json!(hash))
+            }
+            "eth_newBlockFilter" => Ok(json!(U256::one())),
+            // Each poll of the block filter mines one new block.
+            "eth_getFilterChanges" => {
+                state.block_number += 1;
+                Ok(json!([H256::from_low_u64_be(state.block_number)]))
+            }
+            "eth_call" => {
+                let to: Address = serde_json::from_value(params[0]["to"].clone())
+                    .map_err(|e| web3::Error::Decoder(e.to_string()))?;
+                let data: Bytes = serde_json::from_value(params[0]["data"].clone())
+                    .map_err(|e| web3::Error::Decoder(e.to_string()))?;
+                let mut selector = [0u8; 4];
+                selector.copy_from_slice(&data.0[..4]);
+                state
+                    .contract_calls
+                    .get(&(to, selector))
+                    .map(|output| json!(Bytes(output.clone())))
+                    .ok_or_else(|| web3::Error::InvalidResponse("execution reverted".to_string()))
+            }
+            "eth_getLogs" => {
+                let filter = &params[0];
+                let block = |key: &str| {
+                    filter[key].as_str().and_then(|block| {
+                        u64::from_str_radix(block.trim_start_matches("0x"), 16).ok()
+                    })
+                };
+                let from_block = block("fromBlock").unwrap_or(0);
+                let to_block = block("toBlock").unwrap_or(state.block_number);
+                let matches = |value: &Value, expected: H256| match value {
+                    Value::Null => true,
+                    Value::Array(options) => options.iter().any(|option| {
+                        serde_json::from_value::<H256>(option.clone()).ok() == Some(expected)
+                    }),
+                    option => serde_json::from_value::<H256>(option.clone()).ok() == Some(expected),
+                };
+                let address_matches = |address: Address| match &filter["address"] {
+                    Value::Null => true,
+                    value => {
+                        matches(value, address_topic(address))
+                            || serde_json::from_value::<Vec<Address>>(value.clone())
+                                .map(|addresses| addresses.contains(&address))
+                                .unwrap_or_else(|_| {
+                                    serde_json::from_value::<Address>(value.clone()).ok()
+                                        == Some(address)
+                                })
+                    }
+                };
+
+                let mut logs: Vec<&Log> = state
+                    .receipts
+                    .values()
+                    .flat_map(|receipt| receipt.logs.iter())
+                    .filter(|log| {
+                        let block = log.block_number.map_or(0, |block| block.as_u64());
+                        (from_block..=to_block).contains(&block)
+                    })
+                    .filter(|log| address_matches(log.address))
+                    .filter(|log| {
+                        filter["topics"].as_array().is_none_or(|topics| {
+                            topics.iter().enumerate().all(|(index, expected)| {
+                                expected.is_null()
+                                    || log
+                                        .topics
+                                        .get(index)
+                                        .is_some_and(|topic| matches(expected, *topic))
+                            })
+                        })
+                    })
+                    .collect();
+                logs.sort_by_key(|log| (log.block_number, log.transaction_hash, log.log_index));
+                Ok(json!(logs))
+            }
+            _ => Err(web3::Error::Rpc(jsonrpc_core::Error::method_not_found())),
+        }
+    }
+}
+
+impl Transport for FakeChain {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        (0, web3::helpers::build_request(0, method, params))
+    }
+
+    fn send(&self, _id: RequestId, request: Call) -> Self::Out {
+        let Call::MethodCall(call) = request else {
+            return ready(Err(web3::Error::Internal)).boxed();
+        };
+        if let Some(stalls) = self
+            .state
+            .lock()
+            .unwrap()
+            .stalls
+            .get_mut(&call.method)
+            .filter(|stalls| **stalls > 0)
+        {
+            *stalls -= 1;
+            return pending().boxed();
+        }
+        let params = match call.params {
+            jsonrpc_core::Params::Array(params) => params,
+            jsonrpc_core::Params::Map(map) => vec![Value::Object(map)],
+            jsonrpc_core::Params::None => vec![],
+        };
+        ready(self.respond(&call.method, &params)).boxed()
+    }
+}
@@ -0,0 +1,67 @@
+use sqlx::types::Decimal;
+
+/// The platform's cut of a sale: a percentage in basis points plus a flat
+/// amount per order. The flat amount is set in the default currency (USD) and
+/// must be `converted` into the payment token before `fee_for` is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub bps: u32,
+    pub flat: Decimal,
+}
+
+impl FeeSchedule {
+    pub const NONE: FeeSchedule = FeeSchedule {
+        bps: 0,
+        flat: Decimal::ZERO,
+    };
+
+    /// The platform default from `PLATFORM_FEE_BPS` and `PLATFORM_FEE_FLAT`,
+    /// with any per-store overrides applied. No fee is charged until a
+    /// treasury wallet is configured to receive it.
+    pub fn for_store(bps_override: Option<i32>, flat_override: Option<Decimal>) -> Self {
+        if treasury_address().is_none() {
+            return Self::NONE;
+        }
+
+        let bps = bps_override
+            .and_then(|bps| u32::try_from(bps).ok())
+            .or_else(|| {
+                std::env::var("PLATFORM_FEE_BPS")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+            })
+            .unwrap_or(0)
+            .min(10_000);
+        let flat = flat_override
+            .or_else(|| {
+                std::env::var("PLATFORM_FEE_FLAT")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+            })
+            .unwrap_or(Decimal::ZERO);
+
+        Self { bps, flat }
+    }
+
+    /// The schedule with its flat amount converted into the payment token at `rate`.
+    pub fn converted(self, rate: Decimal) -> Self {
+        Self {
+            flat: (self.flat * rate).round_dp(2),
+            ..self
+        }
+    }
+
+    /// The fee on an order of `amount`, rounded to cents and never more
+    /// than the order itself.
+    pub fn fee_for(&self, amount: Decimal) -> Decimal {
+        let fee = amount * Decimal::from(self.bps) / Decimal::from(10_000) + self.flat;
+        fee.round_dp(2).clamp(Decimal::ZERO, amount)
+    }
+}
+
+/// Wallet that collects platform fees, from `PLATFORM_TREASURY_ADDRESS`.
+pub fn treasury_address() -> Option<String> {
+    std::env::var("PLATFORM_TREASURY_ADDRESS")
+        .ok()
+        .filter(|address| !address.is_empty())
+}
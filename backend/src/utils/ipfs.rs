@@ -103,3 +103,49 @@ pub async fn upload_to_ipfs(
 //         .ok_or("Failed to get CID")?
 //         .to_string())
 // }
+
+/// Pins a JSON document, such as NFT metadata, and returns its CID.
+pub async fn upload_json_to_ipfs(
+    state: &AppState,
+    name: &str,
+    content: &serde_json::Value,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    if cfg!(test) {
+        return Ok("mock_cid".to_string());
+    }
+
+    let url = "https://api.pinata.cloud/pinning/pinJSONToIPFS";
+
+    let body = serde_json::json!({
+        "pinataContent": content,
+        "pinataMetadata": {
+            "name": name,
+            "keyvalues": {
+                "source": "marketplace_backend"
+            }
+        }
+    });
+
+    let response = state
+        .pinata_client
+        .post(url)
+        .header("pinata_api_key", &state.pinata_api_key)
+        .header("pinata_secret_api_key", &state.pinata_secret_key)
+        .json(&body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        return Err(format!("Pinata API error: {}", error_text).into());
+    }
+
+    let json: serde_json::Value = response.json().await?;
+
+    let ipfs_hash = json
+        .get("IpfsHash")
+        .and_then(|hash| hash.as_str())
+        .ok_or("Failed to get CID from Pinata response")?;
+
+    Ok(ipfs_hash.to_string())
+}
@@ -1 +1,19 @@
+pub mod balances;
+pub mod carriers;
+pub mod chain;
+pub mod eip191;
+pub mod eip681;
+pub mod escrow;
+pub mod explorer;
+pub mod failover;
+#[cfg(test)]
+pub mod fake_chain;
+pub mod fees;
 pub mod ipfs;
+pub mod pricing;
+pub mod qr;
+pub mod receipts;
+pub mod signer;
+pub mod token_gates;
+pub mod tokens;
+pub mod transport;
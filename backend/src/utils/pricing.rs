@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::types::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Currency products are priced in when none is given. cUSD tracks it 1:1.
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+/// How many units of a payment token one unit of a fiat currency buys, and
+/// until when that rate may be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceQuote {
+    pub rate: Decimal,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Somewhere to get exchange rates from. Pricing only ever goes through
+/// this trait, so sources can be swapped without touching checkout.
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    async fn quote(&self, currency: &str, token_symbol: &str) -> Result<PriceQuote>;
+}
+
+/// Upper-cases a currency code and checks it looks like ISO 4217.
+pub fn normalize_currency(currency: &str) -> Result<String> {
+    let currency = currency.trim().to_ascii_uppercase();
+    if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(anyhow!("Invalid currency code: {}", currency));
+    }
+    Ok(currency)
+}
+
+/// Converts a fiat price into the payment token at the quoted rate.
+pub fn convert_price(price: Decimal, quote: &PriceQuote) -> Decimal {
+    (price * quote.rate).round_dp(2)
+}
+
+fn quote_ttl() -> chrono::Duration {
+    let secs = std::env::var("PRICE_QUOTE_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(300);
+    chrono::Duration::seconds(secs)
+}
+
+/// Fixed rates, configured as `PRICE_TABLE="NGN/cUSD=0.00065,KES/cUSD=0.0077"`.
+/// USD to cUSD is always 1.
+pub struct StaticPriceSource {
+    rates: HashMap<(String, String), Decimal>,
+}
+
+impl StaticPriceSource {
+    pub fn new(rates: impl IntoIterator<Item = (String, String, Decimal)>) -> Self {
+        let mut table = HashMap::from([(
+            (DEFAULT_CURRENCY.to_string(), "CUSD".to_string()),
+            Decimal::ONE,
+        )]);
+        for (currency, token_symbol, rate) in rates {
+            table.insert(
+                (
+                    currency.to_ascii_uppercase(),
+                    token_symbol.to_ascii_uppercase(),
+                ),
+                rate,
+            );
+        }
+        Self { rates: table }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let table = std::env::var("PRICE_TABLE").unwrap_or_default();
+        let mut rates = Vec::new();
+        for entry in table.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (pair, rate) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid PRICE_TABLE entry: {}", entry))?;
+            let (currency, token_symbol) = pair
+                .split_once('/')
+                .ok_or_else(|| anyhow!("Invalid PRICE_TABLE entry: {}", entry))?;
+            let rate = Decimal::from_str(rate.trim())
+                .map_err(|_| anyhow!("Invalid rate in PRICE_TABLE entry: {}", entry))?;
+            rates.push((
+                currency.trim().to_string(),
+                token_symbol.trim().to_string(),
+                rate,
+            ));
+        }
+        Ok(Self::new(rates))
+    }
+}
+
+#[async_trait]
+impl PriceSource for StaticPriceSource {
+    async fn quote(&self, currency: &str, token_symbol: &str) -> Result<PriceQuote> {
+        let key = (
+            currency.to_ascii_uppercase(),
+            token_symbol.to_ascii_uppercase(),
+        );
+        let rate = self
+            .rates
+            .get(&key)
+            .ok_or_else(|| anyhow!("No {} price for {}", token_symbol, currency))?;
+        Ok(PriceQuote {
+            rate: *rate,
+            expires_at: Utc::now() + quote_ttl(),
+        })
+    }
+}
+
+/// Asks an HTTP service for rates: `GET {url}?currency=NGN&token=cUSD`
+/// answering `{"rate": "0.00065", "expires_at": "..."}`. `expires_at` is
+/// optional and defaults to `PRICE_QUOTE_TTL_SECS` from now.
+pub struct HttpPriceSource {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct HttpQuote {
+    rate: Decimal,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl HttpPriceSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl PriceSource for HttpPriceSource {
+    async fn quote(&self, currency: &str, token_symbol: &str) -> Result<PriceQuote> {
+        let quote: HttpQuote = self
+            .client
+            .get(&self.url)
+            .query(&[("currency", currency), ("token", token_symbol)])
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if quote.rate <= Decimal::ZERO {
+            return Err(anyhow!("Price source returned a non-positive rate"));
+        }
+        Ok(PriceQuote {
+            rate: quote.rate,
+            expires_at: quote.expires_at.unwrap_or_else(|| Utc::now() + quote_ttl()),
+        })
+    }
+}
+
+/// `HttpPriceSource` when `PRICE_SOURCE_URL` is set, otherwise the static table.
+pub fn price_source_from_env() -> Result<std::sync::Arc<dyn PriceSource>> {
+    match std::env::var("PRICE_SOURCE_URL") {
+        Ok(url) if !url.is_empty() => Ok(std::sync::Arc::new(HttpPriceSource::new(url))),
+        _ => Ok(std::sync::Arc::new(StaticPriceSource::from_env()?)),
+    }
+}
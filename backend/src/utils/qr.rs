@@ -0,0 +1,27 @@
+use anyhow::Result;
+use image::{ImageFormat, Luma};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use std::io::Cursor;
+
+pub fn qr_svg(data: &str) -> Result<String> {
+    let code = QrCode::new(data.as_bytes())?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build())
+}
+
+pub fn qr_png(data: &str) -> Result<Vec<u8>> {
+    let code = QrCode::new(data.as_bytes())?;
+    let image = code
+        .render::<Luma<u8>>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build();
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
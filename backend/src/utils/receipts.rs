@@ -0,0 +1,42 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use web3::signing::keccak256;
+use web3::types::{Address, TransactionReceipt, H256};
+
+const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+
+/// Address of the deployed MiniPay receipt NFT contract, if receipts are enabled.
+pub fn receipt_contract() -> Option<Address> {
+    std::env::var("MINIPAY_CONTRACT_ADDRESS")
+        .ok()
+        .and_then(|address| Address::from_str(address.trim_start_matches("0x")).ok())
+}
+
+/// Calldata for `safeMint(address to, string uri)`.
+pub fn safe_mint_calldata(to: &str, uri: &str) -> Result<Vec<u8>> {
+    let to = Address::from_str(to.trim_start_matches("0x"))
+        .map_err(|_| anyhow!("Invalid receipt owner address"))?;
+
+    let mut data = ethabi::short_signature(
+        "safeMint",
+        &[ethabi::ParamType::Address, ethabi::ParamType::String],
+    )
+    .to_vec();
+    data.extend(ethabi::encode(&[
+        ethabi::Token::Address(to),
+        ethabi::Token::String(uri.to_string()),
+    ]));
+    Ok(data)
+}
+
+/// The token id of the NFT `contract` minted in a transaction. ERC-721 indexes
+/// all three Transfer arguments, so a mint has four topics and a zero sender.
+pub fn minted_token_id(receipt: &TransactionReceipt, contract: Address) -> Option<u64> {
+    let event_topic = H256(keccak256(TRANSFER_EVENT.as_bytes()));
+    receipt
+        .logs
+        .iter()
+        .filter(|log| log.address == contract && log.topics.len() == 4)
+        .find(|log| log.topics[0] == event_topic && log.topics[1] == H256::zero())
+        .map(|log| web3::types::U256::from_big_endian(log.topics[3].as_bytes()).low_u64())
+}
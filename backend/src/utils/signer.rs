@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Result};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use web3::signing::{keccak256, Key, Signature, SigningError};
+use web3::types::{Address, H256};
+
+/// A backend-held private key that web3 can sign transactions with.
+#[derive(Clone)]
+pub struct Signer {
+    key: SecretKey,
+    address: Address,
+}
+
+impl Signer {
+    pub fn from_hex(private_key: &str) -> Result<Self> {
+        let bytes = hex::decode(private_key.trim().trim_start_matches("0x"))
+            .map_err(|_| anyhow!("Private key is not valid hex"))?;
+        let key = SecretKey::from_slice(&bytes).map_err(|_| anyhow!("Invalid private key"))?;
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &key);
+        let hash = keccak256(&public_key.serialize_uncompressed()[1..]);
+
+        Ok(Self {
+            key,
+            address: Address::from_slice(&hash[12..]),
+        })
+    }
+
+    /// Loads the key held in the given environment variable.
+    pub fn from_env(name: &str) -> Result<Self> {
+        let private_key = std::env::var(name).map_err(|_| anyhow!("{} must be set", name))?;
+        Self::from_hex(&private_key)
+    }
+
+    fn sign_recoverable(&self, message: &[u8]) -> Result<(u64, H256, H256), SigningError> {
+        let message = Message::from_slice(message).map_err(|_| SigningError::InvalidMessage)?;
+        let (recovery_id, signature) = Secp256k1::new()
+            .sign_ecdsa_recoverable(&message, &self.key)
+            .serialize_compact();
+
+        Ok((
+            recovery_id.to_i32() as u64,
+            H256::from_slice(&signature[..32]),
+            H256::from_slice(&signature[32..]),
+        ))
+    }
+}
+
+impl Key for Signer {
+    fn sign(&self, message: &[u8], chain_id: Option<u64>) -> Result<Signature, SigningError> {
+        let (recovery_id, r, s) = self.sign_recoverable(message)?;
+        let v = match chain_id {
+            Some(chain_id) => recovery_id + 35 + chain_id * 2,
+            None => recovery_id + 27,
+        };
+        Ok(Signature { v, r, s })
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, SigningError> {
+        let (v, r, s) = self.sign_recoverable(message)?;
+        Ok(Signature { v, r, s })
+    }
+
+    fn address(&self) -> Address {
+        self.address
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use web3::types::{Address, Bytes, CallRequest, U256};
+
+type HoldingKey = (Address, Option<u64>, Address);
+
+/// Remembers recent NFT holding checks so browsing and checkout do not call
+/// the chain for every cart line.
+pub struct TokenGateCache {
+    ttl: Duration,
+    holdings: Mutex<HashMap<HoldingKey, (bool, Instant)>>,
+}
+
+impl TokenGateCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            holdings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Caches for `TOKEN_GATE_CACHE_SECS`, 60 seconds by default.
+    pub fn from_env() -> Self {
+        let secs = std::env::var("TOKEN_GATE_CACHE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
+        Self::new(Duration::from_secs(secs))
+    }
+
+    /// Whether `holder` owns `token_id` of `contract`, or any token of it when
+    /// `token_id` is `None`.
+    pub async fn holds<T: web3::Transport>(
+        &self,
+        web3: &web3::Web3<T>,
+        contract: &str,
+        token_id: Option<u64>,
+        holder: &str,
+    ) -> Result<bool> {
+        let contract = parse_address(contract)?;
+        let holder = parse_address(holder)?;
+        let key = (contract, token_id, holder);
+
+        let cached = self.holdings.lock().unwrap().get(&key).copied();
+        if let Some((held, _)) = cached.filter(|(_, at)| at.elapsed() < self.ttl) {
+            return Ok(held);
+        }
+
+        let held = match token_id {
+            Some(token_id) => owned_token_ids(web3, contract, holder)
+                .await?
+                .contains(&U256::from(token_id)),
+            None => balance_of(web3, contract, holder).await? > U256::zero(),
+        };
+
+        let mut holdings = self.holdings.lock().unwrap();
+        holdings.retain(|_, (_, checked_at)| checked_at.elapsed() < self.ttl);
+        holdings.insert(key, (held, Instant::now()));
+        Ok(held)
+    }
+}
+
+fn parse_address(address: &str) -> Result<Address> {
+    Address::from_str(address.trim_start_matches("0x"))
+        .map_err(|_| anyhow!("Invalid address: {}", address))
+}
+
+async fn call<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    contract: Address,
+    function: &str,
+    owner: Address,
+    output: ethabi::ParamType,
+) -> Result<ethabi::Token> {
+    let mut data = ethabi::short_signature(function, &[ethabi::ParamType::Address]).to_vec();
+    data.extend(ethabi::encode(&[ethabi::Token::Address(owner)]));
+
+    let result = web3
+        .eth()
+        .call(
+            CallRequest {
+                to: Some(contract),
+                data: Some(Bytes(data)),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+
+    ethabi::decode(&[output], &result.0)?
+        .pop()
+        .ok_or_else(|| anyhow!("Empty response from {}", function))
+}
+
+/// ERC-721 `balanceOf(address)`.
+async fn balance_of<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    contract: Address,
+    owner: Address,
+) -> Result<U256> {
+    call(
+        web3,
+        contract,
+        "balanceOf",
+        owner,
+        ethabi::ParamType::Uint(256),
+    )
+    .await?
+    .into_uint()
+    .ok_or_else(|| anyhow!("Malformed balanceOf response"))
+}
+
+/// MiniPay.sol `getNFTsByAddress(address)`.
+async fn owned_token_ids<T: web3::Transport>(
+    web3: &web3::Web3<T>,
+    contract: Address,
+    owner: Address,
+) -> Result<Vec<U256>> {
+    let output = ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256)));
+    call(web3, contract, "getNFTsByAddress", owner, output)
+        .await?
+        .into_array()
+        .ok_or_else(|| anyhow!("Malformed getNFTsByAddress response"))?
+        .into_iter()
+        .map(|token| {
+            token
+                .into_uint()
+                .ok_or_else(|| anyhow!("Malformed getNFTsByAddress response"))
+        })
+        .collect()
+}
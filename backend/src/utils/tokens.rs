@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use sqlx::types::Decimal;
+use web3::types::U256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub symbol: &'static str,
+    pub address: &'static str,
+    pub decimals: u32,
+    /// Paid as the transaction value rather than through ERC-20 Transfer logs.
+    pub native: bool,
+}
+
+/// cUSD on Alfajores, the chain `chain::chain_id` defaults to.
+pub const CUSD: Token = Token {
+    symbol: "cUSD",
+    address: "0x874069Fa1Eb16D44d622BC6Cf16451f9B2bE0855",
+    decimals: 18,
+    native: false,
+};
+
+pub const CELO: Token = Token {
+    symbol: "CELO",
+    address: "0x0000000000000000000000000000000000000000",
+    decimals: 18,
+    native: true,
+};
+
+/// Tokens the marketplace accepts as payment.
+pub const TOKENS: &[Token] = &[CUSD, CELO];
+
+pub fn find_token(symbol: &str) -> Option<&'static Token> {
+    TOKENS
+        .iter()
+        .find(|token| token.symbol.eq_ignore_ascii_case(symbol))
+}
+
+pub fn find_token_by_address(address: &str) -> Option<&'static Token> {
+    TOKENS
+        .iter()
+        .find(|token| token.address.eq_ignore_ascii_case(address))
+}
+
+/// Converts a human readable token amount (e.g. `19.99`) into the token's base units.
+pub fn to_base_units(amount: Decimal, decimals: u32) -> Result<U256> {
+    let scaled = amount
+        .checked_mul(Decimal::from(10u64.pow(decimals)))
+        .ok_or_else(|| anyhow!("Amount {} is too large", amount))?;
+    if !scaled.fract().is_zero() {
+        return Err(anyhow!(
+            "Amount {} has more than {} decimal places",
+            amount,
+            decimals
+        ));
+    }
+    U256::from_dec_str(&scaled.trunc().to_string())
+        .map_err(|_| anyhow!("Invalid amount: {}", amount))
+}
+
+/// Converts an on-chain amount in base units back into a token amount.
+pub fn from_base_units(amount: U256, decimals: u32) -> Result<Decimal> {
+    if amount > U256::from(i128::MAX as u128) {
+        return Err(anyhow!("Amount {} is too large", amount));
+    }
+    Decimal::try_from_i128_with_scale(amount.as_u128() as i128, decimals)
+        .map_err(|_| anyhow!("Amount {} is too large", amount))
+}
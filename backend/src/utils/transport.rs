@@ -0,0 +1,62 @@
+use crate::utils::failover::{FailoverTransport, ProviderHealth};
+use jsonrpc_core::{Call, Value};
+use web3::futures::future::BoxFuture;
+use web3::transports::Http;
+use web3::{RequestId, Transport};
+
+#[cfg(test)]
+use crate::utils::fake_chain::FakeChain;
+
+/// How the backend reaches the chain. Production talks JSON-RPC over HTTP to
+/// a list of providers with failover; tests swap in a scripted in-process
+/// chain so no node is needed.
+#[derive(Debug, Clone)]
+pub enum ChainTransport {
+    Http(FailoverTransport<Http>),
+    #[cfg(test)]
+    Fake(FakeChain),
+}
+
+impl ChainTransport {
+    /// Health of each RPC provider; empty when there are none to report on.
+    pub fn provider_health(&self) -> Vec<ProviderHealth> {
+        match self {
+            ChainTransport::Http(transport) => transport.health(),
+            #[cfg(test)]
+            ChainTransport::Fake(_) => vec![],
+        }
+    }
+}
+
+impl From<FailoverTransport<Http>> for ChainTransport {
+    fn from(transport: FailoverTransport<Http>) -> Self {
+        ChainTransport::Http(transport)
+    }
+}
+
+#[cfg(test)]
+impl From<FakeChain> for ChainTransport {
+    fn from(chain: FakeChain) -> Self {
+        ChainTransport::Fake(chain)
+    }
+}
+
+impl Transport for ChainTransport {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        match self {
+            ChainTransport::Http(transport) => transport.prepare(method, params),
+            #[cfg(test)]
+            ChainTransport::Fake(chain) => chain.prepare(method, params),
+        }
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        match self {
+            ChainTransport::Http(transport) => transport.send(id, request),
+            #[cfg(test)]
+            ChainTransport::Fake(chain) => chain.send(id, request),
+        }
+    }
+}
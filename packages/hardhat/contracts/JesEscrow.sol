@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.20;
+
+import "@openzeppelin/contracts/token/ERC20/IERC20.sol";
+import "@openzeppelin/contracts/token/ERC20/utils/SafeERC20.sol";
+import "@openzeppelin/contracts/access/Ownable.sol";
+import "@openzeppelin/contracts/utils/ReentrancyGuard.sol";
+
+/// Holds a buyer's ERC-20 payment for one order until it is delivered.
+/// The owner is the marketplace backend, which releases funds on delivery
+/// and refunds cancelled orders. Anyone can release an escrow once its
+/// timeout has passed, so funds never stay locked if the backend is down,
+/// unless the buyer has disputed it; then only the owner can settle it.
+///
+/// Escrows are keyed by buyer and order, so a deposit made by someone else
+/// under the same order id can never take the buyer's place.
+contract JesEscrow is Ownable, ReentrancyGuard {
+    using SafeERC20 for IERC20;
+
+    enum Status {
+        None,
+        Funded,
+        Released,
+        Refunded,
+        Disputed
+    }
+
+    struct Escrow {
+        address buyer;
+        address seller;
+        address token;
+        uint256 amount;
+        uint64 releaseAfter;
+        Status status;
+    }
+
+    uint64 public releaseTimeout;
+    mapping(bytes32 => Escrow) public escrows;
+
+    event Deposited(
+        bytes32 indexed orderId,
+        address indexed buyer,
+        address indexed seller,
+        address token,
+        uint256 amount,
+        uint64 releaseAfter
+    );
+    event Disputed(bytes32 indexed orderId, address indexed buyer);
+    event Released(bytes32 indexed orderId, address indexed seller, uint256 amount);
+    event Refunded(bytes32 indexed orderId, address indexed buyer, uint256 amount);
+    event ReleaseTimeoutChanged(uint64 releaseTimeout);
+
+    constructor(
+        address initialOwner,
+        uint64 initialReleaseTimeout
+    ) Ownable(initialOwner) {
+        releaseTimeout = initialReleaseTimeout;
+    }
+
+    function setReleaseTimeout(uint64 newReleaseTimeout) public onlyOwner {
+        releaseTimeout = newReleaseTimeout;
+        emit ReleaseTimeoutChanged(newReleaseTimeout);
+    }
+
+    /// The key `escrows` stores the buyer's escrow for an order under.
+    function escrowKey(address buyer, bytes32 orderId) public pure returns (bytes32) {
+        return keccak256(abi.encode(buyer, orderId));
+    }
+
+    /// Pulls `amount` of `token` from the buyer. The buyer must have
+    /// approved this contract first.
+    function deposit(
+        bytes32 orderId,
+        address seller,
+        address token,
+        uint256 amount
+    ) public nonReentrant {
+        Escrow storage escrow = escrows[escrowKey(msg.sender, orderId)];
+        require(escrow.status == Status.None, "Escrow exists");
+        require(seller != address(0), "Invalid seller");
+        require(amount > 0, "Invalid amount");
+
+        uint64 releaseAfter = uint64(block.timestamp) + releaseTimeout;
+        escrow.buyer = msg.sender;
+        escrow.seller = seller;
+        escrow.token = token;
+        escrow.amount = amount;
+        escrow.releaseAfter = releaseAfter;
+        escrow.status = Status.Funded;
+
+        IERC20(token).safeTransferFrom(msg.sender, address(this), amount);
+        emit Deposited(orderId, msg.sender, seller, token, amount, releaseAfter);
+    }
+
+    /// Called by the buyer once the goods have arrived. This also settles a
+    /// dispute the buyer opened.
+    function confirmReceipt(bytes32 orderId) public nonReentrant {
+        _release(msg.sender, orderId);
+    }
+
+    /// Called by the buyer before the timeout to stop the escrow being
+    /// released automatically. The owner then releases or refunds it.
+    function dispute(bytes32 orderId) public {
+        Escrow storage escrow = escrows[escrowKey(msg.sender, orderId)];
+        require(escrow.status == Status.Funded, "Escrow not funded");
+        require(block.timestamp < escrow.releaseAfter, "Escrow expired");
+
+        escrow.status = Status.Disputed;
+        emit Disputed(orderId, msg.sender);
+    }
+
+    /// Called by the backend when the order is delivered or a dispute is
+    /// settled in the seller's favour.
+    function release(address buyer, bytes32 orderId) public onlyOwner nonReentrant {
+        _release(buyer, orderId);
+    }
+
+    /// Releases an escrow whose timeout has passed without a dispute.
+    function releaseExpired(address buyer, bytes32 orderId) public nonReentrant {
+        Escrow storage escrow = escrows[escrowKey(buyer, orderId)];
+        require(escrow.status != Status.Disputed, "Escrow disputed");
+        require(block.timestamp >= escrow.releaseAfter, "Escrow not expired");
+        _release(buyer, orderId);
+    }
+
+    /// Returns the funds to the buyer. The backend does this when an order
+    /// is cancelled or a dispute goes the buyer's way, and a seller may
+    /// always give the money back.
+    function refund(address buyer, bytes32 orderId) public nonReentrant {
+        Escrow storage escrow = escrows[escrowKey(buyer, orderId)];
+        require(
+            msg.sender == owner() || msg.sender == escrow.seller,
+            "Not allowed"
+        );
+        require(_isHeld(escrow), "Escrow not funded");
+
+        escrow.status = Status.Refunded;
+        IERC20(escrow.token).safeTransfer(escrow.buyer, escrow.amount);
+        emit Refunded(orderId, escrow.buyer, escrow.amount);
+    }
+
+    function _release(address buyer, bytes32 orderId) internal {
+        Escrow storage escrow = escrows[escrowKey(buyer, orderId)];
+        require(_isHeld(escrow), "Escrow not funded");
+
+        escrow.status = Status.Released;
+        IERC20(escrow.token).safeTransfer(escrow.seller, escrow.amount);
+        emit Released(orderId, escrow.seller, escrow.amount);
+    }
+
+    function _isHeld(Escrow storage escrow) internal view returns (bool) {
+        return escrow.status == Status.Funded || escrow.status == Status.Disputed;
+    }
+}
@@ -0,0 +1,13 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.20;
+
+import "@openzeppelin/contracts/token/ERC20/ERC20.sol";
+
+// Freely mintable ERC-20 used by the escrow tests
+contract TestToken is ERC20 {
+    constructor() ERC20("Test Dollar", "tUSD") {}
+
+    function mint(address to, uint256 amount) public {
+        _mint(to, amount);
+    }
+}
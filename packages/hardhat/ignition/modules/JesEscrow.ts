@@ -0,0 +1,18 @@
+import { buildModule } from "@nomicfoundation/hardhat-ignition/modules";
+
+const JesEscrowModule = buildModule("JesEscrowModule", (m) => {
+  // The owner is the backend wallet that releases and refunds escrows
+  const initialOwner = m.getParameter(
+    "initialOwner",
+    "0x1724707c52de2fa65ad9c586b5d38507f52D3c06"
+  );
+
+  // Seconds after a deposit before anyone can release it (14 days)
+  const releaseTimeout = m.getParameter("releaseTimeout", 14 * 24 * 60 * 60);
+
+  const escrow = m.contract("JesEscrow", [initialOwner, releaseTimeout]);
+
+  return { escrow };
+});
+
+export default JesEscrowModule;
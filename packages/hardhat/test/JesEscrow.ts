@@ -0,0 +1,120 @@
+import { expect } from "chai";
+import { ethers } from "hardhat";
+import { time } from "@nomicfoundation/hardhat-network-helpers";
+import { SignerWithAddress } from "@nomicfoundation/hardhat-ethers/signers";
+import { JesEscrow, TestToken } from "../typechain-types";
+
+describe("JesEscrow Contract", function () {
+  const TIMEOUT = 7 * 24 * 60 * 60;
+  const AMOUNT = ethers.parseEther("19.98");
+  const ORDER_ID = ethers.zeroPadValue("0x0123456789abcdef0123456789abcdef", 32);
+
+  let escrow: JesEscrow;
+  let token: TestToken;
+  let owner: SignerWithAddress;
+  let buyer: SignerWithAddress;
+  let seller: SignerWithAddress;
+  let stranger: SignerWithAddress;
+
+  beforeEach(async () => {
+    [owner, buyer, seller, stranger] = await ethers.getSigners();
+
+    token = await ethers.deployContract("TestToken");
+    escrow = await ethers.deployContract("JesEscrow", [owner.address, TIMEOUT]);
+
+    await token.mint(buyer.address, AMOUNT);
+    await token.connect(buyer).approve(await escrow.getAddress(), AMOUNT);
+  });
+
+  async function deposit() {
+    await escrow
+      .connect(buyer)
+      .deposit(ORDER_ID, seller.address, await token.getAddress(), AMOUNT);
+  }
+
+  it("should hold the deposit for the order", async () => {
+    await expect(deposit()).to.emit(escrow, "Deposited");
+    expect(await token.balanceOf(await escrow.getAddress())).to.equal(AMOUNT);
+
+    const stored = await escrow.escrows(
+      await escrow.escrowKey(buyer.address, ORDER_ID)
+    );
+    expect(stored.buyer).to.equal(buyer.address);
+    expect(stored.seller).to.equal(seller.address);
+    expect(stored.status).to.equal(1);
+  });
+
+  it("should not accept a second deposit for the same order", async () => {
+    await deposit();
+    await expect(deposit()).to.be.revertedWith("Escrow exists");
+  });
+
+  it("should keep a stranger's deposit for the order apart from the buyer's", async () => {
+    await token.mint(stranger.address, 1n);
+    await token.connect(stranger).approve(await escrow.getAddress(), 1n);
+    await escrow
+      .connect(stranger)
+      .deposit(ORDER_ID, stranger.address, await token.getAddress(), 1n);
+
+    await deposit();
+    const stored = await escrow.escrows(
+      await escrow.escrowKey(buyer.address, ORDER_ID)
+    );
+    expect(stored.seller).to.equal(seller.address);
+    expect(stored.amount).to.equal(AMOUNT);
+  });
+
+  it("should release to the seller when the owner or buyer confirms", async () => {
+    await deposit();
+    await expect(
+      escrow.connect(stranger).release(buyer.address, ORDER_ID)
+    ).to.be.reverted;
+
+    await escrow.connect(buyer).confirmReceipt(ORDER_ID);
+    expect(await token.balanceOf(seller.address)).to.equal(AMOUNT);
+    await expect(escrow.release(buyer.address, ORDER_ID)).to.be.revertedWith(
+      "Escrow not funded"
+    );
+  });
+
+  it("should refund the buyer on cancellation", async () => {
+    await deposit();
+    await expect(
+      escrow.connect(stranger).refund(buyer.address, ORDER_ID)
+    ).to.be.revertedWith("Not allowed");
+
+    await expect(escrow.refund(buyer.address, ORDER_ID))
+      .to.emit(escrow, "Refunded")
+      .withArgs(ORDER_ID, buyer.address, AMOUNT);
+    expect(await token.balanceOf(buyer.address)).to.equal(AMOUNT);
+  });
+
+  it("should let anyone release once the timeout has passed", async () => {
+    await deposit();
+    await expect(
+      escrow.connect(stranger).releaseExpired(buyer.address, ORDER_ID)
+    ).to.be.revertedWith("Escrow not expired");
+
+    await time.increase(TIMEOUT);
+    await escrow.connect(stranger).releaseExpired(buyer.address, ORDER_ID);
+    expect(await token.balanceOf(seller.address)).to.equal(AMOUNT);
+  });
+
+  it("should leave a disputed escrow for the owner to settle", async () => {
+    await deposit();
+    await expect(escrow.connect(stranger).dispute(ORDER_ID)).to.be.revertedWith(
+      "Escrow not funded"
+    );
+    await expect(escrow.connect(buyer).dispute(ORDER_ID))
+      .to.emit(escrow, "Disputed")
+      .withArgs(ORDER_ID, buyer.address);
+
+    await time.increase(TIMEOUT);
+    await expect(
+      escrow.connect(stranger).releaseExpired(buyer.address, ORDER_ID)
+    ).to.be.revertedWith("Escrow disputed");
+
+    await escrow.refund(buyer.address, ORDER_ID);
+    expect(await token.balanceOf(buyer.address)).to.equal(AMOUNT);
+  });
+});